@@ -0,0 +1,112 @@
+//! Benchmarks for the EPP response parser.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ngx_inference::grpc::{parse_response_for_header_async, DEFAULT_UPSTREAM_MAX_LEN};
+use ngx_inference::modules::config::EppHeaderSources;
+use ngx_inference::protos::envoy::config::core::v3::{HeaderValue, HeaderValueOption};
+use ngx_inference::protos::envoy::service::ext_proc::v3::{
+    processing_response, BodyResponse, CommonResponse, HeaderMutation, HeadersResponse,
+    ImmediateResponse, ProcessingResponse,
+};
+
+const TARGET: &str = "x-inference-upstream";
+
+/// Header mutation with `extra` unrelated headers ahead of the upstream header.
+fn mutation(extra: usize) -> HeaderMutation {
+    let mut set_headers: Vec<HeaderValueOption> = (0..extra)
+        .map(|i| HeaderValueOption {
+            header: Some(HeaderValue {
+                key: format!("x-extra-{}", i),
+                value: "value".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .collect();
+    set_headers.push(HeaderValueOption {
+        header: Some(HeaderValue {
+            key: "X-Inference-Upstream".to_string(),
+            raw_value: b"10.0.0.1:8000".to_vec(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    HeaderMutation {
+        set_headers,
+        ..Default::default()
+    }
+}
+
+fn common(extra: usize) -> Option<CommonResponse> {
+    Some(CommonResponse {
+        header_mutation: Some(mutation(extra)),
+        ..Default::default()
+    })
+}
+
+fn responses() -> Vec<(&'static str, ProcessingResponse, EppHeaderSources)> {
+    use processing_response::Response;
+
+    let wrap = |response| ProcessingResponse {
+        response: Some(response),
+        ..Default::default()
+    };
+    vec![
+        (
+            "request_headers",
+            wrap(Response::RequestHeaders(HeadersResponse {
+                response: common(0),
+            })),
+            EppHeaderSources::RequestHeaders,
+        ),
+        (
+            "request_headers_64_extra",
+            wrap(Response::RequestHeaders(HeadersResponse {
+                response: common(64),
+            })),
+            EppHeaderSources::RequestHeaders,
+        ),
+        (
+            "request_body",
+            wrap(Response::RequestBody(BodyResponse {
+                response: common(0),
+            })),
+            EppHeaderSources::RequestHeaders,
+        ),
+        (
+            "immediate_response",
+            wrap(Response::ImmediateResponse(ImmediateResponse {
+                headers: Some(mutation(0)),
+                ..Default::default()
+            })),
+            EppHeaderSources::Any,
+        ),
+        (
+            "response_headers_ignored",
+            wrap(Response::ResponseHeaders(HeadersResponse {
+                response: common(0),
+            })),
+            EppHeaderSources::RequestHeaders,
+        ),
+    ]
+}
+
+fn bench_parse_response(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_response_for_header");
+    for (name, resp, sources) in responses() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &resp, |b, resp| {
+            b.iter(|| {
+                parse_response_for_header_async(
+                    black_box(resp),
+                    TARGET,
+                    sources,
+                    DEFAULT_UPSTREAM_MAX_LEN,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_response);
+criterion_main!(benches);
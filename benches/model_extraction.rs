@@ -0,0 +1,54 @@
+//! Benchmarks for the BBR model extractor.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ngx_inference::model_extractor::extract_model_from_body;
+
+fn small_body() -> Vec<u8> {
+    br#"{"model": "meta-llama/Llama-3.1-8B-Instruct", "messages": [{"role": "user", "content": "Hello"}]}"#
+        .to_vec()
+}
+
+/// Chat request with a long conversation and the model key last.
+fn large_body() -> Vec<u8> {
+    let message = r#"{"role": "user", "content": "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua."}"#;
+    let messages = vec![message; 4096].join(",");
+    format!(
+        r#"{{"messages": [{}], "temperature": 0.7, "model": "gpt-4"}}"#,
+        messages
+    )
+    .into_bytes()
+}
+
+/// Deeply nested tool schema ahead of the model key.
+fn nested_body() -> Vec<u8> {
+    let depth = 64;
+    let nested = format!(
+        "{}\"leaf\"{}",
+        r#"{"type": "object", "properties": "#.repeat(depth),
+        "}".repeat(depth)
+    );
+    format!(r#"{{"tools": [{}], "model": "gpt-4"}}"#, nested).into_bytes()
+}
+
+fn bench_extract_model(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extract_model_from_body");
+    for (name, body) in [
+        ("small", small_body()),
+        ("large", large_body()),
+        ("nested", nested_body()),
+    ] {
+        assert!(
+            extract_model_from_body(&body).is_some(),
+            "{} body has no model",
+            name
+        );
+        group.throughput(Throughput::Bytes(body.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &body, |b, body| {
+            b.iter(|| extract_model_from_body(black_box(body)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_extract_model);
+criterion_main!(benches);
@@ -12,17 +12,34 @@
 //! EPP Mode (port 9001):
 //! - On RequestHeaders: immediately responds with X-Inference-Upstream header
 //!
+//! EPP_BODY Mode (MOCK_ROLE=EPP_BODY):
+//! - On RequestHeaders: responds without a header mutation and a mode_override requesting
+//!   the request body (BUFFERED), unless RequestHeaders already says a body follows
+//! - On RequestBody EndOfStream: responds with X-Inference-Upstream header, and
+//!   X-Mock-Body-Chunks with the number of RequestBody messages received
+//!
+//! EPP_NO_HEADER Mode (MOCK_ROLE=EPP_NO_HEADER):
+//! - On RequestHeaders: responds with an empty header mutation (no X-Inference-Upstream)
+//!
 //! Configuration via environment variables:
 //! - EPP_UPSTREAM: value for X-Inference-Upstream (default: "host.docker.internal:18080")
 //! - BBR_MODEL: fallback model name if not found in JSON (default: "bbr-chosen-model")
+//! - MOCK_ROLE: EPP, EPP_BODY, EPP_NO_HEADER or BBR (default: derived from the port)
+//! - MOCK_DELAY_MS: delay before answering RequestHeaders, to simulate a slow EPP (default: 0)
+//! - MOCK_GZIP: when set to 1, accept and send gzip-compressed gRPC messages (default: off)
+//! - MOCK_FORBID_HEADER: in EPP mode, answer without X-Inference-Upstream when RequestHeaders
+//!   carries this header, so tests can tell whether it was forwarded (default: unset)
+//! - MOCK_EXTRA_HEADER: in EPP mode, `name:value` of a header set next to X-Inference-Upstream
+//!   (default: unset)
 //!
 //! CLI:
 //!   cargo run --bin extproc_mock -- 0.0.0.0:9001  # EPP mode
 //!   cargo run --bin extproc_mock -- 0.0.0.0:9000  # BBR mode
 
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, time::Duration};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
+use tonic::codec::CompressionEncoding;
 use tonic::{Request, Response, Status};
 
 mod protos {
@@ -40,6 +57,9 @@ type BodyResponse = envoy::service::ext_proc::v3::BodyResponse;
 type CommonResponse = envoy::service::ext_proc::v3::common_response::ResponseStatus;
 type HeaderMutation = envoy::service::ext_proc::v3::HeaderMutation;
 
+type ProcessingMode = envoy::extensions::filters::http::ext_proc::v3::ProcessingMode;
+type BodySendMode = envoy::extensions::filters::http::ext_proc::v3::processing_mode::BodySendMode;
+
 type HeaderValue = envoy::config::core::v3::HeaderValue;
 type HeaderValueOption = envoy::config::core::v3::HeaderValueOption;
 
@@ -92,8 +112,45 @@ fn build_headers_response(epp_upstream: &str, _bbr_model: &str) -> HeadersRespon
     }
 }
 
-fn build_body_response(_epp_upstream: &str, bbr_model: &str) -> BodyResponse {
-    let mutation = build_header_mutation_bbr(bbr_model);
+fn build_empty_headers_response() -> HeadersResponse {
+    envoy::service::ext_proc::v3::HeadersResponse {
+        response: Some(envoy::service::ext_proc::v3::CommonResponse {
+            status: CommonResponse::Continue as i32,
+            header_mutation: Some(HeaderMutation::default()),
+            body_mutation: None,
+            trailers: None,
+            clear_route_cache: false,
+        }),
+    }
+}
+
+fn build_body_request_headers_response() -> HeadersResponse {
+    envoy::service::ext_proc::v3::HeadersResponse {
+        response: Some(envoy::service::ext_proc::v3::CommonResponse {
+            status: CommonResponse::Continue as i32,
+            header_mutation: None,
+            body_mutation: None,
+            trailers: None,
+            clear_route_cache: false,
+        }),
+    }
+}
+
+fn build_body_response(
+    epp_upstream: &str,
+    bbr_model: &str,
+    role: &str,
+    chunks: usize,
+) -> BodyResponse {
+    let mutation = if role == "EPP_BODY" {
+        let mut mutation = build_header_mutation_headers(epp_upstream);
+        mutation
+            .set_headers
+            .push(hvo("X-Mock-Body-Chunks", &chunks.to_string()));
+        mutation
+    } else {
+        build_header_mutation_bbr(bbr_model)
+    };
     envoy::service::ext_proc::v3::BodyResponse {
         response: Some(envoy::service::ext_proc::v3::CommonResponse {
             status: CommonResponse::Continue as i32,
@@ -110,6 +167,9 @@ struct ExtProcMock {
     epp_upstream: String,
     bbr_model: String,
     role: String,
+    delay: Duration,
+    forbid_header: Option<String>,
+    extra_header: Option<(String, String)>,
 }
 
 #[tonic::async_trait]
@@ -124,22 +184,65 @@ impl ExternalProcessor for ExtProcMock {
         let epp_upstream = self.epp_upstream.clone();
         let bbr_model = self.bbr_model.clone();
         let role = self.role.clone();
+        let delay = self.delay;
+        let forbid_header = self.forbid_header.clone();
+        let extra_header = self.extra_header.clone();
         tokio::spawn(async move {
             let mut sent_headers_response = false;
             let mut body_buf: Vec<u8> = Vec::new();
+            let mut body_chunks = 0usize;
             let mut current_bbr_model = bbr_model.clone();
             while let Some(msg) = inbound.message().await.transpose() {
                 match msg {
                     Ok(pr) => match pr.request {
-                        Some(processing_request::Request::RequestHeaders(_)) => {
-                            if role == "EPP" {
+                        Some(processing_request::Request::RequestHeaders(headers)) => {
+                            if !delay.is_zero() {
+                                tokio::time::sleep(delay).await;
+                            }
+                            // inference_epp_body_send_mode: the body follows without asking
+                            let body_announced = !headers.end_of_stream;
+                            let forbidden = forbid_header.as_deref().is_some_and(|name| {
+                                headers
+                                    .headers
+                                    .iter()
+                                    .flat_map(|map| &map.headers)
+                                    .any(|h| h.key.eq_ignore_ascii_case(name))
+                            });
+                            if role == "EPP" && forbidden {
+                                eprintln!(
+                                    "extproc_mock: EPP headers carry the forbidden header, returning empty mutation"
+                                );
+                                let resp = ProcessingResponse {
+                                    response: Some(processing_response::Response::RequestHeaders(
+                                        build_empty_headers_response(),
+                                    )),
+                                    dynamic_metadata: None,
+                                    mode_override: None,
+                                    override_message_timeout: None,
+                                };
+                                if tx.send(Ok(resp)).await.is_err() {
+                                    break;
+                                }
+                                sent_headers_response = true;
+                            } else if role == "EPP" {
                                 eprintln!(
                                     "extproc_mock: EPP headers received, selecting endpoint: {}",
                                     epp_upstream
                                 );
+                                let mut headers_response =
+                                    build_headers_response(&epp_upstream, &bbr_model);
+                                if let (Some((name, value)), Some(mutation)) = (
+                                    &extra_header,
+                                    headers_response
+                                        .response
+                                        .as_mut()
+                                        .and_then(|common| common.header_mutation.as_mut()),
+                                ) {
+                                    mutation.set_headers.push(hvo(name, value));
+                                }
                                 let resp = ProcessingResponse {
                                     response: Some(processing_response::Response::RequestHeaders(
-                                        build_headers_response(&epp_upstream, &bbr_model),
+                                        headers_response,
                                     )),
                                     dynamic_metadata: None,
                                     mode_override: None,
@@ -149,6 +252,54 @@ impl ExternalProcessor for ExtProcMock {
                                     break;
                                 }
                                 sent_headers_response = true;
+                            } else if role == "EPP_NO_HEADER" {
+                                eprintln!(
+                                    "extproc_mock: EPP headers received, returning empty mutation"
+                                );
+                                let resp = ProcessingResponse {
+                                    response: Some(processing_response::Response::RequestHeaders(
+                                        build_empty_headers_response(),
+                                    )),
+                                    dynamic_metadata: None,
+                                    mode_override: None,
+                                    override_message_timeout: None,
+                                };
+                                if tx.send(Ok(resp)).await.is_err() {
+                                    break;
+                                }
+                            } else if role == "EPP_BODY" && body_announced {
+                                eprintln!(
+                                    "extproc_mock: EPP headers received, body announced by the client"
+                                );
+                                let resp = ProcessingResponse {
+                                    response: Some(processing_response::Response::RequestHeaders(
+                                        build_body_request_headers_response(),
+                                    )),
+                                    dynamic_metadata: None,
+                                    mode_override: None,
+                                    override_message_timeout: None,
+                                };
+                                if tx.send(Ok(resp)).await.is_err() {
+                                    break;
+                                }
+                            } else if role == "EPP_BODY" {
+                                eprintln!(
+                                    "extproc_mock: EPP headers received, requesting body via mode_override"
+                                );
+                                let resp = ProcessingResponse {
+                                    response: Some(processing_response::Response::RequestHeaders(
+                                        build_body_request_headers_response(),
+                                    )),
+                                    dynamic_metadata: None,
+                                    mode_override: Some(ProcessingMode {
+                                        request_body_mode: BodySendMode::Buffered as i32,
+                                        ..Default::default()
+                                    }),
+                                    override_message_timeout: None,
+                                };
+                                if tx.send(Ok(resp)).await.is_err() {
+                                    break;
+                                }
                             } else {
                                 eprintln!(
                                     "extproc_mock: BBR headers received, waiting for body..."
@@ -157,6 +308,7 @@ impl ExternalProcessor for ExtProcMock {
                         }
                         Some(processing_request::Request::RequestBody(body)) => {
                             body_buf.extend_from_slice(&body.body);
+                            body_chunks += 1;
                             if body.end_of_stream {
                                 eprintln!(
                                     "extproc_mock: end of stream, body size: {} bytes",
@@ -173,7 +325,12 @@ impl ExternalProcessor for ExtProcMock {
                                 }
                                 let resp = ProcessingResponse {
                                     response: Some(processing_response::Response::RequestBody(
-                                        build_body_response(&epp_upstream, &current_bbr_model),
+                                        build_body_response(
+                                            &epp_upstream,
+                                            &current_bbr_model,
+                                            &role,
+                                            body_chunks,
+                                        ),
                                     )),
                                     dynamic_metadata: None,
                                     mode_override: None,
@@ -184,6 +341,11 @@ impl ExternalProcessor for ExtProcMock {
                                         "extproc_mock: BBR final response - model: {}",
                                         current_bbr_model
                                     );
+                                } else if role == "EPP_BODY" {
+                                    eprintln!(
+                                        "extproc_mock: EPP body received, selecting endpoint: {}",
+                                        epp_upstream
+                                    );
                                 }
                                 if tx.send(Ok(resp)).await.is_err() {
                                     break;
@@ -261,6 +423,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "EPP"
     };
     let role = env::var("MOCK_ROLE").unwrap_or_else(|_| default_role.to_string());
+    let delay = Duration::from_millis(
+        env::var("MOCK_DELAY_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0),
+    );
+
+    let gzip = env::var("MOCK_GZIP").is_ok_and(|v| v == "1");
+    let forbid_header = env::var("MOCK_FORBID_HEADER").ok();
+    let extra_header = env::var("MOCK_EXTRA_HEADER").ok().and_then(|v| {
+        v.split_once(':')
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+    });
 
     println!(
         "extproc_mock: role={}, configured EPP_UPSTREAM={}, BBR_MODEL={}",
@@ -271,12 +446,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         epp_upstream,
         bbr_model,
         role,
+        delay,
+        forbid_header,
+        extra_header,
     };
 
     println!("extproc_mock listening on {}", addr);
 
+    let mut server = ExternalProcessorServer::new(svc);
+    if gzip {
+        server = server
+            .accept_compressed(CompressionEncoding::Gzip)
+            .send_compressed(CompressionEncoding::Gzip);
+    }
+
     tonic::transport::Server::builder()
-        .add_service(ExternalProcessorServer::new(svc))
+        .add_service(server)
         .serve_with_shutdown(addr, shutdown_signal())
         .await?;
 
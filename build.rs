@@ -14,6 +14,13 @@ fn main() {
         println!("cargo:rustc-cdylib-link-arg=-Wl,-undefined,dynamic_lookup");
     }
 
+    // Embed the git commit for $inference_version when building from a checkout
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    if let Some(hash) = git_hash() {
+        println!("cargo:rustc-env=NGX_INFERENCE_GIT_HASH={hash}");
+    }
+
     // Configure tonic/prost codegen
     let mut cfg = tonic_prost_build::configure()
         // Generate clients and servers for the ext-proc mock server
@@ -37,3 +44,16 @@ fn main() {
     )
     .expect("failed to compile Envoy ext-proc protos");
 }
+
+/// Short hash of the checked-out commit, if git and a repository are available
+fn git_hash() -> Option<String> {
+    let output = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let hash = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!hash.is_empty()).then_some(hash)
+}
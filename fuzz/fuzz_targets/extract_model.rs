@@ -0,0 +1,53 @@
+//! Fuzz the BBR model extractor with arbitrary request bodies.
+//!
+//! Run with `cargo +nightly fuzz run extract_model` from the repository root. The extractor
+//! must never panic, and must agree with a straightforward `serde_json::Value` implementation.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ngx_inference::model_extractor::{extract_model_from_body_with_policy, sanitize_model};
+use ngx_inference::modules::config::BbrArrayPolicy;
+use serde_json::Value;
+
+/// Reference implementation that builds the full JSON tree
+fn reference(body: &[u8], policy: BbrArrayPolicy) -> Option<String> {
+    let json: Value = serde_json::from_str(std::str::from_utf8(body).ok()?).ok()?;
+    let target = match &json {
+        Value::Array(items) => match policy {
+            BbrArrayPolicy::First => items.first()?,
+            BbrArrayPolicy::Last => items.last()?,
+            BbrArrayPolicy::Reject => return None,
+        },
+        _ => &json,
+    };
+    target.get("model")?.as_str().map(str::to_string)
+}
+
+fuzz_target!(|data: &[u8]| {
+    for policy in [
+        BbrArrayPolicy::First,
+        BbrArrayPolicy::Last,
+        BbrArrayPolicy::Reject,
+    ] {
+        let model = extract_model_from_body_with_policy(data, policy);
+
+        // serde_json's recursion limit makes the reference reject deeply nested input that
+        // the extractor accepts, so only compare when the reference could parse the body
+        let parsed = std::str::from_utf8(data)
+            .ok()
+            .is_some_and(|s| serde_json::from_str::<Value>(s).is_ok());
+        if parsed {
+            assert_eq!(model, reference(data, policy));
+        }
+
+        if let Some(model) = model {
+            for url_decode in [false, true] {
+                if let Some(clean) = sanitize_model(&model, url_decode) {
+                    assert!(!clean.is_empty());
+                    assert!(!clean.chars().any(char::is_control));
+                }
+            }
+        }
+    }
+});
@@ -3,26 +3,76 @@
 //! This module implements the actual EPP processing logic that runs asynchronously
 //! on the Tokio runtime. It must NOT call any NGINX FFI functions.
 
-use crate::epp::context::AsyncEppContext;
-use crate::grpc::epp_headers_blocking_internal;
-use std::sync::OnceLock;
+use crate::epp::context::{current_time_ms, AsyncEppContext, EppOutcome};
+use crate::grpc::{epp_headers_blocking_internal, EppError, UpstreamHeader};
+use crate::modules::config::EppBodyHash;
+use crate::modules::ctx::{deadline_exceeded, SharedBody};
+use std::sync::{Arc, OnceLock};
 use tokio::sync::oneshot;
 
-/// Global Tokio runtime for async EPP processing
-static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+/// Built-in pattern for EPP-selected upstreams: `host[:port]` or `scheme://host[:port][/path]`,
+/// where host may be a bracketed IPv6 literal. Rejects whitespace, quotes and control characters.
+const DEFAULT_UPSTREAM_PATTERN: &str = r"^(?:[A-Za-z][A-Za-z0-9+.-]*://)?(?:\[[0-9A-Fa-f:.]+\]|[A-Za-z0-9_](?:[A-Za-z0-9_.-]*[A-Za-z0-9_])?)(?::[0-9]{1,5})?(?:/[A-Za-z0-9._~%/-]*)?$";
+
+static DEFAULT_UPSTREAM_REGEX: OnceLock<regex::Regex> = OnceLock::new();
+
+/// Header carrying the `inference_epp_body_hash sha256` fingerprint to EPP
+pub const BODY_HASH_HEADER: &str = "x-inference-body-sha256";
+
+/// Lowercase hex fingerprint of `body`, or `None` when hashing is off
+pub fn body_hash(kind: EppBodyHash, body: &[u8]) -> Option<String> {
+    match kind {
+        EppBodyHash::None => None,
+        EppBodyHash::Sha256 => {
+            let digest = ring::digest::digest(&ring::digest::SHA256, body);
+            Some(digest.as_ref().iter().map(|b| format!("{b:02x}")).collect())
+        }
+    }
+}
+
+/// Check an EPP-selected upstream against the configured (or built-in) validation regex
+pub fn is_valid_upstream(upstream: &str, regex: Option<&regex::Regex>) -> bool {
+    let regex = regex.unwrap_or_else(|| {
+        DEFAULT_UPSTREAM_REGEX.get_or_init(|| {
+            regex::Regex::new(DEFAULT_UPSTREAM_PATTERN).expect("valid default upstream regex")
+        })
+    });
+    regex.is_match(upstream)
+}
+
+/// Whether an EPP-selected upstream is on the `inference_upstream_allow` list (host names
+/// compare case-insensitively); an empty list allows any upstream
+pub fn is_allowed_upstream(upstream: &str, allow: &[String]) -> bool {
+    allow.is_empty() || allow.iter().any(|a| a.eq_ignore_ascii_case(upstream))
+}
+
+/// Global Tokio runtime for async EPP processing, or the error from building it
+static RUNTIME: OnceLock<Result<tokio::runtime::Runtime, String>> = OnceLock::new();
 
 /// Get or create the global Tokio runtime
-pub fn get_runtime() -> &'static tokio::runtime::Runtime {
-    RUNTIME.get_or_init(|| {
+///
+/// Building the runtime can fail, for example under thread limits. The error is kept so
+/// every EPP request takes the failure-mode path instead of panicking the worker.
+pub fn get_runtime() -> Result<&'static tokio::runtime::Runtime, String> {
+    init_runtime(&RUNTIME, || {
         tokio::runtime::Builder::new_multi_thread()
             .worker_threads(4)
             .thread_name("epp-worker")
             .enable_all()
             .build()
-            .expect("Failed to create Tokio runtime for EPP")
     })
 }
 
+/// Initialize `cell` with `build` on first use and return the runtime or the init error
+fn init_runtime(
+    cell: &'static OnceLock<Result<tokio::runtime::Runtime, String>>,
+    build: impl FnOnce() -> std::io::Result<tokio::runtime::Runtime>,
+) -> Result<&'static tokio::runtime::Runtime, String> {
+    cell.get_or_init(|| build().map_err(|e| format!("failed to create Tokio runtime for EPP: {e}")))
+        .as_ref()
+        .map_err(Clone::clone)
+}
+
 /// Spawn an async EPP task
 ///
 /// This function spawns a Tokio task that performs the EPP gRPC call asynchronously.
@@ -35,18 +85,18 @@ pub fn get_runtime() -> &'static tokio::runtime::Runtime {
 ///
 /// # Parameters
 ///
+/// - `rt`: Runtime from [`get_runtime`]
 /// - `ctx`: EPP configuration and request context
-/// - `body`: Request body bytes
+/// - `body`: Request body bytes, shared with the request context rather than copied
 /// - `sender`: Oneshot channel to send the result
 /// - `eventfd`: File descriptor to notify when result is ready
 pub fn spawn_epp_task(
+    rt: &tokio::runtime::Runtime,
     ctx: AsyncEppContext,
-    body: Vec<u8>,
-    sender: oneshot::Sender<Result<String, String>>,
+    body: Arc<SharedBody>,
+    sender: oneshot::Sender<EppOutcome>,
     eventfd: i32,
 ) {
-    let rt = get_runtime();
-
     rt.spawn(async move {
         let result = process_epp_async(ctx, body).await;
 
@@ -73,78 +123,281 @@ pub fn spawn_epp_task(
 /// This function performs the actual EPP gRPC call. It runs on a Tokio worker thread
 /// and must NOT call any NGINX FFI functions.
 ///
+/// If the primary endpoint fails and `inference_epp_fallback_endpoint` is set, the fallback
+/// is tried once (unless the request deadline has passed) before the failure mode applies.
+///
 /// # Parameters
 ///
 /// - `ctx`: EPP configuration and request context
-/// - `body`: Request body bytes (for future body-aware EPP processing)
+/// - `body`: Request body bytes (sent per `ctx.body_send_mode`, or if the EPP requests it via
+///   `mode_override`)
 ///
 /// # Returns
 ///
-/// - `Ok(upstream_name)` if EPP successfully selected an upstream
-/// - `Err(error_message)` if EPP failed
-async fn process_epp_async(ctx: AsyncEppContext, _body: Vec<u8>) -> Result<String, String> {
-    // For now, we're doing headers-only EPP (like the current implementation)
-    // The body parameter is included for future extension to body-aware EPP
-
-    let endpoint = &ctx.endpoint;
-    let timeout_ms = ctx.timeout_ms;
-    let header_name = &ctx.upstream_header;
-    let headers = ctx.headers.clone();
-    let use_tls = ctx.use_tls;
-    let ca_file = ctx.ca_file.as_deref();
-
-    // Call the internal async EPP function
+/// An [`EppOutcome`] whose `result` is:
+///
+/// - `Ok(Some(upstream_name))` if EPP successfully selected an upstream
+/// - `Ok(None)` if the EPP stream ended without the upstream header
+/// - `Err(error)` if EPP failed
+async fn process_epp_async(ctx: AsyncEppContext, body: Arc<SharedBody>) -> EppOutcome {
+    let mut headers = ctx.headers.clone();
+    // Hashed here rather than in the worker: the body is up to inference_max_body_size.
+    // A client-sent header of the same name is replaced so EPP only sees our fingerprint.
+    if let Some(hash) = body_hash(ctx.body_hash, &body) {
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case(BODY_HASH_HEADER));
+        headers.push((BODY_HASH_HEADER.to_string(), hash.into_bytes()));
+    }
+
+    let mut responses = Vec::new();
+    let result = call_epp(&ctx, &ctx.endpoint, headers.clone(), &body, &mut responses).await;
+    match (result, ctx.fallback_endpoint.as_deref()) {
+        // An ImmediateResponse is the primary's answer, not a failure to retry elsewhere
+        (Err(primary_error), Some(fallback))
+            if !matches!(primary_error, EppError::Immediate(_))
+                && !deadline_exceeded(ctx.deadline_ms, current_time_ms()) =>
+        {
+            EppOutcome {
+                result: call_epp(&ctx, fallback, headers, &body, &mut responses).await,
+                primary_error: Some(primary_error),
+                responses,
+            }
+        }
+        (result, _) => EppOutcome {
+            result,
+            primary_error: None,
+            responses,
+        },
+    }
+}
+
+/// One EPP exchange with `endpoint`, validating the selected upstream. With
+/// `ctx.log_responses`, the EPP's responses are added to `responses` for the worker to log.
+async fn call_epp(
+    ctx: &AsyncEppContext,
+    endpoint: &str,
+    headers: Vec<(String, Vec<u8>)>,
+    body: &[u8],
+    responses: &mut Vec<String>,
+) -> Result<Option<UpstreamHeader>, EppError> {
+    // Headers-first exchange; the body follows per inference_epp_body_send_mode or if the EPP asks
     // This function doesn't use any NGINX logging, making it safe for async context
     match epp_headers_blocking_internal(
-        endpoint,
-        timeout_ms,
-        header_name,
+        &ctx.call_options(endpoint),
         headers,
-        use_tls,
-        ca_file,
+        body,
+        ctx.request_attributes.as_ref(),
+        ctx.log_responses.then_some(responses),
     )
     .await
     {
-        Ok(Some(upstream)) => {
-            // EPP returned an upstream selection
-            Ok(upstream)
+        Ok(Some(mut upstream)) => {
+            // EPP returned an upstream selection; reject malformed values before they
+            // reach the header/variable used by proxy_pass
+            if is_valid_upstream(&upstream.value, ctx.upstream_validate_regex.as_ref()) {
+                // Only the headers named by inference_epp_client_response_header reach the client
+                upstream.headers.retain(|(key, _)| {
+                    ctx.client_response_headers
+                        .iter()
+                        .any(|name| name.eq_ignore_ascii_case(key))
+                });
+                Ok(Some(upstream))
+            } else {
+                Err(EppError::NoUpstream(format!(
+                    "EPP returned invalid upstream: {:?}",
+                    upstream.value
+                )))
+            }
         }
         Ok(None) => {
             // EPP didn't return an upstream
-            // The caller will handle this based on failure_mode_allow
-            Err("EPP returned no upstream".to_string())
+            // The caller will handle this based on on_no_header
+            Ok(None)
         }
         Err(e) => {
-            // gRPC or network error
-            Err(format!("EPP error: {}", e))
+            // gRPC or network error, kept by kind for the failure status
+            Err(e)
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
     fn test_runtime_creation() {
-        let rt = get_runtime();
+        let rt = get_runtime().expect("runtime");
         assert!(rt.handle().metrics().num_workers() > 0);
     }
 
-    #[tokio::test]
-    async fn test_process_epp_async_no_endpoint() {
-        let ctx = AsyncEppContext {
-            endpoint: "".to_string(),
+    #[test]
+    fn test_body_hash_sha256_is_deterministic() {
+        let body = br#"{"model": "llama-3-8b", "prompt": "hello"}"#;
+        let expected = "bc5386c5a1e3ebca8701ea619b6554c3d96ab5b20f1a850cf3f60d977f777956";
+        assert_eq!(
+            body_hash(EppBodyHash::Sha256, body).as_deref(),
+            Some(expected)
+        );
+        assert_eq!(
+            body_hash(EppBodyHash::Sha256, body).as_deref(),
+            Some(expected)
+        );
+        assert_eq!(
+            body_hash(EppBodyHash::Sha256, b"").as_deref(),
+            Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        assert_eq!(body_hash(EppBodyHash::None, body), None);
+    }
+
+    #[test]
+    fn test_runtime_init_failure_is_an_error() {
+        static FAILED: OnceLock<Result<tokio::runtime::Runtime, String>> = OnceLock::new();
+
+        let err = init_runtime(&FAILED, || {
+            Err(std::io::Error::other("Resource temporarily unavailable"))
+        })
+        .unwrap_err();
+        assert!(err.contains("failed to create Tokio runtime"), "{err}");
+        assert!(err.contains("Resource temporarily unavailable"), "{err}");
+
+        // The failure is remembered rather than retried on every request
+        let again = init_runtime(&FAILED, || panic!("runtime build retried"));
+        assert_eq!(again.unwrap_err(), err);
+    }
+
+    pub(crate) fn test_context(endpoint: &str) -> AsyncEppContext {
+        AsyncEppContext {
+            endpoint: endpoint.to_string(),
+            fallback_endpoint: None,
             upstream_header: "X-Inference-Upstream".to_string(),
-            timeout_ms: 100,
+            timeout_ms: 5000,
+            max_timeout_ms: 0,
             headers: vec![],
             use_tls: false,
             ca_file: None,
+            tls_min_version: None,
+            grpc_compression: Default::default(),
+            body_hash: Default::default(),
+            body_send_mode: Default::default(),
+            header_sources: Default::default(),
+            request_attributes: None,
             failure_mode_allow: true,
             default_upstream: None,
-        };
+            upstream_validate_regex: None,
+            upstream_allow: Vec::new(),
+            on_no_header: Default::default(),
+            immediate_status_map: Vec::new(),
+            failure_status: 502,
+            timeout_status: 504,
+            max_messages: 100,
+            upstream_max_len: crate::grpc::DEFAULT_UPSTREAM_MAX_LEN,
+            deadline_ms: None,
+            cache_key: None,
+            cache_ttl_ms: 0,
+            sticky_ttl_ms: 0,
+            service_path: None,
+            channel_idle_ms: 0,
+            max_recv_message_bytes: 0,
+            max_send_message_bytes: 0,
+            user_agent: "ngx-inference/test".to_string(),
+            model: None,
+            log_decisions: Default::default(),
+            log_responses: false,
+            client_response_headers: Vec::new(),
+        }
+    }
+
+    /// Address of a local port with nothing listening on it
+    fn dead_endpoint() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_process_epp_async_no_endpoint() {
+        let outcome = process_epp_async(test_context(""), Arc::new(Vec::new().into())).await;
+        assert!(matches!(outcome.result, Err(EppError::Connect(_))));
+        assert!(outcome.primary_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fallback_endpoint_used_when_primary_is_dead() {
+        let (fallback, recorded) = crate::grpc::tests::spawn_recording_epp().await;
+        let mut ctx = test_context(&dead_endpoint());
+        ctx.fallback_endpoint = Some(fallback.to_string());
+
+        let outcome = process_epp_async(ctx, Arc::new(Vec::new().into())).await;
+        let upstream = outcome.result.unwrap().map(|h| h.value);
+        assert_eq!(upstream.as_deref(), Some("10.0.0.1:8000"));
+        assert!(outcome.primary_error.is_some());
+        assert!(recorded.lock().unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fallback_endpoint_unused_when_primary_answers() {
+        let (primary, _) = crate::grpc::tests::spawn_recording_epp().await;
+        let mut ctx = test_context(&primary.to_string());
+        ctx.fallback_endpoint = Some(dead_endpoint());
+
+        let outcome = process_epp_async(ctx, Arc::new(Vec::new().into())).await;
+        let upstream = outcome.result.unwrap().map(|h| h.value);
+        assert_eq!(upstream.as_deref(), Some("10.0.0.1:8000"));
+        assert!(outcome.primary_error.is_none());
+    }
 
-        let result = process_epp_async(ctx, vec![]).await;
-        assert!(result.is_err());
+    #[tokio::test]
+    async fn test_fallback_endpoint_failure_keeps_both_errors() {
+        let mut ctx = test_context(&dead_endpoint());
+        ctx.fallback_endpoint = Some(dead_endpoint());
+
+        let outcome = process_epp_async(ctx, Arc::new(Vec::new().into())).await;
+        assert!(matches!(outcome.result, Err(EppError::Connect(_))));
+        assert!(matches!(outcome.primary_error, Some(EppError::Connect(_))));
+    }
+
+    #[test]
+    fn test_default_upstream_validation_accepts_well_formed() {
+        for upstream in [
+            "10.0.0.1:8080",
+            "vllm-backend",
+            "vllm.default.svc.cluster.local:8000",
+            "[2001:db8::1]:443",
+            "http://10.0.0.1:8080",
+            "https://backend.example.com/v1",
+        ] {
+            assert!(is_valid_upstream(upstream, None), "{}", upstream);
+        }
+    }
+
+    #[test]
+    fn test_default_upstream_validation_rejects_malformed() {
+        for upstream in [
+            "",
+            "\"; rm -rf",
+            "10.0.0.1:8080\nX-Evil: 1",
+            "host:port",
+            "host name:80",
+            "host:123456",
+        ] {
+            assert!(!is_valid_upstream(upstream, None), "{:?}", upstream);
+        }
+    }
+
+    #[test]
+    fn test_upstream_allowlist() {
+        let allow = vec!["10.0.0.1:8000".to_string(), "vllm-a.svc:8000".to_string()];
+        assert!(is_allowed_upstream("10.0.0.1:8000", &allow));
+        assert!(is_allowed_upstream("VLLM-A.svc:8000", &allow));
+        assert!(!is_allowed_upstream("10.0.0.1:8001", &allow));
+        assert!(!is_allowed_upstream("attacker.example.com:443", &allow));
+        // No allowlist: any upstream passing validation is used
+        assert!(is_allowed_upstream("attacker.example.com:443", &[]));
+    }
+
+    #[test]
+    fn test_custom_upstream_validation_regex() {
+        let re = regex::Regex::new(r"^10\.0\.0\.\d+:8080$").unwrap();
+        assert!(is_valid_upstream("10.0.0.7:8080", Some(&re)));
+        assert!(!is_valid_upstream("example.com:8080", Some(&re)));
     }
 }
@@ -0,0 +1,161 @@
+//! Worker-local cache of EPP upstream decisions (`inference_epp_cache_ttl_ms`)
+//!
+//! Each nginx worker is its own process, so the cache is shared by the requests of one
+//! worker only. Entries are keyed by EPP endpoint and cache key (the model name by default)
+//! and expire after the configured TTL; the least recently used entry is evicted when full.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+/// Maximum number of decisions kept per worker
+pub const EPP_CACHE_CAPACITY: usize = 1024;
+
+struct Entry {
+    upstream: String,
+    expires_ms: u64,
+    last_used: u64,
+}
+
+/// LRU map of cache key to EPP-selected upstream
+pub struct DecisionCache {
+    entries: HashMap<String, Entry>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl DecisionCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    /// Cached upstream for `key`, or `None` if absent or expired at `now_ms`
+    pub fn get(&mut self, key: &str, now_ms: u64) -> Option<String> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        if entry.expires_ms <= now_ms {
+            self.entries.remove(key);
+            return None;
+        }
+        entry.last_used = self.clock;
+        Some(entry.upstream.clone())
+    }
+
+    /// Remember `upstream` for `key` until `now_ms + ttl_ms`; a TTL of 0 caches nothing
+    pub fn insert(&mut self, key: &str, upstream: &str, now_ms: u64, ttl_ms: u64) {
+        if ttl_ms == 0 || self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if !self.entries.contains_key(key) && self.entries.len() >= self.capacity {
+            // Expired entries go first, then the least recently used one
+            self.entries.retain(|_, e| e.expires_ms > now_ms);
+            if self.entries.len() >= self.capacity {
+                if let Some(oldest) = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.last_used)
+                    .map(|(k, _)| k.clone())
+                {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                upstream: upstream.to_string(),
+                expires_ms: now_ms.saturating_add(ttl_ms),
+                last_used: self.clock,
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+static DECISION_CACHE: OnceLock<Mutex<DecisionCache>> = OnceLock::new();
+
+fn with_cache<T>(f: impl FnOnce(&mut DecisionCache) -> T) -> T {
+    let cache = DECISION_CACHE.get_or_init(|| Mutex::new(DecisionCache::new(EPP_CACHE_CAPACITY)));
+    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut cache)
+}
+
+/// Cache key for `key` at `endpoint`, so locations using different EPPs never share entries
+pub fn cache_key(endpoint: &str, key: &str) -> String {
+    format!("{}\n{}", endpoint, key)
+}
+
+/// Look up a cached decision in this worker's cache
+pub fn lookup(key: &str, now_ms: u64) -> Option<String> {
+    with_cache(|cache| cache.get(key, now_ms))
+}
+
+/// Store an EPP decision in this worker's cache
+pub fn store(key: &str, upstream: &str, now_ms: u64, ttl_ms: u64) {
+    with_cache(|cache| cache.insert(key, upstream, now_ms, ttl_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_hit_within_ttl() {
+        let mut cache = DecisionCache::new(8);
+        cache.insert("llama", "10.0.0.1:8000", 1_000, 500);
+        assert_eq!(cache.get("llama", 1_499).as_deref(), Some("10.0.0.1:8000"));
+        assert_eq!(cache.get("mistral", 1_499), None);
+    }
+
+    #[test]
+    fn test_cache_entry_expires() {
+        let mut cache = DecisionCache::new(8);
+        cache.insert("llama", "10.0.0.1:8000", 1_000, 500);
+        assert_eq!(cache.get("llama", 1_500), None);
+        assert!(cache.is_empty());
+
+        // A fresh EPP result repopulates the entry
+        cache.insert("llama", "10.0.0.2:8000", 1_500, 500);
+        assert_eq!(cache.get("llama", 1_600).as_deref(), Some("10.0.0.2:8000"));
+    }
+
+    #[test]
+    fn test_cache_disabled_with_zero_ttl() {
+        let mut cache = DecisionCache::new(8);
+        cache.insert("llama", "10.0.0.1:8000", 1_000, 0);
+        assert_eq!(cache.get("llama", 1_000), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let mut cache = DecisionCache::new(2);
+        cache.insert("a", "up-a", 0, 1_000);
+        cache.insert("b", "up-b", 0, 1_000);
+        assert!(cache.get("a", 10).is_some());
+        cache.insert("c", "up-c", 10, 1_000);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("b", 20), None);
+        assert!(cache.get("a", 20).is_some());
+        assert!(cache.get("c", 20).is_some());
+    }
+
+    #[test]
+    fn test_cache_key_includes_endpoint() {
+        assert_ne!(
+            cache_key("epp-a:9002", "llama"),
+            cache_key("epp-b:9002", "llama")
+        );
+    }
+}
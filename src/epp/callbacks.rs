@@ -4,14 +4,28 @@
 //! All functions in this module run in the NGINX worker thread context.
 
 use crate::epp::async_processor;
-use crate::epp::context::{AsyncEppContext, ResultWatcher};
+use crate::epp::context::{
+    current_time_ms, jittered_timeout_ms, random_u64, AsyncEppContext, EppOutcome, ResultWatcher,
+};
+use crate::grpc::{EppError, UpstreamHeader};
+use crate::logging::{ngx_log_debug_raw, ngx_log_error_raw, ngx_log_info_raw, ngx_log_warn_raw};
+use crate::modules::config::{BodyMemoryAction, EppOnNoHeader};
+use crate::modules::ctx::{
+    begin_body_read, cached_body, deadline_exceeded, in_sample, invalidate_headers, mark_processed,
+    mark_time, mark_upstream_written, request_body_len, request_body_presence, request_ctx,
+    request_deadline, BodyPresence, BodyRead, RequestCtx, SharedBody,
+};
+use crate::modules::metrics::{self, Counter};
+use crate::protos::envoy::config::core::v3::header_value_option::HeaderAppendAction;
 use ngx::core;
 use ngx::ffi::{
     ngx_add_timer, ngx_del_timer, ngx_event_t, ngx_http_core_run_phases, ngx_http_finalize_request,
-    ngx_http_read_client_request_body, ngx_http_request_t, ngx_int_t, ngx_msec_t,
+    ngx_http_read_client_request_body, ngx_http_request_t, ngx_http_run_posted_requests, ngx_int_t,
+    ngx_msec_t,
 };
 use ngx::http::HttpModuleLocationConf;
 use std::ffi::{c_char, c_void, CString};
+use std::sync::Arc;
 use tokio::sync::oneshot;
 
 /// Timer poll interval in milliseconds (hybrid approach: eventfd notifies immediately, timer is backup)
@@ -29,96 +43,75 @@ fn cstr_ptr(s: *const u8) -> *const c_char {
     s.cast::<c_char>()
 }
 
-/// Helper macro for error logging from raw request pointer
-macro_rules! ngx_log_error_raw {
-    ($request:expr, $($arg:tt)*) => {{
-        let r = $request;
-        if !r.is_null() {
-            unsafe {
-                let r_ref = &*r;
-                if let Some(conn) = r_ref.connection.as_ref() {
-                    let msg = format!($($arg)*);
-                    if let Ok(c_msg) = std::ffi::CString::new(msg) {
-                        ngx::ffi::ngx_log_error_core(
-                            ngx::ffi::NGX_LOG_ERR as ngx::ffi::ngx_uint_t,
-                            conn.log,
-                            0,
-                            c_msg.as_ptr(),
-                        );
-                    }
-                }
-            }
+/// Request pointer checked to be still live when an EPP callback resumes it
+///
+/// A timer or body-read callback can fire after the client went away. The handle only exists
+/// for a request that is non-null, still has its connection and is still referenced, so the
+/// accessors below do not repeat those checks. Like the raw pointer it is neither `Send` nor
+/// `Sync`, and must only be used in the NGINX worker thread.
+#[derive(Clone, Copy, Debug)]
+struct RequestHandle(*mut ngx_http_request_t);
+
+impl RequestHandle {
+    /// `r` if the request is still live, `None` if it is null, lost its connection or is being
+    /// freed (reference count 0). NGINX counts references on the main request only, so a
+    /// subrequest is checked against its main request.
+    ///
+    /// # Safety
+    ///
+    /// `r` must be null or point to a request (and main request) whose memory is still
+    /// allocated, and this must be called in the NGINX worker thread.
+    unsafe fn new(r: *mut ngx_http_request_t) -> Option<Self> {
+        if r.is_null() || unsafe { (*r).connection }.is_null() {
+            return None;
         }
-    }};
-}
+        let main = unsafe { (*r).main };
+        let counted = if main.is_null() { r } else { main };
+        if unsafe { (*counted).count() } == 0 {
+            return None;
+        }
+        Some(Self(r))
+    }
 
-/// Helper macro for debug logging from raw request pointer
-macro_rules! ngx_log_debug_raw {
-    ($request:expr, $($arg:tt)*) => {{
-        let r = $request;
-        if !r.is_null() {
-            unsafe {
-                let r_ref = &*r;
-                if let Some(conn) = r_ref.connection.as_ref() {
-                    let msg = format!($($arg)*);
-                    if let Ok(c_msg) = std::ffi::CString::new(msg) {
-                        ngx::ffi::ngx_log_error_core(
-                            ngx::ffi::NGX_LOG_DEBUG as ngx::ffi::ngx_uint_t,
-                            conn.log,
-                            0,
-                            c_msg.as_ptr(),
-                        );
-                    }
-                }
-            }
+    /// Raw pointer, for logging and the NGINX calls not wrapped here
+    fn as_ptr(self) -> *mut ngx_http_request_t {
+        self.0
+    }
+
+    /// Clear the body-read post handler so it cannot run again (like BBR does)
+    fn clear_body_handler(self) {
+        let req_body = unsafe { (*self.0).request_body };
+        if !req_body.is_null() {
+            unsafe { (*req_body).post_handler = None };
         }
-    }};
-}
+    }
 
-/// Helper macro for info logging from raw request pointer
-macro_rules! ngx_log_info_raw {
-    ($request:expr, $($arg:tt)*) => {{
-        let r = $request;
-        if !r.is_null() {
-            unsafe {
-                let r_ref = &*r;
-                if let Some(conn) = r_ref.connection.as_ref() {
-                    let msg = format!($($arg)*);
-                    if let Ok(c_msg) = std::ffi::CString::new(msg) {
-                        ngx::ffi::ngx_log_error_core(
-                            ngx::ffi::NGX_LOG_INFO as ngx::ffi::ngx_uint_t,
-                            conn.log,
-                            0,
-                            c_msg.as_ptr(),
-                        );
-                    }
-                }
-            }
+    /// Record the end of the EPP call in the request timings
+    fn mark_epp_done(self) {
+        unsafe { mark_time(self.0, RequestCtx::mark_epp_done) };
+    }
+
+    /// Resume the phase handlers after the EPP decision, then run the requests posted
+    /// meanwhile, such as the parent waiting on a subrequest (`auth_request`), as nginx's own
+    /// event handlers do
+    fn resume(self) {
+        let c = unsafe { (*self.0).connection };
+        unsafe {
+            ngx_http_core_run_phases(self.0);
+            ngx_http_run_posted_requests(c);
         }
-    }};
-}
+    }
 
-/// Helper macro for warn logging from raw request pointer
-macro_rules! ngx_log_warn_raw {
-    ($request:expr, $($arg:tt)*) => {{
-        let r = $request;
-        if !r.is_null() {
-            unsafe {
-                let r_ref = &*r;
-                if let Some(conn) = r_ref.connection.as_ref() {
-                    let msg = format!($($arg)*);
-                    if let Ok(c_msg) = std::ffi::CString::new(msg) {
-                        ngx::ffi::ngx_log_error_core(
-                            ngx::ffi::NGX_LOG_WARN as ngx::ffi::ngx_uint_t,
-                            conn.log,
-                            0,
-                            c_msg.as_ptr(),
-                        );
-                    }
-                }
-            }
+    /// End the request with `status`. An `error_page` redirect for it does not run BBR/EPP
+    /// again.
+    fn finish(self, status: ngx_int_t) {
+        self.clear_body_handler();
+        unsafe {
+            mark_processed(self.0);
+            ngx::ffi::ngx_http_special_response_handler(self.0, status);
+            ngx_http_finalize_request(self.0, status);
         }
-    }};
+    }
 }
 
 /// Process EPP with body that has already been read (e.g., by BBR)
@@ -137,8 +130,8 @@ pub fn process_with_existing_body(
 
     ngx_log_debug_raw!(r, "ngx-inference: EPP processing with existing body");
 
-    // Extract the already-read body
-    let body = match unsafe { extract_request_body(r) } {
+    // Extract the already-read body, reusing BBR's copy if it cached one
+    let body = match unsafe { cached_epp_body(r) } {
         Ok(b) => b,
         Err(e) => {
             ngx_log_error_raw!(
@@ -154,12 +147,35 @@ pub fn process_with_existing_body(
         }
     };
 
+    // inference_total_body_memory: bodies held by in-flight requests share one budget
+    let Some(body) = body else {
+        return match unsafe { body_memory_action(r) } {
+            BodyMemoryAction::Reject => {
+                core::Status(ngx::ffi::NGX_HTTP_SERVICE_UNAVAILABLE as ngx_int_t)
+            }
+            BodyMemoryAction::Passthrough => core::Status::NGX_DECLINED,
+        };
+    };
+
     ngx_log_debug_raw!(
         r,
         "ngx-inference: EPP extracted {} bytes from pre-read body",
         body.len()
     );
 
+    // No runtime (e.g. thread limits at startup) means no EPP call; apply the failure mode
+    let rt = match async_processor::get_runtime() {
+        Ok(rt) => rt,
+        Err(e) => {
+            ngx_log_error_raw!(r, "ngx-inference: EPP unavailable: {}", e);
+            if ctx.failure_mode_allow {
+                return core::Status::NGX_DECLINED;
+            } else {
+                return core::Status::NGX_ERROR;
+            }
+        }
+    };
+
     // Create eventfd for notification
     let eventfd = match crate::epp::context::create_eventfd() {
         Ok(fd) => fd,
@@ -177,7 +193,7 @@ pub fn process_with_existing_body(
     let (sender, receiver) = oneshot::channel();
 
     // Spawn async EPP task with eventfd
-    async_processor::spawn_epp_task(ctx.clone(), body, sender, eventfd);
+    async_processor::spawn_epp_task(rt, ctx.clone(), body, sender, eventfd);
 
     ngx_log_debug_raw!(r, "ngx-inference: EPP async task spawned, setting up timer");
 
@@ -206,9 +222,25 @@ pub fn process_with_existing_body(
 /// # Thread Safety
 ///
 /// This function runs in the NGINX worker thread and is safe to call.
-pub fn read_body_async(request: &mut ngx::http::Request, _ctx: AsyncEppContext) -> core::Status {
+pub fn read_body_async(request: &mut ngx::http::Request, ctx: AsyncEppContext) -> core::Status {
     let r: *mut ngx_http_request_t = request.as_mut();
 
+    // Reuse a body another stage has read; never start a second read on the request
+    match unsafe { begin_body_read(r) } {
+        BodyRead::Initiate => {}
+        BodyRead::Reuse => {
+            ngx_log_debug_raw!(r, "ngx-inference: EPP reusing body read by another stage");
+            return process_with_existing_body(request, ctx);
+        }
+        BodyRead::InProgress => {
+            ngx_log_debug_raw!(
+                r,
+                "ngx-inference: EPP declining - body read already in progress"
+            );
+            return core::Status::NGX_DECLINED;
+        }
+    }
+
     ngx_log_debug_raw!(r, "ngx-inference: EPP initiating body read");
 
     // DON'T use (*r).ctx - it causes free() errors
@@ -218,8 +250,11 @@ pub fn read_body_async(request: &mut ngx::http::Request, _ctx: AsyncEppContext)
     // NGINX will call body_read_done when body is available
     let rc = unsafe { ngx_http_read_client_request_body(r, Some(body_read_done)) };
 
+    // ngx_http_read_client_request_body takes a reference on the main request for the read and
+    // drops it itself only on error. On NGX_OK and NGX_AGAIN it is released here, exactly once,
+    // with ngx_http_finalize_request(r, NGX_DONE); see the BBR body read for the same pattern.
     if rc >= 300 as ngx_int_t {
-        // Body read failed with HTTP error
+        // Body read failed with HTTP error; the read's reference is already released
         ngx_log_error_raw!(r, "ngx-inference: EPP body read failed with error: {}", rc);
         return core::Status::NGX_ERROR;
     }
@@ -233,13 +268,21 @@ pub fn read_body_async(request: &mut ngx::http::Request, _ctx: AsyncEppContext)
             ngx_http_finalize_request(r, core::Status::NGX_DONE.0 as ngx_int_t);
         }
         core::Status::NGX_DONE
-    } else {
-        // Body was already buffered, callback already called - still need finalize
+    } else if rc == core::Status::NGX_OK.0 as ngx_int_t {
+        // The whole body was already buffered and body_read_done has run synchronously. It
+        // either started the EPP call (its timer resumes the phases later), resumed the phases
+        // itself, or finished the request. None of those release the read's reference, so it
+        // is released here whatever the callback did; the callback must not finalize with
+        // NGX_DONE itself. `r` may be freed by this call and must not be touched again.
         ngx_log_debug_raw!(r, "ngx-inference: EPP body read completed (sync)");
         unsafe {
             ngx_http_finalize_request(r, core::Status::NGX_DONE.0 as ngx_int_t);
         }
         core::Status::NGX_DONE
+    } else {
+        // Not an nginx result for a body read; take no reference action
+        ngx_log_error_raw!(r, "ngx-inference: EPP body read returned unexpected {}", rc);
+        core::Status::NGX_ERROR
     }
 }
 
@@ -255,17 +298,13 @@ pub fn read_body_async(request: &mut ngx::http::Request, _ctx: AsyncEppContext)
 unsafe extern "C" fn body_read_done(r: *mut ngx_http_request_t) {
     ngx_log_debug_raw!(r, "ngx-inference: EPP body_read_done START - r={:p}", r);
 
-    if r.is_null() {
-        ngx_log_error_raw!(r, "ngx-inference: EPP body_read_done - request is NULL");
-        return;
-    }
-
-    // Check connection is valid
-    let conn = unsafe { (*r).connection };
-    if conn.is_null() {
-        ngx_log_error_raw!(r, "ngx-inference: EPP body_read_done - connection is NULL");
+    let Some(handle) = (unsafe { RequestHandle::new(r) }) else {
+        ngx_log_error_raw!(
+            r,
+            "ngx-inference: EPP body_read_done - request or connection is gone"
+        );
         return;
-    }
+    };
 
     ngx_log_debug_raw!(r, "ngx-inference: EPP body_read_done - extracting config");
 
@@ -285,9 +324,9 @@ unsafe extern "C" fn body_read_done(r: *mut ngx_http_request_t) {
         conf.epp_header_name.clone()
     };
 
-    let endpoint = match &conf.epp_endpoint {
-        Some(e) if !e.is_empty() => e.clone(),
-        _ => {
+    let endpoint = match crate::epp::request_endpoint(request, conf) {
+        Some(e) => e,
+        None => {
             ngx_log_debug_raw!(
                 r,
                 "ngx-inference: EPP body_read_done: no endpoint configured"
@@ -297,46 +336,105 @@ unsafe extern "C" fn body_read_done(r: *mut ngx_http_request_t) {
     };
 
     // Collect headers
-    let mut headers: Vec<(String, String)> = Vec::new();
-    for (name, value) in request.headers_in_iterator() {
-        if let (Ok(n), Ok(v)) = (name.to_str(), value.to_str()) {
-            headers.push((n.to_string(), v.to_string()));
-        }
-    }
+    let headers = crate::epp::collect_headers(request, conf);
 
     let epp_ctx = AsyncEppContext {
+        cache_key: crate::epp::decision_cache_key(request, conf, &endpoint),
         endpoint,
+        fallback_endpoint: conf.epp_fallback_endpoint.clone(),
         upstream_header,
-        timeout_ms: conf.epp_timeout_ms,
+        timeout_ms: jittered_timeout_ms(conf.epp_timeout_ms, conf.epp_timeout_jitter_pct),
+        max_timeout_ms: conf.epp_max_timeout_ms,
         headers,
         use_tls: conf.epp_tls,
         ca_file: conf.epp_ca_file.clone(),
-        failure_mode_allow: conf.epp_failure_mode_allow,
+        tls_min_version: conf.epp_tls_min_version,
+        grpc_compression: conf.epp_grpc_compression.unwrap_or_default(),
+        body_hash: conf.epp_body_hash.unwrap_or_default(),
+        body_send_mode: conf.epp_body_send_mode.unwrap_or_default(),
+        header_sources: conf.epp_header_sources.unwrap_or_default(),
+        request_attributes: crate::epp::request_attributes(request, conf),
+        failure_mode_allow: conf.epp_failure_mode_allow.unwrap_or_default(),
         default_upstream: conf.default_upstream.clone(),
+        upstream_validate_regex: conf.upstream_validate_regex.clone(),
+        upstream_allow: conf.upstream_allow.clone(),
+        on_no_header: conf.epp_on_no_header.unwrap_or_default(),
+        immediate_status_map: conf.epp_immediate_status_map.clone(),
+        failure_status: conf.epp_failure_status,
+        timeout_status: conf.epp_timeout_status,
+        max_messages: conf.epp_max_messages,
+        upstream_max_len: conf.upstream_max_len,
+        deadline_ms: unsafe { request_deadline(r, conf.request_deadline_ms) },
+        cache_ttl_ms: conf.epp_cache_ttl_ms,
+        sticky_ttl_ms: conf.epp_sticky_ttl_ms,
+        service_path: conf.epp_service_path.clone().map(|p| p.0),
+        channel_idle_ms: conf.epp_channel_idle_ms,
+        max_recv_message_bytes: conf.epp_max_recv_message_bytes,
+        max_send_message_bytes: conf.epp_max_send_message_bytes,
+        user_agent: conf.epp_user_agent(),
+        model: crate::modules::bbr::model_header(request, conf),
+        log_decisions: conf.log_decisions.unwrap_or_default(),
+        log_responses: in_sample(conf.epp_log_response_sample.unwrap_or(0.0), random_u64()),
+        client_response_headers: conf.epp_client_response_headers.clone(),
     };
 
-    // Extract request body
-    let body = match unsafe { extract_request_body(r) } {
+    // A slow body read may have used up the request deadline
+    if deadline_exceeded(epp_ctx.deadline_ms, current_time_ms()) {
+        ngx_log_error_raw!(
+            r,
+            "ngx-inference: request deadline ({} ms) exceeded while reading body",
+            conf.request_deadline_ms
+        );
+        handle_epp_failure(handle, &epp_ctx, EppFailure::Timeout);
+        return;
+    }
+
+    // Extract request body (cached on the request context)
+    let body = match unsafe { cached_epp_body(r) } {
         Ok(b) => b,
         Err(e) => {
             ngx_log_error_raw!(r, "ngx-inference: EPP failed to extract body: {}", e);
-            unsafe { handle_epp_failure(r, &epp_ctx, ngx::ffi::NGX_HTTP_BAD_GATEWAY as ngx_int_t) };
+            handle_epp_failure(handle, &epp_ctx, EppFailure::Error);
             return;
         }
     };
 
+    // inference_total_body_memory: bodies held by in-flight requests share one budget
+    let Some(body) = body else {
+        match unsafe { body_memory_action(r) } {
+            BodyMemoryAction::Reject => {
+                handle.finish(ngx::ffi::NGX_HTTP_SERVICE_UNAVAILABLE as ngx_int_t)
+            }
+            BodyMemoryAction::Passthrough => {
+                handle.clear_body_handler();
+                handle.resume();
+            }
+        }
+        return;
+    };
+
     ngx_log_debug_raw!(
         r,
         "ngx-inference: EPP extracted {} bytes of request body",
         body.len()
     );
 
+    // No runtime (e.g. thread limits at startup) means no EPP call; apply the failure mode
+    let rt = match async_processor::get_runtime() {
+        Ok(rt) => rt,
+        Err(e) => {
+            ngx_log_error_raw!(r, "ngx-inference: EPP unavailable: {}", e);
+            handle_epp_failure(handle, &epp_ctx, EppFailure::Error);
+            return;
+        }
+    };
+
     // Create eventfd for notification
     let eventfd = match crate::epp::context::create_eventfd() {
         Ok(fd) => fd,
         Err(e) => {
             ngx_log_error_raw!(r, "ngx-inference: EPP failed to create eventfd: {}", e);
-            unsafe { handle_epp_failure(r, &epp_ctx, ngx::ffi::NGX_HTTP_BAD_GATEWAY as ngx_int_t) };
+            handle_epp_failure(handle, &epp_ctx, EppFailure::Error);
             return;
         }
     };
@@ -345,7 +443,7 @@ unsafe extern "C" fn body_read_done(r: *mut ngx_http_request_t) {
     let (sender, receiver) = oneshot::channel();
 
     // Spawn async EPP task with eventfd
-    async_processor::spawn_epp_task(epp_ctx.clone(), body, sender, eventfd);
+    async_processor::spawn_epp_task(rt, epp_ctx.clone(), body, sender, eventfd);
 
     ngx_log_debug_raw!(r, "ngx-inference: EPP async task spawned, setting up timer");
 
@@ -360,10 +458,47 @@ unsafe extern "C" fn body_read_done(r: *mut ngx_http_request_t) {
             let _ = Box::from_raw(watcher_ptr);
         }
         // Just call failure handler - don't finalize in callback!
-        unsafe { handle_epp_failure(r, &epp_ctx, ngx::ffi::NGX_HTTP_BAD_GATEWAY as ngx_int_t) };
+        handle_epp_failure(handle, &epp_ctx, EppFailure::Error);
     }
 }
 
+/// The body of `r`, reusing BBR's copy if it cached one; otherwise charged to
+/// `inference_total_body_memory` before it is copied. `None` if it does not fit, now or when BBR
+/// tried.
+///
+/// # Safety
+///
+/// Must be called with valid request pointer in NGINX worker context.
+unsafe fn cached_epp_body(
+    r: *mut ngx_http_request_t,
+) -> Result<Option<Arc<SharedBody>>, &'static str> {
+    let request: &mut ngx::http::Request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
+    let limit = crate::Module::location_conf(request).map_or(0, |conf| conf.total_body_memory);
+    let len = unsafe { request_body_len(r) };
+    unsafe { cached_body(r, len, limit, || extract_request_body(r)) }
+}
+
+/// The configured `inference_total_body_memory_action` for a body of `r` that did not fit
+///
+/// # Safety
+///
+/// Must be called with valid request pointer in NGINX worker context.
+unsafe fn body_memory_action(r: *mut ngx_http_request_t) -> BodyMemoryAction {
+    let request: &mut ngx::http::Request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
+    let Some(conf) = crate::Module::location_conf(request) else {
+        return BodyMemoryAction::default();
+    };
+    let action = conf.total_body_memory_action.unwrap_or_default();
+    ngx_log_warn_raw!(
+        r,
+        "ngx-inference: EPP {} byte body exceeds inference_total_body_memory ({} bytes), action {:?}",
+        unsafe { request_body_len(r) },
+        conf.total_body_memory,
+        action
+    );
+    action
+}
+
 /// Extract request body from NGINX request (SAFE HYBRID VERSION)
 ///
 /// This implementation reads from BOTH memory and file buffers using BBR's proven approach.
@@ -378,18 +513,17 @@ unsafe fn extract_request_body(r: *mut ngx_http_request_t) -> Result<Vec<u8>, &'
         return Err("null request");
     }
 
-    let req_body = unsafe { (*r).request_body };
-    if req_body.is_null() {
-        return Ok(Vec::new());
+    // The read was initiated before this runs, so a missing request_body is an error (and
+    // goes to the failure mode) while a body without buffers is simply empty
+    match unsafe { request_body_presence(r) } {
+        BodyPresence::Missing => return Err("request body missing after read"),
+        BodyPresence::Empty => return Ok(Vec::new()),
+        BodyPresence::Buffered => {}
     }
 
-    let body_ref = unsafe { &*req_body };
+    let body_ref = unsafe { &*(*r).request_body };
     let mut bufs = body_ref.bufs;
 
-    if bufs.is_null() {
-        return Ok(Vec::new());
-    }
-
     // Get max_body_size from config
     let request: &mut ngx::http::Request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
     let max_body_size = match crate::Module::location_conf(request) {
@@ -417,7 +551,15 @@ unsafe fn extract_request_body(r: *mut ngx_http_request_t) -> Result<Vec<u8>, &'
                 if len > 0 && len < isize::MAX / 2 {
                     let len_usize = len as usize;
                     let slice = unsafe { std::slice::from_raw_parts(pos as *const u8, len_usize) };
-                    body.extend_from_slice(slice);
+                    if let Err(e) = append_within_limit(&mut body, slice, max_body_size) {
+                        ngx_log_error_raw!(
+                            r,
+                            "ngx-inference: EPP body size {} exceeds limit {}",
+                            total_read + len_usize,
+                            max_body_size
+                        );
+                        return Err(e);
+                    }
                     total_read += len_usize;
                 }
             }
@@ -588,43 +730,18 @@ unsafe extern "C" fn check_epp_result(ev: *mut ngx_event_t) {
 
     // Borrow watcher without taking ownership yet
     let watcher = unsafe { &mut *watcher_ptr };
-    let r = watcher.request;
 
-    // Check if request is still valid before proceeding
-    if r.is_null() {
-        // Request is gone, clean up and return
+    // Check the request is still live before proceeding
+    let Some(request) = (unsafe { RequestHandle::new(watcher.request) }) else {
+        // Request, connection or reference count is gone, clean up and return
         unsafe {
             ngx_del_timer(ev);
             let _ = Box::from_raw(watcher_ptr);
             // DON'T free timer event - NGINX manages it
         }
         return;
-    }
-
-    // Check if connection is still valid
-    let conn = unsafe { (*r).connection };
-    if conn.is_null() {
-        // Connection is gone, clean up and return
-        unsafe {
-            ngx_del_timer(ev);
-            let _ = Box::from_raw(watcher_ptr);
-            // DON'T free timer event - NGINX manages it
-        }
-        return;
-    }
-
-    // Check if request has already been finalized/completed
-    // If count is 0, request is being/has been freed
-    let count = unsafe { (*r).count() };
-    if count == 0 {
-        // Request is being freed, clean up timer and return
-        unsafe {
-            ngx_del_timer(ev);
-            let _ = Box::from_raw(watcher_ptr);
-            // DON'T free timer event - NGINX manages it
-        }
-        return;
-    }
+    };
+    let r = request.as_ptr();
 
     // HYBRID APPROACH: Check eventfd first for immediate notification
     let eventfd = watcher.eventfd;
@@ -644,13 +761,21 @@ unsafe extern "C" fn check_epp_result(ev: *mut ngx_event_t) {
         ngx_log_debug_raw!(r, "ngx-inference: EPP eventfd notification received");
     }
 
-    // Check for timeout FIRST
-    if watcher.is_timed_out() {
-        ngx_log_error_raw!(
-            r,
-            "ngx-inference: EPP timer fired - timeout exceeded ({} ms)",
-            watcher.ctx.timeout_ms
-        );
+    // Check for timeout FIRST: the EPP call timeout, then the whole-request deadline
+    let timed_out = watcher.is_timed_out();
+    if timed_out || watcher.is_past_deadline() {
+        if timed_out {
+            ngx_log_error_raw!(
+                r,
+                "ngx-inference: EPP timer fired - timeout exceeded ({} ms)",
+                watcher.timeout_ms()
+            );
+        } else {
+            ngx_log_error_raw!(
+                r,
+                "ngx-inference: EPP timer fired - request deadline exceeded"
+            );
+        }
 
         // Delete the timer
         unsafe {
@@ -664,7 +789,8 @@ unsafe extern "C" fn check_epp_result(ev: *mut ngx_event_t) {
         let _watcher = unsafe { Box::from_raw(watcher_ptr) };
 
         // Handle as failure (timeout => 504)
-        unsafe { handle_epp_failure(r, &ctx, ngx::ffi::NGX_HTTP_GATEWAY_TIME_OUT as ngx_int_t) };
+        request.mark_epp_done();
+        handle_epp_failure(request, &ctx, EppFailure::Timeout);
         return;
     }
 
@@ -703,7 +829,8 @@ unsafe extern "C" fn check_epp_result(ev: *mut ngx_event_t) {
             );
 
             // Process the result with cloned context
-            unsafe { process_epp_result(request_ptr, result, &ctx) };
+            request.mark_epp_done();
+            process_epp_result(request, result, &ctx);
 
             ngx_log_debug_raw!(
                 request_ptr,
@@ -740,66 +867,235 @@ unsafe extern "C" fn check_epp_result(ev: *mut ngx_event_t) {
 
             // DON'T free the timer event
 
-            unsafe {
-                handle_epp_failure(r, &watcher.ctx, ngx::ffi::NGX_HTTP_BAD_GATEWAY as ngx_int_t)
-            };
+            request.mark_epp_done();
+            handle_epp_failure(request, &watcher.ctx, EppFailure::Error);
         }
     }
 }
 
 /// Process EPP result and resume request
-///
-/// # Safety
-///
-/// Must be called with valid request pointer in NGINX worker context.
-unsafe fn process_epp_result(
-    r: *mut ngx_http_request_t,
-    result: Result<String, String>,
-    ctx: &AsyncEppContext,
-) {
+fn process_epp_result(request: RequestHandle, outcome: EppOutcome, ctx: &AsyncEppContext) {
+    let r = request.as_ptr();
     ngx_log_debug_raw!(r, "ngx-inference: EPP process_epp_result ENTER");
 
-    match result {
-        Ok(upstream) => {
-            ngx_log_info_raw!(r, "ngx-inference: EPP selected upstream '{}'", upstream);
+    // inference_epp_log_response_sample: what the EPP sent, before acting on it
+    for response in &outcome.responses {
+        ngx_log_info_raw!(r, "ngx-inference: EPP response: {}", response);
+    }
+
+    let source = match outcome.primary_error {
+        Some(ref primary_error) => {
+            ngx_log_warn_raw!(
+                r,
+                "ngx-inference: EPP primary endpoint '{}' failed ({}), used fallback endpoint '{}'",
+                ctx.endpoint,
+                primary_error,
+                ctx.fallback_endpoint.as_deref().unwrap_or_default()
+            );
+            "epp-fallback"
+        }
+        None => "epp",
+    };
+
+    match outcome.result {
+        Ok(Some(UpstreamHeader {
+            value: upstream,
+            append_action,
+            change_requested,
+            headers,
+        })) => {
+            if !async_processor::is_allowed_upstream(&upstream, &ctx.upstream_allow) {
+                ngx_log_warn_raw!(
+                    r,
+                    "ngx-inference: EPP selected upstream '{}' not in inference_upstream_allow",
+                    upstream
+                );
+                handle_epp_failure(request, ctx, EppFailure::Error);
+                return;
+            }
+
+            // Keep the model on its recent upstream unless the EPP flags the change; fallback
+            // picks are neither held nor overridden
+            let (upstream, source) = match (&ctx.model, &outcome.primary_error) {
+                (Some(model), None) if ctx.sticky_ttl_ms > 0 && !model.is_empty() => {
+                    let key = super::cache::cache_key(&ctx.endpoint, model);
+                    let kept = super::sticky::resolve(
+                        &key,
+                        &upstream,
+                        change_requested,
+                        current_time_ms(),
+                        ctx.sticky_ttl_ms,
+                    );
+                    if kept != upstream {
+                        ngx_log_debug_raw!(
+                            r,
+                            "ngx-inference: EPP selected '{}', keeping sticky upstream '{}'",
+                            upstream,
+                            kept
+                        );
+                        (kept, "epp-sticky")
+                    } else {
+                        (upstream, source)
+                    }
+                }
+                _ => (upstream, source),
+            };
 
             // Set upstream header
             ngx_log_debug_raw!(r, "ngx-inference: EPP about to set header");
-            if !unsafe { set_upstream_header(r, &ctx.upstream_header, &upstream) } {
-                ngx_log_error_raw!(r, "ngx-inference: EPP failed to set upstream header");
-                unsafe { handle_epp_failure(r, ctx, ngx::ffi::NGX_HTTP_BAD_GATEWAY as ngx_int_t) };
-                return;
+            match unsafe {
+                apply_upstream_header(r, &ctx.upstream_header, &upstream, append_action)
+            } {
+                None => {
+                    ngx_log_error_raw!(r, "ngx-inference: EPP failed to set upstream header");
+                    handle_epp_failure(request, ctx, EppFailure::Error);
+                    return;
+                }
+                Some(HeaderWrite::Skip) => {
+                    ngx_log_info_raw!(
+                        r,
+                        "ngx-inference: EPP upstream '{}' not applied to {} (append_action {})",
+                        upstream,
+                        ctx.upstream_header,
+                        append_action.as_str_name()
+                    );
+                }
+                Some(_) => {
+                    unsafe { mark_upstream_written(r) };
+                    metrics::increment(Counter::EppSelected);
+                    unsafe { log_decision(r, ctx, &upstream, source) };
+
+                    // Fallback decisions are not cached, so the primary is asked again once
+                    // it recovers
+                    if let (Some(key), None) = (&ctx.cache_key, &outcome.primary_error) {
+                        super::cache::store(key, &upstream, current_time_ms(), ctx.cache_ttl_ms);
+                    }
+                }
+            }
+
+            // inference_epp_client_response_header: added to headers_out by the header filter
+            if !headers.is_empty() {
+                if let Some(req_ctx) = unsafe { request_ctx(r) } {
+                    req_ctx.set_client_headers(headers);
+                }
             }
 
             ngx_log_debug_raw!(r, "ngx-inference: EPP header set, about to resume phases");
             // Resume request processing
-            unsafe {
-                ngx_http_core_run_phases(r);
-            }
+            request.resume();
             ngx_log_debug_raw!(r, "ngx-inference: EPP phases resumed");
         }
+        Ok(None) => handle_no_header(request, ctx),
+        Err(EppError::Immediate(code)) => {
+            let status = crate::grpc::immediate_response_status(code, &ctx.immediate_status_map);
+            if (400..=599).contains(&status) {
+                ngx_log_info_raw!(
+                    r,
+                    "ngx-inference: EPP sent an ImmediateResponse, returning status {}",
+                    status
+                );
+                request.finish(status as ngx_int_t);
+            } else {
+                // Nothing to reject the request with; same as a stream without the header
+                ngx_log_info_raw!(
+                    r,
+                    "ngx-inference: EPP sent an ImmediateResponse with status {} and no upstream",
+                    status
+                );
+                handle_no_header(request, ctx);
+            }
+        }
+        Err(e @ EppError::UpstreamTooLong(_)) => {
+            ngx_log_warn_raw!(r, "ngx-inference: EPP failed: {}", e);
+            handle_epp_failure(request, ctx, EppFailure::Error);
+        }
         Err(e) => {
             ngx_log_error_raw!(r, "ngx-inference: EPP failed: {}", e);
-            unsafe { handle_epp_failure(r, ctx, ngx::ffi::NGX_HTTP_BAD_GATEWAY as ngx_int_t) };
+            handle_epp_failure(request, ctx, EppFailure::from(&e));
+        }
+    }
+}
+
+/// Apply `inference_epp_on_no_header` when the EPP answered without the upstream header
+fn handle_no_header(request: RequestHandle, ctx: &AsyncEppContext) {
+    let r = request.as_ptr();
+    match ctx.on_no_header {
+        EppOnNoHeader::Error => {
+            ngx_log_error_raw!(r, "ngx-inference: EPP failed: EPP returned no upstream");
+            handle_epp_failure(request, ctx, EppFailure::Error);
+        }
+        EppOnNoHeader::Default => {
+            match ctx.default_upstream {
+                Some(ref default)
+                    if unsafe { set_upstream_header(r, &ctx.upstream_header, default) } =>
+                {
+                    unsafe { mark_upstream_written(r) };
+                    ngx_log_info_raw!(
+                        r,
+                        "ngx-inference: EPP returned no upstream, using default upstream '{}'",
+                        default
+                    );
+                    unsafe { log_decision(r, ctx, default, "default") };
+                }
+                _ => {
+                    ngx_log_warn_raw!(
+                        r,
+                        "ngx-inference: EPP returned no upstream and no default upstream is set, continuing"
+                    );
+                }
+            }
+            request.resume();
+        }
+        EppOnNoHeader::Continue => {
+            ngx_log_info_raw!(
+                r,
+                "ngx-inference: EPP returned no upstream, continuing without upstream header"
+            );
+            request.resume();
+        }
+    }
+}
+
+/// Kind of EPP failure, selecting the fail-closed status
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EppFailure {
+    /// gRPC, protocol or internal error (`inference_epp_failure_status`, default 502)
+    Error,
+    /// EPP timeout or request deadline (`inference_epp_timeout_status`, default 504)
+    Timeout,
+}
+
+impl From<&EppError> for EppFailure {
+    fn from(error: &EppError) -> Self {
+        if error.is_timeout() {
+            EppFailure::Timeout
+        } else {
+            EppFailure::Error
+        }
+    }
+}
+
+impl EppFailure {
+    /// Configured fail-closed status for this failure
+    fn status(self, ctx: &AsyncEppContext) -> ngx_int_t {
+        match self {
+            EppFailure::Error => ctx.failure_status as ngx_int_t,
+            EppFailure::Timeout => ctx.timeout_status as ngx_int_t,
         }
     }
 }
 
 /// Handle EPP failure according to failure mode
-///
-/// # Safety
-///
-/// Must be called with valid request pointer in NGINX worker context.
-unsafe fn handle_epp_failure(
-    r: *mut ngx_http_request_t,
-    ctx: &AsyncEppContext,
-    status_code: ngx_int_t,
-) {
+fn handle_epp_failure(request: RequestHandle, ctx: &AsyncEppContext, failure: EppFailure) {
+    let r = request.as_ptr();
+    let status_code = failure.status(ctx);
+    metrics::increment(match failure {
+        EppFailure::Error => Counter::EppFailure,
+        EppFailure::Timeout => Counter::EppTimeout,
+    });
+
     // Clear the post_handler to prevent callback re-execution (like BBR does)
-    let req_body = unsafe { (*r).request_body };
-    if !req_body.is_null() {
-        unsafe { (*req_body).post_handler = None };
-    }
+    request.clear_body_handler();
 
     if ctx.failure_mode_allow {
         // Fail-open: set default upstream if available
@@ -810,14 +1106,14 @@ unsafe fn handle_epp_failure(
 
         if let Some(ref default) = ctx.default_upstream {
             if unsafe { set_upstream_header(r, &ctx.upstream_header, default) } {
+                unsafe { mark_upstream_written(r) };
                 ngx_log_warn_raw!(r, "ngx-inference: EPP using default upstream '{}'", default);
+                unsafe { log_decision(r, ctx, default, "default") };
             }
         }
 
         // Resume request processing
-        unsafe {
-            ngx_http_core_run_phases(r);
-        }
+        request.resume();
     } else {
         // Fail-closed: send error response using special_response_handler (like BBR does)
         ngx_log_error_raw!(
@@ -825,62 +1121,159 @@ unsafe fn handle_epp_failure(
             "ngx-inference: EPP fail-closed mode, returning error status {}",
             status_code
         );
-        unsafe {
-            ngx::ffi::ngx_http_special_response_handler(r, status_code);
-            ngx::ffi::ngx_http_finalize_request(r, status_code);
-        }
+        request.finish(status_code);
     }
 }
 
-/// Set upstream header on request
+/// Log the routing decision for `r` at the context's `inference_log_decisions` level
 ///
 /// # Safety
 ///
 /// Must be called with valid request pointer in NGINX worker context.
-unsafe fn set_upstream_header(r: *mut ngx_http_request_t, header_name: &str, value: &str) -> bool {
-    if r.is_null() {
-        return false;
+unsafe fn log_decision(
+    r: *mut ngx_http_request_t,
+    ctx: &AsyncEppContext,
+    upstream: &str,
+    source: &str,
+) {
+    unsafe {
+        crate::logging::log_decision(
+            r,
+            ctx.log_decisions.level(),
+            ctx.model.as_deref(),
+            upstream,
+            source,
+        )
+    };
+}
+
+/// Set upstream header on request, overwriting an existing header of the same name
+///
+/// # Safety
+///
+/// Must be called with valid request pointer in NGINX worker context.
+pub unsafe fn set_upstream_header(
+    r: *mut ngx_http_request_t,
+    header_name: &str,
+    value: &str,
+) -> bool {
+    unsafe {
+        apply_upstream_header(
+            r,
+            header_name,
+            value,
+            HeaderAppendAction::OverwriteIfExistsOrAdd,
+        )
     }
+    .is_some()
+}
 
-    let _name_cstr = match CString::new(header_name) {
-        Ok(s) => s,
-        Err(_) => return false,
-    };
+/// Effect of an EPP header mutation on `headers_in`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HeaderWrite {
+    /// Replace the value of the existing header
+    Overwrite,
+    /// Add a header entry (a duplicate if the header is already present)
+    Add,
+    /// Leave `headers_in` unchanged
+    Skip,
+}
 
-    let _value_cstr = match CString::new(value) {
-        Ok(s) => s,
-        Err(_) => return false,
-    };
+/// What `action` does given whether the header is already present, per the ext_proc contract
+fn header_write(action: HeaderAppendAction, exists: bool) -> HeaderWrite {
+    match (action, exists) {
+        (HeaderAppendAction::AppendIfExistsOrAdd, _) => HeaderWrite::Add,
+        (HeaderAppendAction::AddIfAbsent, false) => HeaderWrite::Add,
+        (HeaderAppendAction::AddIfAbsent, true) => HeaderWrite::Skip,
+        (HeaderAppendAction::OverwriteIfExistsOrAdd, false) => HeaderWrite::Add,
+        (
+            HeaderAppendAction::OverwriteIfExistsOrAdd | HeaderAppendAction::OverwriteIfExists,
+            true,
+        ) => HeaderWrite::Overwrite,
+        (HeaderAppendAction::OverwriteIfExists, false) => HeaderWrite::Skip,
+    }
+}
 
-    // Allocate name and value from request pool
-    let pool = unsafe { (*r).pool };
+/// Write upstream header `header_name` according to `action`
+///
+/// Returns what was done, or `None` if the header could not be written.
+///
+/// # Safety
+///
+/// Must be called with valid request pointer in NGINX worker context.
+unsafe fn apply_upstream_header(
+    r: *mut ngx_http_request_t,
+    header_name: &str,
+    value: &str,
+    action: HeaderAppendAction,
+) -> Option<HeaderWrite> {
+    if r.is_null() {
+        return None;
+    }
+
+    let _name_cstr = CString::new(header_name).ok()?;
+    let _value_cstr = CString::new(value).ok()?;
 
     let name_len = header_name.len();
     let value_len = value.len();
 
-    let name_ptr = unsafe { ngx::ffi::ngx_pnalloc(pool, name_len) as *mut u8 };
-    if name_ptr.is_null() {
-        return false;
+    let headers_in = unsafe { &mut (*r).headers_in };
+
+    // Find an existing header of the same name (e.g. one set by the client or BBR when
+    // inference_epp_skip_if_set is off)
+    let mut existing: *mut ngx::ffi::ngx_table_elt_t = std::ptr::null_mut();
+    let mut part: *mut ngx::ffi::ngx_list_part_t = &mut headers_in.headers.part;
+    'search: while !part.is_null() {
+        let elts = unsafe { (*part).elts as *mut ngx::ffi::ngx_table_elt_t };
+        for i in 0..unsafe { (*part).nelts } {
+            let h = unsafe { &mut *elts.add(i) };
+            if h.key.len == name_len
+                && !h.key.data.is_null()
+                && unsafe { std::slice::from_raw_parts(h.key.data, h.key.len) }
+                    .eq_ignore_ascii_case(header_name.as_bytes())
+            {
+                existing = h;
+                break 'search;
+            }
+        }
+        part = unsafe { (*part).next };
     }
 
+    let write = header_write(action, !existing.is_null());
+    if write == HeaderWrite::Skip {
+        return Some(write);
+    }
+
+    // Allocate name and value from request pool
+    let pool = unsafe { (*r).pool };
+
     let value_ptr = unsafe { ngx::ffi::ngx_pnalloc(pool, value_len) as *mut u8 };
     if value_ptr.is_null() {
-        return false;
+        return None;
     }
+    unsafe { std::ptr::copy_nonoverlapping(value.as_ptr(), value_ptr, value_len) };
 
-    // Copy data
-    unsafe {
-        std::ptr::copy_nonoverlapping(header_name.as_ptr(), name_ptr, name_len);
-        std::ptr::copy_nonoverlapping(value.as_ptr(), value_ptr, value_len);
+    if write == HeaderWrite::Overwrite {
+        unsafe {
+            (*existing).value.len = value_len;
+            (*existing).value.data = value_ptr;
+            invalidate_headers(r);
+        }
+        return Some(write);
     }
 
+    let name_ptr = unsafe { ngx::ffi::ngx_pnalloc(pool, name_len) as *mut u8 };
+    if name_ptr.is_null() {
+        return None;
+    }
+    unsafe { std::ptr::copy_nonoverlapping(header_name.as_ptr(), name_ptr, name_len) };
+
     // Add header to request
-    let headers_in = unsafe { &mut (*r).headers_in };
     let header_ptr = unsafe { ngx::ffi::ngx_list_push(&mut headers_in.headers as *mut _) }
         as *mut ngx::ffi::ngx_table_elt_t;
 
     if header_ptr.is_null() {
-        return false;
+        return None;
     }
 
     unsafe {
@@ -890,7 +1283,99 @@ unsafe fn set_upstream_header(r: *mut ngx_http_request_t, header_name: &str, val
         (*header_ptr).value.len = value_len;
         (*header_ptr).value.data = value_ptr;
         (*header_ptr).lowcase_key = std::ptr::null_mut();
+        invalidate_headers(r);
     }
 
-    true
+    Some(write)
+}
+
+/// Append an in-memory body buffer, failing like the file-backed path if the body would
+/// exceed `max_body_size`
+fn append_within_limit(
+    body: &mut Vec<u8>,
+    chunk: &[u8],
+    max_body_size: usize,
+) -> Result<(), &'static str> {
+    if body.len() + chunk.len() > max_body_size {
+        return Err("body too large");
+    }
+    body.extend_from_slice(chunk);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_body_over_limit_is_rejected() {
+        let max_body_size = 1024 * 1024;
+        let mut body = Vec::new();
+
+        append_within_limit(&mut body, &[b'a'; 1024], max_body_size).unwrap();
+        // A single large in-memory buffer is rejected without being copied
+        let large = vec![b'b'; max_body_size];
+        assert_eq!(
+            append_within_limit(&mut body, &large, max_body_size),
+            Err("body too large")
+        );
+        assert_eq!(body.len(), 1024);
+
+        // Exactly at the limit is allowed
+        append_within_limit(&mut body, &large[..max_body_size - 1024], max_body_size).unwrap();
+        assert_eq!(body.len(), max_body_size);
+    }
+
+    #[test]
+    fn test_request_handle_validity() {
+        // SAFETY: plain C structs, all-zero is a valid (null/empty) value
+        let mut conn: ngx::ffi::ngx_connection_t = unsafe { std::mem::zeroed() };
+        let mut main: ngx_http_request_t = unsafe { std::mem::zeroed() };
+        let mut sub: ngx_http_request_t = unsafe { std::mem::zeroed() };
+        let (conn, main, sub) = (&mut conn as *mut _, &mut main as *mut _, &mut sub as *mut _);
+        let live = |r| unsafe { RequestHandle::new(r) }.map(RequestHandle::as_ptr);
+
+        assert_eq!(live(std::ptr::null_mut()), None);
+
+        unsafe {
+            // No connection: the client is gone
+            (*main).set_count(1);
+            assert_eq!(live(main), None);
+
+            // Connected but no longer referenced: the request is being freed
+            (*main).connection = conn;
+            (*main).set_count(0);
+            assert_eq!(live(main), None);
+
+            (*main).set_count(1);
+            assert_eq!(live(main), Some(main));
+
+            // A subrequest's own count is unused; its main request's count decides
+            (*sub).connection = conn;
+            (*sub).main = main;
+            assert_eq!(live(sub), Some(sub));
+            (*main).set_count(0);
+            assert_eq!(live(sub), None);
+        }
+    }
+
+    #[test]
+    fn test_header_write_for_each_append_action() {
+        use HeaderAppendAction::*;
+
+        // (action, write when the header exists, write when it is absent)
+        for (action, existing, absent) in [
+            (AppendIfExistsOrAdd, HeaderWrite::Add, HeaderWrite::Add),
+            (AddIfAbsent, HeaderWrite::Skip, HeaderWrite::Add),
+            (
+                OverwriteIfExistsOrAdd,
+                HeaderWrite::Overwrite,
+                HeaderWrite::Add,
+            ),
+            (OverwriteIfExists, HeaderWrite::Overwrite, HeaderWrite::Skip),
+        ] {
+            assert_eq!(header_write(action, true), existing, "{:?}", action);
+            assert_eq!(header_write(action, false), absent, "{:?}", action);
+        }
+    }
 }
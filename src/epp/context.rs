@@ -3,6 +3,11 @@
 //! This module defines the data structures used to pass information between
 //! NGINX worker thread and Tokio async tasks, ensuring thread safety.
 
+use crate::grpc::{EppCallOptions, EppError, RequestAttributes, UpstreamHeader};
+use crate::modules::config::{
+    EppBodyHash, EppBodySendMode, EppGrpcCompression, EppHeaderSources, EppOnNoHeader,
+    EppTlsMinVersion, LogDecisions,
+};
 use tokio::sync::oneshot;
 
 /// Context for async EPP processing
@@ -14,14 +19,20 @@ pub struct AsyncEppContext {
     /// EPP endpoint (e.g., "localhost:50051" or "https://epp.example.com")
     pub endpoint: String,
 
+    /// Break-glass endpoint tried once when `endpoint` fails (`inference_epp_fallback_endpoint`)
+    pub fallback_endpoint: Option<String>,
+
     /// Header name to set with upstream selection (e.g., "X-Inference-Upstream")
     pub upstream_header: String,
 
-    /// Timeout in milliseconds for EPP call
+    /// Timeout in milliseconds for EPP call (0 = until `deadline_ms`, capped at 5s without one)
     pub timeout_ms: u64,
 
-    /// Request headers to send to EPP
-    pub headers: Vec<(String, String)>,
+    /// Cap on EPP `override_message_timeout` extensions (`inference_epp_max_timeout_ms`, 0 = ignored)
+    pub max_timeout_ms: u64,
+
+    /// Request headers to send to EPP; values are raw bytes and need not be UTF-8
+    pub headers: Vec<(String, Vec<u8>)>,
 
     /// Whether to use TLS for gRPC connection
     pub use_tls: bool,
@@ -29,11 +40,197 @@ pub struct AsyncEppContext {
     /// Optional CA certificate file for TLS verification
     pub ca_file: Option<String>,
 
+    /// Oldest TLS version accepted from the EPP (None = tonic default)
+    pub tls_min_version: Option<EppTlsMinVersion>,
+
+    /// Compression for the gRPC stream
+    pub grpc_compression: EppGrpcCompression,
+
+    /// Body fingerprint added to the EPP headers (`inference_epp_body_hash`)
+    pub body_hash: EppBodyHash,
+
+    /// How the body is sent to EPP (`inference_epp_body_send_mode`)
+    pub body_send_mode: EppBodySendMode,
+
+    /// EPP response variants trusted for the upstream header
+    pub header_sources: EppHeaderSources,
+
+    /// Method, path and host sent as ext_proc attributes (`inference_epp_send_request_attributes`)
+    pub request_attributes: Option<RequestAttributes>,
+
     /// Failure mode: true = fail-open, false = fail-closed
     pub failure_mode_allow: bool,
 
     /// Default upstream to use on EPP failure (if fail-open)
     pub default_upstream: Option<String>,
+
+    /// Regex the EPP-selected upstream must match (None = built-in host[:port] pattern)
+    pub upstream_validate_regex: Option<regex::Regex>,
+
+    /// Upstreams the EPP may select (`inference_upstream_allow`, empty = any valid upstream)
+    pub upstream_allow: Vec<String>,
+
+    /// Action when the EPP stream ends without the upstream header
+    pub on_no_header: EppOnNoHeader,
+
+    /// ImmediateResponse status overrides (`inference_epp_immediate_status_map`)
+    pub immediate_status_map: Vec<(u16, u16)>,
+
+    /// Fail-closed status on EPP errors
+    pub failure_status: u64,
+
+    /// Fail-closed status on EPP timeout or request deadline
+    pub timeout_status: u64,
+
+    /// Max EPP responses read without the upstream header (0 = unlimited)
+    pub max_messages: usize,
+
+    /// Longest upstream header value accepted from the EPP (`inference_upstream_max_len`)
+    pub upstream_max_len: usize,
+
+    /// Absolute request deadline in ms since the epoch (`inference_request_deadline_ms`)
+    pub deadline_ms: Option<u64>,
+
+    /// Worker cache key for the EPP decision (None = not cached)
+    pub cache_key: Option<String>,
+
+    /// How long the decision stays cached (`inference_epp_cache_ttl_ms`)
+    pub cache_ttl_ms: u64,
+
+    /// Minimum time a model keeps its EPP-selected upstream (`inference_epp_sticky_ttl_ms`)
+    pub sticky_ttl_ms: u64,
+
+    /// gRPC service path of the `Process` method (None = Envoy's `ExternalProcessor`)
+    pub service_path: Option<String>,
+
+    /// How long an unused EPP channel is kept for reuse (`inference_epp_channel_idle_ms`, 0 = none)
+    pub channel_idle_ms: u64,
+
+    /// Largest gRPC message from EPP (`inference_epp_max_recv_message_bytes`, 0 = tonic default)
+    pub max_recv_message_bytes: usize,
+
+    /// Largest gRPC message sent to EPP (`inference_epp_max_send_message_bytes`, 0 = tonic default)
+    pub max_send_message_bytes: usize,
+
+    /// gRPC user-agent sent to EPP (`inference_epp_user_agent`)
+    pub user_agent: String,
+
+    /// Model header value when EPP started, for the decision log
+    pub model: Option<String>,
+
+    /// Level of the routing decision log line (`inference_log_decisions`)
+    pub log_decisions: LogDecisions,
+
+    /// Log every EPP response of this request at info (`inference_epp_log_response_sample`)
+    pub log_responses: bool,
+
+    /// EPP-set headers kept for the client response (`inference_epp_client_response_header`)
+    pub client_response_headers: Vec<String>,
+}
+
+impl AsyncEppContext {
+    /// gRPC call settings for an attempt against `endpoint` (the primary or the fallback)
+    pub fn call_options<'a>(&'a self, endpoint: &'a str) -> EppCallOptions<'a> {
+        EppCallOptions {
+            endpoint,
+            timeout_ms: self.timeout_ms,
+            request_deadline_ms: self.deadline_ms,
+            max_timeout_ms: self.max_timeout_ms,
+            header_name: &self.upstream_header,
+            body_mode: self.body_send_mode,
+            use_tls: self.use_tls,
+            ca_file: self.ca_file.as_deref(),
+            tls_min_version: self.tls_min_version,
+            compression: self.grpc_compression,
+            max_recv_message_bytes: self.max_recv_message_bytes,
+            max_send_message_bytes: self.max_send_message_bytes,
+            header_sources: self.header_sources,
+            max_messages: self.max_messages,
+            upstream_max_len: self.upstream_max_len,
+            service_path: self.service_path.as_deref(),
+            channel_idle_ms: self.channel_idle_ms,
+            user_agent: &self.user_agent,
+        }
+    }
+
+    /// How long the worker waits for the EPP task started at `start_ms`; a fallback attempt gets
+    /// its own timeout, and either may be extended up to `max_timeout_ms` by the EPP. A
+    /// `timeout_ms` of 0 means the same as for the gRPC stream (see [`attempt_timeout_ms`]).
+    pub fn total_timeout_ms(&self, start_ms: u64) -> u64 {
+        let timeout_ms = attempt_timeout_ms(
+            self.timeout_ms,
+            self.deadline_ms,
+            start_ms,
+            UNBOUNDED_EPP_TIMEOUT_CAP_MS,
+        );
+        let attempt_ms = timeout_ms.max(self.max_timeout_ms);
+        if self.fallback_endpoint.is_some() {
+            attempt_ms.saturating_mul(2)
+        } else {
+            attempt_ms
+        }
+    }
+}
+
+/// Longest an EPP attempt with `inference_epp_timeout_ms 0` may wait when no request deadline
+/// is set
+pub const UNBOUNDED_EPP_TIMEOUT_CAP_MS: u64 = 5000;
+
+/// Time an EPP attempt starting at `now_ms` may take: `timeout_ms`, or with 0 what is left of
+/// the request deadline (`deadline_ms`, ms since the epoch), or `unbounded_cap_ms` without one
+pub fn attempt_timeout_ms(
+    timeout_ms: u64,
+    deadline_ms: Option<u64>,
+    now_ms: u64,
+    unbounded_cap_ms: u64,
+) -> u64 {
+    match (timeout_ms, deadline_ms) {
+        (0, Some(deadline)) => deadline.saturating_sub(now_ms),
+        (0, None) => unbounded_cap_ms,
+        (timeout_ms, _) => timeout_ms,
+    }
+}
+
+/// Per-request EPP timeout: `timeout_ms` spread by up to ±`jitter_pct` percent
+/// (`inference_epp_timeout_jitter_pct`), so requests that started together do not all time out
+/// and fail over at the same moment. A timeout of 0 (request deadline only) is not jittered.
+pub fn jittered_timeout_ms(timeout_ms: u64, jitter_pct: u64) -> u64 {
+    if timeout_ms == 0 || jitter_pct == 0 {
+        return timeout_ms;
+    }
+    apply_jitter(timeout_ms, jitter_pct, random_u64())
+}
+
+/// `timeout_ms` moved by `random` within ±`jitter_pct` percent, never below 1ms
+fn apply_jitter(timeout_ms: u64, jitter_pct: u64, random: u64) -> u64 {
+    let span = timeout_ms.saturating_mul(jitter_pct.min(100)) / 100;
+    let offset = random % span.saturating_mul(2).saturating_add(1);
+    (timeout_ms - span).saturating_add(offset).max(1)
+}
+
+/// Cheap random value for timeout jitter and debug sampling, without a PRNG dependency:
+/// `RandomState` is randomly keyed and the counter makes successive calls on a thread differ
+pub fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+/// Result of the async EPP task, sent back to the NGINX worker thread
+#[derive(Debug)]
+pub struct EppOutcome {
+    /// Selected upstream, `Ok(None)` if EPP returned no upstream header, or the error
+    pub result: Result<Option<UpstreamHeader>, EppError>,
+
+    /// Error from the primary endpoint when the result came from the fallback endpoint
+    pub primary_error: Option<EppError>,
+
+    /// EPP responses, formatted for the log, when `log_responses` is set
+    pub responses: Vec<String>,
 }
 
 /// Watcher for timer-based result polling with eventfd notification
@@ -46,7 +243,7 @@ pub struct AsyncEppContext {
 /// automatically freed when the connection closes.
 pub struct ResultWatcher {
     /// Receiver for EPP result from async task
-    pub receiver: oneshot::Receiver<Result<String, String>>,
+    pub receiver: oneshot::Receiver<EppOutcome>,
 
     /// Raw request pointer - ONLY dereference in NGINX worker thread
     pub request: *mut ngx::ffi::ngx_http_request_t,
@@ -70,7 +267,7 @@ unsafe impl Send for ResultWatcher {}
 impl ResultWatcher {
     /// Create a new result watcher with eventfd
     pub fn new(
-        receiver: oneshot::Receiver<Result<String, String>>,
+        receiver: oneshot::Receiver<EppOutcome>,
         request: *mut ngx::ffi::ngx_http_request_t,
         ctx: AsyncEppContext,
         eventfd: i32,
@@ -84,10 +281,23 @@ impl ResultWatcher {
         }
     }
 
+    /// How long the worker waits for the EPP task (see [`AsyncEppContext::total_timeout_ms`])
+    pub fn timeout_ms(&self) -> u64 {
+        self.ctx.total_timeout_ms(self.start_time_ms)
+    }
+
     /// Check if the timeout has been exceeded
     pub fn is_timed_out(&self) -> bool {
-        let elapsed_ms = current_time_ms().saturating_sub(self.start_time_ms);
-        elapsed_ms > self.ctx.timeout_ms
+        self.is_timed_out_at(current_time_ms())
+    }
+
+    fn is_timed_out_at(&self, now_ms: u64) -> bool {
+        now_ms.saturating_sub(self.start_time_ms) > self.timeout_ms()
+    }
+
+    /// Check if the request deadline has passed
+    pub fn is_past_deadline(&self) -> bool {
+        crate::modules::ctx::deadline_exceeded(self.ctx.deadline_ms, current_time_ms())
     }
 }
 
@@ -103,7 +313,7 @@ impl Drop for ResultWatcher {
 }
 
 /// Get current time in milliseconds
-fn current_time_ms() -> u64 {
+pub fn current_time_ms() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap_or_default()
@@ -144,3 +354,78 @@ pub fn create_eventfd() -> Result<i32, &'static str> {
         Ok(fd)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epp::async_processor::tests::test_context;
+
+    #[test]
+    fn test_jittered_timeout_within_band() {
+        // Off, or no timeout at all: unchanged
+        assert_eq!(jittered_timeout_ms(1000, 0), 1000);
+        assert_eq!(jittered_timeout_ms(0, 50), 0);
+
+        // Both ends of the band are reachable
+        assert_eq!(apply_jitter(1000, 10, 0), 900);
+        assert_eq!(apply_jitter(1000, 10, 200), 1100);
+        assert_eq!(apply_jitter(1000, 100, 0), 1);
+
+        let timeouts: Vec<u64> = (0..200).map(|_| jittered_timeout_ms(1000, 10)).collect();
+        assert!(timeouts.iter().all(|t| (900..=1100).contains(t)));
+        assert!(timeouts.iter().any(|&t| t != timeouts[0]));
+    }
+
+    #[test]
+    fn test_call_options_follow_context() {
+        let mut ctx = test_context("127.0.0.1:1");
+        ctx.deadline_ms = Some(9_000);
+        ctx.ca_file = Some("/etc/epp-ca.pem".to_string());
+        ctx.max_send_message_bytes = 1024;
+        ctx.service_path = Some("/custom.v1.Processor".to_string());
+
+        // The endpoint is per attempt; everything else comes from the context
+        let options = ctx.call_options("127.0.0.1:2");
+        assert_eq!(options.endpoint, "127.0.0.1:2");
+        assert_eq!(options.timeout_ms, ctx.timeout_ms);
+        assert_eq!(options.request_deadline_ms, Some(9_000));
+        assert_eq!(options.header_name, "X-Inference-Upstream");
+        assert_eq!(options.ca_file, Some("/etc/epp-ca.pem"));
+        assert_eq!(options.max_recv_message_bytes, 0);
+        assert_eq!(options.max_send_message_bytes, 1024);
+        assert_eq!(options.service_path, Some("/custom.v1.Processor"));
+        assert_eq!(options.user_agent, ctx.user_agent);
+    }
+
+    #[test]
+    fn test_zero_timeout_total_uses_deadline_or_cap() {
+        let mut ctx = test_context("127.0.0.1:1");
+        ctx.timeout_ms = 0;
+        // No request deadline: the unbounded cap, as for the gRPC stream
+        assert_eq!(ctx.total_timeout_ms(1_000), UNBOUNDED_EPP_TIMEOUT_CAP_MS);
+
+        // What is left of the request deadline when the task started
+        ctx.deadline_ms = Some(1_300);
+        assert_eq!(ctx.total_timeout_ms(1_000), 300);
+        ctx.fallback_endpoint = Some("127.0.0.1:2".to_string());
+        assert_eq!(ctx.total_timeout_ms(1_000), 600);
+
+        // An explicit timeout is used as is
+        ctx.timeout_ms = 200;
+        assert_eq!(ctx.total_timeout_ms(1_000), 400);
+    }
+
+    #[test]
+    fn test_zero_timeout_watcher_waits_for_cap() {
+        let (_sender, receiver) = oneshot::channel();
+        let mut ctx = test_context("127.0.0.1:1");
+        ctx.timeout_ms = 0;
+        let watcher = ResultWatcher::new(receiver, std::ptr::null_mut(), ctx, -1);
+        let start = watcher.start_time_ms;
+
+        // Not timed out on the first timer tick
+        assert!(!watcher.is_timed_out_at(start + 10));
+        assert!(!watcher.is_timed_out_at(start + UNBOUNDED_EPP_TIMEOUT_CAP_MS));
+        assert!(watcher.is_timed_out_at(start + UNBOUNDED_EPP_TIMEOUT_CAP_MS + 1));
+    }
+}
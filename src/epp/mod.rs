@@ -37,11 +37,17 @@
 //! - Raw pointers are only dereferenced in the correct thread context
 
 pub mod async_processor;
+pub mod cache;
 pub mod callbacks;
 pub mod context;
+pub mod sticky;
 
+use crate::grpc::RequestAttributes;
+use crate::logging::{ngx_log_debug_http, ngx_log_warn_http};
 use crate::modules::config::ModuleConfig;
-use ngx::{core, http, ngx_log_debug_http};
+use crate::modules::ctx::{in_sample, mark_upstream_written, request_deadline, request_headers};
+use context::{current_time_ms, jittered_timeout_ms, random_u64};
+use ngx::{core, http};
 
 // Re-export for convenience
 pub use context::AsyncEppContext;
@@ -59,25 +65,26 @@ impl EppProcessor {
         ngx_log_debug_http!(
             request,
             "ngx-inference: EPP process_request called, enabled={}",
-            conf.epp_enable
+            conf.epp_enable.unwrap_or_default()
         );
 
-        if !conf.epp_enable {
+        if !conf.epp_enable.unwrap_or_default() {
             ngx_log_debug_http!(request, "ngx-inference: EPP disabled, declining");
             return core::Status::NGX_DECLINED;
         }
 
-        // Check if EPP endpoint is configured
-        let endpoint = match &conf.epp_endpoint {
-            Some(e) if !e.is_empty() => e.as_str(),
-            _ => {
+        // Check if EPP endpoint is configured (or its variable set for this request)
+        let endpoint = match request_endpoint(request, conf) {
+            Some(e) => e,
+            None => {
                 ngx_log_debug_http!(
                     request,
-                    "ngx-inference: EPP endpoint not configured, skipping"
+                    "ngx-inference: EPP endpoint not configured or empty for this request, skipping"
                 );
                 return core::Status::NGX_DECLINED;
             }
         };
+        let endpoint = endpoint.as_str();
 
         let upstream_header = if conf.epp_header_name.is_empty() {
             "X-Inference-Upstream"
@@ -85,8 +92,11 @@ impl EppProcessor {
             &conf.epp_header_name
         };
 
-        // If upstream already set, skip EPP
-        if crate::modules::bbr::get_header_in(request, upstream_header).is_some() {
+        // If upstream already set, skip EPP unless configured to override it
+        let r = crate::logging::request_ptr(request) as *mut ngx::ffi::ngx_http_request_t;
+        // SAFETY: `request` is the live request, in the worker thread
+        let header_present = unsafe { request_headers(r, conf) }.upstream.is_some();
+        if skip_for_existing_header(conf.epp_skip_if_set.unwrap_or(true), header_present) {
             ngx_log_debug_http!(
                 request,
                 "ngx-inference: Upstream header '{}' already set, skipping EPP",
@@ -94,6 +104,40 @@ impl EppProcessor {
             );
             return core::Status::NGX_DECLINED;
         }
+        if header_present {
+            ngx_log_debug_http!(
+                request,
+                "ngx-inference: Upstream header '{}' already set, running EPP anyway (inference_epp_skip_if_set off)",
+                upstream_header
+            );
+        }
+
+        // A recent decision for the same key in this worker saves the EPP call
+        let cache_key = decision_cache_key(request, conf, endpoint);
+        if let Some(key) = &cache_key {
+            if let Some(upstream) = cache::lookup(key, current_time_ms()) {
+                ngx_log_debug_http!(
+                    request,
+                    "ngx-inference: EPP cache hit, using upstream '{}'",
+                    upstream
+                );
+                if unsafe {
+                    callbacks::set_upstream_header(request.as_mut(), upstream_header, &upstream)
+                } {
+                    unsafe { mark_upstream_written(request.as_mut()) };
+                    unsafe {
+                        crate::logging::log_decision(
+                            crate::logging::request_ptr(request),
+                            conf.log_decisions.unwrap_or_default().level(),
+                            crate::modules::bbr::model_header(request, conf).as_deref(),
+                            &upstream,
+                            "epp-cache",
+                        )
+                    };
+                    return core::Status::NGX_DECLINED;
+                }
+            }
+        }
 
         ngx_log_debug_http!(
             request,
@@ -102,12 +146,7 @@ impl EppProcessor {
         );
 
         // Collect headers before async processing
-        let mut headers: Vec<(String, String)> = Vec::new();
-        for (name, value) in request.headers_in_iterator() {
-            if let (Ok(n), Ok(v)) = (name.to_str(), value.to_str()) {
-                headers.push((n.to_string(), v.to_string()));
-            }
-        }
+        let headers = collect_headers(request, conf);
 
         ngx_log_debug_http!(
             request,
@@ -118,13 +157,42 @@ impl EppProcessor {
         // Create context for async processing
         let ctx = AsyncEppContext {
             endpoint: endpoint.to_string(),
+            fallback_endpoint: conf.epp_fallback_endpoint.clone(),
             upstream_header: upstream_header.to_string(),
-            timeout_ms: conf.epp_timeout_ms,
+            timeout_ms: jittered_timeout_ms(conf.epp_timeout_ms, conf.epp_timeout_jitter_pct),
+            max_timeout_ms: conf.epp_max_timeout_ms,
             headers,
             use_tls: conf.epp_tls,
             ca_file: conf.epp_ca_file.clone(),
-            failure_mode_allow: conf.epp_failure_mode_allow,
+            tls_min_version: conf.epp_tls_min_version,
+            grpc_compression: conf.epp_grpc_compression.unwrap_or_default(),
+            body_hash: conf.epp_body_hash.unwrap_or_default(),
+            body_send_mode: conf.epp_body_send_mode.unwrap_or_default(),
+            header_sources: conf.epp_header_sources.unwrap_or_default(),
+            request_attributes: request_attributes(request, conf),
+            failure_mode_allow: conf.epp_failure_mode_allow.unwrap_or_default(),
             default_upstream: conf.default_upstream.clone(),
+            upstream_validate_regex: conf.upstream_validate_regex.clone(),
+            upstream_allow: conf.upstream_allow.clone(),
+            on_no_header: conf.epp_on_no_header.unwrap_or_default(),
+            immediate_status_map: conf.epp_immediate_status_map.clone(),
+            failure_status: conf.epp_failure_status,
+            timeout_status: conf.epp_timeout_status,
+            max_messages: conf.epp_max_messages,
+            upstream_max_len: conf.upstream_max_len,
+            deadline_ms: unsafe { request_deadline(request.as_mut(), conf.request_deadline_ms) },
+            cache_key,
+            cache_ttl_ms: conf.epp_cache_ttl_ms,
+            sticky_ttl_ms: conf.epp_sticky_ttl_ms,
+            service_path: conf.epp_service_path.clone().map(|p| p.0),
+            channel_idle_ms: conf.epp_channel_idle_ms,
+            max_recv_message_bytes: conf.epp_max_recv_message_bytes,
+            max_send_message_bytes: conf.epp_max_send_message_bytes,
+            user_agent: conf.epp_user_agent(),
+            model: crate::modules::bbr::model_header(request, conf),
+            log_decisions: conf.log_decisions.unwrap_or_default(),
+            log_responses: in_sample(conf.epp_log_response_sample.unwrap_or(0.0), random_u64()),
+            client_response_headers: conf.epp_client_response_headers.clone(),
         };
 
         // Check if body has already been read (e.g., by BBR)
@@ -172,3 +240,350 @@ impl EppProcessor {
         callbacks::read_body_async(request, ctx)
     }
 }
+
+/// Collect the request headers forwarded to EPP, bounded by `inference_epp_max_headers`
+/// and `inference_epp_max_header_bytes`. Logs a warning when headers are dropped.
+/// With `inference_epp_send_headers off` no client header is forwarded.
+///
+/// Values are forwarded as raw bytes; non-UTF-8 values reach the EPP in `raw_value`.
+pub fn collect_headers(request: &http::Request, conf: &ModuleConfig) -> Vec<(String, Vec<u8>)> {
+    let r = crate::logging::request_ptr(request) as *mut ngx::ffi::ngx_http_request_t;
+    // SAFETY: `request` is the live request, in the worker thread
+    let collected = unsafe { request_headers(r, conf) };
+    if collected.dropped > 0 {
+        ngx_log_warn_http!(
+            request,
+            "ngx-inference: EPP header limits exceeded (max {} headers, {} bytes), dropped {} headers",
+            conf.epp_max_headers,
+            conf.epp_max_header_bytes,
+            collected.dropped
+        );
+    }
+    let mut headers = collected.forwarded.clone();
+    if let Some((name, value)) = &conf.epp_request_id_header {
+        // SAFETY: compiled from the configuration pool, which outlives the request
+        if let Some(value) =
+            unsafe { value.0.as_ref() }.and_then(|cv| request.get_complex_value(cv))
+        {
+            add_request_id_header(&mut headers, name, value.as_bytes());
+        }
+    }
+    headers
+}
+
+/// Whether request header `name` may be forwarded to EPP under `inference_epp_header_allow`
+pub(crate) fn header_allowed(allow: &[String], name: &str) -> bool {
+    allow.is_empty() || allow.iter().any(|a| a.eq_ignore_ascii_case(name))
+}
+
+/// Add the `inference_epp_request_id_header` header unless the client already sent a header of
+/// that name (which is forwarded as is) or the value is empty
+pub(crate) fn add_request_id_header(
+    headers: &mut Vec<(String, Vec<u8>)>,
+    name: &str,
+    value: &[u8],
+) {
+    if value.is_empty() || headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        return;
+    }
+    headers.push((name.to_string(), value.to_vec()));
+}
+
+/// Attribute carrying `$ssl_client_s_dn` (`inference_epp_send_client_cert`), named like Envoy's
+const CLIENT_CERT_SUBJECT_ATTRIBUTE: &str = "connection.subject_peer_certificate";
+/// Attribute carrying `$ssl_client_verify` (`inference_epp_send_client_cert`)
+const CLIENT_CERT_VERIFY_ATTRIBUTE: &str = "connection.client_verify";
+
+/// Client certificate attributes from `$ssl_client_s_dn` and `$ssl_client_verify`; values
+/// that are not available (plain HTTP, no client certificate) are left out
+pub(crate) fn client_cert_attributes(
+    subject_dn: Option<String>,
+    verify: Option<String>,
+) -> Vec<(String, String)> {
+    [
+        (CLIENT_CERT_SUBJECT_ATTRIBUTE, subject_dn),
+        (CLIENT_CERT_VERIFY_ATTRIBUTE, verify),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((key.to_string(), value?)))
+    .collect()
+}
+
+/// Name of the variable (without `$`, lowercase) an `inference_epp_endpoint` value refers to,
+/// or `None` for a literal address
+fn endpoint_variable(endpoint: &str) -> Option<String> {
+    endpoint
+        .strip_prefix('$')
+        .filter(|name| !name.is_empty())
+        .map(str::to_ascii_lowercase)
+}
+
+/// EPP endpoint for this request: the `inference_epp_endpoint_map` entry for its variable value,
+/// else `inference_epp_endpoint`, with a `$variable` value evaluated per request. `None` if no
+/// endpoint is configured or the variable is unknown or empty.
+pub fn request_endpoint(request: &http::Request, conf: &ModuleConfig) -> Option<String> {
+    if let Some(map) = &conf.epp_endpoint_map {
+        let mapped = request_variable(request, &map.variable)
+            .and_then(|value| map.endpoint_for(&value).map(str::to_string));
+        if mapped.is_some() {
+            return mapped;
+        }
+    }
+    let endpoint = conf.epp_endpoint.as_deref().filter(|e| !e.is_empty())?;
+    match endpoint_variable(endpoint) {
+        Some(name) => request_variable(request, &name),
+        None => Some(endpoint.to_string()),
+    }
+}
+
+/// Value of the nginx variable `name` (lowercase, without `$`) for this request; `None` if the
+/// variable is unknown, not set or empty
+fn request_variable(request: &http::Request, name: &str) -> Option<String> {
+    let mut ngx_name = ngx::ffi::ngx_str_t {
+        len: name.len(),
+        data: name.as_ptr() as *mut u8,
+    };
+    let r = crate::logging::request_ptr(request) as *mut ngx::ffi::ngx_http_request_t;
+    // SAFETY: `r` is the live request and the name outlives the call; nginx only reads it
+    let value = unsafe {
+        let key = ngx::ffi::ngx_hash_key(ngx_name.data, ngx_name.len);
+        ngx::ffi::ngx_http_get_variable(r, &mut ngx_name, key).as_ref()
+    }?;
+    if value.not_found() != 0 || value.len() == 0 || value.data.is_null() {
+        return None;
+    }
+    // SAFETY: `data` holds `len` bytes allocated from the request pool
+    let bytes = unsafe { std::slice::from_raw_parts(value.data, value.len() as usize) };
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Attributes for the EPP: method, path and host with `inference_epp_send_request_attributes on`,
+/// the host alone with `inference_epp_send_host on`, the client certificate with
+/// `inference_epp_send_client_cert on`, plus every `inference_epp_attribute` evaluated for this
+/// request. `None` if none is configured.
+pub fn request_attributes(
+    request: &http::Request,
+    conf: &ModuleConfig,
+) -> Option<RequestAttributes> {
+    let request_attributes = conf.epp_send_request_attributes.unwrap_or_default();
+    let host = conf.epp_send_host.unwrap_or_default();
+    let client_cert = conf.epp_send_client_cert.unwrap_or_default();
+    if !request_attributes && !host && !client_cert && conf.epp_attributes.is_empty() {
+        return None;
+    }
+    let mut attributes = RequestAttributes::default();
+    let r = request.as_ref();
+    if request_attributes || host {
+        // SAFETY: nginx sets `headers_in.host` to the Host header entry or null
+        let host = unsafe { r.headers_in.host.as_ref() }.map_or(r.headers_in.server, |h| h.value);
+        attributes.host = String::from_utf8_lossy(host.as_bytes()).into_owned();
+    }
+    if request_attributes {
+        attributes.method = String::from_utf8_lossy(r.method_name.as_bytes()).into_owned();
+        attributes.path = String::from_utf8_lossy(r.unparsed_uri.as_bytes()).into_owned();
+    }
+    if client_cert {
+        attributes.extra.extend(client_cert_attributes(
+            request_variable(request, "ssl_client_s_dn"),
+            request_variable(request, "ssl_client_verify"),
+        ));
+    }
+    for (key, value) in &conf.epp_attributes {
+        // SAFETY: compiled from the configuration pool, which outlives the request
+        let value = unsafe { value.0.as_ref() }
+            .and_then(|cv| request.get_complex_value(cv))
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .unwrap_or_default();
+        attributes.extra.push((key.clone(), value));
+    }
+    Some(attributes)
+}
+
+/// Worker cache key for this request's EPP decision, or `None` when `inference_epp_cache_ttl_ms`
+/// is off or the key is empty. The key is `inference_epp_cache_key` if set, else the BBR model.
+pub fn decision_cache_key(
+    request: &http::Request,
+    conf: &ModuleConfig,
+    endpoint: &str,
+) -> Option<String> {
+    if conf.epp_cache_ttl_ms == 0 {
+        return None;
+    }
+    let key = match conf.epp_cache_key {
+        // SAFETY: compiled from the configuration pool, which outlives the request
+        Some(template) => unsafe { template.0.as_ref() }
+            .and_then(|cv| request.get_complex_value(cv))
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())?,
+        None => crate::modules::bbr::model_header(request, conf)?,
+    };
+    (!key.is_empty()).then(|| cache::cache_key(endpoint, &key))
+}
+
+/// Keep headers in order until either limit would be exceeded; returns the kept headers and
+/// the number dropped. Header size is counted as name length plus value length.
+pub(crate) fn limit_headers(
+    headers: impl IntoIterator<Item = (String, Vec<u8>)>,
+    max_headers: usize,
+    max_bytes: usize,
+) -> (Vec<(String, Vec<u8>)>, usize) {
+    let mut kept = Vec::new();
+    let mut total_bytes = 0usize;
+    let mut dropped = 0usize;
+    for (name, value) in headers {
+        let size = name.len() + value.len();
+        if dropped > 0 || kept.len() >= max_headers || total_bytes + size > max_bytes {
+            dropped += 1;
+            continue;
+        }
+        total_bytes += size;
+        kept.push((name, value));
+    }
+    (kept, dropped)
+}
+
+/// Whether EPP should be skipped because the upstream header is already present.
+///
+/// With `inference_epp_skip_if_set off` EPP always runs and its result overwrites the header.
+fn skip_for_existing_header(skip_if_set: bool, header_present: bool) -> bool {
+    skip_if_set && header_present
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_if_set_on_skips_when_header_present() {
+        assert!(skip_for_existing_header(true, true));
+        assert!(!skip_for_existing_header(true, false));
+    }
+
+    #[test]
+    fn test_skip_if_set_off_always_runs() {
+        assert!(!skip_for_existing_header(false, true));
+        assert!(!skip_for_existing_header(false, false));
+    }
+
+    fn headers(n: usize) -> Vec<(String, Vec<u8>)> {
+        (0..n)
+            .map(|i| (format!("x-h{}", i), vec![b'v'; 10]))
+            .collect()
+    }
+
+    #[test]
+    fn test_limit_headers_within_limits() {
+        let (kept, dropped) = limit_headers(headers(5), 100, 64 * 1024);
+        assert_eq!(kept.len(), 5);
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn test_limit_headers_truncates_by_count() {
+        let (kept, dropped) = limit_headers(headers(1000), 100, usize::MAX);
+        assert_eq!(kept.len(), 100);
+        assert_eq!(dropped, 900);
+        assert_eq!(kept[99].0, "x-h99");
+    }
+
+    #[test]
+    fn test_limit_headers_truncates_by_bytes() {
+        // Each header is 4 + 10 = 14 bytes
+        let (kept, dropped) = limit_headers(headers(10), 100, 14 * 3 + 5);
+        assert_eq!(kept.len(), 3);
+        assert_eq!(dropped, 7);
+    }
+
+    #[test]
+    fn test_header_allow_list() {
+        assert!(header_allowed(&[], "cookie"));
+
+        let allow = vec!["x-request-id".to_string(), "x-tenant".to_string()];
+        assert!(header_allowed(&allow, "X-Request-Id"));
+        assert!(header_allowed(&allow, "x-tenant"));
+        assert!(!header_allowed(&allow, "authorization"));
+    }
+
+    #[test]
+    fn test_request_id_header_added_once() {
+        let mut sent = headers(2);
+        add_request_id_header(&mut sent, "X-Request-Id", b"4f1c9a");
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[2], ("X-Request-Id".to_string(), b"4f1c9a".to_vec()));
+
+        // Already forwarded (any case): the client's value is kept, not duplicated
+        let mut sent = vec![("x-request-id".to_string(), b"client".to_vec())];
+        add_request_id_header(&mut sent, "X-Request-Id", b"4f1c9a");
+        assert_eq!(sent, vec![("x-request-id".to_string(), b"client".to_vec())]);
+
+        let mut sent = headers(1);
+        add_request_id_header(&mut sent, "X-Request-Id", b"");
+        assert_eq!(sent.len(), 1);
+    }
+
+    #[test]
+    fn test_client_cert_attributes() {
+        let attrs = client_cert_attributes(
+            Some("CN=tenant-a,O=Example".to_string()),
+            Some("SUCCESS".to_string()),
+        );
+        assert_eq!(
+            attrs,
+            vec![
+                (
+                    "connection.subject_peer_certificate".to_string(),
+                    "CN=tenant-a,O=Example".to_string()
+                ),
+                (
+                    "connection.client_verify".to_string(),
+                    "SUCCESS".to_string()
+                ),
+            ]
+        );
+
+        // No client certificate: only the verify result is known
+        let attrs = client_cert_attributes(None, Some("NONE".to_string()));
+        assert_eq!(
+            attrs,
+            vec![("connection.client_verify".to_string(), "NONE".to_string())]
+        );
+        assert!(client_cert_attributes(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_skip_if_set_defaults_on_and_inherits_off() {
+        use ngx::http::Merge;
+
+        assert!(ModuleConfig::default().epp_skip_if_set.unwrap_or(true));
+
+        let parent = ModuleConfig {
+            epp_skip_if_set: Some(false),
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert_eq!(child.epp_skip_if_set, Some(false));
+    }
+
+    #[test]
+    fn test_endpoint_variable() {
+        assert_eq!(endpoint_variable("$EPP_Host"), Some("epp_host".to_string()));
+        assert_eq!(endpoint_variable("epp-service:9001"), None);
+        assert_eq!(endpoint_variable("https://epp:9001"), None);
+        assert_eq!(endpoint_variable("$"), None);
+    }
+
+    #[test]
+    fn test_send_headers_defaults_on_and_inherits_off() {
+        use ngx::http::Merge;
+
+        assert!(ModuleConfig::default().epp_send_headers.unwrap_or(true));
+
+        let parent = ModuleConfig {
+            epp_send_headers: Some(false),
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert_eq!(child.epp_send_headers, Some(false));
+    }
+}
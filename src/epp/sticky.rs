@@ -0,0 +1,166 @@
+//! Worker-local sticky EPP decisions (`inference_epp_sticky_ttl_ms`)
+//!
+//! EPP is still called for every request, but once it has picked an upstream for a model,
+//! a different pick within the TTL is overridden with the previous upstream so bursty
+//! sessions keep reusing backend connections. The EPP can force the change by setting the
+//! `upstream_changed` flag in its dynamic metadata. Like the decision cache, the state is
+//! per worker and keyed by EPP endpoint and model.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+/// Maximum number of sticky decisions kept per worker
+pub const EPP_STICKY_CAPACITY: usize = 1024;
+
+struct Entry {
+    upstream: String,
+    since_ms: u64,
+}
+
+/// Map of key to the upstream in use and when it was first selected
+pub struct StickyDecisions {
+    entries: HashMap<String, Entry>,
+    capacity: usize,
+}
+
+impl StickyDecisions {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Upstream to use for `key` when the EPP selected `selected` at `now_ms`.
+    ///
+    /// Returns the previous upstream if it was selected less than `ttl_ms` ago and the EPP
+    /// did not request the change; otherwise `selected`, which becomes the sticky upstream.
+    pub fn resolve(
+        &mut self,
+        key: &str,
+        selected: &str,
+        change_requested: bool,
+        now_ms: u64,
+        ttl_ms: u64,
+    ) -> String {
+        if ttl_ms == 0 || self.capacity == 0 {
+            return selected.to_string();
+        }
+        if let Some(entry) = self.entries.get(key) {
+            if entry.upstream == selected {
+                return selected.to_string();
+            }
+            if !change_requested && now_ms < entry.since_ms.saturating_add(ttl_ms) {
+                return entry.upstream.clone();
+            }
+        } else if self.entries.len() >= self.capacity {
+            // Expired entries go first, then the one held longest
+            self.entries
+                .retain(|_, e| e.since_ms.saturating_add(ttl_ms) > now_ms);
+            if self.entries.len() >= self.capacity {
+                if let Some(oldest) = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.since_ms)
+                    .map(|(k, _)| k.clone())
+                {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                upstream: selected.to_string(),
+                since_ms: now_ms,
+            },
+        );
+        selected.to_string()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+static STICKY_DECISIONS: OnceLock<Mutex<StickyDecisions>> = OnceLock::new();
+
+/// Resolve an EPP decision against this worker's sticky decisions
+pub fn resolve(
+    key: &str,
+    selected: &str,
+    change_requested: bool,
+    now_ms: u64,
+    ttl_ms: u64,
+) -> String {
+    let sticky =
+        STICKY_DECISIONS.get_or_init(|| Mutex::new(StickyDecisions::new(EPP_STICKY_CAPACITY)));
+    let mut sticky = sticky.lock().unwrap_or_else(|e| e.into_inner());
+    sticky.resolve(key, selected, change_requested, now_ms, ttl_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sticky_within_ttl() {
+        let mut sticky = StickyDecisions::new(8);
+        assert_eq!(sticky.resolve("llama", "up-a", false, 1_000, 500), "up-a");
+        // A different pick inside the TTL keeps the first upstream
+        assert_eq!(sticky.resolve("llama", "up-b", false, 1_200, 500), "up-a");
+        assert_eq!(sticky.resolve("llama", "up-b", false, 1_499, 500), "up-a");
+        // Other models are independent
+        assert_eq!(sticky.resolve("mistral", "up-b", false, 1_200, 500), "up-b");
+    }
+
+    #[test]
+    fn test_sticky_changes_after_expiry() {
+        let mut sticky = StickyDecisions::new(8);
+        sticky.resolve("llama", "up-a", false, 1_000, 500);
+        assert_eq!(sticky.resolve("llama", "up-b", false, 1_500, 500), "up-b");
+        // The new upstream starts its own TTL
+        assert_eq!(sticky.resolve("llama", "up-a", false, 1_900, 500), "up-b");
+        assert_eq!(sticky.resolve("llama", "up-a", false, 2_000, 500), "up-a");
+    }
+
+    #[test]
+    fn test_sticky_agreeing_pick_keeps_ttl() {
+        let mut sticky = StickyDecisions::new(8);
+        sticky.resolve("llama", "up-a", false, 1_000, 500);
+        // Repeating the same pick does not extend how long it is held
+        sticky.resolve("llama", "up-a", false, 1_400, 500);
+        assert_eq!(sticky.resolve("llama", "up-b", false, 1_500, 500), "up-b");
+    }
+
+    #[test]
+    fn test_sticky_change_requested_by_epp() {
+        let mut sticky = StickyDecisions::new(8);
+        sticky.resolve("llama", "up-a", false, 1_000, 500);
+        assert_eq!(sticky.resolve("llama", "up-b", true, 1_100, 500), "up-b");
+        assert_eq!(sticky.resolve("llama", "up-a", false, 1_200, 500), "up-b");
+    }
+
+    #[test]
+    fn test_sticky_disabled_with_zero_ttl() {
+        let mut sticky = StickyDecisions::new(8);
+        assert_eq!(sticky.resolve("llama", "up-a", false, 1_000, 0), "up-a");
+        assert_eq!(sticky.resolve("llama", "up-b", false, 1_001, 0), "up-b");
+        assert!(sticky.is_empty());
+    }
+
+    #[test]
+    fn test_sticky_evicts_when_full() {
+        let mut sticky = StickyDecisions::new(2);
+        sticky.resolve("a", "up-a", false, 0, 1_000);
+        sticky.resolve("b", "up-b", false, 10, 1_000);
+        sticky.resolve("c", "up-c", false, 20, 1_000);
+        assert_eq!(sticky.len(), 2);
+        // "a" was held longest and is gone, so a new pick is taken as-is
+        assert_eq!(sticky.resolve("a", "up-x", false, 30, 1_000), "up-x");
+    }
+}
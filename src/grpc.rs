@@ -2,6 +2,7 @@
 //!
 //! This module implements EPP (Endpoint Picker Processor) for Gateway API Inference Extension:
 //! - Headers-only exchange for upstream endpoint selection
+//! - Request body sent as a follow-up when the EPP asks for it via `mode_override`
 //!
 //! The implementation follows the Gateway API Inference Extension specification.
 //!
@@ -15,7 +16,7 @@
 //!
 //! **Helper Functions (Testing/Internal):**
 //! - `epp_headers_blocking_internal()` - Internal async function without nginx dependencies
-//!   - Used for testing gRPC logic independently
+//!   - Used by the async EPP processor and for testing gRPC logic independently
 //!   - No nginx logging (pure async)
 //!   - Uses `parse_response_for_header_async()` (no logging overhead)
 //!
@@ -25,12 +26,23 @@
 //!   - Demonstrates why naive async doesn't work with nginx
 //!   - DO NOT USE - causes worker crashes
 
+use crate::epp::context::{attempt_timeout_ms, current_time_ms, UNBOUNDED_EPP_TIMEOUT_CAP_MS};
+use crate::logging::{
+    ngx_log_debug_http, ngx_log_error_http, ngx_log_info_http, ngx_log_warn_http,
+};
+use crate::modules::config::{
+    EppBodySendMode, EppGrpcCompression, EppHeaderSources, EppTlsMinVersion,
+};
 use crate::protos::envoy;
-use ngx::{http, ngx_log_debug_http};
+use envoy::config::core::v3::header_value_option::HeaderAppendAction;
+use ngx::http;
 
-use std::sync::OnceLock;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use tonic::transport::{Channel, Uri};
+use tonic::codec::CompressionEncoding;
+use tonic::transport::{Certificate, Channel, Endpoint, Uri};
 
 // Helper function to extract domain/host from URI for TLS verification
 // Handles IPv6, schemes, and various endpoint formats correctly
@@ -39,32 +51,14 @@ fn extract_domain_from_uri(uri: &str) -> Result<String, String> {
         .map_err(|e| format!("Invalid URI: {}", e))?
         .authority()
         .ok_or_else(|| format!("Missing authority in URI: {}", uri))
-        .map(|auth| auth.host().to_string())
+        .map(|auth| strip_ipv6_brackets(auth.host()).to_string())
 }
 
-// Helper macro for error-level logging in gRPC operations
-macro_rules! ngx_log_error_http {
-    ($request:expr, $($arg:tt)*) => {{
-        #[allow(unused_unsafe)]
-        unsafe {
-            // Access raw request pointer for immutable connection access
-            let r_ptr = $request as *const ngx::http::Request as *const ngx::ffi::ngx_http_request_t;
-            if !r_ptr.is_null() {
-                let r_ref = &*r_ptr;
-                if let Some(conn) = r_ref.connection.as_ref() {
-                    let msg = format!($($arg)*);
-                    if let Ok(c_msg) = std::ffi::CString::new(msg) {
-                        ngx::ffi::ngx_log_error_core(
-                            ngx::ffi::NGX_LOG_ERR as ngx::ffi::ngx_uint_t,
-                            conn.log,
-                            0,
-                            c_msg.as_ptr(),
-                        );
-                    }
-                }
-            }
-        }
-    }};
+// `Authority::host()` keeps the brackets around IPv6 literals; TLS server names must not have them
+fn strip_ipv6_brackets(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
 }
 
 /// Extract detailed error information from transport errors
@@ -87,16 +81,87 @@ fn extract_error_details(error: &tonic::transport::Error) -> String {
     }
 }
 
-static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+/// Failure of an EPP exchange, by kind so callers can pick the failure handling
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EppError {
+    /// Invalid endpoint or plaintext connection failure
+    Connect(String),
+    /// gRPC call or stream failure after the connection was established
+    Transport(String),
+    /// No upstream within `inference_epp_timeout_ms`
+    Timeout,
+    /// TLS configuration or handshake failure
+    Tls(String),
+    /// EPP responses that cannot be used, e.g. too many without the upstream header
+    Parse(String),
+    /// The EPP-selected upstream was rejected (`inference_upstream_validate_regex`)
+    NoUpstream(String),
+    /// The upstream header value is longer than `inference_upstream_max_len`; holds its length
+    UpstreamTooLong(usize),
+    /// The EPP ended the exchange with an ImmediateResponse carrying this
+    /// `envoy.type.v3.StatusCode`, see [`immediate_response_status`]
+    Immediate(i32),
+}
 
-fn get_runtime() -> &'static tokio::runtime::Runtime {
-    RUNTIME.get_or_init(|| {
-        tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .thread_name("ngx-inference-grpc")
-            .build()
-            .expect("Failed to create Tokio runtime")
-    })
+impl EppError {
+    /// Whether the failure is a timeout (`inference_epp_timeout_status`) rather than an
+    /// error (`inference_epp_failure_status`)
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, EppError::Timeout)
+    }
+
+    /// Whether the connection itself failed, so a cached channel should not be reused; other
+    /// errors are answers from a working connection
+    pub fn is_connection_failure(&self) -> bool {
+        matches!(
+            self,
+            EppError::Connect(_) | EppError::Tls(_) | EppError::Transport(_)
+        )
+    }
+}
+
+impl std::fmt::Display for EppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EppError::Connect(e) => write!(f, "connect error: {e}"),
+            EppError::Transport(e) => write!(f, "transport error: {e}"),
+            EppError::Timeout => write!(f, "timed out waiting for the upstream header"),
+            EppError::Tls(e) => write!(f, "tls error: {e}"),
+            EppError::Parse(e) => write!(f, "response error: {e}"),
+            EppError::NoUpstream(e) => write!(f, "no usable upstream: {e}"),
+            EppError::UpstreamTooLong(len) => write!(
+                f,
+                "upstream header value is {len} bytes, over inference_upstream_max_len"
+            ),
+            EppError::Immediate(code) => write!(f, "immediate response with status {code}"),
+        }
+    }
+}
+
+impl std::error::Error for EppError {}
+
+static SYSTEM_ROOTS: OnceLock<bool> = OnceLock::new();
+
+/// Whether the system trust store has any certificate for verifying the EPP without
+/// `inference_epp_ca_file`; loaded once per process
+pub fn system_roots_available() -> bool {
+    *SYSTEM_ROOTS.get_or_init(|| !rustls_native_certs::load_native_certs().certs.is_empty())
+}
+
+static RUNTIME: OnceLock<Result<tokio::runtime::Runtime, String>> = OnceLock::new();
+
+/// Runtime for the blocking client; a build failure is kept and returned as an EPP error
+fn get_runtime() -> Result<&'static tokio::runtime::Runtime, String> {
+    RUNTIME
+        .get_or_init(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .thread_name("ngx-inference-grpc")
+                .build()
+                .map_err(|e| format!("failed to create Tokio runtime: {e}"))
+        })
+        .as_ref()
+        .map_err(Clone::clone)
 }
 
 type ExternalProcessorClient<T> =
@@ -109,15 +174,422 @@ type ProtocolConfiguration = envoy::service::ext_proc::v3::ProtocolConfiguration
 type BodySendMode = envoy::extensions::filters::http::ext_proc::v3::processing_mode::BodySendMode;
 
 type HttpHeaders = envoy::service::ext_proc::v3::HttpHeaders;
+type HttpBody = envoy::service::ext_proc::v3::HttpBody;
 type HeaderMap = envoy::config::core::v3::HeaderMap;
 
+/// Attribute namespace Envoy uses for ext_proc request attributes
+const EXT_PROC_ATTRIBUTES_KEY: &str = "envoy.filters.http.ext_proc";
+
+/// Request attributes sent to the EPP (`inference_epp_send_request_attributes`,
+/// `inference_epp_attribute`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestAttributes {
+    /// Request method (`request.method`)
+    pub method: String,
+    /// Request path including the query string (`request.path`)
+    pub path: String,
+    /// Request host (`request.host`)
+    pub host: String,
+    /// Operator-defined attributes from `inference_epp_attribute`, after variable expansion
+    pub extra: Vec<(String, String)>,
+}
+
+impl RequestAttributes {
+    /// `ProcessingRequest.attributes` map, keyed by the ext_proc filter name like Envoy does
+    fn to_proto(&self) -> HashMap<String, prost_types::Struct> {
+        use prost_types::value::Kind;
+
+        let string_value = |v: &str| prost_types::Value {
+            kind: Some(Kind::StringValue(v.to_string())),
+        };
+        // Empty request fields were not requested; extra attributes are sent as configured
+        let fields = [
+            ("request.method", &self.method),
+            ("request.path", &self.path),
+            ("request.host", &self.host),
+        ]
+        .into_iter()
+        .filter(|(_, v)| !v.is_empty())
+        .chain(self.extra.iter().map(|(k, v)| (k.as_str(), v)))
+        .map(|(k, v)| (k.to_string(), string_value(v)))
+        .collect();
+        HashMap::from([(
+            EXT_PROC_ATTRIBUTES_KEY.to_string(),
+            prost_types::Struct { fields },
+        )])
+    }
+}
+
+/// ext_proc header entry for a request header: UTF-8 values go in `value`, anything else is
+/// sent unchanged in `raw_value` rather than dropped
+fn header_value(key: String, value: Vec<u8>) -> envoy::config::core::v3::HeaderValue {
+    match String::from_utf8(value) {
+        Ok(value) => envoy::config::core::v3::HeaderValue {
+            key,
+            value,
+            raw_value: Vec::new(),
+        },
+        Err(e) => envoy::config::core::v3::HeaderValue {
+            key,
+            value: String::new(),
+            raw_value: e.into_bytes(),
+        },
+    }
+}
+
+/// Maximum number of EPP channels kept per worker
+const EPP_CHANNEL_CACHE_CAPACITY: usize = 64;
+
+struct CachedChannel<C> {
+    channel: C,
+    last_used: Instant,
+    idle: Duration,
+}
+
+/// EPP channels reused across calls with `inference_epp_channel_idle_ms`, keyed by endpoint and
+/// TLS settings. A channel idle for longer is dropped and the next call connects again, which
+/// re-resolves the EPP host, so a rescheduled EPP pod is picked up.
+struct ChannelCache<C> {
+    entries: HashMap<String, CachedChannel<C>>,
+    capacity: usize,
+}
+
+impl<C: Clone> ChannelCache<C> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// The channel for `key` if it was last used within `idle`, marked as used at `now`
+    fn get(&mut self, key: &str, idle: Duration, now: Instant) -> Option<C> {
+        let entry = self.entries.get_mut(key)?;
+        if now.saturating_duration_since(entry.last_used) > idle {
+            self.entries.remove(key);
+            return None;
+        }
+        entry.last_used = now;
+        Some(entry.channel.clone())
+    }
+
+    /// Keep `channel` for `key` until it has been idle for `idle`. Channels idle past their own
+    /// limit are dropped first, so endpoints that are never called again (an old pod IP, a
+    /// `$variable` value) do not keep their connections open; at capacity, the least recently
+    /// used channel goes too.
+    fn insert(&mut self, key: String, channel: C, idle: Duration, now: Instant) {
+        self.entries
+            .retain(|_, e| now.saturating_duration_since(e.last_used) <= e.idle);
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(
+            key,
+            CachedChannel {
+                channel,
+                last_used: now,
+                idle,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+}
+
+static CHANNEL_CACHE: OnceLock<Mutex<ChannelCache<Channel>>> = OnceLock::new();
+
+fn channel_cache() -> std::sync::MutexGuard<'static, ChannelCache<Channel>> {
+    CHANNEL_CACHE
+        .get_or_init(|| Mutex::new(ChannelCache::new(EPP_CHANNEL_CACHE_CAPACITY)))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Parsed CA certificate with the file metadata it was read at
+struct CachedCa {
+    modified: SystemTime,
+    len: u64,
+    cert: Certificate,
+}
+
+static CA_CACHE: OnceLock<Mutex<HashMap<String, CachedCa>>> = OnceLock::new();
+
+/// Load the CA certificate at `path`, reusing the cached copy until the file's modification
+/// time or size changes. Avoids a file read per EPP call and picks up rotated certificates
+/// without an nginx restart.
+fn load_ca_certificate(path: &str) -> Result<Certificate, String> {
+    let meta = std::fs::metadata(path)
+        .map_err(|e| format!("Failed to read CA certificate file '{}': {}", path, e))?;
+    let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    let len = meta.len();
+
+    let cache = CA_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(cached) = cache.get(path) {
+        if cached.modified == modified && cached.len == len {
+            return Ok(cached.cert.clone());
+        }
+    }
+
+    let ca_cert = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read CA certificate file '{}': {}", path, e))?;
+    let cert = Certificate::from_pem(&ca_cert);
+    cache.insert(
+        path.to_string(),
+        CachedCa {
+            modified,
+            len,
+            cert: cert.clone(),
+        },
+    );
+    Ok(cert)
+}
+
+/// The certificates of the CA bundle at `path`, one per PEM block, so a file of concatenated
+/// CA certificates (e.g. an intermediate and its root) is trusted in full
+fn load_ca_certificates(path: &str) -> Result<Vec<Certificate>, String> {
+    let certs = split_pem_certificates(load_ca_certificate(path)?.get_ref());
+    if certs.is_empty() {
+        return Err(format!(
+            "CA certificate file '{}' contains no PEM certificates",
+            path
+        ));
+    }
+    Ok(certs)
+}
+
+/// Each `CERTIFICATE` block of a PEM bundle as its own certificate; text between blocks
+/// (comments, other PEM types) is ignored
+fn split_pem_certificates(pem: &[u8]) -> Vec<Certificate> {
+    const BEGIN: &[u8] = b"-----BEGIN CERTIFICATE-----";
+    const END: &[u8] = b"-----END CERTIFICATE-----";
+    let find = |haystack: &[u8], needle: &[u8]| {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    };
+
+    let mut certs = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = find(rest, BEGIN) {
+        let Some(len) = find(&rest[start..], END) else {
+            break;
+        };
+        let end = start + len + END.len();
+        let mut block = rest[start..end].to_vec();
+        block.push(b'\n');
+        certs.push(Certificate::from_pem(block));
+        rest = &rest[end..];
+    }
+    certs
+}
+
 fn normalize_endpoint(endpoint: &str, use_tls: bool) -> String {
     if endpoint.starts_with("http://") || endpoint.starts_with("https://") {
+        return endpoint.to_string();
+    }
+    // A bare IPv6 literal (no port) must be bracketed to form a valid authority
+    let authority = if endpoint.parse::<std::net::Ipv6Addr>().is_ok() {
+        format!("[{}]", endpoint)
+    } else {
         endpoint.to_string()
-    } else if use_tls {
-        format!("https://{}", endpoint)
+    };
+    if use_tls {
+        format!("https://{}", authority)
+    } else {
+        format!("http://{}", authority)
+    }
+}
+
+/// rustls client configuration accepting only `min_version` and newer, trusting `ca_file` or
+/// the system roots like `ClientTlsConfig` does
+fn rustls_client_config(
+    ca_file: Option<&str>,
+    min_version: EppTlsMinVersion,
+) -> Result<rustls::ClientConfig, String> {
+    use rustls_pki_types::pem::PemObject;
+    use rustls_pki_types::CertificateDer;
+
+    let versions: &[&'static rustls::SupportedProtocolVersion] = match min_version {
+        EppTlsMinVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        EppTlsMinVersion::Tls13 => &[&rustls::version::TLS13],
+    };
+
+    let mut roots = rustls::RootCertStore::empty();
+    if let Some(ca_path) = ca_file {
+        let ca = load_ca_certificate(ca_path)?;
+        for cert in CertificateDer::pem_slice_iter(ca.get_ref()) {
+            let cert =
+                cert.map_err(|e| format!("Invalid CA certificate file '{}': {}", ca_path, e))?;
+            roots
+                .add(cert)
+                .map_err(|e| format!("Invalid CA certificate file '{}': {}", ca_path, e))?;
+        }
     } else {
-        format!("http://{}", endpoint)
+        roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    }
+
+    let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)
+        .map_err(|e| format!("tls config error: {e}"))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(config)
+}
+
+/// Connect to the EPP at `uri` over TLS restricted to `min_version` and newer.
+///
+/// `ClientTlsConfig` cannot limit protocol versions, so the handshake is done by a rustls
+/// connector and tonic speaks HTTP/2 over the resulting stream.
+async fn connect_tls_min_version(
+    uri: &str,
+    domain: &str,
+    ca_file: Option<&str>,
+    min_version: EppTlsMinVersion,
+    user_agent: &str,
+) -> Result<Channel, String> {
+    let origin = uri
+        .parse::<Uri>()
+        .map_err(|e| format!("Invalid URI: {}", e))?;
+    let port = origin.port_u16().unwrap_or(443);
+    let config = rustls_client_config(ca_file, min_version)?;
+    let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(config));
+    let server_name = rustls_pki_types::ServerName::try_from(domain.to_string())
+        .map_err(|e| format!("Invalid TLS server name '{}': {}", domain, e))?;
+
+    // The channel URI is only handed to the connector; `origin` keeps the https authority on
+    // requests. An https channel URI would make tonic insist on its own TLS layer.
+    let authority = origin.authority().map(|a| a.as_str()).unwrap_or(domain);
+    let host = domain.to_string();
+    Endpoint::from_shared(format!("http://{}", authority))
+        .map_err(|e| format!("channel error: {e}"))?
+        .user_agent(user_agent)
+        .map_err(|e| format!("invalid user agent: {e}"))?
+        .origin(origin)
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let connector = connector.clone();
+            let server_name = server_name.clone();
+            let host = host.clone();
+            async move {
+                let tcp = tokio::net::TcpStream::connect((host.as_str(), port)).await?;
+                let tls = connector.connect(server_name, tcp).await?;
+                Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(tls))
+            }
+        }))
+        .await
+        .map_err(|e| extract_error_details(&e))
+}
+
+/// Whether the response's `mode_override` asks for the request body to be sent.
+///
+/// EPPs may decide per request that they need the body to pick an endpoint; they signal this
+/// by overriding `request_body_mode` to `BUFFERED` or `STREAMED` in a response.
+fn override_requests_body(resp: &ProcessingResponse) -> bool {
+    resp.mode_override.as_ref().is_some_and(|mode| {
+        mode.request_body_mode == BodySendMode::Buffered as i32
+            || mode.request_body_mode == BodySendMode::Streamed as i32
+    })
+}
+
+/// Headers whose values `inference_epp_log_response_sample` never writes to the log
+const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+/// `resp` formatted for the `inference_epp_log_response_sample` log line, with the values of
+/// [`REDACTED_HEADERS`] in its header mutations replaced
+pub fn describe_response(resp: &ProcessingResponse) -> String {
+    use envoy::service::ext_proc::v3::processing_response::Response;
+
+    let mut resp = resp.clone();
+    let mutation = match &mut resp.response {
+        Some(Response::RequestHeaders(r)) | Some(Response::ResponseHeaders(r)) => r
+            .response
+            .as_mut()
+            .and_then(|common| common.header_mutation.as_mut()),
+        Some(Response::RequestBody(r)) | Some(Response::ResponseBody(r)) => r
+            .response
+            .as_mut()
+            .and_then(|common| common.header_mutation.as_mut()),
+        Some(Response::RequestTrailers(r)) | Some(Response::ResponseTrailers(r)) => {
+            r.header_mutation.as_mut()
+        }
+        Some(Response::ImmediateResponse(r)) => r.headers.as_mut(),
+        None => None,
+    };
+    for header in mutation
+        .into_iter()
+        .flat_map(|m| m.set_headers.iter_mut())
+        .filter_map(|hvo| hvo.header.as_mut())
+        .filter(|h| {
+            REDACTED_HEADERS
+                .iter()
+                .any(|n| h.key.eq_ignore_ascii_case(n))
+        })
+    {
+        header.value = "<redacted>".to_string();
+        header.raw_value.clear();
+    }
+    format!("{:?}", resp)
+}
+
+/// Build the follow-up `ProcessingRequest` carrying the whole request body in one chunk.
+fn build_body_request(body: &[u8]) -> ProcessingRequest {
+    build_body_chunk(body, true)
+}
+
+/// Size of each `RequestBody` message with `inference_epp_body_send_mode streamed`
+const STREAMED_BODY_CHUNK_SIZE: usize = 16 * 1024;
+
+/// The `RequestBody` messages carrying `body`: chunks of [`STREAMED_BODY_CHUNK_SIZE`] with
+/// `streamed`, the last one marked end of stream, otherwise the whole body in one message.
+fn build_body_requests(body: &[u8], mode: EppBodySendMode) -> Vec<ProcessingRequest> {
+    if mode != EppBodySendMode::Streamed || body.len() <= STREAMED_BODY_CHUNK_SIZE {
+        return vec![build_body_request(body)];
+    }
+    let last = (body.len() - 1) / STREAMED_BODY_CHUNK_SIZE;
+    body.chunks(STREAMED_BODY_CHUNK_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| build_body_chunk(chunk, i == last))
+        .collect()
+}
+
+/// ext_proc `request_body_mode` announced to the EPP for `inference_epp_body_send_mode`
+fn request_body_mode(mode: EppBodySendMode) -> BodySendMode {
+    match mode {
+        EppBodySendMode::None => BodySendMode::None,
+        EppBodySendMode::Buffered => BodySendMode::Buffered,
+        EppBodySendMode::Streamed => BodySendMode::Streamed,
+        EppBodySendMode::BufferedPartial => BodySendMode::BufferedPartial,
+    }
+}
+
+fn build_body_chunk(chunk: &[u8], end_of_stream: bool) -> ProcessingRequest {
+    use envoy::service::ext_proc::v3::processing_request;
+    ProcessingRequest {
+        request: Some(processing_request::Request::RequestBody(HttpBody {
+            body: chunk.to_vec(),
+            end_of_stream,
+        })),
+        metadata_context: None,
+        attributes: std::collections::HashMap::new(),
+        observability_mode: false,
+        protocol_config: None,
     }
 }
 
@@ -125,7 +597,8 @@ fn extract_header_from_mutation(
     request: &http::Request,
     mutation: &envoy::service::ext_proc::v3::HeaderMutation,
     target_key_lower: &str,
-) -> Option<String> {
+    max_len: usize,
+) -> Result<Option<String>, EppError> {
     ngx_log_debug_http!(
         request,
         "ngx-inference: Searching for header '{}' in mutation with {} headers",
@@ -157,28 +630,26 @@ fn extract_header_from_mutation(
             );
             // Keys are lower-cased in HttpHeaders; we compare ASCII-case-insensitively just in case.
             if hdr.key.eq_ignore_ascii_case(target_key_lower) {
-                if !hdr.value.is_empty() {
-                    let value = hdr.value.clone();
-                    ngx_log_debug_http!(
-                        request,
-                        "ngx-inference: Found matching header with value: '{}'",
-                        value
-                    );
-                    return Some(value);
-                }
-                if !hdr.raw_value.is_empty() {
-                    let value = String::from_utf8_lossy(&hdr.raw_value).to_string();
-                    ngx_log_debug_http!(
-                        request,
-                        "ngx-inference: Found matching header with raw_value: '{}'",
-                        value
-                    );
-                    return Some(value);
+                match mutation_value(hdr, max_len) {
+                    Some(Ok(value)) => {
+                        ngx_log_debug_http!(
+                            request,
+                            "ngx-inference: Found matching header with value: '{}'",
+                            value
+                        );
+                        return Ok(Some(value));
+                    }
+                    Some(Err(e)) => {
+                        ngx_log_warn_http!(request, "ngx-inference: EPP {}", e);
+                        return Err(e);
+                    }
+                    None => {
+                        ngx_log_debug_http!(
+                            request,
+                            "ngx-inference: Found matching header key but no value"
+                        );
+                    }
                 }
-                ngx_log_debug_http!(
-                    request,
-                    "ngx-inference: Found matching header key but no value"
-                );
             }
         }
     }
@@ -188,14 +659,60 @@ fn extract_header_from_mutation(
         "ngx-inference: Target header '{}' not found in header mutation",
         target_key_lower
     );
-    None
+    Ok(None)
+}
+
+/// Upstream header value of `hdr`, `value` before `raw_value`; `None` if both are empty.
+///
+/// The length is checked against `max_len` (`inference_upstream_max_len`) before the value is
+/// copied, so an oversized value from the EPP is never duplicated.
+fn mutation_value(
+    hdr: &envoy::config::core::v3::HeaderValue,
+    max_len: usize,
+) -> Option<Result<String, EppError>> {
+    let len = if hdr.value.is_empty() {
+        hdr.raw_value.len()
+    } else {
+        hdr.value.len()
+    };
+    if len == 0 {
+        return None;
+    }
+    if len > max_len {
+        return Some(Err(EppError::UpstreamTooLong(len)));
+    }
+    Some(Ok(if hdr.value.is_empty() {
+        String::from_utf8_lossy(&hdr.raw_value).into_owned()
+    } else {
+        hdr.value.clone()
+    }))
+}
+
+/// Whether `resp` is a response variant trusted for the upstream header under `sources`.
+///
+/// With `request-headers` only mutations applied to the request headers are used; response-side
+/// mutations and ImmediateResponse headers never describe upstream selection.
+fn header_source_allowed(resp: &ProcessingResponse, sources: EppHeaderSources) -> bool {
+    use envoy::service::ext_proc::v3::processing_response;
+
+    match sources {
+        EppHeaderSources::Any => true,
+        EppHeaderSources::RequestHeaders => matches!(
+            resp.response,
+            Some(processing_response::Response::RequestHeaders(_))
+                | Some(processing_response::Response::RequestBody(_))
+                | Some(processing_response::Response::RequestTrailers(_))
+        ),
+    }
 }
 
 fn parse_response_for_header(
     request: &http::Request,
     resp: &ProcessingResponse,
     target_key_lower: &str,
-) -> Option<String> {
+    sources: EppHeaderSources,
+    max_len: usize,
+) -> Result<Option<String>, EppError> {
     use envoy::service::ext_proc::v3::processing_response;
 
     ngx_log_debug_http!(
@@ -204,6 +721,14 @@ fn parse_response_for_header(
         target_key_lower
     );
 
+    if !header_source_allowed(resp, sources) {
+        ngx_log_debug_http!(
+            request,
+            "ngx-inference: Ignoring response-side mutation (inference_epp_header_sources request-headers)"
+        );
+        return Ok(None);
+    }
+
     match &resp.response {
         Some(processing_response::Response::RequestHeaders(hdrs)) => {
             ngx_log_debug_http!(request, "ngx-inference: Processing RequestHeaders response");
@@ -214,7 +739,7 @@ fn parse_response_for_header(
                         "ngx-inference: Found header mutation with {} headers",
                         hm.set_headers.len()
                     );
-                    return extract_header_from_mutation(request, hm, target_key_lower);
+                    return extract_header_from_mutation(request, hm, target_key_lower, max_len);
                 } else {
                     ngx_log_debug_http!(
                         request,
@@ -240,7 +765,7 @@ fn parse_response_for_header(
                         "ngx-inference: Found header mutation with {} headers",
                         hm.set_headers.len()
                     );
-                    return extract_header_from_mutation(request, hm, target_key_lower);
+                    return extract_header_from_mutation(request, hm, target_key_lower, max_len);
                 } else {
                     ngx_log_debug_http!(
                         request,
@@ -263,7 +788,7 @@ fn parse_response_for_header(
                         "ngx-inference: Found header mutation with {} headers",
                         hm.set_headers.len()
                     );
-                    return extract_header_from_mutation(request, hm, target_key_lower);
+                    return extract_header_from_mutation(request, hm, target_key_lower, max_len);
                 } else {
                     ngx_log_debug_http!(
                         request,
@@ -283,7 +808,7 @@ fn parse_response_for_header(
                         "ngx-inference: Found header mutation with {} headers",
                         hm.set_headers.len()
                     );
-                    return extract_header_from_mutation(request, hm, target_key_lower);
+                    return extract_header_from_mutation(request, hm, target_key_lower, max_len);
                 } else {
                     ngx_log_debug_http!(
                         request,
@@ -305,7 +830,7 @@ fn parse_response_for_header(
                     "ngx-inference: Found header mutation with {} headers",
                     hm.set_headers.len()
                 );
-                return extract_header_from_mutation(request, hm, target_key_lower);
+                return extract_header_from_mutation(request, hm, target_key_lower, max_len);
             } else {
                 ngx_log_debug_http!(
                     request,
@@ -324,7 +849,7 @@ fn parse_response_for_header(
                     "ngx-inference: Found header mutation with {} headers",
                     hm.set_headers.len()
                 );
-                return extract_header_from_mutation(request, hm, target_key_lower);
+                return extract_header_from_mutation(request, hm, target_key_lower, max_len);
             } else {
                 ngx_log_debug_http!(
                     request,
@@ -344,7 +869,7 @@ fn parse_response_for_header(
                     "ngx-inference: Found header mutation with {} headers",
                     hm.set_headers.len()
                 );
-                return extract_header_from_mutation(request, hm, target_key_lower);
+                return extract_header_from_mutation(request, hm, target_key_lower, max_len);
             } else {
                 ngx_log_debug_http!(
                     request,
@@ -361,97 +886,232 @@ fn parse_response_for_header(
         request,
         "ngx-inference: No matching header found in response"
     );
-    None
+    Ok(None)
+}
+
+/// Status code of `resp` if it is an ImmediateResponse; `Empty` (0) when it has no status
+fn immediate_response_code(resp: &ProcessingResponse) -> Option<i32> {
+    use envoy::service::ext_proc::v3::processing_response;
+
+    match &resp.response {
+        Some(processing_response::Response::ImmediateResponse(ir)) => {
+            Some(ir.status.as_ref().map_or(0, |status| status.code))
+        }
+        _ => None,
+    }
+}
+
+/// HTTP status nginx returns for an ImmediateResponse `code` (an `envoy.type.v3.StatusCode`).
+///
+/// `map` (`inference_epp_immediate_status_map`) is checked first. Otherwise codes in 200-599
+/// are returned as they are, and `Empty` or out-of-range codes become 500.
+pub fn immediate_response_status(code: i32, map: &[(u16, u16)]) -> u16 {
+    use envoy::r#type::v3::StatusCode;
+
+    if let Some(&(_, status)) = map.iter().find(|(from, _)| i32::from(*from) == code) {
+        return status;
+    }
+    match u16::try_from(code) {
+        Ok(status) if (200..=599).contains(&status) => status,
+        _ => StatusCode::InternalServerError as u16,
+    }
+}
+
+/// Upstream header selected by the EPP, with how it combines with an existing header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamHeader {
+    pub value: String,
+    /// `HeaderValueOption.append_action`; unknown values are treated as overwrite
+    pub append_action: HeaderAppendAction,
+    /// The EPP flagged this pick as a deliberate change (`upstream_changed` in its dynamic
+    /// metadata), overriding `inference_epp_sticky_ttl_ms`
+    pub change_requested: bool,
+    /// Other headers set by the same mutation, for `inference_epp_client_response_header`;
+    /// values longer than `inference_upstream_max_len` are left out
+    pub headers: Vec<(String, String)>,
+}
+
+/// Dynamic metadata namespace and key of the flag that overrides sticky decisions
+const UPSTREAM_CHANGED_NAMESPACE: &str = "envoy.lb";
+const UPSTREAM_CHANGED_KEY: &str = "upstream_changed";
+
+/// Whether `resp` carries `envoy.lb.upstream_changed: true` in its dynamic metadata
+fn upstream_change_requested(resp: &ProcessingResponse) -> bool {
+    use prost_types::value::Kind;
+
+    fn field<'a>(s: &'a prost_types::Struct, key: &str) -> Option<&'a Kind> {
+        s.fields.get(key)?.kind.as_ref()
+    }
+    let Some(metadata) = &resp.dynamic_metadata else {
+        return false;
+    };
+    match field(metadata, UPSTREAM_CHANGED_NAMESPACE) {
+        Some(Kind::StructValue(ns)) => {
+            matches!(field(ns, UPSTREAM_CHANGED_KEY), Some(Kind::BoolValue(true)))
+        }
+        _ => false,
+    }
+}
+
+/// Upstream header value carried by an EPP response, without touching the nginx request.
+///
+/// `target_key_lower` is matched case-insensitively; variants outside `sources` are ignored.
+pub fn parse_response_for_header_async(
+    resp: &ProcessingResponse,
+    target_key_lower: &str,
+    sources: EppHeaderSources,
+    max_len: usize,
+) -> Result<Option<String>, EppError> {
+    Ok(
+        parse_response_for_upstream_async(resp, target_key_lower, sources, max_len)?
+            .map(|h| h.value),
+    )
+}
+
+/// Like [`parse_response_for_header_async`], keeping the mutation's `append_action` and the
+/// EPP's `upstream_changed` flag
+pub fn parse_response_for_upstream_async(
+    resp: &ProcessingResponse,
+    target_key_lower: &str,
+    sources: EppHeaderSources,
+    max_len: usize,
+) -> Result<Option<UpstreamHeader>, EppError> {
+    let Some(mut upstream) = upstream_from_response(resp, target_key_lower, sources, max_len)?
+    else {
+        return Ok(None);
+    };
+    upstream.change_requested = upstream_change_requested(resp);
+    Ok(Some(upstream))
 }
 
-fn parse_response_for_header_async(
+fn upstream_from_response(
     resp: &ProcessingResponse,
     target_key_lower: &str,
-) -> Option<String> {
+    sources: EppHeaderSources,
+    max_len: usize,
+) -> Result<Option<UpstreamHeader>, EppError> {
     use envoy::service::ext_proc::v3::processing_response;
 
+    if !header_source_allowed(resp, sources) {
+        return Ok(None);
+    }
+
     match &resp.response {
         Some(processing_response::Response::RequestHeaders(hdrs)) => {
             if let Some(common) = &hdrs.response {
                 if let Some(hm) = &common.header_mutation {
-                    return extract_header_from_mutation_async(hm, target_key_lower);
+                    return extract_header_from_mutation_async(hm, target_key_lower, max_len);
                 }
             }
         }
         Some(processing_response::Response::ResponseHeaders(hdrs)) => {
             if let Some(common) = &hdrs.response {
                 if let Some(hm) = &common.header_mutation {
-                    return extract_header_from_mutation_async(hm, target_key_lower);
+                    return extract_header_from_mutation_async(hm, target_key_lower, max_len);
                 }
             }
         }
         Some(processing_response::Response::RequestBody(body)) => {
             if let Some(common) = &body.response {
                 if let Some(hm) = &common.header_mutation {
-                    return extract_header_from_mutation_async(hm, target_key_lower);
+                    return extract_header_from_mutation_async(hm, target_key_lower, max_len);
                 }
             }
         }
         Some(processing_response::Response::ResponseBody(body)) => {
             if let Some(common) = &body.response {
                 if let Some(hm) = &common.header_mutation {
-                    return extract_header_from_mutation_async(hm, target_key_lower);
+                    return extract_header_from_mutation_async(hm, target_key_lower, max_len);
                 }
             }
         }
         Some(processing_response::Response::RequestTrailers(tr)) => {
             if let Some(hm) = &tr.header_mutation {
-                return extract_header_from_mutation_async(hm, target_key_lower);
+                return extract_header_from_mutation_async(hm, target_key_lower, max_len);
             }
         }
         Some(processing_response::Response::ResponseTrailers(tr)) => {
             if let Some(hm) = &tr.header_mutation {
-                return extract_header_from_mutation_async(hm, target_key_lower);
+                return extract_header_from_mutation_async(hm, target_key_lower, max_len);
             }
         }
         Some(processing_response::Response::ImmediateResponse(ir)) => {
             if let Some(hm) = &ir.headers {
-                return extract_header_from_mutation_async(hm, target_key_lower);
+                return extract_header_from_mutation_async(hm, target_key_lower, max_len);
             }
         }
         None => {}
     }
 
-    None
+    Ok(None)
 }
 
 fn extract_header_from_mutation_async(
     mutation: &envoy::service::ext_proc::v3::HeaderMutation,
     target_key_lower: &str,
-) -> Option<String> {
+    max_len: usize,
+) -> Result<Option<UpstreamHeader>, EppError> {
     for hvo in &mutation.set_headers {
         if let Some(hdr) = &hvo.header {
             if hdr.key.eq_ignore_ascii_case(target_key_lower) {
-                if !hdr.value.is_empty() {
-                    return Some(hdr.value.clone());
-                }
-                if !hdr.raw_value.is_empty() {
-                    return Some(String::from_utf8_lossy(&hdr.raw_value).to_string());
-                }
+                let Some(value) = mutation_value(hdr, max_len) else {
+                    continue;
+                };
+                return Ok(Some(UpstreamHeader {
+                    value: value?,
+                    append_action: HeaderAppendAction::try_from(hvo.append_action)
+                        .unwrap_or(HeaderAppendAction::OverwriteIfExistsOrAdd),
+                    change_requested: false,
+                    headers: other_mutation_headers(mutation, target_key_lower, max_len),
+                }));
             }
         }
     }
-    None
+    Ok(None)
+}
+
+/// Headers of `mutation` other than the upstream header, as (key, value) pairs
+fn other_mutation_headers(
+    mutation: &envoy::service::ext_proc::v3::HeaderMutation,
+    target_key_lower: &str,
+    max_len: usize,
+) -> Vec<(String, String)> {
+    mutation
+        .set_headers
+        .iter()
+        .filter_map(|hvo| hvo.header.as_ref())
+        .filter(|hdr| !hdr.key.eq_ignore_ascii_case(target_key_lower))
+        .filter_map(|hdr| Some((hdr.key.clone(), mutation_value(hdr, max_len)?.ok()?)))
+        .collect()
 }
 
 /// EPP: Request headers and body exchange for upstream endpoint selection.
 ///
 /// Returns Ok(Some(value)) if the ext-proc service replies with a header mutation
-/// for the specified header name; Ok(None) if not present; Err(...) on transport-level errors.
+/// for the specified header name; Ok(None) if the stream ends without it; Err([`EppError`])
+/// on connection, TLS, gRPC or response errors and on timeout. Only the headers are sent, over
+/// a new connection, so `options.body_mode`, `max_timeout_ms` and `channel_idle_ms` are unused.
 pub fn epp_headers_blocking(
     request: &http::Request,
-    endpoint: &str,
-    timeout_ms: u64,
-    header_name: &str,
-    headers: Vec<(String, String)>,
-    use_tls: bool,
-    ca_file: Option<&str>,
-) -> Result<Option<String>, String> {
+    options: &EppCallOptions<'_>,
+    headers: Vec<(String, Vec<u8>)>,
+    attributes: Option<&RequestAttributes>,
+    log_responses: bool,
+) -> Result<Option<String>, EppError> {
+    let EppCallOptions {
+        endpoint,
+        timeout_ms,
+        request_deadline_ms,
+        header_name,
+        use_tls,
+        ca_file,
+        tls_min_version,
+        header_sources,
+        max_messages,
+        upstream_max_len,
+        user_agent,
+        ..
+    } = *options;
     // Wrap the entire EPP operation in a panic handler to prevent worker crashes
     let result = std::panic::catch_unwind(|| {
         let target_key_lower = header_name.to_ascii_lowercase();
@@ -461,9 +1121,12 @@ pub fn epp_headers_blocking(
         let endpoint_copy = endpoint.to_string();
         let use_tls_copy = use_tls;
 
-        get_runtime().block_on(async move {
-            let channel_builder =
-                Channel::from_shared(uri.clone()).map_err(|e| format!("channel error: {e}"))?;
+        let runtime = get_runtime().map_err(EppError::Transport)?;
+        runtime.block_on(async move {
+            let channel_builder = Channel::from_shared(uri.clone())
+                .map_err(|e| EppError::Connect(format!("channel error: {e}")))?
+                .user_agent(user_agent)
+                .map_err(|e| EppError::Connect(format!("invalid user agent: {e}")))?;
 
             // Build the channel with appropriate TLS configuration
             let channel = if use_tls_copy {
@@ -471,46 +1134,52 @@ pub fn epp_headers_blocking(
                 use tonic::transport::ClientTlsConfig;
 
                 // Extract domain from URI for TLS verification (handles IPv6, schemes, etc.)
-                let domain = extract_domain_from_uri(&uri)?;
-
-                let mut tls_config = ClientTlsConfig::new().domain_name(&domain);
-
-                // Use custom CA certificate if provided, otherwise use system roots
-                if let Some(ca_path) = ca_file {
-                    // Read the CA certificate file
-                    let ca_cert = std::fs::read_to_string(ca_path).map_err(|e| {
-                        format!("Failed to read CA certificate file '{}': {}", ca_path, e)
-                    })?;
-
-                    // Add the CA certificate to the TLS config
-                    tls_config = tls_config
-                        .ca_certificate(tonic::transport::Certificate::from_pem(&ca_cert));
+                let domain = extract_domain_from_uri(&uri).map_err(EppError::Connect)?;
+
+                if let Some(min_version) = tls_min_version {
+                    connect_tls_min_version(&uri, &domain, ca_file, min_version, user_agent)
+                        .await
+                        .map_err(|detailed_error| {
+                            EppError::Tls(format!(
+                                "TLS connection failed (endpoint: {}, domain: {}): {}",
+                                endpoint_copy, domain, detailed_error
+                            ))
+                        })?
                 } else {
-                    tls_config = tls_config.with_enabled_roots();
-                }
+                    let mut tls_config = ClientTlsConfig::new().domain_name(&domain);
+
+                    // Use custom CA certificate if provided, otherwise use system roots
+                    if let Some(ca_path) = ca_file {
+                        // Add the CA certificates to the TLS config (cached until the file changes)
+                        tls_config = tls_config
+                            .ca_certificates(load_ca_certificates(ca_path).map_err(EppError::Tls)?);
+                    } else {
+                        tls_config = tls_config.with_enabled_roots();
+                    }
 
-                let tls_result = channel_builder
-                    .tls_config(tls_config)
-                    .map_err(|e| format!("tls config error: {e}"))?;
+                    let tls_result = channel_builder
+                        .tls_config(tls_config)
+                        .map_err(|e| EppError::Tls(format!("tls config error: {e}")))?;
 
-                let connect_result = tls_result.connect().await;
+                    let connect_result = tls_result.connect().await;
 
-                connect_result.map_err(|e| {
-                    let detailed_error = extract_error_details(&e);
-                    format!(
-                        "TLS connection failed (endpoint: {}, domain: {}): {}",
-                        endpoint_copy, domain, detailed_error
-                    )
-                })?
+                    connect_result.map_err(|e| {
+                        let detailed_error = extract_error_details(&e);
+                        EppError::Tls(format!(
+                            "TLS connection failed (endpoint: {}, domain: {}): {}",
+                            endpoint_copy, domain, detailed_error
+                        ))
+                    })?
+                }
             } else {
                 // PLAINTEXT MODE: No TLS configuration
                 channel_builder.connect().await.map_err(|e| {
                     let detailed_error = extract_error_details(&e);
-                    format!("HTTP connection failed: {}", detailed_error)
+                    EppError::Connect(format!("HTTP connection failed: {}", detailed_error))
                 })?
             };
 
-            let mut client = ExternalProcessorClient::new(channel);
+            let mut client = ProcessClient::new(channel, options);
 
             // EPP: For headers-only exchange, we still need to indicate body mode
             // but we mark end_of_stream=true on headers to indicate no body follows
@@ -521,16 +1190,11 @@ pub fn epp_headers_blocking(
             };
 
             // Build HeaderMap from provided request headers.
-            let mut header_entries: Vec<envoy::config::core::v3::HeaderValue> = Vec::new();
-            for (k, v) in headers {
-                header_entries.push(envoy::config::core::v3::HeaderValue {
-                    key: k,
-                    value: v,
-                    raw_value: Vec::new(),
-                });
-            }
             let header_map = HeaderMap {
-                headers: header_entries,
+                headers: headers
+                    .into_iter()
+                    .map(|(k, v)| header_value(k, v))
+                    .collect(),
             };
 
             // Build metadata_context for EPP routing metadata
@@ -562,7 +1226,9 @@ pub fn epp_headers_blocking(
             let headers_msg = ProcessingRequest {
                 request: Some(processing_request::Request::RequestHeaders(req_headers)),
                 metadata_context,
-                attributes: std::collections::HashMap::new(),
+                attributes: attributes
+                    .map(RequestAttributes::to_proto)
+                    .unwrap_or_default(),
                 observability_mode: false,
                 protocol_config: Some(proto_cfg),
             };
@@ -572,53 +1238,51 @@ pub fn epp_headers_blocking(
             let process_result = client.process(outbound).await;
 
             let mut inbound = process_result
-                .map_err(|e| format!("rpc error: {e}"))?
+                .map_err(|e| EppError::Transport(format!("rpc error: {e}")))?
                 .into_inner();
 
-            let next = if timeout_ms == 0 {
-                inbound.message().await
-            } else {
-                match tokio::time::timeout(
-                    std::time::Duration::from_millis(timeout_ms),
-                    inbound.message(),
-                )
-                .await
-                {
-                    Ok(res) => res,
-                    Err(_) => return Ok(None),
-                }
-            };
-
-            match next {
-                Ok(Some(resp)) => {
-                    if let Some(val) = parse_response_for_header(request, &resp, &target_key_lower)
-                    {
-                        return Ok(Some(val));
-                    }
-                }
-                Ok(None) => {
-                    // EPP response stream closed, no header provided
-                }
-                Err(e) => {
-                    return Err(format!("stream recv error: {e}"));
-                }
-            }
-
-            // Continue reading additional responses until stream ends or we find the header.
+            // timeout_ms bounds the whole response stream, not just the first message
+            let deadline = stream_deadline(
+                timeout_ms,
+                request_deadline_ms,
+                UNBOUNDED_EPP_TIMEOUT_CAP_MS,
+            );
+            let mut received = 0usize;
             loop {
-                match inbound.message().await {
+                let Some(next) = next_response(&mut inbound, deadline).await else {
+                    return Err(EppError::Timeout);
+                };
+                match next {
                     Ok(Some(resp)) => {
-                        if let Some(val) =
-                            parse_response_for_header(request, &resp, &target_key_lower)
-                        {
+                        // inference_epp_log_response_sample
+                        if log_responses {
+                            ngx_log_info_http!(
+                                request,
+                                "ngx-inference: EPP response: {}",
+                                describe_response(&resp)
+                            );
+                        }
+                        if let Some(val) = parse_response_for_header(
+                            request,
+                            &resp,
+                            &target_key_lower,
+                            header_sources,
+                            upstream_max_len,
+                        )? {
                             return Ok(Some(val));
                         }
+                        if let Some(code) = immediate_response_code(&resp) {
+                            return Err(EppError::Immediate(code));
+                        }
+                        received += 1;
+                        check_message_limit(received, max_messages)?;
                     }
                     Ok(None) => {
+                        // EPP response stream closed, no header provided
                         break;
                     }
                     Err(e) => {
-                        return Err(format!("stream recv error: {e}"));
+                        return Err(EppError::Transport(format!("stream recv error: {e}")));
                     }
                 }
             }
@@ -657,7 +1321,9 @@ pub fn epp_headers_blocking(
                 "ngx-inference: EPP gRPC operation panicked, endpoint: {}",
                 endpoint
             );
-            Err("EPP gRPC operation panicked".to_string())
+            Err(EppError::Transport(
+                "EPP gRPC operation panicked".to_string(),
+            ))
         }
     }
 }
@@ -682,7 +1348,7 @@ pub fn epp_headers_async<F>(
     endpoint: String,
     timeout_ms: u64,
     header_name: String,
-    headers: Vec<(String, String)>,
+    headers: Vec<(String, Vec<u8>)>,
     use_tls: bool,
     ca_file: Option<String>,
     completion_callback: F,
@@ -699,7 +1365,13 @@ pub fn epp_headers_async<F>(
     // Note: This logging happens before we enter the async context
 
     // Spawn the async operation without blocking
-    let rt = get_runtime();
+    let rt = match get_runtime() {
+        Ok(rt) => rt,
+        Err(e) => {
+            completion_callback(request_ptr, Err(e));
+            return;
+        }
+    };
     rt.spawn(async move {
         let result = async move {
             let channel_builder =
@@ -721,13 +1393,8 @@ pub fn epp_headers_async<F>(
 
                 // Use custom CA certificate if provided, otherwise use system roots
                 if let Some(ca_path) = ca_file {
-                    // Read the CA certificate file
-                    let ca_cert = std::fs::read_to_string(ca_path)
-                        .map_err(|e| format!("Failed to read CA certificate file: {}", e))?;
-
-                    // Add the CA certificate to the TLS config
-                    tls_config = tls_config
-                        .ca_certificate(tonic::transport::Certificate::from_pem(&ca_cert));
+                    // Add the CA certificates to the TLS config (cached until the file changes)
+                    tls_config = tls_config.ca_certificates(load_ca_certificates(&ca_path)?);
                 } else {
                     tls_config = tls_config.with_enabled_roots();
                 }
@@ -762,16 +1429,11 @@ pub fn epp_headers_async<F>(
             };
 
             // Build HeaderMap from provided request headers.
-            let mut header_entries: Vec<envoy::config::core::v3::HeaderValue> = Vec::new();
-            for (k, v) in headers {
-                header_entries.push(envoy::config::core::v3::HeaderValue {
-                    key: k,
-                    value: v,
-                    raw_value: Vec::new(),
-                });
-            }
             let header_map = HeaderMap {
-                headers: header_entries,
+                headers: headers
+                    .into_iter()
+                    .map(|(k, v)| header_value(k, v))
+                    .collect(),
             };
 
             // Build metadata_context for EPP routing metadata
@@ -814,25 +1476,24 @@ pub fn epp_headers_async<F>(
                 .map_err(|e| format!("rpc error: {e}"))?
                 .into_inner();
 
-            let next = if timeout_ms == 0 {
-                inbound.message().await
-            } else {
-                match tokio::time::timeout(
-                    std::time::Duration::from_millis(timeout_ms),
-                    inbound.message(),
-                )
-                .await
-                {
-                    Ok(res) => res,
-                    Err(_) => return Ok(None),
-                }
+            let deadline = stream_deadline(timeout_ms, None, UNBOUNDED_EPP_TIMEOUT_CAP_MS);
+            let next = match next_response(&mut inbound, deadline).await {
+                Some(res) => res,
+                None => return Ok(None),
             };
 
             match next {
                 Ok(Some(resp)) => {
                     // We can't safely log from async context without request reference
                     // The callback will handle logging instead
-                    if let Some(val) = parse_response_for_header_async(&resp, &target_key_lower) {
+                    if let Some(val) = parse_response_for_header_async(
+                        &resp,
+                        &target_key_lower,
+                        EppHeaderSources::default(),
+                        DEFAULT_UPSTREAM_MAX_LEN,
+                    )
+                    .map_err(|e| e.to_string())?
+                    {
                         return Ok(Some(val));
                     }
                 }
@@ -848,7 +1509,13 @@ pub fn epp_headers_async<F>(
             loop {
                 match inbound.message().await {
                     Ok(Some(resp)) => {
-                        if let Some(val) = parse_response_for_header_async(&resp, &target_key_lower)
+                        if let Some(val) = parse_response_for_header_async(
+                            &resp,
+                            &target_key_lower,
+                            EppHeaderSources::default(),
+                            DEFAULT_UPSTREAM_MAX_LEN,
+                        )
+                        .map_err(|e| e.to_string())?
                         {
                             return Ok(Some(val));
                         }
@@ -877,27 +1544,194 @@ pub fn epp_headers_async<F>(
     });
 }
 
+/// Default `inference_upstream_max_len`: longest upstream header value accepted from the EPP
+pub const DEFAULT_UPSTREAM_MAX_LEN: usize = 512;
+
+/// Path of the ext_proc `Process` method on Envoy's `ExternalProcessor` service
+pub const DEFAULT_SERVICE_PATH: &str = "/envoy.service.ext_proc.v3.ExternalProcessor";
+
+/// Full `Process` method path for `service_path` (`inference_epp_service_path`), falling back
+/// to Envoy's `ExternalProcessor` service
+pub fn process_path(service_path: Option<&str>) -> String {
+    format!("{}/Process", service_path.unwrap_or(DEFAULT_SERVICE_PATH))
+}
+
+/// ext_proc client calling `Process` at a configurable path.
+///
+/// Same wire protocol as the generated `ExternalProcessorClient`, which always calls Envoy's
+/// service path; EPPs or proxies that expose the method under another service name need the
+/// path remapped.
+struct ProcessClient {
+    inner: tonic::client::Grpc<Channel>,
+    path: String,
+}
+
+impl ProcessClient {
+    /// Client over `channel` with the service path, compression and message size limits of
+    /// `options`
+    fn new(channel: Channel, options: &EppCallOptions<'_>) -> Self {
+        let mut inner = tonic::client::Grpc::new(channel);
+        if options.max_recv_message_bytes != 0 {
+            inner = inner.max_decoding_message_size(options.max_recv_message_bytes);
+        }
+        if options.max_send_message_bytes != 0 {
+            inner = inner.max_encoding_message_size(options.max_send_message_bytes);
+        }
+        let inner = match options.compression {
+            EppGrpcCompression::None => inner,
+            EppGrpcCompression::Gzip => inner
+                .send_compressed(CompressionEncoding::Gzip)
+                .accept_compressed(CompressionEncoding::Gzip),
+        };
+        Self {
+            inner,
+            path: process_path(options.service_path),
+        }
+    }
+
+    /// Open the bidirectional `Process` stream
+    async fn process(
+        &mut self,
+        request: impl tonic::IntoStreamingRequest<Message = ProcessingRequest>,
+    ) -> Result<tonic::Response<tonic::Streaming<ProcessingResponse>>, tonic::Status> {
+        let path = tonic::codegen::http::uri::PathAndQuery::try_from(self.path.as_str())
+            .map_err(|e| tonic::Status::invalid_argument(format!("invalid service path: {e}")))?;
+        self.inner
+            .ready()
+            .await
+            .map_err(|e| tonic::Status::unknown(format!("Service was not ready: {e}")))?;
+        let codec = tonic_prost::ProstCodec::default();
+        self.inner
+            .streaming(request.into_streaming_request(), path, codec)
+            .await
+    }
+}
+
 /// Make the runtime accessible to other modules
-pub fn get_tokio_runtime() -> &'static tokio::runtime::Runtime {
+pub fn get_tokio_runtime() -> Result<&'static tokio::runtime::Runtime, String> {
     get_runtime()
 }
 
-/// Internal async EPP function for testing and potential future use.
-/// This is thread-safe but currently unused in production.
-/// The main implementation uses epp_headers_blocking() instead.
+/// Settings of one EPP call, from the `inference_epp_*` directives (see
+/// [`AsyncEppContext::call_options`](crate::epp::context::AsyncEppContext::call_options))
+#[derive(Clone, Copy, Debug)]
+pub struct EppCallOptions<'a> {
+    /// EPP endpoint (e.g., "localhost:50051" or "https://epp.example.com")
+    pub endpoint: &'a str,
+    /// Timeout for the whole response stream (0 = until `request_deadline_ms`, at most 5s)
+    pub timeout_ms: u64,
+    /// Absolute request deadline in ms since the epoch (`inference_request_deadline_ms`)
+    pub request_deadline_ms: Option<u64>,
+    /// Cap on EPP `override_message_timeout` extensions (0 = ignored)
+    pub max_timeout_ms: u64,
+    /// Header the EPP sets to the selected upstream
+    pub header_name: &'a str,
+    /// How the body is sent to EPP (`inference_epp_body_send_mode`)
+    pub body_mode: EppBodySendMode,
+    /// Whether to use TLS for the gRPC connection
+    pub use_tls: bool,
+    /// CA certificate file for TLS verification (None = system roots)
+    pub ca_file: Option<&'a str>,
+    /// Oldest TLS version accepted from the EPP (None = tonic default)
+    pub tls_min_version: Option<EppTlsMinVersion>,
+    /// Compression for the gRPC stream, in both directions
+    pub compression: EppGrpcCompression,
+    /// Largest gRPC message from EPP (0 = tonic default, 4MB)
+    pub max_recv_message_bytes: usize,
+    /// Largest gRPC message sent to EPP (0 = tonic default, unlimited)
+    pub max_send_message_bytes: usize,
+    /// EPP response variants trusted for the upstream header
+    pub header_sources: EppHeaderSources,
+    /// Max EPP responses read without the upstream header (0 = unlimited)
+    pub max_messages: usize,
+    /// Longest upstream header value accepted from the EPP
+    pub upstream_max_len: usize,
+    /// gRPC service path of the `Process` method (None = Envoy's `ExternalProcessor`)
+    pub service_path: Option<&'a str>,
+    /// How long an unused channel is kept for reuse (0 = a new connection per call)
+    pub channel_idle_ms: u64,
+    /// gRPC user-agent sent to EPP
+    pub user_agent: &'a str,
+}
+
+/// Internal async EPP function without nginx dependencies.
+/// This is thread-safe and used by the async EPP processor on the Tokio runtime.
+///
+/// `body` follows the EPP's first response as `options.body_mode` says, or if the EPP asks
+/// for it through a `mode_override` response.
+/// With `options.channel_idle_ms` set, the connection is reused across calls (see
+/// [`ChannelCache`]).
 pub async fn epp_headers_blocking_internal(
+    options: &EppCallOptions<'_>,
+    headers: Vec<(String, Vec<u8>)>,
+    body: &[u8],
+    attributes: Option<&RequestAttributes>,
+    response_log: Option<&mut Vec<String>>,
+) -> Result<Option<UpstreamHeader>, EppError> {
+    let EppCallOptions {
+        endpoint,
+        use_tls,
+        ca_file,
+        tls_min_version,
+        user_agent,
+        channel_idle_ms,
+        ..
+    } = *options;
+    let uri = normalize_endpoint(endpoint, use_tls);
+    let key = (channel_idle_ms != 0).then(|| {
+        format!(
+            "{}|{}|{:?}|{:?}|{}",
+            uri, use_tls, ca_file, tls_min_version, user_agent
+        )
+    });
+    let idle = Duration::from_millis(channel_idle_ms);
+
+    let cached = key
+        .as_deref()
+        .and_then(|key| channel_cache().get(key, idle, Instant::now()));
+    let channel = match cached {
+        Some(channel) => channel,
+        None => {
+            // A new channel resolves the endpoint's host again
+            let channel = connect_channel(
+                endpoint,
+                &uri,
+                use_tls,
+                ca_file,
+                tls_min_version,
+                user_agent,
+            )
+            .await?;
+            if let Some(key) = &key {
+                channel_cache().insert(key.clone(), channel.clone(), idle, Instant::now());
+            }
+            channel
+        }
+    };
+
+    let result = epp_exchange(channel, options, headers, body, attributes, response_log).await;
+    // A broken connection may mean the EPP moved; the next call connects afresh
+    if result.as_ref().is_err_and(EppError::is_connection_failure) {
+        if let Some(key) = &key {
+            channel_cache().remove(key);
+        }
+    }
+    result
+}
+
+/// Connect to the EPP at `uri` (the normalized `endpoint`), identifying as `user_agent`
+async fn connect_channel(
     endpoint: &str,
-    timeout_ms: u64,
-    header_name: &str,
-    headers: Vec<(String, String)>,
+    uri: &str,
     use_tls: bool,
     ca_file: Option<&str>,
-) -> Result<Option<String>, String> {
-    let target_key_lower = header_name.to_ascii_lowercase();
-    let uri = normalize_endpoint(endpoint, use_tls);
-
-    let channel_builder =
-        Channel::from_shared(uri.clone()).map_err(|e| format!("channel error: {e}"))?;
+    tls_min_version: Option<EppTlsMinVersion>,
+    user_agent: &str,
+) -> Result<Channel, EppError> {
+    let channel_builder = Channel::from_shared(uri.to_string())
+        .map_err(|e| EppError::Connect(format!("channel error: {e}")))?
+        .user_agent(user_agent)
+        .map_err(|e| EppError::Connect(format!("invalid user agent: {e}")))?;
 
     // Build the channel with appropriate TLS configuration
     let channel = if use_tls {
@@ -905,63 +1739,93 @@ pub async fn epp_headers_blocking_internal(
         use tonic::transport::ClientTlsConfig;
 
         // Extract domain from URI for TLS verification (handles IPv6, schemes, etc.)
-        let domain = extract_domain_from_uri(&uri)?;
-
-        let mut tls_config = ClientTlsConfig::new().domain_name(&domain);
+        let domain = extract_domain_from_uri(uri).map_err(EppError::Connect)?;
 
-        // Use custom CA certificate if provided, otherwise use system roots
-        if let Some(ca_path) = ca_file {
-            // Read the CA certificate file
-            let ca_cert = std::fs::read_to_string(ca_path)
-                .map_err(|e| format!("Failed to read CA certificate file '{}': {}", ca_path, e))?;
-
-            // Add the CA certificate to the TLS config
-            tls_config =
-                tls_config.ca_certificate(tonic::transport::Certificate::from_pem(&ca_cert));
+        if let Some(min_version) = tls_min_version {
+            connect_tls_min_version(uri, &domain, ca_file, min_version, user_agent)
+                .await
+                .map_err(|detailed_error| {
+                    EppError::Tls(format!(
+                        "TLS connection failed (endpoint: {}, domain: {}): {}",
+                        endpoint, domain, detailed_error
+                    ))
+                })?
         } else {
-            tls_config = tls_config.with_enabled_roots();
-        }
+            let mut tls_config = ClientTlsConfig::new().domain_name(&domain);
 
-        let tls_result = channel_builder
-            .tls_config(tls_config)
-            .map_err(|e| format!("tls config error: {e}"))?;
+            // Use custom CA certificate if provided, otherwise use system roots
+            if let Some(ca_path) = ca_file {
+                // Add the CA certificates to the TLS config (cached until the file changes)
+                tls_config = tls_config
+                    .ca_certificates(load_ca_certificates(ca_path).map_err(EppError::Tls)?);
+            } else {
+                tls_config = tls_config.with_enabled_roots();
+            }
 
-        tls_result.connect().await.map_err(|e| {
-            let detailed_error = extract_error_details(&e);
-            format!(
-                "TLS connection failed (endpoint: {}, domain: {}): {}",
-                endpoint, domain, detailed_error
-            )
-        })?
+            let tls_result = channel_builder
+                .tls_config(tls_config)
+                .map_err(|e| EppError::Tls(format!("tls config error: {e}")))?;
+
+            tls_result.connect().await.map_err(|e| {
+                let detailed_error = extract_error_details(&e);
+                EppError::Tls(format!(
+                    "TLS connection failed (endpoint: {}, domain: {}): {}",
+                    endpoint, domain, detailed_error
+                ))
+            })?
+        }
     } else {
         // No TLS
         channel_builder.connect().await.map_err(|e| {
             let detailed_error = extract_error_details(&e);
-            format!("HTTP connection failed: {}", detailed_error)
+            EppError::Connect(format!("HTTP connection failed: {}", detailed_error))
         })?
     };
+    Ok(channel)
+}
 
-    let mut client = ExternalProcessorClient::new(channel);
+/// One ext_proc exchange over `channel`: request headers, then the body under
+/// `options.body_mode` or if the EPP asks.
+/// Each response is also added, formatted by [`describe_response`], to `response_log`.
+async fn epp_exchange(
+    channel: Channel,
+    options: &EppCallOptions<'_>,
+    headers: Vec<(String, Vec<u8>)>,
+    body: &[u8],
+    attributes: Option<&RequestAttributes>,
+    mut response_log: Option<&mut Vec<String>>,
+) -> Result<Option<UpstreamHeader>, EppError> {
+    let EppCallOptions {
+        timeout_ms,
+        request_deadline_ms,
+        max_timeout_ms,
+        header_name,
+        body_mode,
+        header_sources,
+        max_messages,
+        upstream_max_len,
+        ..
+    } = *options;
+    let target_key_lower = header_name.to_ascii_lowercase();
+    let mut client = ProcessClient::new(channel, options);
 
-    // EPP: For headers-only exchange, we still need to indicate body mode
-    // but we mark end_of_stream=true on headers to indicate no body follows
+    // inference_epp_body_send_mode: the body follows the headers unless the mode is none or
+    // there is no body; the EPP may still ask for it with a mode_override, so the headers end
+    // the stream only when there is no body to send at all
+    let has_body = !body.is_empty();
+    let send_body = body_mode != EppBodySendMode::None && has_body;
     let proto_cfg = ProtocolConfiguration {
-        request_body_mode: BodySendMode::None as i32,
+        request_body_mode: request_body_mode(body_mode) as i32,
         response_body_mode: BodySendMode::None as i32,
         send_body_without_waiting_for_header_response: false,
     };
 
     // Build HeaderMap from provided request headers.
-    let mut header_entries: Vec<envoy::config::core::v3::HeaderValue> = Vec::new();
-    for (k, v) in headers {
-        header_entries.push(envoy::config::core::v3::HeaderValue {
-            key: k,
-            value: v,
-            raw_value: Vec::new(),
-        });
-    }
     let header_map = HeaderMap {
-        headers: header_entries,
+        headers: headers
+            .into_iter()
+            .map(|(k, v)| header_value(k, v))
+            .collect(),
     };
 
     // Build metadata_context for EPP routing metadata
@@ -985,69 +1849,1924 @@ pub async fn epp_headers_blocking_internal(
     let req_headers = HttpHeaders {
         headers: Some(header_map),
         attributes: std::collections::HashMap::new(),
-        end_of_stream: true, // No body follows for headers-only exchange
+        end_of_stream: !has_body,
     };
 
     use envoy::service::ext_proc::v3::processing_request;
     let headers_msg = ProcessingRequest {
         request: Some(processing_request::Request::RequestHeaders(req_headers)),
         metadata_context,
-        attributes: std::collections::HashMap::new(),
+        attributes: attributes
+            .map(RequestAttributes::to_proto)
+            .unwrap_or_default(),
         observability_mode: false,
         protocol_config: Some(proto_cfg),
     };
 
-    let outbound = tokio_stream::iter(vec![headers_msg]);
+    // Keep the outbound stream open until the first response so the body can still be sent
+    // if the EPP requests it via mode_override; otherwise half-close as before.
+    let (outbound_tx, outbound_rx) = tokio::sync::mpsc::channel::<ProcessingRequest>(2);
+    outbound_tx
+        .send(headers_msg)
+        .await
+        .map_err(|e| EppError::Transport(format!("stream send error: {e}")))?;
+    let mut outbound_tx = Some(outbound_tx);
+    let outbound = tokio_stream::wrappers::ReceiverStream::new(outbound_rx);
 
     let process_result = client.process(outbound).await;
     let mut inbound = process_result
-        .map_err(|e| format!("rpc error: {e}"))?
+        .map_err(|e| EppError::Transport(format!("rpc error: {e}")))?
         .into_inner();
 
-    let next = if timeout_ms == 0 {
-        inbound.message().await
-    } else {
-        match tokio::time::timeout(
-            std::time::Duration::from_millis(timeout_ms),
-            inbound.message(),
-        )
+    // timeout_ms bounds the whole response stream, not just the first message
+    let started = tokio::time::Instant::now();
+    let mut deadline = stream_deadline(
+        timeout_ms,
+        request_deadline_ms,
+        UNBOUNDED_EPP_TIMEOUT_CAP_MS,
+    );
+    let mut received = 0usize;
+    loop {
+        let Some(next) = next_response(&mut inbound, deadline).await else {
+            return Err(EppError::Timeout);
+        };
+
+        match next {
+            Ok(Some(resp)) => {
+                if let Some(log) = response_log.as_deref_mut() {
+                    log.push(describe_response(&resp));
+                }
+                deadline = extended_deadline(
+                    &resp,
+                    deadline,
+                    started,
+                    tokio::time::Instant::now(),
+                    max_timeout_ms,
+                );
+                if let Some(upstream) = parse_response_for_upstream_async(
+                    &resp,
+                    &target_key_lower,
+                    header_sources,
+                    upstream_max_len,
+                )? {
+                    return Ok(Some(upstream));
+                }
+                // An ImmediateResponse without the upstream header ends the exchange
+                if let Some(code) = immediate_response_code(&resp) {
+                    return Err(EppError::Immediate(code));
+                }
+                received += 1;
+                check_message_limit(received, max_messages)?;
+                // Send the body once, after the first response, if configured or requested;
+                // dropping the sender half-closes the stream.
+                match outbound_tx.take() {
+                    Some(tx) if send_body || (has_body && override_requests_body(&resp)) => {
+                        for msg in build_body_requests(body, body_mode) {
+                            tx.send(msg).await.map_err(|e| {
+                                EppError::Transport(format!("stream send error: {e}"))
+                            })?;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(None) => {
+                // Stream closed, no header provided
+                break;
+            }
+            Err(e) => {
+                return Err(EppError::Transport(format!("stream recv error: {e}")));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Deadline for the whole EPP response stream. A `timeout_ms` of 0 leaves the call bounded by
+/// the request deadline only (`request_deadline_ms`, ms since the epoch), or by
+/// `unbounded_cap_ms` without one, so a stalled EPP never holds a request forever; see
+/// [`attempt_timeout_ms`].
+fn stream_deadline(
+    timeout_ms: u64,
+    request_deadline_ms: Option<u64>,
+    unbounded_cap_ms: u64,
+) -> tokio::time::Instant {
+    let timeout_ms = attempt_timeout_ms(
+        timeout_ms,
+        request_deadline_ms,
+        current_time_ms(),
+        unbounded_cap_ms,
+    );
+    tokio::time::Instant::now() + Duration::from_millis(timeout_ms)
+}
+
+/// Stream deadline after a response: an `override_message_timeout` asks for that much time from
+/// `now`, bounded by `inference_epp_max_timeout_ms` from the start of the stream. As in Envoy,
+/// overrides above the cap, or with no cap (0), are ignored, and the deadline never shrinks.
+fn extended_deadline(
+    resp: &ProcessingResponse,
+    deadline: tokio::time::Instant,
+    started: tokio::time::Instant,
+    now: tokio::time::Instant,
+    max_timeout_ms: u64,
+) -> tokio::time::Instant {
+    let cap = Duration::from_millis(max_timeout_ms);
+    let requested = resp
+        .override_message_timeout
+        .and_then(|timeout| Duration::try_from(timeout).ok())
+        .filter(|requested| max_timeout_ms != 0 && *requested <= cap);
+    match requested {
+        Some(requested) => deadline.max((now + requested).min(started + cap)),
+        None => deadline,
+    }
+}
+
+/// Next message from the EPP response stream, or `None` once `deadline` has passed
+async fn next_response(
+    inbound: &mut tonic::Streaming<ProcessingResponse>,
+    deadline: tokio::time::Instant,
+) -> Option<Result<Option<ProcessingResponse>, tonic::Status>> {
+    tokio::time::timeout_at(deadline, inbound.message())
         .await
-        {
-            Ok(res) => res,
-            Err(_) => return Ok(None),
+        .ok()
+}
+
+/// Fail once the EPP has sent `max_messages` responses without the upstream header
+/// (`inference_epp_max_messages`, 0 = unlimited)
+fn check_message_limit(received: usize, max_messages: usize) -> Result<(), EppError> {
+    if max_messages != 0 && received >= max_messages {
+        return Err(EppError::Parse(format!(
+            "EPP sent {received} responses without the upstream header (inference_epp_max_messages)"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Plaintext call options for the test EPPs, without a body, deadline or channel reuse
+    fn test_options(endpoint: &str) -> EppCallOptions<'_> {
+        EppCallOptions {
+            endpoint,
+            timeout_ms: 5000,
+            request_deadline_ms: None,
+            max_timeout_ms: 0,
+            header_name: "X-Inference-Upstream",
+            body_mode: EppBodySendMode::None,
+            use_tls: false,
+            ca_file: None,
+            tls_min_version: None,
+            compression: EppGrpcCompression::None,
+            max_recv_message_bytes: 0,
+            max_send_message_bytes: 0,
+            header_sources: EppHeaderSources::RequestHeaders,
+            max_messages: 100,
+            upstream_max_len: DEFAULT_UPSTREAM_MAX_LEN,
+            service_path: None,
+            channel_idle_ms: 0,
+            user_agent: "ngx-inference/test",
         }
-    };
+    }
 
-    match next {
-        Ok(Some(resp)) => {
-            if let Some(val) = parse_response_for_header_async(&resp, &target_key_lower) {
-                return Ok(Some(val));
+    fn response_with_body_mode(mode: Option<BodySendMode>) -> ProcessingResponse {
+        ProcessingResponse {
+            response: None,
+            dynamic_metadata: None,
+            mode_override: mode.map(|m| {
+                envoy::extensions::filters::http::ext_proc::v3::ProcessingMode {
+                    request_body_mode: m as i32,
+                    ..Default::default()
+                }
+            }),
+            override_message_timeout: None,
+        }
+    }
+
+    #[test]
+    fn test_normalize_endpoint_ipv6_and_hosts() {
+        assert_eq!(normalize_endpoint("[::1]:9001", false), "http://[::1]:9001");
+        assert_eq!(
+            normalize_endpoint("[2001:db8::1]", true),
+            "https://[2001:db8::1]"
+        );
+        assert_eq!(
+            normalize_endpoint("2001:db8::1", true),
+            "https://[2001:db8::1]"
+        );
+        assert_eq!(normalize_endpoint("host:443", true), "https://host:443");
+        assert_eq!(normalize_endpoint("host", false), "http://host");
+        assert_eq!(
+            normalize_endpoint("http://[::1]:9001", true),
+            "http://[::1]:9001"
+        );
+    }
+
+    #[test]
+    fn test_extract_domain_strips_port_and_brackets() {
+        let domain = |endpoint: &str| extract_domain_from_uri(&normalize_endpoint(endpoint, true));
+        assert_eq!(domain("[::1]:9001").unwrap(), "::1");
+        assert_eq!(domain("[2001:db8::1]").unwrap(), "2001:db8::1");
+        assert_eq!(domain("host:443").unwrap(), "host");
+        assert_eq!(domain("host").unwrap(), "host");
+    }
+
+    #[test]
+    fn test_override_requests_body() {
+        assert!(override_requests_body(&response_with_body_mode(Some(
+            BodySendMode::Buffered
+        ))));
+        assert!(override_requests_body(&response_with_body_mode(Some(
+            BodySendMode::Streamed
+        ))));
+        assert!(!override_requests_body(&response_with_body_mode(Some(
+            BodySendMode::None
+        ))));
+        assert!(!override_requests_body(&response_with_body_mode(None)));
+    }
+
+    #[test]
+    fn test_build_body_request_sends_whole_body() {
+        use envoy::service::ext_proc::v3::processing_request;
+
+        let req = build_body_request(b"{\"model\":\"m\"}");
+        match req.request {
+            Some(processing_request::Request::RequestBody(b)) => {
+                assert_eq!(b.body, b"{\"model\":\"m\"}");
+                assert!(b.end_of_stream);
             }
+            _ => panic!("expected RequestBody"),
+        }
+    }
+
+    #[test]
+    fn test_build_body_requests_streams_in_chunks() {
+        use envoy::service::ext_proc::v3::processing_request;
+
+        let chunks = |body: &[u8], mode| -> Vec<(usize, bool)> {
+            build_body_requests(body, mode)
+                .into_iter()
+                .map(|req| match req.request {
+                    Some(processing_request::Request::RequestBody(b)) => {
+                        (b.body.len(), b.end_of_stream)
+                    }
+                    _ => panic!("expected RequestBody"),
+                })
+                .collect()
+        };
+        let body = vec![b'x'; 2 * STREAMED_BODY_CHUNK_SIZE + 10];
+
+        assert_eq!(
+            chunks(&body, EppBodySendMode::Streamed),
+            vec![
+                (STREAMED_BODY_CHUNK_SIZE, false),
+                (STREAMED_BODY_CHUNK_SIZE, false),
+                (10, true)
+            ]
+        );
+        assert_eq!(
+            chunks(&body[..STREAMED_BODY_CHUNK_SIZE], EppBodySendMode::Streamed),
+            vec![(STREAMED_BODY_CHUNK_SIZE, true)]
+        );
+        for mode in [
+            EppBodySendMode::None,
+            EppBodySendMode::Buffered,
+            EppBodySendMode::BufferedPartial,
+        ] {
+            assert_eq!(chunks(&body, mode), vec![(body.len(), true)], "{:?}", mode);
         }
-        Ok(None) => {
-            // Stream closed
+    }
+
+    #[test]
+    fn test_request_body_mode_matches_directive() {
+        assert_eq!(request_body_mode(EppBodySendMode::None), BodySendMode::None);
+        assert_eq!(
+            request_body_mode(EppBodySendMode::Buffered),
+            BodySendMode::Buffered
+        );
+        assert_eq!(
+            request_body_mode(EppBodySendMode::Streamed),
+            BodySendMode::Streamed
+        );
+        assert_eq!(
+            request_body_mode(EppBodySendMode::BufferedPartial),
+            BodySendMode::BufferedPartial
+        );
+    }
+
+    fn upstream_mutation() -> envoy::service::ext_proc::v3::HeaderMutation {
+        envoy::service::ext_proc::v3::HeaderMutation {
+            set_headers: vec![envoy::config::core::v3::HeaderValueOption {
+                header: Some(envoy::config::core::v3::HeaderValue {
+                    key: "X-Inference-Upstream".to_string(),
+                    value: "10.0.0.1:8000".to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
         }
-        Err(e) => {
-            return Err(format!("stream recv error: {e}"));
+    }
+
+    fn headers_response(request_side: bool) -> ProcessingResponse {
+        use envoy::service::ext_proc::v3::{processing_response, CommonResponse, HeadersResponse};
+
+        let hdrs = HeadersResponse {
+            response: Some(CommonResponse {
+                header_mutation: Some(upstream_mutation()),
+                ..Default::default()
+            }),
+        };
+        ProcessingResponse {
+            response: Some(if request_side {
+                processing_response::Response::RequestHeaders(hdrs)
+            } else {
+                processing_response::Response::ResponseHeaders(hdrs)
+            }),
+            ..response_with_body_mode(None)
         }
     }
 
-    // Continue reading additional responses until stream ends or we find the header.
-    loop {
-        match inbound.message().await {
-            Ok(Some(resp)) => {
-                if let Some(val) = parse_response_for_header_async(&resp, &target_key_lower) {
-                    return Ok(Some(val));
-                }
+    #[test]
+    fn test_describe_response_redacts_credentials() {
+        use envoy::service::ext_proc::v3::processing_response;
+
+        let mut resp = headers_response(true);
+        let Some(processing_response::Response::RequestHeaders(hdrs)) = &mut resp.response else {
+            unreachable!()
+        };
+        let mutation = hdrs.response.as_mut().unwrap().header_mutation.as_mut();
+        mutation
+            .unwrap()
+            .set_headers
+            .push(envoy::config::core::v3::HeaderValueOption {
+                header: Some(envoy::config::core::v3::HeaderValue {
+                    key: "Authorization".to_string(),
+                    raw_value: b"Bearer s3cr3t".to_vec(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+
+        let described = describe_response(&resp);
+        assert!(described.contains("10.0.0.1:8000"), "{}", described);
+        assert!(described.contains("<redacted>"), "{}", described);
+        assert!(!described.contains("s3cr3t"), "{}", described);
+        // raw_value is formatted as bytes
+        assert!(!described.contains(&format!("{:?}", b"Bearer s3cr3t")));
+    }
+
+    #[test]
+    fn test_upstream_header_keeps_append_action() {
+        let mut resp = headers_response(true);
+        let parse = |resp: &ProcessingResponse| {
+            parse_response_for_upstream_async(
+                resp,
+                "x-inference-upstream",
+                EppHeaderSources::Any,
+                DEFAULT_UPSTREAM_MAX_LEN,
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let set_action = |resp: &mut ProcessingResponse, action: i32| {
+            use envoy::service::ext_proc::v3::processing_response;
+            let Some(processing_response::Response::RequestHeaders(hdrs)) = &mut resp.response
+            else {
+                unreachable!()
+            };
+            let mutation = hdrs.response.as_mut().unwrap().header_mutation.as_mut();
+            mutation.unwrap().set_headers[0].append_action = action;
+        };
+
+        // Unset is the proto default, APPEND_IF_EXISTS_OR_ADD
+        assert_eq!(
+            parse(&resp),
+            UpstreamHeader {
+                value: "10.0.0.1:8000".to_string(),
+                append_action: HeaderAppendAction::AppendIfExistsOrAdd,
+                change_requested: false,
+                headers: Vec::new(),
             }
-            Ok(None) => {
-                break;
+        );
+        for action in [
+            HeaderAppendAction::AddIfAbsent,
+            HeaderAppendAction::OverwriteIfExistsOrAdd,
+            HeaderAppendAction::OverwriteIfExists,
+        ] {
+            set_action(&mut resp, action as i32);
+            assert_eq!(parse(&resp).append_action, action);
+        }
+
+        // Values from a newer proto fall back to overwrite
+        set_action(&mut resp, 42);
+        assert_eq!(
+            parse(&resp).append_action,
+            HeaderAppendAction::OverwriteIfExistsOrAdd
+        );
+    }
+
+    #[test]
+    fn test_upstream_header_carries_other_mutation_headers() {
+        use envoy::service::ext_proc::v3::processing_response;
+
+        let mut resp = headers_response(true);
+        let Some(processing_response::Response::RequestHeaders(hdrs)) = &mut resp.response else {
+            unreachable!()
+        };
+        let mutation = hdrs.response.as_mut().unwrap().header_mutation.as_mut();
+        let set_headers = &mut mutation.unwrap().set_headers;
+        for (key, value) in [
+            ("x-served-model", "llama-3".to_string()),
+            ("x-ratelimit-remaining", String::new()),
+            ("x-oversized", "x".repeat(DEFAULT_UPSTREAM_MAX_LEN + 1)),
+        ] {
+            set_headers.push(envoy::config::core::v3::HeaderValueOption {
+                header: Some(envoy::config::core::v3::HeaderValue {
+                    key: key.to_string(),
+                    value,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+
+        let upstream = parse_response_for_upstream_async(
+            &resp,
+            "x-inference-upstream",
+            EppHeaderSources::RequestHeaders,
+            DEFAULT_UPSTREAM_MAX_LEN,
+        )
+        .unwrap()
+        .unwrap();
+        // Neither the upstream header itself nor empty or oversized values are carried
+        assert_eq!(
+            upstream.headers,
+            vec![("x-served-model".to_string(), "llama-3".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_upstream_changed_flag_from_dynamic_metadata() {
+        use prost_types::value::Kind;
+        use prost_types::{Struct, Value};
+
+        let with_flag = |flag: Kind| {
+            let ns = Struct {
+                fields: [("upstream_changed".to_string(), Value { kind: Some(flag) })].into(),
+            };
+            ProcessingResponse {
+                dynamic_metadata: Some(Struct {
+                    fields: [(
+                        "envoy.lb".to_string(),
+                        Value {
+                            kind: Some(Kind::StructValue(ns)),
+                        },
+                    )]
+                    .into(),
+                }),
+                ..headers_response(true)
             }
-            Err(e) => {
-                return Err(format!("stream recv error: {e}"));
+        };
+        let changed = |resp: &ProcessingResponse| {
+            parse_response_for_upstream_async(
+                resp,
+                "x-inference-upstream",
+                EppHeaderSources::Any,
+                DEFAULT_UPSTREAM_MAX_LEN,
+            )
+            .unwrap()
+            .unwrap()
+            .change_requested
+        };
+
+        assert!(changed(&with_flag(Kind::BoolValue(true))));
+        assert!(!changed(&with_flag(Kind::BoolValue(false))));
+        assert!(!changed(&with_flag(Kind::StringValue("true".to_string()))));
+        assert!(!changed(&headers_response(true)));
+    }
+
+    #[test]
+    fn test_oversized_upstream_value_rejected() {
+        let mutation =
+            |value: &str, raw_value: &[u8]| envoy::service::ext_proc::v3::HeaderMutation {
+                set_headers: vec![envoy::config::core::v3::HeaderValueOption {
+                    header: Some(envoy::config::core::v3::HeaderValue {
+                        key: "x-inference-upstream".to_string(),
+                        value: value.to_string(),
+                        raw_value: raw_value.to_vec(),
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+        let extract = |m: &envoy::service::ext_proc::v3::HeaderMutation| {
+            extract_header_from_mutation_async(m, "x-inference-upstream", 16)
+                .map(|h| h.map(|h| h.value))
+        };
+
+        // value form
+        let long = "a".repeat(17);
+        assert_eq!(
+            extract(&mutation(&long, b"")),
+            Err(EppError::UpstreamTooLong(17))
+        );
+        // raw_value form
+        assert_eq!(
+            extract(&mutation("", long.as_bytes())),
+            Err(EppError::UpstreamTooLong(17))
+        );
+
+        // Values up to the limit are accepted in either form
+        let fits = "10.0.0.1:8000123";
+        assert_eq!(extract(&mutation(fits, b"")), Ok(Some(fits.to_string())));
+        assert_eq!(
+            extract(&mutation("", fits.as_bytes())),
+            Ok(Some(fits.to_string()))
+        );
+        assert!(EppError::UpstreamTooLong(17)
+            .to_string()
+            .contains("inference_upstream_max_len"));
+    }
+
+    #[test]
+    fn test_response_headers_ignored_under_request_headers_sources() {
+        let resp = headers_response(false);
+        assert_eq!(
+            parse_response_for_header_async(
+                &resp,
+                "x-inference-upstream",
+                EppHeaderSources::RequestHeaders,
+                DEFAULT_UPSTREAM_MAX_LEN,
+            ),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_response_for_header_async(
+                &resp,
+                "x-inference-upstream",
+                EppHeaderSources::Any,
+                DEFAULT_UPSTREAM_MAX_LEN,
+            )
+            .unwrap()
+            .as_deref(),
+            Some("10.0.0.1:8000")
+        );
+    }
+
+    #[test]
+    fn test_request_headers_trusted_under_default_sources() {
+        let resp = headers_response(true);
+        assert_eq!(
+            parse_response_for_header_async(
+                &resp,
+                "x-inference-upstream",
+                EppHeaderSources::default(),
+                DEFAULT_UPSTREAM_MAX_LEN,
+            )
+            .unwrap()
+            .as_deref(),
+            Some("10.0.0.1:8000")
+        );
+    }
+
+    #[test]
+    fn test_immediate_response_ignored_under_request_headers_sources() {
+        use envoy::service::ext_proc::v3::{processing_response, ImmediateResponse};
+
+        let resp = ProcessingResponse {
+            response: Some(processing_response::Response::ImmediateResponse(
+                ImmediateResponse {
+                    headers: Some(upstream_mutation()),
+                    ..Default::default()
+                },
+            )),
+            ..response_with_body_mode(None)
+        };
+        assert!(!header_source_allowed(
+            &resp,
+            EppHeaderSources::RequestHeaders
+        ));
+        assert!(header_source_allowed(&resp, EppHeaderSources::Any));
+    }
+
+    #[test]
+    fn test_immediate_response_status() {
+        use envoy::r#type::v3::{HttpStatus, StatusCode};
+        use envoy::service::ext_proc::v3::{processing_response, ImmediateResponse};
+
+        for code in [200, 401, 429, 503] {
+            assert_eq!(immediate_response_status(code, &[]), code as u16);
+        }
+        assert_eq!(
+            immediate_response_status(StatusCode::Empty as i32, &[]),
+            500
+        );
+        assert_eq!(immediate_response_status(999, &[]), 500);
+        assert_eq!(immediate_response_status(-1, &[]), 500);
+
+        // inference_epp_immediate_status_map overrides the default for listed codes only
+        let map = [(429, 503), (0, 502)];
+        assert_eq!(immediate_response_status(429, &map), 503);
+        assert_eq!(immediate_response_status(0, &map), 502);
+        assert_eq!(immediate_response_status(401, &map), 401);
+
+        let immediate = |status: Option<HttpStatus>| ProcessingResponse {
+            response: Some(processing_response::Response::ImmediateResponse(
+                ImmediateResponse {
+                    status,
+                    ..Default::default()
+                },
+            )),
+            ..response_with_body_mode(None)
+        };
+        assert_eq!(
+            immediate_response_code(&immediate(Some(HttpStatus { code: 429 }))),
+            Some(429)
+        );
+        assert_eq!(immediate_response_code(&immediate(None)), Some(0));
+        assert_eq!(
+            immediate_response_code(&response_with_body_mode(None)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ca_certificate_reloaded_on_mtime_change() {
+        use std::time::Duration;
+
+        let path =
+            std::env::temp_dir().join(format!("ngx-inference-ca-{}.pem", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let set_mtime = |secs: u64| {
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        std::fs::write(&path, "CERT-A").unwrap();
+        set_mtime(1_000);
+        assert_eq!(load_ca_certificate(path_str).unwrap().get_ref(), b"CERT-A");
+
+        // Same mtime and size: the cached certificate is reused without re-reading
+        std::fs::write(&path, "CERT-B").unwrap();
+        set_mtime(1_000);
+        assert_eq!(load_ca_certificate(path_str).unwrap().get_ref(), b"CERT-A");
+
+        // Rotated file with a new mtime is picked up
+        set_mtime(2_000);
+        assert_eq!(load_ca_certificate(path_str).unwrap().get_ref(), b"CERT-B");
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_ca_certificate(path_str).is_err());
+    }
+
+    #[test]
+    fn test_request_attributes_to_proto() {
+        let attrs = RequestAttributes {
+            method: "POST".to_string(),
+            path: "/v1/embeddings?x=1".to_string(),
+            host: "api.example.com".to_string(),
+            ..Default::default()
+        };
+        let proto = attrs.to_proto();
+        let fields = &proto[EXT_PROC_ATTRIBUTES_KEY].fields;
+        let string = |k: &str| match &fields[k].kind {
+            Some(prost_types::value::Kind::StringValue(v)) => v.clone(),
+            other => panic!("unexpected value {:?}", other),
+        };
+        assert_eq!(string("request.method"), "POST");
+        assert_eq!(string("request.path"), "/v1/embeddings?x=1");
+        assert_eq!(string("request.host"), "api.example.com");
+    }
+
+    #[test]
+    fn test_extra_attributes_to_proto() {
+        let attrs = RequestAttributes {
+            extra: vec![
+                ("source.address".to_string(), "10.1.2.3".to_string()),
+                ("tenant".to_string(), String::new()),
+            ],
+            ..Default::default()
+        };
+        let fields = &attrs.to_proto()[EXT_PROC_ATTRIBUTES_KEY].fields;
+
+        // Request fields are only sent with inference_epp_send_request_attributes on
+        assert!(!fields.contains_key("request.method"));
+        assert_eq!(
+            fields["source.address"].kind,
+            Some(prost_types::value::Kind::StringValue(
+                "10.1.2.3".to_string()
+            ))
+        );
+        // Configured attributes are sent even when the variable expands to nothing
+        assert_eq!(
+            fields["tenant"].kind,
+            Some(prost_types::value::Kind::StringValue(String::new()))
+        );
+    }
+
+    /// EPP stub recording the first request and selecting an upstream
+    struct RecordingEpp {
+        first: std::sync::Arc<Mutex<Option<ProcessingRequest>>>,
+    }
+
+    #[tonic::async_trait]
+    impl envoy::service::ext_proc::v3::external_processor_server::ExternalProcessor for RecordingEpp {
+        type ProcessStream =
+            tokio_stream::wrappers::ReceiverStream<Result<ProcessingResponse, tonic::Status>>;
+
+        async fn process(
+            &self,
+            request: tonic::Request<tonic::Streaming<ProcessingRequest>>,
+        ) -> Result<tonic::Response<Self::ProcessStream>, tonic::Status> {
+            let first = request
+                .into_inner()
+                .message()
+                .await?
+                .ok_or_else(|| tonic::Status::invalid_argument("empty stream"))?;
+            *self.first.lock().unwrap() = Some(first);
+
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            tx.send(Ok(headers_response(true))).await.unwrap();
+            Ok(tonic::Response::new(
+                tokio_stream::wrappers::ReceiverStream::new(rx),
+            ))
+        }
+    }
+
+    /// Start a [`RecordingEpp`] selecting `10.0.0.1:8000` on a local port
+    pub(crate) async fn spawn_recording_epp() -> (
+        std::net::SocketAddr,
+        std::sync::Arc<Mutex<Option<ProcessingRequest>>>,
+    ) {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+
+        let recorded = std::sync::Arc::new(Mutex::new(None));
+        let svc = RecordingEpp {
+            first: recorded.clone(),
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ExternalProcessorServer::new(svc))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+        (addr, recorded)
+    }
+
+    /// EPP stub recording the `user-agent` of each call and selecting an upstream
+    struct UserAgentEpp {
+        seen: std::sync::Arc<Mutex<Vec<String>>>,
+    }
+
+    #[tonic::async_trait]
+    impl envoy::service::ext_proc::v3::external_processor_server::ExternalProcessor for UserAgentEpp {
+        type ProcessStream =
+            tokio_stream::wrappers::ReceiverStream<Result<ProcessingResponse, tonic::Status>>;
+
+        async fn process(
+            &self,
+            request: tonic::Request<tonic::Streaming<ProcessingRequest>>,
+        ) -> Result<tonic::Response<Self::ProcessStream>, tonic::Status> {
+            let user_agent = request
+                .metadata()
+                .get("user-agent")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            self.seen.lock().unwrap().push(user_agent);
+
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            tx.send(Ok(headers_response(true))).await.unwrap();
+            Ok(tonic::Response::new(
+                tokio_stream::wrappers::ReceiverStream::new(rx),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_configured_user_agent_reaches_epp() {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+
+        let seen = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let svc = UserAgentEpp { seen: seen.clone() };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ExternalProcessorServer::new(svc))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+
+        let upstream = epp_headers_blocking_internal(
+            &EppCallOptions {
+                user_agent: "acme-gateway/2.1",
+                ..test_options(&addr.to_string())
+            },
+            vec![],
+            b"",
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(upstream.is_some());
+
+        // tonic appends its own product token
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert!(seen[0].starts_with("acme-gateway/2.1"), "{}", seen[0]);
+    }
+
+    #[tokio::test]
+    async fn test_response_log_collects_each_response() {
+        let (addr, _) = spawn_recording_epp().await;
+        let mut log = Vec::new();
+
+        let upstream = epp_headers_blocking_internal(
+            &test_options(&addr.to_string()),
+            vec![],
+            b"",
+            None,
+            Some(&mut log),
+        )
+        .await
+        .unwrap();
+
+        assert!(upstream.is_some());
+        assert_eq!(log.len(), 1);
+        assert!(log[0].contains("X-Inference-Upstream"), "{}", log[0]);
+    }
+
+    async fn epp_call_recorded(
+        headers: Vec<(String, Vec<u8>)>,
+        attributes: Option<&RequestAttributes>,
+    ) -> ProcessingRequest {
+        let (addr, recorded) = spawn_recording_epp().await;
+
+        let upstream = epp_headers_blocking_internal(
+            &test_options(&addr.to_string()),
+            headers,
+            b"",
+            attributes,
+            None,
+        )
+        .await
+        .unwrap()
+        .map(|h| h.value);
+        assert_eq!(upstream.as_deref(), Some("10.0.0.1:8000"));
+
+        let first = recorded.lock().unwrap().take();
+        first.expect("EPP received no request")
+    }
+
+    /// Serves an EPP under `custom.v1.Processor` instead of Envoy's service name, like a proxy
+    /// that remaps the gRPC path
+    #[derive(Clone)]
+    struct Remapped<S>(S);
+
+    impl<S> tonic::server::NamedService for Remapped<S> {
+        const NAME: &'static str = "custom.v1.Processor";
+    }
+
+    impl<S, B> tonic::codegen::Service<tonic::codegen::http::Request<B>> for Remapped<S>
+    where
+        S: tonic::codegen::Service<tonic::codegen::http::Request<B>>,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = S::Future;
+
+        fn poll_ready(
+            &mut self,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            self.0.poll_ready(cx)
+        }
+
+        fn call(&mut self, mut req: tonic::codegen::http::Request<B>) -> Self::Future {
+            let path = req
+                .uri()
+                .path()
+                .replacen("/custom.v1.Processor", DEFAULT_SERVICE_PATH, 1);
+            *req.uri_mut() = path.parse().unwrap();
+            self.0.call(req)
+        }
+    }
+
+    #[test]
+    fn test_channel_cache_idle_eviction() {
+        let idle = Duration::from_millis(100);
+        let start = Instant::now();
+        let mut cache = ChannelCache::new(8);
+        cache.insert("epp".to_string(), 1, idle, start);
+
+        // Each use restarts the idle period
+        assert_eq!(
+            cache.get("epp", idle, start + Duration::from_millis(80)),
+            Some(1)
+        );
+        assert_eq!(
+            cache.get("epp", idle, start + Duration::from_millis(160)),
+            Some(1)
+        );
+
+        // Idle for too long: dropped, and the caller stores a new channel
+        assert_eq!(
+            cache.get("epp", idle, start + Duration::from_millis(300)),
+            None
+        );
+        assert!(cache.entries.is_empty());
+        cache.insert(
+            "epp".to_string(),
+            2,
+            idle,
+            start + Duration::from_millis(300),
+        );
+        assert_eq!(
+            cache.get("epp", idle, start + Duration::from_millis(310)),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_channel_cache_sweeps_idle_and_caps_entries() {
+        let idle = Duration::from_millis(100);
+        let start = Instant::now();
+        let mut cache = ChannelCache::new(2);
+        cache.insert("old-pod".to_string(), 1, idle, start);
+        cache.insert("long-idle".to_string(), 2, Duration::from_secs(60), start);
+
+        // An endpoint never called again is dropped when another channel is stored
+        let later = start + Duration::from_millis(500);
+        cache.insert("new-pod".to_string(), 3, idle, later);
+        assert!(!cache.entries.contains_key("old-pod"));
+        assert_eq!(cache.entries.len(), 2);
+
+        // At capacity, the least recently used channel makes room
+        assert_eq!(cache.get("new-pod", idle, later), Some(3));
+        cache.insert(
+            "another".to_string(),
+            4,
+            idle,
+            later + Duration::from_millis(10),
+        );
+        assert_eq!(cache.entries.len(), 2);
+        assert!(!cache.entries.contains_key("long-idle"));
+        assert!(cache.entries.contains_key("new-pod"));
+    }
+
+    #[test]
+    fn test_only_connection_failures_evict_channels() {
+        assert!(EppError::Connect("refused".to_string()).is_connection_failure());
+        assert!(EppError::Tls("handshake".to_string()).is_connection_failure());
+        assert!(EppError::Transport("reset".to_string()).is_connection_failure());
+        assert!(!EppError::Immediate(429).is_connection_failure());
+        assert!(!EppError::Timeout.is_connection_failure());
+        assert!(!EppError::UpstreamTooLong(600).is_connection_failure());
+        assert!(!EppError::Parse("too many".to_string()).is_connection_failure());
+        assert!(!EppError::NoUpstream("bad".to_string()).is_connection_failure());
+    }
+
+    #[tokio::test]
+    async fn test_idle_channel_reestablished_on_next_call() {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Count the TCP connections the EPP accepts
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accepted = std::sync::Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                if tx.send(Ok::<_, std::io::Error>(stream)).await.is_err() {
+                    break;
+                }
             }
+        });
+        let svc = RecordingEpp {
+            first: std::sync::Arc::new(Mutex::new(None)),
+        };
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ExternalProcessorServer::new(svc))
+                .serve_with_incoming(tokio_stream::wrappers::ReceiverStream::new(rx)),
+        );
+
+        let call = || async {
+            epp_headers_blocking_internal(
+                &EppCallOptions {
+                    channel_idle_ms: 200,
+                    ..test_options(&addr)
+                },
+                vec![],
+                b"",
+                None,
+                None,
+            )
+            .await
+            .unwrap()
+            .map(|h| h.value)
+        };
+
+        // Back-to-back calls share one connection
+        assert_eq!(call().await.as_deref(), Some("10.0.0.1:8000"));
+        assert_eq!(call().await.as_deref(), Some("10.0.0.1:8000"));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // After the idle time the channel is dropped and the next call connects again
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(call().await.as_deref(), Some("10.0.0.1:8000"));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_epp_called_at_custom_service_path() {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+
+        let recorded = std::sync::Arc::new(Mutex::new(None));
+        let svc = RecordingEpp {
+            first: recorded.clone(),
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(Remapped(ExternalProcessorServer::new(svc)))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+
+        let call = |service_path| {
+            epp_headers_blocking_internal(
+                &EppCallOptions {
+                    service_path,
+                    ..test_options(&addr)
+                },
+                vec![],
+                b"",
+                None,
+                None,
+            )
+        };
+
+        // Envoy's path is not served here
+        assert!(call(None).await.is_err());
+        assert!(recorded.lock().unwrap().is_none());
+
+        let upstream = call(Some("/custom.v1.Processor")).await.unwrap();
+        assert_eq!(upstream.map(|h| h.value).as_deref(), Some("10.0.0.1:8000"));
+        assert!(recorded.lock().unwrap().is_some());
+    }
+
+    /// Accept one TLS connection on a local port with a server limited to `versions`, using
+    /// the `localhost` certificate from `tests/certs`; the receiver reports whether the
+    /// handshake succeeded
+    async fn spawn_tls_server(
+        versions: &'static [&'static rustls::SupportedProtocolVersion],
+    ) -> (std::net::SocketAddr, tokio::sync::oneshot::Receiver<bool>) {
+        spawn_tls_server_with_cert(
+            versions,
+            include_bytes!("../tests/certs/localhost.pem"),
+            include_bytes!("../tests/certs/localhost-key.pem"),
+        )
+        .await
+    }
+
+    /// Like [`spawn_tls_server`], presenting the PEM certificate `cert` with key `key`
+    async fn spawn_tls_server_with_cert(
+        versions: &'static [&'static rustls::SupportedProtocolVersion],
+        cert: &[u8],
+        key: &[u8],
+    ) -> (std::net::SocketAddr, tokio::sync::oneshot::Receiver<bool>) {
+        use rustls_pki_types::pem::PemObject;
+        use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+        let cert = CertificateDer::from_pem_slice(cert).unwrap();
+        let key = PrivateKeyDer::from_pem_slice(key).unwrap();
+        let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(versions)
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let accepted = acceptor.accept(tcp).await;
+            let _ = tx.send(accepted.is_ok());
+            // Keep the connection open while the client finishes connecting
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn test_tls13_only_rejects_tls12_epp() {
+        const TLS12_ONLY: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS12];
+        let ca = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/localhost.pem");
+
+        let (addr, handshake) = spawn_tls_server(TLS12_ONLY).await;
+        let uri = format!("https://localhost:{}", addr.port());
+        let err =
+            connect_tls_min_version(&uri, "localhost", Some(ca), EppTlsMinVersion::Tls13, "test")
+                .await
+                .unwrap_err();
+        assert!(!handshake.await.unwrap(), "{err}");
+
+        // The same server is accepted once TLS 1.2 is allowed
+        let (addr, handshake) = spawn_tls_server(TLS12_ONLY).await;
+        let uri = format!("https://localhost:{}", addr.port());
+        let _ =
+            connect_tls_min_version(&uri, "localhost", Some(ca), EppTlsMinVersion::Tls12, "test")
+                .await;
+        assert!(handshake.await.unwrap());
+    }
+
+    #[test]
+    fn test_ca_bundle_split_into_certificates() {
+        use rustls_pki_types::pem::PemObject;
+        use rustls_pki_types::CertificateDer;
+
+        let certs = split_pem_certificates(include_bytes!("../tests/certs/ca-bundle.pem"));
+        assert_eq!(certs.len(), 2);
+        for cert in &certs {
+            assert!(CertificateDer::from_pem_slice(cert.get_ref()).is_ok());
         }
+        assert!(split_pem_certificates(b"CERT-A").is_empty());
     }
 
-    Ok(None)
+    #[tokio::test]
+    async fn test_ca_bundle_trusts_intermediate_signed_epp() {
+        use tonic::transport::ClientTlsConfig;
+
+        // rustls has both ring and aws-lc-rs enabled, so tonic needs a process-wide provider
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        // Root CA followed by the intermediate; the EPP presents only its leaf, signed by the
+        // intermediate, so the handshake needs the second certificate of the bundle
+        let bundle = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/ca-bundle.pem");
+        let certs = load_ca_certificates(bundle).unwrap();
+        let handshake = |tls: ClientTlsConfig| async move {
+            let (addr, handshake) = spawn_tls_server_with_cert(
+                rustls::DEFAULT_VERSIONS,
+                include_bytes!("../tests/certs/intermediate-leaf.pem"),
+                include_bytes!("../tests/certs/intermediate-leaf-key.pem"),
+            )
+            .await;
+            let _ = Endpoint::from_shared(format!("https://localhost:{}", addr.port()))
+                .unwrap()
+                .tls_config(tls.domain_name("localhost"))
+                .unwrap()
+                .connect()
+                .await;
+            handshake.await.unwrap()
+        };
+
+        assert!(handshake(ClientTlsConfig::new().ca_certificates(certs.clone())).await);
+        // The root alone cannot verify the leaf
+        assert!(!handshake(ClientTlsConfig::new().ca_certificate(certs[0].clone())).await);
+    }
+
+    #[test]
+    fn test_process_path() {
+        assert_eq!(
+            process_path(None),
+            "/envoy.service.ext_proc.v3.ExternalProcessor/Process"
+        );
+        assert_eq!(
+            process_path(Some("/epp/custom.v1.Processor")),
+            "/epp/custom.v1.Processor/Process"
+        );
+    }
+
+    /// EPP stub streaming `count` responses without the upstream header, `interval` apart
+    struct StreamingEpp {
+        count: usize,
+        interval: std::time::Duration,
+    }
+
+    #[tonic::async_trait]
+    impl envoy::service::ext_proc::v3::external_processor_server::ExternalProcessor for StreamingEpp {
+        type ProcessStream =
+            tokio_stream::wrappers::ReceiverStream<Result<ProcessingResponse, tonic::Status>>;
+
+        async fn process(
+            &self,
+            request: tonic::Request<tonic::Streaming<ProcessingRequest>>,
+        ) -> Result<tonic::Response<Self::ProcessStream>, tonic::Status> {
+            let mut inbound = request.into_inner();
+            let (tx, rx) = tokio::sync::mpsc::channel(16);
+            let (count, interval) = (self.count, self.interval);
+            tokio::spawn(async move {
+                let _ = inbound.message().await;
+                for _ in 0..count {
+                    if tx.send(Ok(response_with_body_mode(None))).await.is_err() {
+                        break;
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            });
+            Ok(tonic::Response::new(
+                tokio_stream::wrappers::ReceiverStream::new(rx),
+            ))
+        }
+    }
+
+    async fn epp_call_streaming(
+        epp: StreamingEpp,
+        timeout_ms: u64,
+        max_messages: usize,
+    ) -> Result<Option<String>, EppError> {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ExternalProcessorServer::new(epp))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+
+        epp_headers_blocking_internal(
+            &EppCallOptions {
+                timeout_ms,
+                max_messages,
+                ..test_options(&addr.to_string())
+            },
+            vec![],
+            b"",
+            None,
+            None,
+        )
+        .await
+        .map(|upstream| upstream.map(|h| h.value))
+    }
+
+    #[tokio::test]
+    async fn test_epp_stream_capped_by_max_messages() {
+        let epp = StreamingEpp {
+            count: 10_000,
+            interval: std::time::Duration::ZERO,
+        };
+        let err = epp_call_streaming(epp, 5000, 10).await.unwrap_err();
+        assert!(matches!(err, EppError::Parse(_)), "{:?}", err);
+        assert!(
+            err.to_string().contains("inference_epp_max_messages"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_epp_timeout_bounds_whole_stream() {
+        // Each message arrives well within the timeout, but the stream never ends
+        let epp = StreamingEpp {
+            count: usize::MAX,
+            interval: std::time::Duration::from_millis(20),
+        };
+        let start = std::time::Instant::now();
+        let result = epp_call_streaming(epp, 300, 0).await;
+        assert_eq!(result, Err(EppError::Timeout));
+        assert!(
+            start.elapsed() < std::time::Duration::from_secs(2),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    /// EPP stub recording every request; it answers the headers without the upstream header,
+    /// with `mode_override` if set, and selects `10.0.0.1:8000` once the body has ended
+    struct BodyRecordingEpp {
+        seen: std::sync::Arc<Mutex<Vec<ProcessingRequest>>>,
+        mode_override: Option<BodySendMode>,
+    }
+
+    #[tonic::async_trait]
+    impl envoy::service::ext_proc::v3::external_processor_server::ExternalProcessor
+        for BodyRecordingEpp
+    {
+        type ProcessStream =
+            tokio_stream::wrappers::ReceiverStream<Result<ProcessingResponse, tonic::Status>>;
+
+        async fn process(
+            &self,
+            request: tonic::Request<tonic::Streaming<ProcessingRequest>>,
+        ) -> Result<tonic::Response<Self::ProcessStream>, tonic::Status> {
+            use envoy::service::ext_proc::v3::{
+                processing_request, processing_response, BodyResponse, CommonResponse,
+                HeadersResponse,
+            };
+
+            let mut inbound = request.into_inner();
+            let (tx, rx) = tokio::sync::mpsc::channel(16);
+            let seen = self.seen.clone();
+            let mode_override = self.mode_override;
+            tokio::spawn(async move {
+                while let Ok(Some(req)) = inbound.message().await {
+                    let resp = match &req.request {
+                        Some(processing_request::Request::RequestHeaders(_)) => {
+                            Some(processing_response::Response::RequestHeaders(
+                                HeadersResponse::default(),
+                            ))
+                        }
+                        Some(processing_request::Request::RequestBody(b)) if b.end_of_stream => {
+                            Some(processing_response::Response::RequestBody(BodyResponse {
+                                response: Some(CommonResponse {
+                                    header_mutation: Some(upstream_mutation()),
+                                    ..Default::default()
+                                }),
+                            }))
+                        }
+                        _ => None,
+                    };
+                    seen.lock().unwrap().push(req);
+                    if let Some(response) = resp {
+                        let resp = ProcessingResponse {
+                            response: Some(response),
+                            ..response_with_body_mode(mode_override)
+                        };
+                        if tx.send(Ok(resp)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+            Ok(tonic::Response::new(
+                tokio_stream::wrappers::ReceiverStream::new(rx),
+            ))
+        }
+    }
+
+    /// One call to a [`BodyRecordingEpp`] sending `body` under `mode`; returns the selected
+    /// upstream and the requests the EPP received
+    async fn epp_call_with_body(
+        body: &[u8],
+        mode: EppBodySendMode,
+    ) -> (Option<String>, Vec<ProcessingRequest>) {
+        let (upstream, seen) = epp_call_with_body_limit(body, mode, 0).await;
+        (upstream.unwrap(), seen)
+    }
+
+    /// Like [`epp_call_with_body`], with `inference_epp_max_send_message_bytes`; the EPP
+    /// accepts messages up to 64MB
+    async fn epp_call_with_body_limit(
+        body: &[u8],
+        mode: EppBodySendMode,
+        max_send_message_bytes: usize,
+    ) -> (Result<Option<String>, EppError>, Vec<ProcessingRequest>) {
+        epp_call_recorded(body, mode, max_send_message_bytes, None).await
+    }
+
+    /// Like [`epp_call_with_body`], to an EPP that asks for the body with a `mode_override`
+    async fn epp_call_requesting_body(
+        body: &[u8],
+        mode: EppBodySendMode,
+    ) -> (Option<String>, Vec<ProcessingRequest>) {
+        let (upstream, seen) = epp_call_recorded(body, mode, 0, Some(BodySendMode::Buffered)).await;
+        (upstream.unwrap(), seen)
+    }
+
+    async fn epp_call_recorded(
+        body: &[u8],
+        mode: EppBodySendMode,
+        max_send_message_bytes: usize,
+        mode_override: Option<BodySendMode>,
+    ) -> (Result<Option<String>, EppError>, Vec<ProcessingRequest>) {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+
+        let seen = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(
+                    ExternalProcessorServer::new(BodyRecordingEpp {
+                        seen: seen.clone(),
+                        mode_override,
+                    })
+                    .max_decoding_message_size(64 * 1024 * 1024),
+                )
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+
+        let upstream = epp_headers_blocking_internal(
+            &EppCallOptions {
+                body_mode: mode,
+                max_send_message_bytes,
+                ..test_options(&addr.to_string())
+            },
+            vec![],
+            body,
+            None,
+            None,
+        )
+        .await
+        .map(|upstream| upstream.map(|h| h.value));
+        let seen = std::mem::take(&mut *seen.lock().unwrap());
+        (upstream, seen)
+    }
+
+    /// Body messages in `seen` as (length, end_of_stream)
+    fn body_messages(seen: &[ProcessingRequest]) -> Vec<(usize, bool)> {
+        use envoy::service::ext_proc::v3::processing_request;
+
+        seen.iter()
+            .filter_map(|req| match &req.request {
+                Some(processing_request::Request::RequestBody(b)) => {
+                    Some((b.body.len(), b.end_of_stream))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The `request_body_mode` and `end_of_stream` of the headers message in `seen`
+    fn headers_message(seen: &[ProcessingRequest]) -> (i32, bool) {
+        use envoy::service::ext_proc::v3::processing_request;
+
+        match &seen[0].request {
+            Some(processing_request::Request::RequestHeaders(h)) => (
+                seen[0].protocol_config.as_ref().unwrap().request_body_mode,
+                h.end_of_stream,
+            ),
+            _ => panic!("expected RequestHeaders first"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_epp_body_send_mode_buffered() {
+        let body = br#"{"model": "llama-3-8b"}"#;
+        let (upstream, seen) = epp_call_with_body(body, EppBodySendMode::Buffered).await;
+
+        assert_eq!(upstream.as_deref(), Some("10.0.0.1:8000"));
+        assert_eq!(
+            headers_message(&seen),
+            (BodySendMode::Buffered as i32, false)
+        );
+        assert_eq!(body_messages(&seen), vec![(body.len(), true)]);
+    }
+
+    #[tokio::test]
+    async fn test_epp_body_send_mode_streamed() {
+        let body = vec![b'x'; STREAMED_BODY_CHUNK_SIZE * 2 + 1];
+        let (upstream, seen) = epp_call_with_body(&body, EppBodySendMode::Streamed).await;
+
+        assert_eq!(upstream.as_deref(), Some("10.0.0.1:8000"));
+        assert_eq!(
+            headers_message(&seen),
+            (BodySendMode::Streamed as i32, false)
+        );
+        assert_eq!(
+            body_messages(&seen),
+            vec![
+                (STREAMED_BODY_CHUNK_SIZE, false),
+                (STREAMED_BODY_CHUNK_SIZE, false),
+                (1, true)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_epp_body_send_mode_none_and_empty_body_send_headers_only() {
+        // The stub never selects an upstream without a body, so the stream ends without one.
+        // The headers do not end the stream: the EPP could still ask for the body.
+        let (upstream, seen) = epp_call_with_body(b"{}", EppBodySendMode::None).await;
+        assert_eq!(upstream, None);
+        assert_eq!(headers_message(&seen), (BodySendMode::None as i32, false));
+        assert!(body_messages(&seen).is_empty());
+
+        let (upstream, seen) = epp_call_with_body(b"", EppBodySendMode::Streamed).await;
+        assert_eq!(upstream, None);
+        assert_eq!(
+            headers_message(&seen),
+            (BodySendMode::Streamed as i32, true)
+        );
+        assert!(body_messages(&seen).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_epp_mode_override_body_follows_open_headers() {
+        // Mode none: the body is sent only because the EPP asks, after headers left open
+        let body = br#"{"model": "llama-3-8b"}"#;
+        let (upstream, seen) = epp_call_requesting_body(body, EppBodySendMode::None).await;
+        assert_eq!(upstream.as_deref(), Some("10.0.0.1:8000"));
+        assert_eq!(headers_message(&seen), (BodySendMode::None as i32, false));
+        assert_eq!(body_messages(&seen), vec![(body.len(), true)]);
+
+        // No body: the headers end the stream and nothing follows them, even when asked
+        let (upstream, seen) = epp_call_requesting_body(b"", EppBodySendMode::None).await;
+        assert_eq!(upstream, None);
+        assert_eq!(headers_message(&seen), (BodySendMode::None as i32, true));
+        assert!(body_messages(&seen).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_epp_max_send_message_bytes() {
+        // Over tonic's 4MB receive default, which tonic does not apply when sending
+        let body = vec![b'x'; 5 * 1024 * 1024];
+
+        let (upstream, seen) = epp_call_with_body_limit(&body, EppBodySendMode::Buffered, 0).await;
+        assert_eq!(upstream, Ok(Some("10.0.0.1:8000".to_string())));
+        assert_eq!(body_messages(&seen), vec![(body.len(), true)]);
+
+        // A configured limit below the body size stops the body message from being sent
+        let (upstream, seen) =
+            epp_call_with_body_limit(&body, EppBodySendMode::Buffered, 1024 * 1024).await;
+        assert_ne!(upstream, Ok(Some("10.0.0.1:8000".to_string())));
+        assert!(body_messages(&seen).is_empty());
+    }
+
+    /// EPP stub selecting `10.0.0.1:8000` in a response padded with a `size` byte body
+    /// mutation
+    struct LargeResponseEpp {
+        size: usize,
+    }
+
+    #[tonic::async_trait]
+    impl envoy::service::ext_proc::v3::external_processor_server::ExternalProcessor
+        for LargeResponseEpp
+    {
+        type ProcessStream =
+            tokio_stream::wrappers::ReceiverStream<Result<ProcessingResponse, tonic::Status>>;
+
+        async fn process(
+            &self,
+            request: tonic::Request<tonic::Streaming<ProcessingRequest>>,
+        ) -> Result<tonic::Response<Self::ProcessStream>, tonic::Status> {
+            use envoy::service::ext_proc::v3::{
+                body_mutation, processing_response, BodyMutation, CommonResponse, HeadersResponse,
+            };
+
+            let _ = request.into_inner().message().await?;
+            let resp = ProcessingResponse {
+                response: Some(processing_response::Response::RequestHeaders(
+                    HeadersResponse {
+                        response: Some(CommonResponse {
+                            header_mutation: Some(upstream_mutation()),
+                            body_mutation: Some(BodyMutation {
+                                mutation: Some(body_mutation::Mutation::Body(vec![
+                                    b'x';
+                                    self.size
+                                ])),
+                            }),
+                            ..Default::default()
+                        }),
+                    },
+                )),
+                ..response_with_body_mode(None)
+            };
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            tx.send(Ok(resp)).await.unwrap();
+            Ok(tonic::Response::new(
+                tokio_stream::wrappers::ReceiverStream::new(rx),
+            ))
+        }
+    }
+
+    async fn epp_call_large_response(
+        size: usize,
+        max_recv_message_bytes: usize,
+    ) -> Result<Option<String>, EppError> {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ExternalProcessorServer::new(LargeResponseEpp { size }))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+
+        epp_headers_blocking_internal(
+            &EppCallOptions {
+                max_recv_message_bytes,
+                ..test_options(&addr.to_string())
+            },
+            vec![],
+            b"",
+            None,
+            None,
+        )
+        .await
+        .map(|upstream| upstream.map(|h| h.value))
+    }
+
+    #[tokio::test]
+    async fn test_epp_max_recv_message_bytes() {
+        let size = 5 * 1024 * 1024;
+
+        // tonic's 4MB default rejects the response
+        let err = epp_call_large_response(size, 0).await.unwrap_err();
+        assert!(matches!(err, EppError::Transport(_)), "{:?}", err);
+
+        let upstream = epp_call_large_response(size, 8 * 1024 * 1024).await;
+        assert_eq!(upstream, Ok(Some("10.0.0.1:8000".to_string())));
+    }
+
+    /// EPP stub that reads the request headers and never replies or closes the stream
+    struct SilentEpp;
+
+    #[tonic::async_trait]
+    impl envoy::service::ext_proc::v3::external_processor_server::ExternalProcessor for SilentEpp {
+        type ProcessStream =
+            tokio_stream::wrappers::ReceiverStream<Result<ProcessingResponse, tonic::Status>>;
+
+        async fn process(
+            &self,
+            request: tonic::Request<tonic::Streaming<ProcessingRequest>>,
+        ) -> Result<tonic::Response<Self::ProcessStream>, tonic::Status> {
+            let mut inbound = request.into_inner();
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            tokio::spawn(async move {
+                let _ = inbound.message().await;
+                tx.closed().await;
+            });
+            Ok(tonic::Response::new(
+                tokio_stream::wrappers::ReceiverStream::new(rx),
+            ))
+        }
+    }
+
+    async fn epp_call_silent(request_deadline_ms: Option<u64>) -> Result<Option<String>, EppError> {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ExternalProcessorServer::new(SilentEpp))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+
+        epp_headers_blocking_internal(
+            &EppCallOptions {
+                timeout_ms: 0,
+                request_deadline_ms,
+                ..test_options(&addr.to_string())
+            },
+            vec![],
+            b"",
+            None,
+            None,
+        )
+        .await
+        .map(|upstream| upstream.map(|h| h.value))
+    }
+
+    #[tokio::test]
+    async fn test_epp_zero_timeout_bounded_by_request_deadline() {
+        let start = std::time::Instant::now();
+        let deadline = current_time_ms() + 300;
+        assert_eq!(
+            epp_call_silent(Some(deadline)).await,
+            Err(EppError::Timeout)
+        );
+        assert!(
+            start.elapsed() < std::time::Duration::from_secs(2),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_epp_zero_timeout_without_deadline_is_capped() {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ExternalProcessorServer::new(SilentEpp))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+        let channel = Channel::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = ProcessClient::new(channel, &test_options(""));
+        let mut inbound = client
+            .process(tokio_stream::iter(vec![ProcessingRequest::default()]))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // A short cap in place of UNBOUNDED_EPP_TIMEOUT_CAP_MS
+        let cap = std::time::Duration::from_millis(200);
+        let start = std::time::Instant::now();
+        let deadline = stream_deadline(0, None, cap.as_millis() as u64);
+        assert!(next_response(&mut inbound, deadline).await.is_none());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= cap, "{:?}", elapsed);
+        assert!(
+            elapsed < cap + std::time::Duration::from_secs(2),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    /// EPP stub that asks for `extend_ms` via `override_message_timeout`, then selects an
+    /// upstream after `delay`
+    struct ExtendingEpp {
+        extend_ms: u64,
+        delay: std::time::Duration,
+    }
+
+    #[tonic::async_trait]
+    impl envoy::service::ext_proc::v3::external_processor_server::ExternalProcessor for ExtendingEpp {
+        type ProcessStream =
+            tokio_stream::wrappers::ReceiverStream<Result<ProcessingResponse, tonic::Status>>;
+
+        async fn process(
+            &self,
+            request: tonic::Request<tonic::Streaming<ProcessingRequest>>,
+        ) -> Result<tonic::Response<Self::ProcessStream>, tonic::Status> {
+            let mut inbound = request.into_inner();
+            let (tx, rx) = tokio::sync::mpsc::channel(2);
+            let (extend_ms, delay) = (self.extend_ms, self.delay);
+            tokio::spawn(async move {
+                let _ = inbound.message().await;
+                let extend = ProcessingResponse {
+                    override_message_timeout: Some(prost_types::Duration {
+                        seconds: (extend_ms / 1000) as i64,
+                        nanos: (extend_ms % 1000 * 1_000_000) as i32,
+                    }),
+                    ..response_with_body_mode(None)
+                };
+                let _ = tx.send(Ok(extend)).await;
+                tokio::time::sleep(delay).await;
+                let _ = tx.send(Ok(headers_response(true))).await;
+                // Keep the stream open until the client is done
+                tx.closed().await;
+            });
+            Ok(tonic::Response::new(
+                tokio_stream::wrappers::ReceiverStream::new(rx),
+            ))
+        }
+    }
+
+    async fn epp_call_extending(
+        epp: ExtendingEpp,
+        max_timeout_ms: u64,
+    ) -> Result<Option<String>, EppError> {
+        use envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ExternalProcessorServer::new(epp))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+
+        epp_headers_blocking_internal(
+            &EppCallOptions {
+                timeout_ms: 200,
+                max_timeout_ms,
+                ..test_options(&addr.to_string())
+            },
+            vec![],
+            b"",
+            None,
+            None,
+        )
+        .await
+        .map(|upstream| upstream.map(|h| h.value))
+    }
+
+    #[tokio::test]
+    async fn test_epp_override_message_timeout_extends_deadline() {
+        // The upstream arrives after 400ms, past the 200ms timeout
+        let delay = std::time::Duration::from_millis(400);
+
+        // Extension within inference_epp_max_timeout_ms is honored
+        let epp = ExtendingEpp {
+            extend_ms: 800,
+            delay,
+        };
+        assert_eq!(
+            epp_call_extending(epp, 1000).await,
+            Ok(Some("10.0.0.1:8000".to_string()))
+        );
+
+        // Without a cap, or above it, the override is ignored
+        let epp = ExtendingEpp {
+            extend_ms: 800,
+            delay,
+        };
+        assert_eq!(epp_call_extending(epp, 0).await, Err(EppError::Timeout));
+        let epp = ExtendingEpp {
+            extend_ms: 2000,
+            delay,
+        };
+        assert_eq!(epp_call_extending(epp, 1000).await, Err(EppError::Timeout));
+    }
+
+    async fn epp_call_with_attributes(
+        attributes: Option<&RequestAttributes>,
+    ) -> HashMap<String, prost_types::Struct> {
+        epp_call_recorded(vec![], attributes).await.attributes
+    }
+
+    #[tokio::test]
+    async fn test_epp_receives_non_utf8_header_as_raw_value() {
+        use envoy::service::ext_proc::v3::processing_request;
+
+        let headers = vec![
+            ("x-request-id".to_string(), b"abc-123".to_vec()),
+            ("x-binary".to_string(), vec![0x66, 0x6f, 0xff, 0xfe]),
+        ];
+        let first = epp_call_recorded(headers, None).await;
+        let Some(processing_request::Request::RequestHeaders(hdrs)) = first.request else {
+            panic!("first message is not RequestHeaders");
+        };
+        let sent = hdrs.headers.unwrap().headers;
+        let find = |k: &str| sent.iter().find(|h| h.key == k).unwrap();
+
+        assert_eq!(find("x-request-id").value, "abc-123");
+        assert!(find("x-request-id").raw_value.is_empty());
+        assert_eq!(find("x-binary").value, "");
+        assert_eq!(find("x-binary").raw_value, vec![0x66, 0x6f, 0xff, 0xfe]);
+    }
+
+    #[tokio::test]
+    async fn test_epp_receives_request_id_header() {
+        use envoy::service::ext_proc::v3::processing_request;
+
+        let mut headers = vec![("host".to_string(), b"localhost".to_vec())];
+        crate::epp::add_request_id_header(&mut headers, "X-Request-Id", b"7b3e0c2a9f");
+        let first = epp_call_recorded(headers, None).await;
+        let Some(processing_request::Request::RequestHeaders(hdrs)) = first.request else {
+            panic!("first message is not RequestHeaders");
+        };
+        let sent = hdrs.headers.unwrap().headers;
+        let ids: Vec<_> = sent
+            .iter()
+            .filter(|h| h.key.eq_ignore_ascii_case("x-request-id"))
+            .collect();
+        assert_eq!(ids.len(), 1);
+        assert_eq!(ids[0].value, "7b3e0c2a9f");
+    }
+
+    #[tokio::test]
+    async fn test_epp_receives_request_attributes() {
+        let attrs = RequestAttributes {
+            method: "POST".to_string(),
+            path: "/v1/chat/completions".to_string(),
+            host: "localhost".to_string(),
+            extra: vec![("source.address".to_string(), "127.0.0.1".to_string())],
+        };
+        let received = epp_call_with_attributes(Some(&attrs)).await;
+        let fields = &received[EXT_PROC_ATTRIBUTES_KEY].fields;
+        assert_eq!(
+            fields["request.path"].kind,
+            Some(prost_types::value::Kind::StringValue(
+                "/v1/chat/completions".to_string()
+            ))
+        );
+        assert_eq!(
+            fields["source.address"].kind,
+            Some(prost_types::value::Kind::StringValue(
+                "127.0.0.1".to_string()
+            ))
+        );
+
+        // Attributes are only sent when enabled
+        let received = epp_call_with_attributes(None).await;
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_epp_receives_host_attribute() {
+        // inference_epp_send_host on: the host alone, without method and path
+        let attrs = RequestAttributes {
+            host: "models.example.com".to_string(),
+            ..Default::default()
+        };
+        let received = epp_call_with_attributes(Some(&attrs)).await;
+        let fields = &received[EXT_PROC_ATTRIBUTES_KEY].fields;
+        assert_eq!(
+            fields["request.host"].kind,
+            Some(prost_types::value::Kind::StringValue(
+                "models.example.com".to_string()
+            ))
+        );
+        assert!(!fields.contains_key("request.method"));
+        assert!(!fields.contains_key("request.path"));
+    }
 }
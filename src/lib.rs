@@ -2,25 +2,43 @@ use std::ffi::{c_char, c_void};
 
 use ngx::core;
 use ngx::ffi::{
-    ngx_array_push, ngx_command_t, ngx_conf_t, ngx_http_add_variable, ngx_http_handler_pt,
-    ngx_http_module_t, ngx_http_phases_NGX_HTTP_ACCESS_PHASE, ngx_int_t, ngx_module_t, ngx_str_t,
-    ngx_uint_t, NGX_CONF_TAKE1, NGX_HTTP_LOC_CONF, NGX_HTTP_LOC_CONF_OFFSET, NGX_HTTP_MAIN_CONF,
-    NGX_HTTP_MODULE, NGX_HTTP_SRV_CONF, NGX_LOG_EMERG,
+    ngx_array_push, ngx_command_t, ngx_conf_t, ngx_cycle_t, ngx_http_add_variable,
+    ngx_http_compile_complex_value, ngx_http_compile_complex_value_t, ngx_http_complex_value_t,
+    ngx_http_conf_ctx_t, ngx_http_handler_pt, ngx_http_module, ngx_http_module_t,
+    ngx_http_phases_NGX_HTTP_ACCESS_PHASE, ngx_http_phases_NGX_HTTP_PREACCESS_PHASE,
+    ngx_http_phases_NGX_HTTP_PRECONTENT_PHASE, ngx_int_t, ngx_module_t, ngx_pcalloc, ngx_str_t,
+    ngx_uint_t, NGX_CONF_TAKE1, NGX_CONF_TAKE12, NGX_CONF_TAKE2, NGX_CONF_TAKE3, NGX_HTTP_LOC_CONF,
+    NGX_HTTP_LOC_CONF_OFFSET, NGX_HTTP_MAIN_CONF, NGX_HTTP_MODULE, NGX_HTTP_SRV_CONF,
+    NGX_HTTP_VAR_NOCACHEABLE, NGX_LOG_EMERG, NGX_LOG_NOTICE, NGX_LOG_WARN, NGX_OK,
 };
 use ngx::http::{self, HttpModule};
-use ngx::http::{HttpModuleLocationConf, HttpModuleMainConf, NgxHttpCoreModule};
+use ngx::http::{
+    HttpModuleLocationConf, HttpModuleMainConf, HttpModuleServerConf, Merge, NgxHttpCoreModule,
+};
 use ngx::{http_request_handler, http_variable_get, ngx_conf_log_error, ngx_string};
 
 /* Internal modules for gRPC ext-proc client and generated protos */
 pub mod epp;
 pub mod grpc;
+pub mod logging;
 pub mod model_extractor;
 pub mod modules;
 pub mod protos;
 
-use modules::bbr::get_header_in;
-use modules::config::{set_on_off, set_string_opt, set_u64, set_usize};
-use modules::{BbrProcessor, EppProcessor, ModuleConfig};
+use logging::{ngx_log_debug_http, ngx_log_warn_http};
+use modules::bbr::{get_header_in, model_header};
+use modules::config::{
+    add_epp_attribute, add_epp_endpoint_map, add_immediate_status, add_model_alias,
+    add_model_route, is_header_name, push_string_list, set_http_status, set_on_off, set_percent,
+    set_rate, set_regex, set_string_opt, set_u64, set_usize, EppAttributeValue, ParseError,
+};
+use modules::ctx::{
+    already_processed, body_spill_size, invalidate_headers, mark_processed, mark_time,
+    mark_upstream_written, request_ctx, request_deadline, request_headers, sample_debug,
+    RequestCtx,
+};
+use modules::decision::{self, Action, StageOutcome};
+use modules::{BbrProcessor, EppProcessor, ModuleConfig, OnMissingConfig, Stage};
 
 // Platform-agnostic string pointer casting for nginx FFI
 // c_char can be either i8 or u8 depending on platform
@@ -47,18 +65,87 @@ impl http::HttpModule for Module {
 
     unsafe extern "C" fn preconfiguration(cf: *mut ngx_conf_t) -> ngx_int_t {
         // Register $inference_upstream variable so it can be used in NGINX config (e.g. proxy_pass http://$inference_upstream;)
-        let cf_ref = unsafe { &mut *cf };
-        // Allocate variable name from configuration pool
-        let name = unsafe { &mut ngx_str_t::from_str(cf_ref.pool, "inference_upstream") as *mut _ };
-        // Add variable with no special flags
-        let v = unsafe { ngx_http_add_variable(cf, name, 0) };
-        if v.is_null() {
+        if unsafe {
+            add_variable(
+                cf,
+                "inference_upstream",
+                0,
+                Some(inference_upstream_var_get),
+            )
+        }
+        .is_err()
+        {
             return core::Status::NGX_ERROR.into();
         }
-        // Attach evaluator handler
-        unsafe {
-            (*v).get_handler = Some(inference_upstream_var_get);
-            (*v).data = 0;
+        // $inference_bbr_body_size is only known once BBR has run, so never cache it
+        if unsafe {
+            add_variable(
+                cf,
+                "inference_bbr_body_size",
+                NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+                Some(inference_bbr_body_size_var_get),
+            )
+        }
+        .is_err()
+        {
+            return core::Status::NGX_ERROR.into();
+        }
+        // The body may be spilled to disk only once it has been read, so never cache it
+        if unsafe {
+            add_variable(
+                cf,
+                "inference_body_spilled",
+                NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+                Some(inference_body_spilled_var_get),
+            )
+        }
+        .is_err()
+        {
+            return core::Status::NGX_ERROR.into();
+        }
+        // Stage timings change while the request is processed, so never cache them
+        if unsafe {
+            add_variable(
+                cf,
+                "inference_bbr_ms",
+                NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+                Some(inference_bbr_ms_var_get),
+            )
+        }
+        .is_err()
+        {
+            return core::Status::NGX_ERROR.into();
+        }
+        if unsafe {
+            add_variable(
+                cf,
+                "inference_epp_rpc_ms",
+                NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+                Some(inference_epp_rpc_ms_var_get),
+            )
+        }
+        .is_err()
+        {
+            return core::Status::NGX_ERROR.into();
+        }
+        // The response model is only known once the response body has passed the filter
+        if unsafe {
+            add_variable(
+                cf,
+                "inference_response_model",
+                NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+                Some(inference_response_model_var_get),
+            )
+        }
+        .is_err()
+        {
+            return core::Status::NGX_ERROR.into();
+        }
+        // $inference_version reports the running module build
+        if unsafe { add_variable(cf, "inference_version", 0, Some(inference_version_var_get)) }
+            .is_err()
+        {
+            return core::Status::NGX_ERROR.into();
         }
         core::Status::NGX_OK.into()
     }
@@ -66,26 +153,154 @@ impl http::HttpModule for Module {
     unsafe extern "C" fn postconfiguration(cf: *mut ngx_conf_t) -> ngx_int_t {
         // SAFETY: called by NGINX with non-null cf
         let cf = unsafe { &mut *cf };
+        // The http {} level conf, where `inference_handler` is stored
+        let access_handler =
+            Module::server_conf(cf).is_none_or(|conf| conf.access_handler.unwrap_or(true));
         let cmcf = NgxHttpCoreModule::main_conf_mut(cf).expect("http core main conf");
 
-        // Register an Access phase handler to run before upstream selection.
+        // Register an Access phase handler to run before upstream selection, unless
+        // `inference_handler off` leaves only $inference_upstream (no BBR or EPP).
+        if access_handler {
+            let h = unsafe {
+                ngx_array_push(
+                    &mut cmcf.phases[ngx_http_phases_NGX_HTTP_ACCESS_PHASE as usize].handlers,
+                ) as *mut ngx_http_handler_pt
+            };
+            if h.is_null() {
+                return core::Status::NGX_ERROR.into();
+            }
+            unsafe { *h = Some(inference_access_handler) };
+
+            // nginx skips the access phase for subrequests; with inference_process_subrequests
+            // they run BBR/EPP from the preaccess phase instead
+            let h = unsafe {
+                ngx_array_push(
+                    &mut cmcf.phases[ngx_http_phases_NGX_HTTP_PREACCESS_PHASE as usize].handlers,
+                ) as *mut ngx_http_handler_pt
+            };
+            if h.is_null() {
+                return core::Status::NGX_ERROR.into();
+            }
+            unsafe { *h = Some(inference_subrequest_handler) };
+        }
+
+        // Register a Precontent phase handler to strip the routing header before proxying.
         let h = unsafe {
             ngx_array_push(
-                &mut cmcf.phases[ngx_http_phases_NGX_HTTP_ACCESS_PHASE as usize].handlers,
+                &mut cmcf.phases[ngx_http_phases_NGX_HTTP_PRECONTENT_PHASE as usize].handlers,
             ) as *mut ngx_http_handler_pt
         };
         if h.is_null() {
             return core::Status::NGX_ERROR.into();
         }
-        unsafe { *h = Some(inference_access_handler) };
+        unsafe { *h = Some(inference_precontent_handler) };
+
+        // Response filters for inference_bbr_response, inference_bbr_set_response_header and
+        // inference_epp_client_response_header; they pass other responses through untouched
+        unsafe { modules::response::init_filters() };
         core::Status::NGX_OK.into()
     }
+
+    unsafe extern "C" fn create_srv_conf(cf: *mut ngx_conf_t) -> *mut c_void {
+        unsafe { create_conf(cf) }
+    }
+
+    unsafe extern "C" fn merge_srv_conf(
+        cf: *mut ngx_conf_t,
+        prev: *mut c_void,
+        conf: *mut c_void,
+    ) -> *mut c_char {
+        let prev = unsafe { &*(prev as *const ModuleConfig) };
+        let conf = unsafe { &mut *(conf as *mut ModuleConfig) };
+        if conf.merge(prev).is_err() {
+            return core::NGX_CONF_ERROR;
+        }
+
+        // Seed the server-level location conf with the merged server settings, so every
+        // location {} in this server inherits them through the regular loc conf merge.
+        let cf = unsafe { &*cf };
+        if let Some(cscf) = NgxHttpCoreModule::server_conf(cf) {
+            if let Some(loc) = unsafe { cscf.ctx.as_ref() }.and_then(Module::location_conf_mut) {
+                *loc = conf.clone();
+            }
+        }
+        core::NGX_CONF_OK
+    }
+
+    unsafe extern "C" fn create_loc_conf(cf: *mut ngx_conf_t) -> *mut c_void {
+        unsafe { create_conf(cf) }
+    }
+
+    unsafe extern "C" fn merge_loc_conf(
+        cf: *mut ngx_conf_t,
+        prev: *mut c_void,
+        conf: *mut c_void,
+    ) -> *mut c_char {
+        let prev = unsafe { &*(prev as *const ModuleConfig) };
+        let conf = unsafe { &mut *(conf as *mut ModuleConfig) };
+        if conf.merge(prev).is_err() {
+            return core::NGX_CONF_ERROR;
+        }
+
+        // Only location {} blocks serve requests. The server{} level conf (no location name)
+        // may leave settings to its locations, e.g. `inference_epp on` at http level with an
+        // endpoint per location.
+        let in_location =
+            NgxHttpCoreModule::location_conf(unsafe { &*cf }).is_some_and(|clcf| clcf.name.len > 0);
+        if !in_location {
+            return core::NGX_CONF_OK;
+        }
+        if let Err(msg) = conf.validate() {
+            ngx_conf_log_error!(NGX_LOG_EMERG, cf, "{}", msg);
+            return core::NGX_CONF_ERROR;
+        }
+        if conf.epp_uses_system_roots() && !grpc::system_roots_available() {
+            ngx_conf_log_error!(
+                NGX_LOG_WARN,
+                cf,
+                "`inference_epp_tls` is on without `inference_epp_ca_file` and no system CA certificates were found; EPP TLS verification will fail"
+            );
+        }
+        core::NGX_CONF_OK
+    }
+}
+
+unsafe impl HttpModuleServerConf for Module {
+    type ServerConf = ModuleConfig;
 }
 
 unsafe impl HttpModuleLocationConf for Module {
     type LocationConf = ModuleConfig;
 }
 
+/// Allocate an unset `ModuleConfig` for one configuration level.
+///
+/// # Safety
+///
+/// `cf` must be a valid configuration pointer provided by NGINX.
+unsafe fn create_conf(cf: *mut ngx_conf_t) -> *mut c_void {
+    let pool = unsafe { core::Pool::from_ngx_pool((*cf).pool) };
+    pool.allocate(ModuleConfig::unset()) as *mut c_void
+}
+
+/// Resolve the `ModuleConfig` a directive is stored in.
+///
+/// Directives are registered with `NGX_HTTP_LOC_CONF_OFFSET`, but at `http {}` and `server {}`
+/// level they are written to this module's srv conf. Precedence is then location over server
+/// over main: `merge_srv_conf` merges main into server, and locations inherit the result.
+///
+/// # Safety
+///
+/// `conf` must be the location conf pointer NGINX passed to the directive handler.
+unsafe fn directive_conf<'a>(cf: &ngx_conf_t, conf: *mut c_void) -> &'a mut ModuleConfig {
+    if cf.cmd_type & (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF) as ngx_uint_t != 0 {
+        if let Some(srv) = Module::server_conf_mut(cf) {
+            return srv;
+        }
+    }
+    unsafe { &mut *(conf as *mut ModuleConfig) }
+}
+
 // -------------------- Directives --------------------
 
 // Macro to generate configuration directive handlers with reduced boilerplate
@@ -107,7 +322,7 @@ macro_rules! ngx_conf_handler {
                         return core::NGX_CONF_ERROR;
                     }
 
-                    let conf = &mut *(conf as *mut ModuleConfig);
+                    let conf = directive_conf(cf_ref, conf);
                     let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
 
                     // Defensive check: ensure we have at least 2 args (directive name + value)
@@ -125,7 +340,7 @@ macro_rules! ngx_conf_handler {
                     };
 
                     match set_on_off(val) {
-                        Some(b) => conf.$field = b,
+                        Some(b) => conf.$field = Some(b),
                         None => {
                             ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` expects on|off"));
                             return core::NGX_CONF_ERROR;
@@ -154,7 +369,7 @@ macro_rules! ngx_conf_handler {
                         return core::NGX_CONF_ERROR;
                     }
 
-                    let conf = &mut *(conf as *mut ModuleConfig);
+                    let conf = directive_conf(cf_ref, conf);
                     let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
 
                     // Defensive check: ensure we have at least 2 args (directive name + value)
@@ -195,7 +410,7 @@ macro_rules! ngx_conf_handler {
                         return core::NGX_CONF_ERROR;
                     }
 
-                    let conf = &mut *(conf as *mut ModuleConfig);
+                    let conf = directive_conf(cf_ref, conf);
                     let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
 
                     // Defensive check: ensure we have at least 2 args (directive name + value)
@@ -236,7 +451,7 @@ macro_rules! ngx_conf_handler {
                         return core::NGX_CONF_ERROR;
                     }
 
-                    let conf = &mut *(conf as *mut ModuleConfig);
+                    let conf = directive_conf(cf_ref, conf);
                     let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
 
                     // Defensive check: ensure we have at least 2 args (directive name + value)
@@ -280,7 +495,7 @@ macro_rules! ngx_conf_handler {
                         return core::NGX_CONF_ERROR;
                     }
 
-                    let conf = &mut *(conf as *mut ModuleConfig);
+                    let conf = directive_conf(cf_ref, conf);
                     let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
 
                     // Defensive check: ensure we have at least 2 args (directive name + value)
@@ -307,6 +522,138 @@ macro_rules! ngx_conf_handler {
         }
     };
 
+    // Handler for u64 HTTP error status codes (400-599)
+    (status, $name:literal, $field:ident) => {
+        paste::paste! {
+            extern "C" fn [<ngx_http_inference_set_ $field>](
+                cf: *mut ngx_conf_t,
+                _cmd: *mut ngx_command_t,
+                conf: *mut c_void,
+            ) -> *mut c_char {
+                unsafe {
+                    if cf.is_null() || conf.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+                    let cf_ref = &mut *cf;
+                    if cf_ref.args.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let conf = directive_conf(cf_ref, conf);
+                    let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
+
+                    // Defensive check: ensure we have at least 2 args (directive name + value)
+                    if args.len() < 2 {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` missing argument"));
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let val = match args[1].to_str() {
+                        Ok(s) => s,
+                        Err(_) => {
+                            ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` not utf-8"));
+                            return core::NGX_CONF_ERROR;
+                        }
+                    };
+
+                    if set_http_status(&mut conf.$field, val).is_err() {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` must be an HTTP error status (400-599)"));
+                        return core::NGX_CONF_ERROR;
+                    }
+                }
+                core::NGX_CONF_OK
+            }
+        }
+    };
+
+    // Handler for u64 percentages (0-100)
+    (percent, $name:literal, $field:ident) => {
+        paste::paste! {
+            extern "C" fn [<ngx_http_inference_set_ $field>](
+                cf: *mut ngx_conf_t,
+                _cmd: *mut ngx_command_t,
+                conf: *mut c_void,
+            ) -> *mut c_char {
+                unsafe {
+                    if cf.is_null() || conf.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+                    let cf_ref = &mut *cf;
+                    if cf_ref.args.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let conf = directive_conf(cf_ref, conf);
+                    let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
+
+                    // Defensive check: ensure we have at least 2 args (directive name + value)
+                    if args.len() < 2 {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` missing argument"));
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let val = match args[1].to_str() {
+                        Ok(s) => s,
+                        Err(_) => {
+                            ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` not utf-8"));
+                            return core::NGX_CONF_ERROR;
+                        }
+                    };
+
+                    if set_percent(&mut conf.$field, val).is_err() {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` must be a percentage (0-100)"));
+                        return core::NGX_CONF_ERROR;
+                    }
+                }
+                core::NGX_CONF_OK
+            }
+        }
+    };
+
+    // Handler for Option<f64> sampling rates (0.0-1.0)
+    (rate, $name:literal, $field:ident) => {
+        paste::paste! {
+            extern "C" fn [<ngx_http_inference_set_ $field>](
+                cf: *mut ngx_conf_t,
+                _cmd: *mut ngx_command_t,
+                conf: *mut c_void,
+            ) -> *mut c_char {
+                unsafe {
+                    if cf.is_null() || conf.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+                    let cf_ref = &mut *cf;
+                    if cf_ref.args.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let conf = directive_conf(cf_ref, conf);
+                    let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
+
+                    // Defensive check: ensure we have at least 2 args (directive name + value)
+                    if args.len() < 2 {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` missing argument"));
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let val = match args[1].to_str() {
+                        Ok(s) => s,
+                        Err(_) => {
+                            ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` not utf-8"));
+                            return core::NGX_CONF_ERROR;
+                        }
+                    };
+
+                    if set_rate(&mut conf.$field, val).is_err() {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` must be a rate between 0.0 and 1.0"));
+                        return core::NGX_CONF_ERROR;
+                    }
+                }
+                core::NGX_CONF_OK
+            }
+        }
+    };
+
     // Handler for Option<String> path values
     (path, $name:literal, $field:ident) => {
         paste::paste! {
@@ -324,7 +671,7 @@ macro_rules! ngx_conf_handler {
                         return core::NGX_CONF_ERROR;
                     }
 
-                    let conf = &mut *(conf as *mut ModuleConfig);
+                    let conf = directive_conf(cf_ref, conf);
                     let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
 
                     // Defensive check: ensure we have at least 2 args (directive name + value)
@@ -347,136 +694,1364 @@ macro_rules! ngx_conf_handler {
             }
         }
     };
-}
+    // Handler for Option<Regex> values, compiled at configuration time
+    (regex, $name:literal, $field:ident) => {
+        paste::paste! {
+            extern "C" fn [<ngx_http_inference_set_ $field>](
+                cf: *mut ngx_conf_t,
+                _cmd: *mut ngx_command_t,
+                conf: *mut c_void,
+            ) -> *mut c_char {
+                unsafe {
+                    if cf.is_null() || conf.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+                    let cf_ref = &mut *cf;
+                    if cf_ref.args.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
 
-// Generate all configuration handlers using the macro
-ngx_conf_handler!(on_off, "inference_bbr", bbr_enable);
-ngx_conf_handler!(usize, "inference_max_body_size", max_body_size);
-ngx_conf_handler!(string, "inference_bbr_header_name", bbr_header_name);
-ngx_conf_handler!(string, "inference_bbr_default_model", bbr_default_model);
-ngx_conf_handler!(string_opt, "inference_default_upstream", default_upstream);
-ngx_conf_handler!(on_off, "inference_epp", epp_enable);
-ngx_conf_handler!(string_opt, "inference_epp_endpoint", epp_endpoint);
-ngx_conf_handler!(u64, "inference_epp_timeout_ms", epp_timeout_ms);
-ngx_conf_handler!(
-    on_off,
-    "inference_epp_failure_mode_allow",
-    epp_failure_mode_allow
-);
-ngx_conf_handler!(string, "inference_epp_header_name", epp_header_name);
-ngx_conf_handler!(on_off, "inference_epp_tls", epp_tls);
-ngx_conf_handler!(path, "inference_epp_ca_file", epp_ca_file);
+                    let conf = directive_conf(cf_ref, conf);
+                    let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
 
-// NGINX directives table
-// SAFETY: Must be `static mut` because ngx_command_t contains raw pointers (*mut c_void, *mut u8)
-// which don't implement Sync, preventing use of immutable `static`. However, this is only written
-// during module initialization (single-threaded) and only read afterwards. nginx expects a mutable
-// pointer but never mutates it after initialization.
-static mut NGX_HTTP_INFERENCE_COMMANDS: [ngx_command_t; 13] = [
-    ngx_command_t {
-        name: ngx_string!("inference_default_upstream"),
-        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
-            as ngx_uint_t,
-        set: Some(ngx_http_inference_set_default_upstream),
-        conf: NGX_HTTP_LOC_CONF_OFFSET,
-        offset: 0,
-        post: std::ptr::null_mut(),
-    },
-    ngx_command_t {
-        name: ngx_string!("inference_bbr"),
-        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
-            as ngx_uint_t,
-        set: Some(ngx_http_inference_set_bbr_enable),
-        conf: NGX_HTTP_LOC_CONF_OFFSET,
-        offset: 0,
-        post: std::ptr::null_mut(),
-    },
-    ngx_command_t {
-        name: ngx_string!("inference_max_body_size"),
-        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
-            as ngx_uint_t,
-        set: Some(ngx_http_inference_set_max_body_size),
-        conf: NGX_HTTP_LOC_CONF_OFFSET,
-        offset: 0,
-        post: std::ptr::null_mut(),
-    },
-    ngx_command_t {
-        name: ngx_string!("inference_bbr_header_name"),
-        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
-            as ngx_uint_t,
-        set: Some(ngx_http_inference_set_bbr_header_name),
-        conf: NGX_HTTP_LOC_CONF_OFFSET,
-        offset: 0,
-        post: std::ptr::null_mut(),
-    },
-    ngx_command_t {
-        name: ngx_string!("inference_bbr_default_model"),
-        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+                    // Defensive check: ensure we have at least 2 args (directive name + value)
+                    if args.len() < 2 {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` missing argument"));
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let val = match args[1].to_str() {
+                        Ok(s) => s,
+                        Err(_) => {
+                            ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` not utf-8"));
+                            return core::NGX_CONF_ERROR;
+                        }
+                    };
+
+                    if set_regex(&mut conf.$field, val).is_err() {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` is not a valid regex"));
+                        return core::NGX_CONF_ERROR;
+                    }
+                }
+                core::NGX_CONF_OK
+            }
+        }
+    };
+    // Handler for Option<T: FromStr> keyword values
+    (keyword, $name:literal, $field:ident) => {
+        paste::paste! {
+            extern "C" fn [<ngx_http_inference_set_ $field>](
+                cf: *mut ngx_conf_t,
+                _cmd: *mut ngx_command_t,
+                conf: *mut c_void,
+            ) -> *mut c_char {
+                unsafe {
+                    if cf.is_null() || conf.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+                    let cf_ref = &mut *cf;
+                    if cf_ref.args.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let conf = directive_conf(cf_ref, conf);
+                    let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
+
+                    // Defensive check: ensure we have at least 2 args (directive name + value)
+                    if args.len() < 2 {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` missing argument"));
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let val = match args[1].to_str() {
+                        Ok(s) => s,
+                        Err(_) => {
+                            ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` not utf-8"));
+                            return core::NGX_CONF_ERROR;
+                        }
+                    };
+
+                    match val.parse() {
+                        Ok(v) => conf.$field = Some(v),
+                        Err(_) => {
+                            ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` has an invalid value"));
+                            return core::NGX_CONF_ERROR;
+                        }
+                    }
+                }
+                core::NGX_CONF_OK
+            }
+        }
+    };
+    // Handler for repeatable Vec<String> values: each occurrence appends its argument
+    (string_list, $name:literal, $field:ident) => {
+        paste::paste! {
+            extern "C" fn [<ngx_http_inference_set_ $field>](
+                cf: *mut ngx_conf_t,
+                _cmd: *mut ngx_command_t,
+                conf: *mut c_void,
+            ) -> *mut c_char {
+                unsafe {
+                    if cf.is_null() || conf.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+                    let cf_ref = &mut *cf;
+                    if cf_ref.args.is_null() {
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let conf = directive_conf(cf_ref, conf);
+                    let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
+
+                    // Defensive check: ensure we have at least 2 args (directive name + value)
+                    if args.len() < 2 {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` missing argument"));
+                        return core::NGX_CONF_ERROR;
+                    }
+
+                    let val = match args[1].to_str() {
+                        Ok(s) => s,
+                        Err(_) => {
+                            ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` not utf-8"));
+                            return core::NGX_CONF_ERROR;
+                        }
+                    };
+
+                    if push_string_list(&mut conf.$field, val).is_err() {
+                        ngx_conf_log_error!(NGX_LOG_EMERG, cf, concat!("`", $name, "` value is empty or duplicate"));
+                        return core::NGX_CONF_ERROR;
+                    }
+                }
+                core::NGX_CONF_OK
+            }
+        }
+    };
+}
+
+// Generate all configuration handlers using the macro
+ngx_conf_handler!(on_off, "inference_bbr", bbr_enable);
+ngx_conf_handler!(usize, "inference_max_body_size", max_body_size);
+ngx_conf_handler!(string, "inference_bbr_header_name", bbr_header_name);
+ngx_conf_handler!(string, "inference_bbr_default_model", bbr_default_model);
+ngx_conf_handler!(string_opt, "inference_default_upstream", default_upstream);
+ngx_conf_handler!(string_opt, "inference_force_upstream", force_upstream);
+ngx_conf_handler!(
+    keyword,
+    "inference_upstream_model_header",
+    upstream_model_header
+);
+ngx_conf_handler!(
+    string_opt,
+    "inference_upstream_unresolved_value",
+    upstream_unresolved_value
+);
+ngx_conf_handler!(on_off, "inference_epp", epp_enable);
+ngx_conf_handler!(string_opt, "inference_epp_endpoint", epp_endpoint);
+ngx_conf_handler!(u64, "inference_epp_timeout_ms", epp_timeout_ms);
+ngx_conf_handler!(u64, "inference_epp_max_timeout_ms", epp_max_timeout_ms);
+ngx_conf_handler!(
+    percent,
+    "inference_epp_timeout_jitter_pct",
+    epp_timeout_jitter_pct
+);
+ngx_conf_handler!(
+    on_off,
+    "inference_epp_failure_mode_allow",
+    epp_failure_mode_allow
+);
+ngx_conf_handler!(string, "inference_epp_header_name", epp_header_name);
+ngx_conf_handler!(on_off, "inference_epp_tls", epp_tls);
+ngx_conf_handler!(path, "inference_epp_ca_file", epp_ca_file);
+ngx_conf_handler!(on_off, "inference_epp_skip_if_set", epp_skip_if_set);
+ngx_conf_handler!(
+    regex,
+    "inference_upstream_validate_regex",
+    upstream_validate_regex
+);
+ngx_conf_handler!(usize, "inference_upstream_max_len", upstream_max_len);
+ngx_conf_handler!(keyword, "inference_epp_on_no_header", epp_on_no_header);
+ngx_conf_handler!(usize, "inference_epp_max_headers", epp_max_headers);
+ngx_conf_handler!(
+    usize,
+    "inference_epp_max_header_bytes",
+    epp_max_header_bytes
+);
+ngx_conf_handler!(u64, "inference_request_deadline_ms", request_deadline_ms);
+ngx_conf_handler!(keyword, "inference_bbr_array_policy", bbr_array_policy);
+ngx_conf_handler!(
+    on_off,
+    "inference_strip_upstream_header",
+    strip_upstream_header
+);
+ngx_conf_handler!(
+    keyword,
+    "inference_epp_grpc_compression",
+    epp_grpc_compression
+);
+ngx_conf_handler!(keyword, "inference_epp_header_sources", epp_header_sources);
+ngx_conf_handler!(
+    on_off,
+    "inference_bbr_url_decode_model",
+    bbr_url_decode_model
+);
+ngx_conf_handler!(keyword, "inference_bbr_empty_body", bbr_empty_body);
+ngx_conf_handler!(keyword, "inference_bbr_on_parse_error", bbr_on_parse_error);
+ngx_conf_handler!(
+    on_off,
+    "inference_epp_send_request_attributes",
+    epp_send_request_attributes
+);
+ngx_conf_handler!(status, "inference_epp_failure_status", epp_failure_status);
+ngx_conf_handler!(status, "inference_epp_timeout_status", epp_timeout_status);
+ngx_conf_handler!(on_off, "inference_model_alias_ci", model_alias_ci);
+ngx_conf_handler!(on_off, "inference", enable);
+ngx_conf_handler!(usize, "inference_epp_max_messages", epp_max_messages);
+ngx_conf_handler!(u64, "inference_bbr_proto_field", bbr_proto_field);
+ngx_conf_handler!(
+    usize,
+    "inference_bbr_parse_prefix_bytes",
+    bbr_parse_prefix_bytes
+);
+ngx_conf_handler!(keyword, "inference_pipeline_order", pipeline_order);
+ngx_conf_handler!(u64, "inference_epp_cache_ttl_ms", epp_cache_ttl_ms);
+ngx_conf_handler!(
+    on_off,
+    "inference_bbr_field_case_insensitive",
+    bbr_field_case_insensitive
+);
+ngx_conf_handler!(on_off, "inference_process_subrequests", process_subrequests);
+ngx_conf_handler!(keyword, "inference_epp_service_path", epp_service_path);
+ngx_conf_handler!(keyword, "inference_on_missing_config", on_missing_config);
+ngx_conf_handler!(
+    keyword,
+    "inference_bbr_model_path_regex",
+    bbr_model_path_regex
+);
+ngx_conf_handler!(keyword, "inference_bbr_model_template", bbr_model_template);
+ngx_conf_handler!(keyword, "inference_log_decisions", log_decisions);
+ngx_conf_handler!(rate, "inference_debug_sample_rate", debug_sample_rate);
+ngx_conf_handler!(
+    rate,
+    "inference_epp_log_response_sample",
+    epp_log_response_sample
+);
+ngx_conf_handler!(
+    on_off,
+    "inference_bbr_oversize_upstream",
+    bbr_oversize_upstream
+);
+ngx_conf_handler!(string_list, "inference_epp_header_allow", epp_header_allow);
+ngx_conf_handler!(on_off, "inference_epp_send_headers", epp_send_headers);
+ngx_conf_handler!(keyword, "inference_epp_body_hash", epp_body_hash);
+ngx_conf_handler!(keyword, "inference_epp_body_send_mode", epp_body_send_mode);
+ngx_conf_handler!(
+    string_opt,
+    "inference_epp_fallback_endpoint",
+    epp_fallback_endpoint
+);
+ngx_conf_handler!(on_off, "inference_log_body_spill", log_body_spill);
+ngx_conf_handler!(string_list, "inference_upstream_allow", upstream_allow);
+ngx_conf_handler!(
+    keyword,
+    "inference_epp_tls_min_version",
+    epp_tls_min_version
+);
+ngx_conf_handler!(string_list, "inference_bbr_model_field", bbr_model_fields);
+ngx_conf_handler!(u64, "inference_epp_sticky_ttl_ms", epp_sticky_ttl_ms);
+ngx_conf_handler!(u64, "inference_epp_channel_idle_ms", epp_channel_idle_ms);
+ngx_conf_handler!(
+    usize,
+    "inference_epp_max_recv_message_bytes",
+    epp_max_recv_message_bytes
+);
+ngx_conf_handler!(
+    usize,
+    "inference_epp_max_send_message_bytes",
+    epp_max_send_message_bytes
+);
+ngx_conf_handler!(string_opt, "inference_epp_user_agent", epp_user_agent);
+ngx_conf_handler!(
+    on_off,
+    "inference_epp_send_client_cert",
+    epp_send_client_cert
+);
+ngx_conf_handler!(on_off, "inference_epp_send_host", epp_send_host);
+ngx_conf_handler!(on_off, "inference_bbr_overwrite", bbr_overwrite);
+ngx_conf_handler!(
+    on_off,
+    "inference_bbr_set_response_header",
+    bbr_set_response_header
+);
+ngx_conf_handler!(on_off, "inference_bbr_response", bbr_response);
+ngx_conf_handler!(
+    on_off,
+    "inference_bbr_response_streaming",
+    bbr_response_streaming
+);
+ngx_conf_handler!(usize, "inference_total_body_memory", total_body_memory);
+ngx_conf_handler!(on_off, "inference_metrics_on_exit", metrics_on_exit);
+ngx_conf_handler!(on_off, "inference_handler", access_handler);
+ngx_conf_handler!(
+    string_list,
+    "inference_epp_client_response_header",
+    epp_client_response_headers
+);
+ngx_conf_handler!(
+    keyword,
+    "inference_total_body_memory_action",
+    total_body_memory_action
+);
+
+// Handler for `inference_model_route <model> <upstream>`, which may be repeated
+extern "C" fn ngx_http_inference_set_model_route(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        set_model_pair(
+            cf,
+            conf,
+            "inference_model_route",
+            "duplicate or empty model",
+            |conf, model, upstream| add_model_route(&mut conf.model_routes, model, upstream),
+        )
+    }
+}
+
+// Handler for `inference_model_alias <from> <to>`, which may be repeated
+extern "C" fn ngx_http_inference_set_model_alias(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        set_model_pair(
+            cf,
+            conf,
+            "inference_model_alias",
+            "duplicate or empty model",
+            |conf, from, to| add_model_alias(&mut conf.model_aliases, from, to),
+        )
+    }
+}
+
+// Handler for `inference_epp_immediate_status_map <code> <status>`, which may be repeated
+extern "C" fn ngx_http_inference_set_epp_immediate_status_map(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        set_model_pair(
+            cf,
+            conf,
+            "inference_epp_immediate_status_map",
+            "duplicate code or invalid status (code 0-599, status 200-599)",
+            |conf, from, to| add_immediate_status(&mut conf.epp_immediate_status_map, from, to),
+        )
+    }
+}
+
+// Handler for `inference_epp_attribute <key> <value>`, which may be repeated. The value is
+// compiled as a complex value so it may contain variables.
+extern "C" fn ngx_http_inference_set_epp_attribute(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        if cf.is_null() || conf.is_null() {
+            return core::NGX_CONF_ERROR;
+        }
+        let cf_ref = &mut *cf;
+        if cf_ref.args.is_null() || (*cf_ref.args).nelts < 3 {
+            ngx_conf_log_error!(
+                NGX_LOG_EMERG,
+                cf,
+                "`inference_epp_attribute` missing argument"
+            );
+            return core::NGX_CONF_ERROR;
+        }
+
+        let conf = directive_conf(cf_ref, conf);
+        let args = (*cf_ref.args).elts as *mut ngx_str_t;
+        let key = match (*args.add(1)).to_str() {
+            Ok(k) => k,
+            Err(_) => {
+                ngx_conf_log_error!(NGX_LOG_EMERG, cf, "`inference_epp_attribute` not utf-8");
+                return core::NGX_CONF_ERROR;
+            }
+        };
+
+        let cv = match compile_complex_value(cf, args.add(2)) {
+            Some(cv) => cv,
+            None => return core::NGX_CONF_ERROR,
+        };
+
+        if add_epp_attribute(&mut conf.epp_attributes, key, EppAttributeValue(cv)).is_err() {
+            ngx_conf_log_error!(
+                NGX_LOG_EMERG,
+                cf,
+                "`inference_epp_attribute` duplicate or empty key"
+            );
+            return core::NGX_CONF_ERROR;
+        }
+    }
+    core::NGX_CONF_OK
+}
+
+// Handler for `inference_epp_cache_key <template>`; the template may contain variables
+extern "C" fn ngx_http_inference_set_epp_cache_key(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        if cf.is_null() || conf.is_null() {
+            return core::NGX_CONF_ERROR;
+        }
+        let cf_ref = &mut *cf;
+        if cf_ref.args.is_null() || (*cf_ref.args).nelts < 2 {
+            ngx_conf_log_error!(
+                NGX_LOG_EMERG,
+                cf,
+                "`inference_epp_cache_key` missing argument"
+            );
+            return core::NGX_CONF_ERROR;
+        }
+
+        let conf = directive_conf(cf_ref, conf);
+        if conf.epp_cache_key.is_some() {
+            ngx_conf_log_error!(NGX_LOG_EMERG, cf, "`inference_epp_cache_key` is duplicate");
+            return core::NGX_CONF_ERROR;
+        }
+        let args = (*cf_ref.args).elts as *mut ngx_str_t;
+        match compile_complex_value(cf, args.add(1)) {
+            Some(cv) => conf.epp_cache_key = Some(EppAttributeValue(cv)),
+            None => return core::NGX_CONF_ERROR,
+        }
+    }
+    core::NGX_CONF_OK
+}
+
+// Handler for `inference_epp_request_id_header <name> [<value>]`; the value defaults to
+// `$request_id` and may contain variables
+extern "C" fn ngx_http_inference_set_epp_request_id_header(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        if cf.is_null() || conf.is_null() {
+            return core::NGX_CONF_ERROR;
+        }
+        let cf_ref = &mut *cf;
+        if cf_ref.args.is_null() || (*cf_ref.args).nelts < 2 {
+            ngx_conf_log_error!(
+                NGX_LOG_EMERG,
+                cf,
+                "`inference_epp_request_id_header` missing argument"
+            );
+            return core::NGX_CONF_ERROR;
+        }
+
+        let conf = directive_conf(cf_ref, conf);
+        if conf.epp_request_id_header.is_some() {
+            ngx_conf_log_error!(
+                NGX_LOG_EMERG,
+                cf,
+                "`inference_epp_request_id_header` is duplicate"
+            );
+            return core::NGX_CONF_ERROR;
+        }
+        let args = (*cf_ref.args).elts as *mut ngx_str_t;
+        let name = match (*args.add(1)).to_str() {
+            Ok(name) if is_header_name(name) => name.to_string(),
+            _ => {
+                ngx_conf_log_error!(
+                    NGX_LOG_EMERG,
+                    cf,
+                    "`inference_epp_request_id_header` invalid header name"
+                );
+                return core::NGX_CONF_ERROR;
+            }
+        };
+        let mut default_value = ngx_string!("$request_id");
+        let value = if (*cf_ref.args).nelts > 2 {
+            args.add(2)
+        } else {
+            &mut default_value as *mut ngx_str_t
+        };
+        match compile_complex_value(cf, value) {
+            Some(cv) => conf.epp_request_id_header = Some((name, EppAttributeValue(cv))),
+            None => return core::NGX_CONF_ERROR,
+        }
+    }
+    core::NGX_CONF_OK
+}
+
+// Compile a directive argument as a complex value allocated from the configuration pool
+unsafe fn compile_complex_value(
+    cf: *mut ngx_conf_t,
+    value: *mut ngx_str_t,
+) -> Option<*mut ngx_http_complex_value_t> {
+    unsafe {
+        let cv = ngx_pcalloc((*cf).pool, std::mem::size_of::<ngx_http_complex_value_t>())
+            as *mut ngx_http_complex_value_t;
+        if cv.is_null() {
+            return None;
+        }
+        let mut ccv: ngx_http_compile_complex_value_t = std::mem::zeroed();
+        ccv.cf = cf;
+        ccv.value = value;
+        ccv.complex_value = cv;
+        if ngx_http_compile_complex_value(&mut ccv) != NGX_OK as ngx_int_t {
+            return None;
+        }
+        Some(cv)
+    }
+}
+
+extern "C" fn ngx_http_inference_set_epp_endpoint_map(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        if cf.is_null() || conf.is_null() {
+            return core::NGX_CONF_ERROR;
+        }
+        let cf_ref = &mut *cf;
+        if cf_ref.args.is_null() {
+            return core::NGX_CONF_ERROR;
+        }
+
+        let conf = directive_conf(cf_ref, conf);
+        let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
+
+        // Defensive check: directive name + three arguments
+        if args.len() < 4 {
+            ngx_conf_log_error!(
+                NGX_LOG_EMERG,
+                cf,
+                "`inference_epp_endpoint_map` missing argument"
+            );
+            return core::NGX_CONF_ERROR;
+        }
+
+        let (variable, value, endpoint) =
+            match (args[1].to_str(), args[2].to_str(), args[3].to_str()) {
+                (Ok(a), Ok(v), Ok(e)) => (a, v, e),
+                _ => {
+                    ngx_conf_log_error!(
+                        NGX_LOG_EMERG,
+                        cf,
+                        "`inference_epp_endpoint_map` not utf-8"
+                    );
+                    return core::NGX_CONF_ERROR;
+                }
+            };
+
+        if add_epp_endpoint_map(&mut conf.epp_endpoint_map, variable, value, endpoint).is_err() {
+            ngx_conf_log_error!(
+                NGX_LOG_EMERG,
+                cf,
+                "`inference_epp_endpoint_map` needs one $variable per level, unique values and a literal endpoint"
+            );
+            return core::NGX_CONF_ERROR;
+        }
+    }
+    core::NGX_CONF_OK
+}
+
+// Shared argument handling for the TAKE2 table directives; `invalid` describes a rejected entry
+unsafe fn set_model_pair(
+    cf: *mut ngx_conf_t,
+    conf: *mut c_void,
+    directive: &str,
+    invalid: &str,
+    add: fn(&mut ModuleConfig, &str, &str) -> Result<(), ParseError>,
+) -> *mut c_char {
+    unsafe {
+        if cf.is_null() || conf.is_null() {
+            return core::NGX_CONF_ERROR;
+        }
+        let cf_ref = &mut *cf;
+        if cf_ref.args.is_null() {
+            return core::NGX_CONF_ERROR;
+        }
+
+        let conf = directive_conf(cf_ref, conf);
+        let args: &[ngx_str_t] = (*cf_ref.args).as_slice();
+
+        // Defensive check: directive name + two arguments
+        if args.len() < 3 {
+            ngx_conf_log_error!(NGX_LOG_EMERG, cf, "`{}` missing argument", directive);
+            return core::NGX_CONF_ERROR;
+        }
+
+        let (key, value) = match (args[1].to_str(), args[2].to_str()) {
+            (Ok(k), Ok(v)) => (k, v),
+            _ => {
+                ngx_conf_log_error!(NGX_LOG_EMERG, cf, "`{}` not utf-8", directive);
+                return core::NGX_CONF_ERROR;
+            }
+        };
+
+        if add(conf, key, value).is_err() {
+            ngx_conf_log_error!(NGX_LOG_EMERG, cf, "`{}` {}", directive, invalid);
+            return core::NGX_CONF_ERROR;
+        }
+    }
+    core::NGX_CONF_OK
+}
+
+// NGINX directives table
+// SAFETY: Must be `static mut` because ngx_command_t contains raw pointers (*mut c_void, *mut u8)
+// which don't implement Sync, preventing use of immutable `static`. However, this is only written
+// during module initialization (single-threaded) and only read afterwards. nginx expects a mutable
+// pointer but never mutates it after initialization.
+static mut NGX_HTTP_INFERENCE_COMMANDS: [ngx_command_t; 84] = [
+    ngx_command_t {
+        name: ngx_string!("inference_default_upstream"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_default_upstream),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_force_upstream"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_force_upstream),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_upstream_model_header"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_upstream_model_header),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_upstream_unresolved_value"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_upstream_unresolved_value),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_enable),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_max_body_size"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_max_body_size),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_header_name"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_header_name),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_default_model"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_default_model),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_enable),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_endpoint"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_endpoint),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_endpoint_map"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE3)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_endpoint_map),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_timeout_ms"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_timeout_ms),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_max_timeout_ms"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_max_timeout_ms),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_timeout_jitter_pct"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_timeout_jitter_pct),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_failure_mode_allow"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_failure_mode_allow),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_header_name"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_header_name),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_tls"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_tls),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_ca_file"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_ca_file),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_skip_if_set"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_skip_if_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_upstream_validate_regex"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_upstream_validate_regex),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_upstream_max_len"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_upstream_max_len),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_on_no_header"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_on_no_header),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_max_headers"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_max_headers),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_max_header_bytes"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_max_header_bytes),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_request_deadline_ms"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_request_deadline_ms),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_array_policy"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_array_policy),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_strip_upstream_header"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_strip_upstream_header),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_grpc_compression"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_grpc_compression),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_header_sources"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_header_sources),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_url_decode_model"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_url_decode_model),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_empty_body"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_empty_body),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_on_parse_error"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_on_parse_error),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_send_request_attributes"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_send_request_attributes),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_model_route"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE2)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_model_route),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_model_alias"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE2)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_model_alias),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_attribute"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE2)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_attribute),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_failure_status"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_failure_status),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_timeout_status"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_timeout_status),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_model_alias_ci"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_model_alias_ci),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_enable),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_max_messages"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_max_messages),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_proto_field"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_proto_field),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_parse_prefix_bytes"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_parse_prefix_bytes),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_pipeline_order"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_pipeline_order),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_cache_ttl_ms"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_cache_ttl_ms),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_cache_key"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_cache_key),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_request_id_header"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE12)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_request_id_header),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_field_case_insensitive"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_field_case_insensitive),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_process_subrequests"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_process_subrequests),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_service_path"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_service_path),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_on_missing_config"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF) | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_inference_set_on_missing_config),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_model_path_regex"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_model_path_regex),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_model_template"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_model_template),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_log_decisions"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_log_decisions),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_debug_sample_rate"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_debug_sample_rate),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_log_response_sample"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_log_response_sample),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_oversize_upstream"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_oversize_upstream),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_header_allow"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_header_allow),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_send_headers"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_send_headers),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_body_hash"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_body_hash),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_body_send_mode"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_body_send_mode),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_fallback_endpoint"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_fallback_endpoint),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_log_body_spill"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_log_body_spill),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_upstream_allow"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_upstream_allow),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_tls_min_version"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_tls_min_version),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_model_field"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_model_fields),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_sticky_ttl_ms"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
             as ngx_uint_t,
-        set: Some(ngx_http_inference_set_bbr_default_model),
+        set: Some(ngx_http_inference_set_epp_sticky_ttl_ms),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
     },
     ngx_command_t {
-        name: ngx_string!("inference_epp"),
+        name: ngx_string!("inference_epp_channel_idle_ms"),
         type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
             as ngx_uint_t,
-        set: Some(ngx_http_inference_set_epp_enable),
+        set: Some(ngx_http_inference_set_epp_channel_idle_ms),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
     },
     ngx_command_t {
-        name: ngx_string!("inference_epp_endpoint"),
+        name: ngx_string!("inference_epp_max_recv_message_bytes"),
         type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
             as ngx_uint_t,
-        set: Some(ngx_http_inference_set_epp_endpoint),
+        set: Some(ngx_http_inference_set_epp_max_recv_message_bytes),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
     },
     ngx_command_t {
-        name: ngx_string!("inference_epp_timeout_ms"),
+        name: ngx_string!("inference_epp_max_send_message_bytes"),
         type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
             as ngx_uint_t,
-        set: Some(ngx_http_inference_set_epp_timeout_ms),
+        set: Some(ngx_http_inference_set_epp_max_send_message_bytes),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
     },
     ngx_command_t {
-        name: ngx_string!("inference_epp_failure_mode_allow"),
+        name: ngx_string!("inference_epp_user_agent"),
         type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
             as ngx_uint_t,
-        set: Some(ngx_http_inference_set_epp_failure_mode_allow),
+        set: Some(ngx_http_inference_set_epp_user_agent),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
     },
     ngx_command_t {
-        name: ngx_string!("inference_epp_header_name"),
+        name: ngx_string!("inference_epp_send_client_cert"),
         type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
             as ngx_uint_t,
-        set: Some(ngx_http_inference_set_epp_header_name),
+        set: Some(ngx_http_inference_set_epp_send_client_cert),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
     },
     ngx_command_t {
-        name: ngx_string!("inference_epp_tls"),
+        name: ngx_string!("inference_epp_send_host"),
         type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
             as ngx_uint_t,
-        set: Some(ngx_http_inference_set_epp_tls),
+        set: Some(ngx_http_inference_set_epp_send_host),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
     },
     ngx_command_t {
-        name: ngx_string!("inference_epp_ca_file"),
+        name: ngx_string!("inference_bbr_overwrite"),
         type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
             as ngx_uint_t,
-        set: Some(ngx_http_inference_set_epp_ca_file),
+        set: Some(ngx_http_inference_set_bbr_overwrite),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_set_response_header"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_set_response_header),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_response"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_response),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_bbr_response_streaming"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_bbr_response_streaming),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_total_body_memory"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_inference_set_total_body_memory),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_metrics_on_exit"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_inference_set_metrics_on_exit),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_handler"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_inference_set_access_handler),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_total_body_memory_action"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_total_body_memory_action),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_immediate_status_map"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE2)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_immediate_status_map),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("inference_epp_client_response_header"),
+        type_: ((NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF) | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_inference_set_epp_client_response_headers),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
@@ -489,8 +2064,8 @@ static NGX_HTTP_INFERENCE_MODULE_CTX: ngx_http_module_t = ngx_http_module_t {
     postconfiguration: Some(Module::postconfiguration),
     create_main_conf: None,
     init_main_conf: None,
-    create_srv_conf: None,
-    merge_srv_conf: None,
+    create_srv_conf: Some(Module::create_srv_conf),
+    merge_srv_conf: Some(Module::merge_srv_conf),
     create_loc_conf: Some(Module::create_loc_conf),
     merge_loc_conf: Some(Module::merge_loc_conf),
 };
@@ -506,9 +2081,83 @@ pub static mut ngx_http_inference_module: ngx_module_t = ngx_module_t {
     ctx: std::ptr::addr_of!(NGX_HTTP_INFERENCE_MODULE_CTX) as _,
     commands: unsafe { &NGX_HTTP_INFERENCE_COMMANDS[0] as *const _ as *mut _ },
     type_: NGX_HTTP_MODULE as _,
+    exit_process: Some(inference_exit_process),
     ..ngx_module_t::default()
 };
 
+/// Worker exit: log the worker's BBR/EPP counters with `inference_metrics_on_exit on`; a no-op
+/// otherwise
+///
+/// # Safety
+///
+/// Called by nginx with the worker's cycle, whose configuration is still loaded.
+unsafe extern "C" fn inference_exit_process(cycle: *mut ngx_cycle_t) {
+    let Some(conf) = (unsafe { http_main_conf(cycle) }) else {
+        return;
+    };
+    if !conf.metrics_on_exit.unwrap_or_default() {
+        return;
+    }
+    unsafe {
+        logging::log_message(
+            (*cycle).log,
+            NGX_LOG_NOTICE,
+            format!(
+                "ngx-inference: worker exiting, counters: {}",
+                modules::metrics::summary()
+            ),
+        )
+    };
+}
+
+/// This module's `http {}` level configuration in `cycle`, where `http`-only directives are
+/// stored (see [`directive_conf`]); `None` without an `http {}` block
+///
+/// # Safety
+///
+/// `cycle` must be null or a valid cycle whose configuration has been loaded.
+unsafe fn http_main_conf<'a>(cycle: *mut ngx_cycle_t) -> Option<&'a ModuleConfig> {
+    let cycle = unsafe { cycle.as_ref() }?;
+    if cycle.conf_ctx.is_null() {
+        return None;
+    }
+    unsafe {
+        let http_index = (*std::ptr::addr_of!(ngx_http_module)).index;
+        let ctx = (*cycle.conf_ctx.add(http_index) as *const ngx_http_conf_ctx_t).as_ref()?;
+        if ctx.srv_conf.is_null() {
+            return None;
+        }
+        let ctx_index = (*std::ptr::addr_of!(ngx_http_inference_module)).ctx_index;
+        (*ctx.srv_conf.add(ctx_index) as *const ModuleConfig).as_ref()
+    }
+}
+
+/// Register a variable with `handler` as its evaluator
+///
+/// # Safety
+///
+/// `cf` must be a valid configuration pointer passed by nginx during preconfiguration.
+unsafe fn add_variable(
+    cf: *mut ngx_conf_t,
+    name: &str,
+    flags: ngx_uint_t,
+    handler: ngx::ffi::ngx_http_get_variable_pt,
+) -> Result<(), ()> {
+    let cf_ref = unsafe { &mut *cf };
+    // Allocate variable name from configuration pool
+    let name = unsafe { &mut ngx_str_t::from_str(cf_ref.pool, name) as *mut _ };
+    let v = unsafe { ngx_http_add_variable(cf, name, flags) };
+    if v.is_null() {
+        return Err(());
+    }
+    // Attach evaluator handler
+    unsafe {
+        (*v).get_handler = handler;
+        (*v).data = 0;
+    }
+    Ok(())
+}
+
 // -------------------- Variable: $inference_upstream --------------------
 // Exposes the value of the "X-Inference-Upstream" header set by EPP for upstream selection.
 // Usage: proxy_pass http://$inference_upstream; (configured endpoint from EPP response)
@@ -598,8 +2247,21 @@ http_variable_get!(
 
             if let Some(val) = get_header_in(request, &upstream_header) {
                 return set_variable_from_bytes(v, &pool, val.as_bytes());
+            } else if let Some(val) =
+                request_ctx(request.as_mut()).and_then(|ctx| ctx.stripped_upstream())
+            {
+                // Header already stripped by inference_strip_upstream_header
+                return set_variable_from_bytes(v, &pool, val.as_bytes());
+            } else if let Some(val) =
+                request_ctx(request.as_mut()).and_then(|ctx| ctx.routed_upstream())
+            {
+                // Static inference_model_route match for the BBR model
+                return set_variable_from_bytes(v, &pool, val.as_bytes());
             } else if let Some(ref default_upstream) = conf.default_upstream {
                 return set_variable_from_bytes(v, &pool, default_upstream.as_bytes());
+            } else if let Some(ref unresolved) = conf.upstream_unresolved_value {
+                // inference_upstream_unresolved_value, e.g. a blackhole upstream answering 503
+                return set_variable_from_bytes(v, &pool, unresolved.as_bytes());
             } else {
                 // mark variable as not found
                 (*v).set_not_found(1);
@@ -611,12 +2273,206 @@ http_variable_get!(
     }
 );
 
+// -------------------- Variable: $inference_bbr_body_size --------------------
+// Number of request body bytes read by BBR, for logging body-size distributions.
+// Usage: log_format inference '... bbr_body=$inference_bbr_body_size';
+// Resolves to 0 when BBR did not run or the body was empty.
+
+http_variable_get!(
+    inference_bbr_body_size_var_get,
+    |request: &mut http::Request, v: *mut ngx::ffi::ngx_variable_value_t, _data: usize| {
+        if v.is_null() {
+            return core::Status::NGX_ERROR;
+        }
+        let size = unsafe { request_ctx(request.as_mut()) }
+            .map(|ctx| ctx.bbr_body_size())
+            .unwrap_or(0);
+        let pool = request.pool();
+        unsafe { set_variable_from_bytes(v, &pool, size.to_string().as_bytes()) }
+    }
+);
+
+// -------------------- Variable: $inference_body_spilled --------------------
+// `1` if nginx wrote the request body to a temp file (client_body_buffer_size too small),
+// otherwise `0`. Usage: log_format inference '... spilled=$inference_body_spilled';
+
+http_variable_get!(
+    inference_body_spilled_var_get,
+    |request: &mut http::Request, v: *mut ngx::ffi::ngx_variable_value_t, _data: usize| {
+        if v.is_null() {
+            return core::Status::NGX_ERROR;
+        }
+        let spilled = unsafe { body_spill_size(request.as_mut()) }.is_some();
+        let pool = request.pool();
+        unsafe { set_variable_from_bytes(v, &pool, if spilled { b"1" } else { b"0" }) }
+    }
+);
+
+// -------------------- Variables: $inference_bbr_ms, $inference_epp_rpc_ms --------------------
+// Milliseconds from module start until the body was read, and from then until the EPP result,
+// to tell slow clients from a slow EPP.
+// Usage: log_format inference '... body=$inference_bbr_ms epp=$inference_epp_rpc_ms';
+// Resolve to 0 when the stage did not run.
+
+http_variable_get!(
+    inference_bbr_ms_var_get,
+    |request: &mut http::Request, v: *mut ngx::ffi::ngx_variable_value_t, _data: usize| {
+        if v.is_null() {
+            return core::Status::NGX_ERROR;
+        }
+        let ms = unsafe { request_ctx(request.as_mut()) }
+            .map(|ctx| ctx.bbr_ms())
+            .unwrap_or(0);
+        let pool = request.pool();
+        unsafe { set_variable_from_bytes(v, &pool, ms.to_string().as_bytes()) }
+    }
+);
+
+http_variable_get!(
+    inference_epp_rpc_ms_var_get,
+    |request: &mut http::Request, v: *mut ngx::ffi::ngx_variable_value_t, _data: usize| {
+        if v.is_null() {
+            return core::Status::NGX_ERROR;
+        }
+        let ms = unsafe { request_ctx(request.as_mut()) }
+            .map(|ctx| ctx.epp_rpc_ms())
+            .unwrap_or(0);
+        let pool = request.pool();
+        unsafe { set_variable_from_bytes(v, &pool, ms.to_string().as_bytes()) }
+    }
+);
+
+// -------------------- Variable: $inference_response_model --------------------
+// Model reported in the response body with `inference_bbr_response on`, e.g. to log which model
+// actually served a request. Usage: log_format inference '... served=$inference_response_model';
+// Not found until the response body has been searched, or if it names no model.
+
+http_variable_get!(
+    inference_response_model_var_get,
+    |request: &mut http::Request, v: *mut ngx::ffi::ngx_variable_value_t, _data: usize| {
+        if v.is_null() {
+            return core::Status::NGX_ERROR;
+        }
+        let model = unsafe { request_ctx(request.as_mut()) }
+            .and_then(|ctx| ctx.response_model().map(str::to_string))
+            .unwrap_or_default();
+        let pool = request.pool();
+        unsafe { set_variable_from_bytes(v, &pool, model.as_bytes()) }
+    }
+);
+
+// -------------------- Variable: $inference_version --------------------
+// Module version, with the git commit appended as build metadata when known (e.g. 0.1.0+1a2b3c4).
+// Usage: add_header X-Inference-Version $inference_version;
+
+/// Version string reported by `$inference_version`
+pub fn module_version() -> String {
+    match option_env!("NGX_INFERENCE_GIT_HASH") {
+        Some(hash) => format!("{}+{}", env!("CARGO_PKG_VERSION"), hash),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+http_variable_get!(
+    inference_version_var_get,
+    |request: &mut http::Request, v: *mut ngx::ffi::ngx_variable_value_t, _data: usize| {
+        if v.is_null() {
+            return core::Status::NGX_ERROR;
+        }
+        let pool = request.pool();
+        unsafe { set_variable_from_bytes(v, &pool, module_version().as_bytes()) }
+    }
+);
+
+// -------------------- Precontent Phase Handler --------------------
+// Removes the routing header from headers_in before the content phase builds the upstream
+// request, so internal routing info is not forwarded to the backend
+// (inference_strip_upstream_header). The value is kept on the request context, so
+// $inference_upstream still resolves to it.
+
+http_request_handler!(
+    inference_precontent_handler,
+    |request: &mut http::Request| {
+        let conf = match Module::location_conf(request) {
+            Some(c) => c,
+            None => return core::Status::NGX_DECLINED,
+        };
+        if !conf.applies_to(!request.is_main()) || !conf.strip_upstream_header.unwrap_or(true) {
+            return core::Status::NGX_DECLINED;
+        }
+
+        // The header is the module's routing decision when it wrote it, or with EPP on, when
+        // it was already present and EPP was skipped
+        let r: *mut ngx::ffi::ngx_http_request_t = request.as_mut();
+        let written = unsafe { request_ctx(r) }.is_some_and(|ctx| ctx.upstream_written());
+        if !written && !conf.epp_enable.unwrap_or_default() {
+            return core::Status::NGX_DECLINED;
+        }
+
+        let upstream_header = if conf.epp_header_name.is_empty() {
+            "X-Inference-Upstream"
+        } else {
+            &conf.epp_header_name
+        };
+
+        if let Some(value) = unsafe { take_header_in(r, upstream_header) } {
+            match unsafe { request_ctx(r) } {
+                Some(ctx) => {
+                    ctx.set_stripped_upstream(value);
+                    ctx.invalidate_headers();
+                }
+                None => return http::HTTPStatus::INTERNAL_SERVER_ERROR.into(),
+            }
+        }
+        core::Status::NGX_DECLINED
+    }
+);
+
+/// Remove the first incoming request header named `name` (case-insensitive) and return its value.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+unsafe fn take_header_in(r: *mut ngx::ffi::ngx_http_request_t, name: &str) -> Option<String> {
+    let headers_in = unsafe { &mut (*r).headers_in };
+    let mut part: *mut ngx::ffi::ngx_list_part_t = &mut headers_in.headers.part;
+    while !part.is_null() {
+        let elts = unsafe { (*part).elts as *mut ngx::ffi::ngx_table_elt_t };
+        let nelts = unsafe { (*part).nelts };
+        for i in 0..nelts {
+            let h = unsafe { &*elts.add(i) };
+            if h.key.len == name.len()
+                && !h.key.data.is_null()
+                && unsafe { std::slice::from_raw_parts(h.key.data, h.key.len) }
+                    .eq_ignore_ascii_case(name.as_bytes())
+            {
+                let value = if h.value.data.is_null() {
+                    String::new()
+                } else {
+                    String::from_utf8_lossy(unsafe {
+                        std::slice::from_raw_parts(h.value.data, h.value.len)
+                    })
+                    .into_owned()
+                };
+                // Close the gap within this part; other parts are unaffected
+                unsafe {
+                    std::ptr::copy(elts.add(i + 1), elts.add(i), nelts - i - 1);
+                    (*part).nelts -= 1;
+                }
+                return Some(value);
+            }
+        }
+        part = unsafe { (*part).next };
+    }
+    None
+}
+
 // -------------------- Access Phase Handler --------------------
 //
 // Module Processing Pipeline:
 // ===========================
 // This handler runs in the ACCESS phase before upstream selection. It executes two
-// optional stages in sequence:
+// optional stages, in this order by default (`inference_pipeline_order epp-bbr` swaps them):
 //
 // 1. BBR (Body-Based Routing) - Extracts model name from request body
 //    - Reads request body (may be async)
@@ -629,13 +2485,17 @@ http_variable_get!(
 //    - Receives upstream endpoint selection
 //    - Sets X-Inference-Upstream header (or configured header name)
 //
+// The request body is read at most once. In epp-bbr order EPP may read it first; when the
+// handler re-runs afterwards, BBR extracts the model from that body instead of reading again.
+//
 // Error Handling Strategy:
 // ========================
 // - BBR errors (except 413): Return HTTP 500, request terminates
 // - BBR 413 error: Return NGX_OK (request already finalized), proceeds to log phase
-// - EPP errors with fail-closed mode: Return HTTP 502 (Bad Gateway), or 504 on timeout; request terminates
+// - EPP errors with fail-closed mode: Return inference_epp_failure_status (502), or
+//   inference_epp_timeout_status (504) on timeout; request terminates
 // - EPP errors with fail-open mode: Log error, continue processing (uses default_upstream if set)
-// - If BBR fails fatally, EPP never runs
+// - If the first stage fails fatally, the second never runs
 // - If BBR succeeds and EPP fails (fail-open), request continues to upstream with BBR headers
 //
 // Return Codes:
@@ -645,113 +2505,283 @@ http_variable_get!(
 // - NGX_DECLINED: Processing complete, continue to next nginx phase (content/proxy)
 // - HTTP_500: Fatal error, nginx will send error response
 
-http_request_handler!(inference_access_handler, |request: &mut http::Request| {
+http_request_handler!(inference_access_handler, inference_handler);
+
+// Subrequests (auth_request, SSI, ...) never reach the access phase, so the preaccess phase
+// runs the same pipeline for them; main requests are left to the access phase.
+http_request_handler!(
+    inference_subrequest_handler,
+    |request: &mut http::Request| {
+        if request.is_main() {
+            return core::Status::NGX_DECLINED;
+        }
+        inference_handler(request)
+    }
+);
+
+/// BBR and EPP for `request`, from [`inference_access_handler`] or, for subrequests,
+/// [`inference_subrequest_handler`]
+fn inference_handler(request: &mut http::Request) -> core::Status {
     let conf = match Module::location_conf(request) {
         Some(c) => c,
         None => {
-            // Missing config is a fatal setup issue - fail the request
+            // Missing config, e.g. a partially applied reload: inference_on_missing_config
+            // (from the server conf, if any) decides between failing and passing through
+            let policy = OnMissingConfig::for_server(Module::server_conf(request));
+            let (level, msg, status): (_, &[u8], _) = match policy {
+                OnMissingConfig::Fail => (
+                    ngx::ffi::NGX_LOG_ERR,
+                    b"ngx-inference: module config missing, cannot process request\0",
+                    http::HTTPStatus::INTERNAL_SERVER_ERROR.into(),
+                ),
+                OnMissingConfig::Passthrough => (
+                    ngx::ffi::NGX_LOG_WARN,
+                    b"ngx-inference: module config missing, passing request through\0",
+                    core::Status::NGX_DECLINED,
+                ),
+            };
             unsafe {
                 let r = request.as_mut();
                 if let Some(conn) = r.connection.as_ref() {
-                    let msg = b"ngx-inference: module config missing, cannot process request\0";
                     ngx::ffi::ngx_log_error_core(
-                        ngx::ffi::NGX_LOG_ERR as ngx::ffi::ngx_uint_t,
+                        level as ngx::ffi::ngx_uint_t,
                         conn.log,
                         0,
                         cstr_ptr(msg.as_ptr()),
                     );
                 }
             }
-            return http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
+            return status;
         }
     };
 
+    // `inference off`: nothing else is inspected and the body is never read. Subrequests
+    // (auth_request, SSI, ...) are skipped too unless inference_process_subrequests is on:
+    // they would run BBR/EPP a second time for the same client request.
+    if !conf.applies_to(!request.is_main()) {
+        return core::Status::NGX_DECLINED;
+    }
+
+    // An internal redirect (error_page, X-Accel-Redirect, named location) runs the access
+    // phase again for the same client request: keep the routing decision made the first time
+    if unsafe { already_processed(request.as_mut()) } {
+        ngx_log_debug_http!(
+            request,
+            "ngx-inference: request already routed before an internal redirect, skipping BBR/EPP"
+        );
+        return core::Status::NGX_DECLINED;
+    }
+
     // No routine logging - only log errors and warnings
 
-    // Stage 1: BBR (Body-Based Routing)
-    // If this fails, EPP will NOT run (request terminates or is already finalized)
-    if conf.bbr_enable {
-        let bbr_status = BbrProcessor::process_request(request, conf);
-        match bbr_status {
-            core::Status::NGX_DONE => {
-                // Body reading started, handler will be called later
-                return core::Status::NGX_DONE;
-            }
-            core::Status::NGX_OK => {
-                // Check if request was finalized (e.g., 413 error)
-                let response_status = request.as_mut().headers_out.status;
-                if response_status
-                    == ngx::ffi::NGX_HTTP_REQUEST_ENTITY_TOO_LARGE as ngx::ffi::ngx_uint_t
-                {
-                    // IMPORTANT: Return NGX_OK here, not an error status.
-                    // When BBR processing detects oversized body and sets 413:
-                    // - The request has already been finalized via ngx_http_finalize_request()
-                    // - Response headers and special response handler were already triggered
-                    // - Returning NGX_OK tells nginx: "access phase complete, proceed to next phase"
-                    // - The next phase will see the finalized status and skip to log phase
-                    // - Returning an error here would cause nginx to send *another* error response
-                    return core::Status::NGX_OK;
-                }
-                // Otherwise continue processing
-            }
-            core::Status::NGX_ERROR => {
-                // Other BBR error - return 500 Internal Server Error
-                return http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
-            }
-            _ => {
-                // Continue processing
+    // Start the inference_request_deadline_ms clock; later calls keep the first deadline
+    unsafe { request_deadline(request.as_mut(), conf.request_deadline_ms) };
+    // Start of the $inference_bbr_ms / $inference_epp_rpc_ms timings
+    unsafe { mark_time(request.as_mut(), RequestCtx::mark_started) };
+    // inference_debug_sample_rate: pick this request for the debug trace before BBR/EPP log
+    unsafe { sample_debug(request.as_mut(), conf.debug_sample_rate.unwrap_or(0.0)) };
+    // Read headers_in once; BBR and EPP look headers up in this copy until one of them
+    // writes a header
+    unsafe { request_headers(request.as_mut(), conf) };
+
+    // inference_force_upstream: a fixed upstream for canary testing, set before BBR so EPP
+    // skips the call whatever the pipeline order
+    if let Some(upstream) = conf.force_upstream.as_deref() {
+        apply_forced_upstream(request, conf, upstream);
+    }
+
+    // Run BBR and EPP in the inference_pipeline_order sequence; a stage that suspends or
+    // ends the request stops the pipeline
+    for stage in conf.pipeline_order.unwrap_or_default().stages() {
+        let action = match stage {
+            Stage::Bbr => run_bbr_stage(request, conf),
+            Stage::Epp if conf.force_upstream.is_some() => Action::Continue,
+            Stage::Epp => run_epp_stage(request, conf),
+        };
+        match action {
+            Action::Continue => {}
+            // The handler runs again when the stage resumes the request
+            Action::Suspend => return action.status(),
+            action => {
+                unsafe { mark_processed(request.as_mut()) };
+                return action.status();
             }
         }
     }
 
-    // Stage 2: EPP (Endpoint Picker Processor) - headers-only exchange for upstream selection
-    if conf.epp_enable {
-        match EppProcessor::process_request(request, conf) {
-            core::Status::NGX_DECLINED => {
-                // EPP processed successfully or was skipped, continue
-            }
-            core::Status::NGX_DONE => {
-                // EPP started async processing, suspend request
-                return core::Status::NGX_DONE;
+    // inference_upstream_model_header: the model BBR settled on, under the backend's name
+    if let Some(header) = &conf.upstream_model_header {
+        apply_upstream_model_header(request, conf, &header.0);
+    }
+
+    // Continue normal processing
+    unsafe { mark_processed(request.as_mut()) };
+    core::Status::NGX_DECLINED
+}
+
+/// BBR (Body-Based Routing) stage, followed by the static model route table.
+///
+/// Returns what the access handler does next; see [`decision::next_action`].
+fn run_bbr_stage(request: &mut http::Request, conf: &ModuleConfig) -> Action {
+    if conf.bbr_enable.unwrap_or_default() {
+        let status = BbrProcessor::process_request(request, conf);
+        // With inference_bbr_oversize_upstream the 413 status was cleared and the request
+        // continues to the default upstream instead.
+        //
+        // IMPORTANT: a finalized 413 returns NGX_OK, not an error status.
+        // When BBR processing detects oversized body and sets 413:
+        // - The request has already been finalized via ngx_http_finalize_request()
+        // - Response headers and special response handler were already triggered
+        // - Returning NGX_OK tells nginx: "access phase complete, proceed to next phase"
+        // - The next phase will see the finalized status and skip to log phase
+        // - Returning an error here would cause nginx to send *another* error response
+        let outcome = StageOutcome::from_status(status, || request.as_mut().headers_out.status);
+        let action = decision::next_action(
+            Stage::Bbr,
+            outcome,
+            conf.epp_failure_mode_allow.unwrap_or_default(),
+            conf.epp_failure_status as ngx_int_t,
+        );
+        if action != Action::Continue {
+            return action;
+        }
+    }
+
+    // Static route table: map the BBR model to an upstream, independent of inference_epp
+    if !conf.model_routes.is_empty() && conf.force_upstream.is_none() {
+        resolve_model_route(request, conf);
+    }
+
+    Action::Continue
+}
+
+/// EPP (Endpoint Picker Processor) stage: headers-only exchange for upstream selection.
+///
+/// Returns what the access handler does next; see [`decision::next_action`].
+fn run_epp_stage(request: &mut http::Request, conf: &ModuleConfig) -> Action {
+    if !conf.epp_enable.unwrap_or_default() {
+        return Action::Continue;
+    }
+    let status = EppProcessor::process_request(request, conf);
+    let outcome = StageOutcome::from_status(status, || request.as_mut().headers_out.status);
+    let action = decision::next_action(
+        Stage::Epp,
+        outcome,
+        conf.epp_failure_mode_allow.unwrap_or_default(),
+        conf.epp_failure_status as ngx_int_t,
+    );
+    if outcome == StageOutcome::Error {
+        unsafe {
+            let r = request.as_mut();
+            if let Some(conn) = r.connection.as_ref() {
+                let msg = b"ngx-inference: EPP module processing failed internally\0";
+                ngx::ffi::ngx_log_error_core(
+                    ngx::ffi::NGX_LOG_ERR as ngx::ffi::ngx_uint_t,
+                    conn.log,
+                    0,
+                    cstr_ptr(msg.as_ptr()),
+                );
             }
-            core::Status::NGX_ERROR => {
-                unsafe {
-                    let r = request.as_mut();
-                    if let Some(conn) = r.connection.as_ref() {
-                        let msg = b"ngx-inference: EPP module processing failed internally\0";
-                        ngx::ffi::ngx_log_error_core(
-                            ngx::ffi::NGX_LOG_ERR as ngx::ffi::ngx_uint_t,
-                            conn.log,
-                            0,
-                            cstr_ptr(msg.as_ptr()),
-                        );
-                    }
-                }
-                if !conf.epp_failure_mode_allow {
-                    // Fail closed
-                    unsafe {
-                        let r = request.as_mut();
-                        if let Some(conn) = r.connection.as_ref() {
-                            ngx::ffi::ngx_log_error_core(
-                                ngx::ffi::NGX_LOG_WARN as ngx::ffi::ngx_uint_t,
-                                conn.log,
-                                0,
-                                #[allow(clippy::manual_c_str_literals)] // FFI code
-                                cstr_ptr(b"ngx-inference: Module returning HTTP 502 (Bad Gateway) due to EPP processing failure (fail-closed mode)\0".as_ptr()),
-                            );
-                        }
-                    }
-                    return http::HTTPStatus::BAD_GATEWAY.into();
+        }
+        if let Action::Fail(_) = action {
+            // Fail closed
+            unsafe {
+                let r = request.as_mut();
+                if let Some(conn) = r.connection.as_ref() {
+                    ngx::ffi::ngx_log_error_core(
+                        ngx::ffi::NGX_LOG_WARN as ngx::ffi::ngx_uint_t,
+                        conn.log,
+                        0,
+                        #[allow(clippy::manual_c_str_literals)] // FFI code
+                        cstr_ptr(b"ngx-inference: Module returning inference_epp_failure_status due to EPP processing failure (fail-closed mode)\0".as_ptr()),
+                    );
                 }
             }
-            _ => {
-                // Other status, continue processing
-            }
         }
     }
+    action
+}
 
-    // Continue normal processing
-    core::Status::NGX_DECLINED
-});
+/// Set the upstream header to `inference_force_upstream`, replacing any value already present
+fn apply_forced_upstream(request: &mut http::Request, conf: &ModuleConfig, upstream: &str) {
+    let header_name = if conf.epp_header_name.is_empty() {
+        "X-Inference-Upstream"
+    } else {
+        &conf.epp_header_name
+    };
+    // Already set before BBR suspended the request to read the body
+    if get_header_in(request, header_name) == Some(upstream) {
+        return;
+    }
+    if !unsafe { epp::callbacks::set_upstream_header(request.as_mut(), header_name, upstream) } {
+        return;
+    }
+    unsafe { mark_upstream_written(request.as_mut()) };
+    unsafe { invalidate_headers(request.as_mut()) };
+    ngx_log_warn_http!(
+        request,
+        "ngx-inference: inference_force_upstream in effect, routing to '{}' without EPP",
+        upstream
+    );
+    unsafe {
+        logging::log_decision(
+            logging::request_ptr(request),
+            conf.log_decisions.unwrap_or_default().level(),
+            None,
+            upstream,
+            "forced",
+        )
+    };
+}
+
+/// Set `header_name` to the canonical model (after `inference_model_alias`), replacing any
+/// value already present; nothing is set without a model header
+fn apply_upstream_model_header(
+    request: &mut http::Request,
+    conf: &ModuleConfig,
+    header_name: &str,
+) {
+    let Some(model) = model_header(request, conf) else {
+        return;
+    };
+    let model = conf.canonical_model(&model);
+    if get_header_in(request, header_name) == Some(model) {
+        return;
+    }
+    if unsafe { epp::callbacks::set_upstream_header(request.as_mut(), header_name, model) } {
+        unsafe { invalidate_headers(request.as_mut()) };
+    } else {
+        ngx_log_warn_http!(
+            request,
+            "ngx-inference: failed to set inference_upstream_model_header {}",
+            header_name
+        );
+    }
+}
+
+/// Look up the model header in `inference_model_route` and remember the upstream on the
+/// request context for `$inference_upstream`
+fn resolve_model_route(request: &mut http::Request, conf: &ModuleConfig) {
+    let model = model_header(request, conf);
+    let upstream = match model
+        .as_deref()
+        .and_then(|model| conf.route_for_model(model))
+    {
+        Some(upstream) => upstream.to_string(),
+        None => return,
+    };
+    unsafe {
+        logging::log_decision(
+            logging::request_ptr(request),
+            conf.log_decisions.unwrap_or_default().level(),
+            model.as_deref(),
+            &upstream,
+            "model-route",
+        )
+    };
+    if let Some(ctx) = unsafe { request_ctx(request.as_mut()) } {
+        ctx.set_routed_upstream(upstream);
+    }
+}
 
 // Module configuration and command definitions...
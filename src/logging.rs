@@ -0,0 +1,253 @@
+//! Logging helpers shared by BBR, EPP and the gRPC client.
+//!
+//! All module log lines go through [`log_request`], which:
+//! - honors the log's configured level (like nginx's `ngx_log_error` macro),
+//! - passes the message as a `%s` argument so `%` in request data is never treated as a
+//!   format specifier,
+//! - escapes interior NUL bytes instead of dropping the message or panicking.
+//!
+//! Two macro families are provided: `ngx_log_*_raw!` for raw `*mut ngx_http_request_t`
+//! pointers (callbacks) and `ngx_log_*_http!` for `&http::Request` wrappers.
+//!
+//! Debug lines of a request sampled by `inference_debug_sample_rate` are written whatever the
+//! log's level.
+
+use std::ffi::{c_char, CString};
+
+use ngx::ffi::{ngx_http_request_t, ngx_log_t, ngx_uint_t};
+use ngx::http;
+
+/// Convert a formatted message into a C string, escaping interior NUL bytes as `\0`.
+pub fn log_cstring(msg: String) -> CString {
+    CString::new(msg).unwrap_or_else(|e| {
+        let escaped = String::from_utf8_lossy(&e.into_vec()).replace('\0', "\\0");
+        CString::new(escaped).unwrap_or_default()
+    })
+}
+
+/// Write `msg` to `log` at `level` if the log is configured to accept it.
+///
+/// # Safety
+///
+/// `log` must be null or a valid nginx log, and this must be called in the NGINX worker thread.
+pub unsafe fn log_message(log: *mut ngx_log_t, level: u32, msg: String) {
+    if log.is_null() || unsafe { (*log).log_level } < level as ngx_uint_t {
+        return;
+    }
+    let c_msg = log_cstring(msg);
+    unsafe {
+        ngx::ffi::ngx_log_error_core(
+            level as ngx_uint_t,
+            log,
+            0,
+            #[allow(clippy::manual_c_str_literals)] // FFI code
+            b"%s\0".as_ptr().cast::<c_char>(),
+            c_msg.as_ptr(),
+        );
+    }
+}
+
+/// Write `msg` to the connection log of request `r`.
+///
+/// # Safety
+///
+/// `r` must be null or a valid request pointer, and this must be called in the NGINX worker
+/// thread.
+pub unsafe fn log_request(r: *const ngx_http_request_t, level: u32, msg: String) {
+    if r.is_null() {
+        return;
+    }
+    if let Some(conn) = unsafe { (*r).connection.as_ref() } {
+        if level == ngx::ffi::NGX_LOG_DEBUG && unsafe { debug_sampled(r) } {
+            unsafe { log_sampled_debug(conn.log, msg) };
+        } else {
+            unsafe { log_message(conn.log, level, msg) };
+        }
+    }
+}
+
+/// Whether `inference_debug_sample_rate` picked request `r` for the debug trace
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer, in the NGINX worker thread.
+unsafe fn debug_sampled(r: *const ngx_http_request_t) -> bool {
+    unsafe { crate::modules::ctx::existing_request_ctx(r.cast_mut()) }
+        .is_some_and(|ctx| ctx.debug_sampled())
+}
+
+/// Write a debug line of a sampled request even if `log` is set to a lower level, the way
+/// nginx's `debug_connection` does. The level is restored before returning.
+///
+/// # Safety
+///
+/// Same as [`log_message`].
+unsafe fn log_sampled_debug(log: *mut ngx_log_t, msg: String) {
+    if log.is_null() {
+        return;
+    }
+    let level = unsafe { (*log).log_level };
+    unsafe { (*log).log_level = level | ngx::ffi::NGX_LOG_DEBUG_CONNECTION as ngx_uint_t };
+    unsafe { log_message(log, ngx::ffi::NGX_LOG_DEBUG, msg) };
+    unsafe { (*log).log_level = level };
+}
+
+/// Raw request pointer of an `http::Request` wrapper, for use with [`log_request`].
+#[inline]
+pub fn request_ptr(request: &http::Request) -> *const ngx_http_request_t {
+    request as *const http::Request as *const ngx_http_request_t
+}
+
+/// Text of a routing decision log line (`inference_log_decisions`)
+pub fn decision_message(model: Option<&str>, upstream: &str, source: &str) -> String {
+    format!(
+        "ngx-inference: routing decision: model='{}' upstream='{}' source={}",
+        model.unwrap_or("-"),
+        upstream,
+        source
+    )
+}
+
+/// Log the final routing decision of request `r` at `level`; `None` logs nothing.
+///
+/// # Safety
+///
+/// Same as [`log_request`].
+pub unsafe fn log_decision(
+    r: *const ngx_http_request_t,
+    level: Option<u32>,
+    model: Option<&str>,
+    upstream: &str,
+    source: &str,
+) {
+    if let Some(level) = level {
+        unsafe { log_request(r, level, decision_message(model, upstream, source)) };
+    }
+}
+
+macro_rules! ngx_log_raw {
+    ($level:expr, $request:expr, $($arg:tt)*) => {{
+        #[allow(unused_unsafe)]
+        unsafe {
+            $crate::logging::log_request($request as *const _, $level, format!($($arg)*));
+        }
+    }};
+}
+
+/// Error logging from a raw request pointer
+macro_rules! ngx_log_error_raw {
+    ($request:expr, $($arg:tt)*) => {
+        $crate::logging::ngx_log_raw!(ngx::ffi::NGX_LOG_ERR, $request, $($arg)*)
+    };
+}
+
+/// Warn logging from a raw request pointer
+macro_rules! ngx_log_warn_raw {
+    ($request:expr, $($arg:tt)*) => {
+        $crate::logging::ngx_log_raw!(ngx::ffi::NGX_LOG_WARN, $request, $($arg)*)
+    };
+}
+
+/// Info logging from a raw request pointer
+macro_rules! ngx_log_info_raw {
+    ($request:expr, $($arg:tt)*) => {
+        $crate::logging::ngx_log_raw!(ngx::ffi::NGX_LOG_INFO, $request, $($arg)*)
+    };
+}
+
+/// Debug logging from a raw request pointer
+macro_rules! ngx_log_debug_raw {
+    ($request:expr, $($arg:tt)*) => {
+        $crate::logging::ngx_log_raw!(ngx::ffi::NGX_LOG_DEBUG, $request, $($arg)*)
+    };
+}
+
+/// Error logging from an `http::Request`
+macro_rules! ngx_log_error_http {
+    ($request:expr, $($arg:tt)*) => {
+        $crate::logging::ngx_log_raw!(
+            ngx::ffi::NGX_LOG_ERR,
+            $crate::logging::request_ptr($request),
+            $($arg)*
+        )
+    };
+}
+
+/// Warn logging from an `http::Request`
+macro_rules! ngx_log_warn_http {
+    ($request:expr, $($arg:tt)*) => {
+        $crate::logging::ngx_log_raw!(
+            ngx::ffi::NGX_LOG_WARN,
+            $crate::logging::request_ptr($request),
+            $($arg)*
+        )
+    };
+}
+
+/// Info logging from an `http::Request`
+macro_rules! ngx_log_info_http {
+    ($request:expr, $($arg:tt)*) => {
+        $crate::logging::ngx_log_raw!(
+            ngx::ffi::NGX_LOG_INFO,
+            $crate::logging::request_ptr($request),
+            $($arg)*
+        )
+    };
+}
+
+/// Debug logging from an `http::Request`
+macro_rules! ngx_log_debug_http {
+    ($request:expr, $($arg:tt)*) => {
+        $crate::logging::ngx_log_raw!(
+            ngx::ffi::NGX_LOG_DEBUG,
+            $crate::logging::request_ptr($request),
+            $($arg)*
+        )
+    };
+}
+
+#[allow(unused_imports)]
+pub(crate) use {
+    ngx_log_debug_http, ngx_log_debug_raw, ngx_log_error_http, ngx_log_error_raw,
+    ngx_log_info_http, ngx_log_info_raw, ngx_log_raw, ngx_log_warn_http, ngx_log_warn_raw,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_cstring_plain_message() {
+        assert_eq!(
+            log_cstring("EPP selected upstream".to_string()).to_str(),
+            Ok("EPP selected upstream")
+        );
+    }
+
+    #[test]
+    fn test_log_cstring_escapes_interior_nul() {
+        let c_msg = log_cstring("model 'a\0b' selected".to_string());
+        assert_eq!(c_msg.to_str(), Ok("model 'a\\0b' selected"));
+    }
+
+    #[test]
+    fn test_decision_message() {
+        assert_eq!(
+            decision_message(Some("llama-3-8b"), "10.0.0.1:8000", "epp"),
+            "ngx-inference: routing decision: model='llama-3-8b' upstream='10.0.0.1:8000' source=epp"
+        );
+        assert_eq!(
+            decision_message(None, "10.0.0.1:8000", "default"),
+            "ngx-inference: routing decision: model='-' upstream='10.0.0.1:8000' source=default"
+        );
+    }
+
+    #[test]
+    fn test_log_with_interior_nul_and_null_request_does_not_panic() {
+        ngx_log_error_raw!(
+            std::ptr::null_mut::<ngx_http_request_t>(),
+            "bad value: {}",
+            "x\0y"
+        );
+    }
+}
@@ -1,26 +1,824 @@
 // Model extraction utilities for BBR (Body-Based Routing)
 // Separated for easier unit testing without nginx dependencies
 
-use serde_json::Value;
+use crate::modules::config::{BbrArrayPolicy, ModelTemplate, TemplatePart};
+use serde_json::value::RawValue;
+use std::collections::BTreeMap;
 
-/// Extract model name from JSON request body following OpenAI API specification
+/// Outcome of searching a request body for the model
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BodyModel {
+    /// The model named by the body
+    Found(String),
+    /// The body is valid JSON (or empty) but names no model
+    Missing,
+    /// The body is not valid UTF-8 JSON
+    Unparseable,
+}
+
+impl BodyModel {
+    /// The model, if one was found
+    pub fn into_model(self) -> Option<String> {
+        match self {
+            BodyModel::Found(model) => Some(model),
+            BodyModel::Missing | BodyModel::Unparseable => None,
+        }
+    }
+}
+
+/// Extract model name from JSON request body following OpenAI API specification: the first
+/// non-empty string of `model`, `deployment` (Azure OpenAI) and `engine` (older OpenAI APIs)
 pub fn extract_model_from_body(body: &[u8]) -> Option<String> {
+    extract_model_from_body_with_policy(body, BbrArrayPolicy::default())
+}
+
+/// C entry point for other native modules in the same nginx build: the model of the JSON body
+/// `body[..body_len]`, as found by [`extract_model_from_body`].
+///
+/// On entry `*out_len` is the capacity of `out`. Returns:
+/// - `0`: the model was copied to `out`, without a NUL terminator, and `*out_len` is its length
+/// - `1`: the body has no model; `*out_len` is set to 0
+/// - `-1`: `out` is too small; nothing is copied and `*out_len` is the length needed
+/// - `-2`: `out_len` is null, or `body`/`out` is null with a non-zero length
+///
+/// The model is not sanitized (see [`sanitize_model`]).
+///
+/// # Safety
+///
+/// `body` must be readable for `body_len` bytes and `out` writable for `*out_len` bytes. No
+/// pointer is kept after the call returns.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_inference_extract_model(
+    body: *const u8,
+    body_len: usize,
+    out: *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    if out_len.is_null() || (body.is_null() && body_len != 0) {
+        return -2;
+    }
+    let capacity = unsafe { *out_len };
+    if out.is_null() && capacity != 0 {
+        return -2;
+    }
+    let body: &[u8] = if body_len == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(body, body_len) }
+    };
+
+    let Some(model) = extract_model_from_body(body) else {
+        unsafe { *out_len = 0 };
+        return 1;
+    };
+    unsafe { *out_len = model.len() };
+    if model.len() > capacity {
+        return -1;
+    }
+    unsafe { std::ptr::copy_nonoverlapping(model.as_ptr(), out, model.len()) };
+    0
+}
+
+/// Extract model name from JSON request body, using `policy` to pick the element when the
+/// root is an array (batch APIs send `[{"model": "a", ...}, {"model": "b", ...}]`)
+///
+/// Runs on untrusted input, so it never builds a full JSON tree: nested values are only
+/// validated and borrowed as raw slices of `body`. Allocation is limited to the top-level keys
+/// (or one pointer per top-level array element) and the model string itself.
+pub fn extract_model_from_body_with_policy(body: &[u8], policy: BbrArrayPolicy) -> Option<String> {
+    extract_model_from_body_with_options(body, policy, false)
+}
+
+/// Like [`extract_model_from_body_with_policy`], optionally matching the model keys ignoring
+/// ASCII case (`inference_bbr_field_case_insensitive`). An exact key still wins; among other
+/// spellings the first in byte order is used.
+pub fn extract_model_from_body_with_options(
+    body: &[u8],
+    policy: BbrArrayPolicy,
+    case_insensitive: bool,
+) -> Option<String> {
+    extract_model_from_body_with_fields(body, policy, case_insensitive, &[]).into_model()
+}
+
+/// Like [`extract_model_from_body_with_options`], trying the dotted field paths in `fields`
+/// in order (`inference_bbr_model_field`, e.g. `model`, `request.model`) and using the first
+/// that holds a non-empty string. An empty list means the top-level `model`, `deployment` and
+/// `engine` fields, in that order.
+///
+/// Tells a body that is not JSON ([`BodyModel::Unparseable`], for `inference_bbr_on_parse_error`)
+/// from valid JSON without a model; an empty body is [`BodyModel::Missing`].
+pub fn extract_model_from_body_with_fields(
+    body: &[u8],
+    policy: BbrArrayPolicy,
+    case_insensitive: bool,
+    fields: &[String],
+) -> BodyModel {
+    model_from_body(body, policy, |object| {
+        model_from_fields(object, case_insensitive, fields)
+    })
+}
+
+/// Like [`extract_model_from_body_with_fields`], building the model from `template`
+/// (`inference_bbr_model_template`): each placeholder is replaced by the string at its field
+/// path, or by nothing if that field is missing or not a string. A body with none of the
+/// fields has no model.
+pub fn extract_model_with_template(
+    body: &[u8],
+    policy: BbrArrayPolicy,
+    case_insensitive: bool,
+    template: &ModelTemplate,
+) -> BodyModel {
+    model_from_body(body, policy, |object| {
+        let mut model = String::new();
+        let mut found = false;
+        for part in &template.0 {
+            match part {
+                TemplatePart::Literal(text) => model.push_str(text),
+                TemplatePart::Field(path) => {
+                    if let Some(value) = string_at_path(object, path, case_insensitive) {
+                        model.push_str(&value);
+                        found = true;
+                    }
+                }
+            }
+        }
+        found.then_some(model)
+    })
+}
+
+/// Model of a JSON body found by `find` in the root object, or in the element of a root array
+/// picked by `policy`. The root is parsed once, which also validates the body as a whole.
+fn model_from_body(
+    body: &[u8],
+    policy: BbrArrayPolicy,
+    find: impl Fn(&JsonObject<'_>) -> Option<String>,
+) -> BodyModel {
+    // Some Windows clients prepend a UTF-8 BOM, which serde_json rejects
+    let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
     // Parse JSON to extract model field following OpenAI API specification
-    if let Ok(json_str) = std::str::from_utf8(body) {
-        if let Ok(json) = serde_json::from_str::<Value>(json_str) {
-            return json
-                .get("model")
-                .and_then(|v| v.as_str())
-                .map(|s| s.to_string());
+    let Ok(json_str) = std::str::from_utf8(body) else {
+        return BodyModel::Unparseable;
+    };
+    let model = match json_str.trim_start().as_bytes().first() {
+        None => None,
+        Some(b'[') => {
+            let Ok(items) = serde_json::from_str::<Vec<&RawValue>>(json_str) else {
+                return BodyModel::Unparseable;
+            };
+            let target = match policy {
+                BbrArrayPolicy::First => items.first(),
+                BbrArrayPolicy::Last => items.last(),
+                BbrArrayPolicy::Reject => None,
+            };
+            // An element that is not an object has no model
+            target
+                .and_then(|target| JsonObject::parse(target.get()))
+                .and_then(|object| find(&object))
+        }
+        Some(b'{') => {
+            let Some(object) = JsonObject::parse(json_str) else {
+                return BodyModel::Unparseable;
+            };
+            find(&object)
+        }
+        Some(_) if serde_json::from_str::<&RawValue>(json_str).is_ok() => None,
+        Some(_) => return BodyModel::Unparseable,
+    };
+    model.map_or(BodyModel::Missing, BodyModel::Found)
+}
+
+/// Like [`extract_model_from_body_with_fields`] for the first bytes of a body
+/// (`inference_bbr_parse_prefix_bytes`).
+///
+/// The JSON object is scanned key by key without being parsed as a whole, and the scan stops
+/// as soon as the first of `fields` is found; a later field seen on the way is used if the
+/// prefix ends first. Values of other keys are skipped, not validated, so only syntax errors
+/// inside the prefix make it [`BodyModel::Unparseable`]. A root array is only searched with
+/// [`BbrArrayPolicy::First`], since its other elements may lie past the prefix.
+pub fn extract_model_from_prefix(
+    prefix: &[u8],
+    policy: BbrArrayPolicy,
+    case_insensitive: bool,
+    fields: &[String],
+) -> BodyModel {
+    let prefix = prefix.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(prefix);
+    let paths: Vec<Vec<&str>> = if fields.is_empty() {
+        DEFAULT_MODEL_FIELDS
+            .iter()
+            .map(|path| vec![*path])
+            .collect()
+    } else {
+        fields
+            .iter()
+            .map(|path| path.split('.').collect())
+            .collect()
+    };
+    let mut scanner = PrefixScanner {
+        bytes: prefix,
+        pos: 0,
+        paths: &paths,
+        case_insensitive,
+        best: None,
+    };
+
+    scanner.skip_ws();
+    match scanner.peek() {
+        None => return BodyModel::Missing,
+        Some(b'[') if policy == BbrArrayPolicy::First => {
+            scanner.pos += 1;
+            scanner.skip_ws();
+            match scanner.peek() {
+                Some(b'{') => {}
+                Some(_) | None => return BodyModel::Missing,
+            }
+        }
+        Some(b'{') => {}
+        Some(b'[' | b'"' | b'-' | b'0'..=b'9' | b't' | b'f' | b'n') => return BodyModel::Missing,
+        Some(_) => return BodyModel::Unparseable,
+    }
+
+    let all: Vec<usize> = (0..paths.len()).collect();
+    match scanner.scan_object(0, &all) {
+        Scan::Invalid => BodyModel::Unparseable,
+        Scan::Closed | Scan::Found | Scan::End => scanner
+            .best
+            .map_or(BodyModel::Missing, |(_, model)| BodyModel::Found(model)),
+    }
+}
+
+/// How a scan of a JSON object in a body prefix ended
+enum Scan {
+    /// The object was closed
+    Closed,
+    /// The first field path was found, nothing more to look for
+    Found,
+    /// The prefix ended inside the object
+    End,
+    /// The prefix is not JSON
+    Invalid,
+}
+
+/// Scanner for [`extract_model_from_prefix`]
+struct PrefixScanner<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Field paths split into keys, in order of preference
+    paths: &'a [Vec<&'a str>],
+    case_insensitive: bool,
+    /// Best model found so far: rank (twice the path index, plus one for a key matched
+    /// ignoring case, so an exact key wins) and the model
+    best: Option<(usize, String)>,
+}
+
+impl<'a> PrefixScanner<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Raw JSON string at the cursor, quotes included; `Err(Scan::End)` if it is cut off
+    fn string(&mut self) -> Result<&'a [u8], Scan> {
+        let start = self.pos;
+        self.pos += 1;
+        while let Some(b) = self.peek() {
+            self.pos += 1;
+            match b {
+                b'"' => return Ok(&self.bytes[start..self.pos]),
+                b'\\' => self.pos += 1,
+                _ => {}
+            }
+        }
+        self.pos = self.bytes.len();
+        Err(Scan::End)
+    }
+
+    /// Skip the value at the cursor, tracking nesting but not validating it
+    fn skip_value(&mut self) -> Result<(), Scan> {
+        let mut depth = 0usize;
+        loop {
+            match self.peek() {
+                None => return Err(Scan::End),
+                Some(b'"') => {
+                    self.string()?;
+                }
+                Some(b'{' | b'[') => {
+                    depth += 1;
+                    self.pos += 1;
+                }
+                Some(b'}' | b']') if depth > 0 => {
+                    depth -= 1;
+                    self.pos += 1;
+                }
+                Some(b',' | b'}' | b']') if depth == 0 => return Err(Scan::Invalid),
+                Some(_) => self.pos += 1,
+            }
+            if depth == 0 {
+                // A scalar runs to the next delimiter
+                while matches!(self.peek(), Some(b) if !b",}] \t\r\n".contains(&b)) {
+                    self.pos += 1;
+                }
+                return match self.peek() {
+                    None => Err(Scan::End),
+                    Some(_) => Ok(()),
+                };
+            }
+        }
+    }
+
+    /// Scan the object at the cursor for the keys at `depth` of the paths in `active`
+    fn scan_object(&mut self, depth: usize, active: &[usize]) -> Scan {
+        self.pos += 1;
+        self.skip_ws();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Scan::Closed;
+        }
+        loop {
+            self.skip_ws();
+            match self.peek() {
+                None => return Scan::End,
+                Some(b'"') => {}
+                Some(_) => return Scan::Invalid,
+            }
+            let key = match self.string() {
+                Ok(raw) => match serde_json::from_slice::<String>(raw) {
+                    Ok(key) => key,
+                    Err(_) => return Scan::Invalid,
+                },
+                Err(scan) => return scan,
+            };
+            self.skip_ws();
+            match self.peek() {
+                None => return Scan::End,
+                Some(b':') => self.pos += 1,
+                Some(_) => return Scan::Invalid,
+            }
+            self.skip_ws();
+
+            // (path index, matched exactly) for the paths whose key at `depth` is `key`
+            let matching: Vec<(usize, bool)> = active
+                .iter()
+                .filter_map(|&i| {
+                    let segment = self.paths[i][depth];
+                    if segment == key {
+                        Some((i, true))
+                    } else if self.case_insensitive && segment.eq_ignore_ascii_case(&key) {
+                        Some((i, false))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            let leaf = matching
+                .iter()
+                .filter(|(i, _)| self.paths[*i].len() == depth + 1)
+                .map(|&(i, exact)| 2 * i + usize::from(!exact))
+                .min();
+            let nested: Vec<usize> = matching
+                .iter()
+                .filter(|(i, _)| self.paths[*i].len() > depth + 1)
+                .map(|&(i, _)| i)
+                .collect();
+
+            match (self.peek(), leaf) {
+                (None, _) => return Scan::End,
+                (Some(b'"'), Some(rank)) => {
+                    let raw = match self.string() {
+                        Ok(raw) => raw,
+                        Err(scan) => return scan,
+                    };
+                    let Ok(model) = serde_json::from_slice::<String>(raw) else {
+                        return Scan::Invalid;
+                    };
+                    let better = self.best.as_ref().is_none_or(|(best, _)| rank < *best);
+                    if !model.is_empty() && better {
+                        self.best = Some((rank, model));
+                        if rank == 0 {
+                            return Scan::Found;
+                        }
+                    }
+                }
+                (Some(b'{'), _) if !nested.is_empty() => match self.scan_object(depth + 1, &nested)
+                {
+                    Scan::Closed => {}
+                    scan => return scan,
+                },
+                (Some(_), _) => {
+                    if let Err(scan) = self.skip_value() {
+                        return scan;
+                    }
+                }
+            }
+
+            self.skip_ws();
+            match self.peek() {
+                None => return Scan::End,
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Scan::Closed;
+                }
+                Some(_) => return Scan::Invalid,
+            }
+        }
+    }
+}
+
+/// Model from the request path: capture group 1 of `inference_bbr_model_path_regex`.
+///
+/// `None` if the pattern does not match or the group is empty, so BBR falls back to the body.
+pub fn extract_model_from_path(path: &str, pattern: &regex::Regex) -> Option<String> {
+    pattern
+        .captures(path)?
+        .get(1)
+        .map(|m| m.as_str())
+        .filter(|model| !model.is_empty())
+        .map(str::to_string)
+}
+
+/// Model from a streamed response body: server-sent events (`data: {...}` lines, as sent by
+/// OpenAI-compatible servers with `"stream": true`) or newline-delimited JSON.
+///
+/// Only complete lines are read, so a partial body can be checked as it arrives; the first
+/// line whose JSON object carries a model wins.
+pub fn extract_model_from_stream(body: &[u8]) -> Option<String> {
+    let complete = &body[..body.iter().rposition(|&b| b == b'\n')?];
+    complete
+        .split(|&b| b == b'\n')
+        .map(|line| {
+            let line = line.trim_ascii();
+            line.strip_prefix(b"data:").unwrap_or(line).trim_ascii()
+        })
+        .filter(|data| data.first() == Some(&b'{'))
+        .find_map(extract_model_from_body)
+}
+
+/// Model fields tried in order when no `inference_bbr_model_field` is configured
+const DEFAULT_MODEL_FIELDS: [&str; 3] = ["model", "deployment", "engine"];
+
+/// Model from the first of `fields` holding a non-empty string in a JSON object
+fn model_from_fields(
+    object: &JsonObject<'_>,
+    case_insensitive: bool,
+    fields: &[String],
+) -> Option<String> {
+    if fields.is_empty() {
+        return DEFAULT_MODEL_FIELDS
+            .iter()
+            .find_map(|path| string_at_path(object, path, case_insensitive));
+    }
+    fields
+        .iter()
+        .find_map(|path| string_at_path(object, path, case_insensitive))
+}
+
+/// Non-empty string at dotted `path` of a JSON object; `None` if a segment is missing or not
+/// an object, or the value is not a string
+fn string_at_path(object: &JsonObject<'_>, path: &str, case_insensitive: bool) -> Option<String> {
+    let mut keys = path.split('.');
+    let mut value = object.get(keys.next()?, case_insensitive)?;
+    for key in keys {
+        value = JsonObject::parse(value.get())?.get(key, case_insensitive)?;
+    }
+    serde_json::from_str::<String>(value.get())
+        .ok()
+        .filter(|model| !model.is_empty())
+}
+
+/// A JSON object parsed one level deep: its values are borrowed as raw slices of the body and
+/// only parsed when a field path goes through them
+struct JsonObject<'a>(BTreeMap<String, &'a RawValue>);
+
+impl<'a> JsonObject<'a> {
+    /// `None` if `json` is not a valid JSON object
+    fn parse(json: &'a str) -> Option<Self> {
+        serde_json::from_str(json).ok().map(Self)
+    }
+
+    /// Raw value of `key`; with `case_insensitive`, an exact key still wins
+    fn get(&self, key: &str, case_insensitive: bool) -> Option<&'a RawValue> {
+        match self.0.get(key) {
+            Some(value) => Some(*value),
+            None if case_insensitive => self
+                .0
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, value)| *value),
+            None => None,
+        }
+    }
+}
+
+/// Make an extracted model safe to use as an HTTP header value.
+///
+/// Optionally percent-decodes the value first (`inference_bbr_url_decode_model`), then strips
+/// control characters, including CR/LF, so the model cannot inject additional headers.
+/// Returns `None` if nothing is left after sanitizing.
+pub fn sanitize_model(model: &str, url_decode: bool) -> Option<String> {
+    let decoded = if url_decode {
+        percent_decode(model)
+    } else {
+        model.to_string()
+    };
+    let sanitized: String = decoded.chars().filter(|c| !c.is_control()).collect();
+    if sanitized.is_empty() {
+        None
+    } else {
+        Some(sanitized)
+    }
+}
+
+/// How a protobuf request body is framed, from its `Content-Type`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtoFraming {
+    /// `application/grpc[+proto]`: one length-prefixed gRPC message
+    Grpc,
+    /// `application/x-protobuf`, `application/protobuf`: the body is the message
+    Raw,
+}
+
+/// Protobuf framing for `content_type`, or `None` if the body is not protobuf
+pub fn proto_framing(content_type: &str) -> Option<ProtoFraming> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    match mime.as_str() {
+        "application/grpc" | "application/grpc+proto" => Some(ProtoFraming::Grpc),
+        "application/x-protobuf" | "application/protobuf" => Some(ProtoFraming::Raw),
+        _ => None,
+    }
+}
+
+/// Largest valid protobuf field number
+const MAX_PROTO_FIELD: u64 = (1 << 29) - 1;
+
+/// Extract the model from top-level string field `field` of a protobuf request body
+/// (`inference_bbr_proto_field`).
+///
+/// Only the wire format is scanned; no schema is needed. gRPC bodies must hold a single
+/// uncompressed message. Returns `None` for malformed or truncated input, a missing field or a
+/// non-UTF-8 value. As in protobuf, the last occurrence of the field wins.
+pub fn extract_model_from_protobuf(
+    body: &[u8],
+    field: u64,
+    framing: ProtoFraming,
+) -> Option<String> {
+    if field == 0 || field > MAX_PROTO_FIELD {
+        return None;
+    }
+    let message = match framing {
+        ProtoFraming::Raw => body,
+        ProtoFraming::Grpc => {
+            // 1-byte compressed flag, 4-byte big-endian length, then the message
+            let (header, rest) = body.split_at_checked(5)?;
+            if header[0] != 0 {
+                return None;
+            }
+            let len = u32::from_be_bytes(header[1..5].try_into().ok()?) as usize;
+            rest.get(..len)?
+        }
+    };
+
+    let mut model = None;
+    let mut input = message;
+    while !input.is_empty() {
+        let key = read_varint(&mut input)?;
+        let (number, wire_type) = (key >> 3, key & 0x7);
+        match wire_type {
+            // varint
+            0 => {
+                read_varint(&mut input)?;
+            }
+            // fixed64
+            1 => input = input.get(8..)?,
+            // length-delimited: strings, bytes, embedded messages, packed repeated fields
+            2 => {
+                let len = usize::try_from(read_varint(&mut input)?).ok()?;
+                let (value, rest) = input.split_at_checked(len)?;
+                if number == field {
+                    model = Some(std::str::from_utf8(value).ok()?.to_string());
+                }
+                input = rest;
+            }
+            // fixed32
+            5 => input = input.get(4..)?,
+            // groups (deprecated) and invalid wire types
+            _ => return None,
+        }
+    }
+    model.filter(|m| !m.is_empty())
+}
+
+/// Read a base-128 varint, advancing `input`; `None` if truncated or longer than 10 bytes
+fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, &byte) in input.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *input = &input[i + 1..];
+            return Some(value);
         }
     }
     None
 }
 
+/// Decode `%XX` escapes; malformed escapes are kept as-is and invalid UTF-8 is replaced
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && bytes[i + 1].is_ascii_hexdigit()
+            && bytes[i + 2].is_ascii_hexdigit()
+        {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+            out.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Encode a protobuf key and length for a length-delimited field
+    fn proto_string(field: u64, value: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut push_varint = |mut v: u64| loop {
+            if v < 0x80 {
+                out.push(v as u8);
+                break;
+            }
+            out.push((v as u8 & 0x7f) | 0x80);
+            v >>= 7;
+        };
+        push_varint((field << 3) | 2);
+        push_varint(value.len() as u64);
+        out.extend_from_slice(value);
+        out
+    }
+
+    fn grpc_frame(message: &[u8]) -> Vec<u8> {
+        let mut out = vec![0];
+        out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        out.extend_from_slice(message);
+        out
+    }
+
+    #[test]
+    fn test_proto_framing_from_content_type() {
+        assert_eq!(proto_framing("application/grpc"), Some(ProtoFraming::Grpc));
+        assert_eq!(
+            proto_framing("application/grpc+proto; charset=utf-8"),
+            Some(ProtoFraming::Grpc)
+        );
+        assert_eq!(
+            proto_framing("Application/X-Protobuf"),
+            Some(ProtoFraming::Raw)
+        );
+        assert_eq!(proto_framing("application/json"), None);
+        assert_eq!(proto_framing("application/grpc+json"), None);
+    }
+
+    #[test]
+    fn test_extract_model_from_protobuf_skips_other_fields() {
+        // field 1: varint 150, field 2: fixed64, field 3: "llama-3-8b", field 4: fixed32,
+        // field 5: embedded message containing its own field 3
+        let mut message = vec![0x08, 0x96, 0x01];
+        message.extend_from_slice(&[0x11, 1, 2, 3, 4, 5, 6, 7, 8]);
+        message.extend(proto_string(3, b"llama-3-8b"));
+        message.extend_from_slice(&[0x25, 1, 2, 3, 4]);
+        message.extend(proto_string(5, &proto_string(3, b"nested")));
+
+        assert_eq!(
+            extract_model_from_protobuf(&message, 3, ProtoFraming::Raw),
+            Some("llama-3-8b".to_string())
+        );
+        assert_eq!(
+            extract_model_from_protobuf(&grpc_frame(&message), 3, ProtoFraming::Grpc),
+            Some("llama-3-8b".to_string())
+        );
+        assert_eq!(
+            extract_model_from_protobuf(&message, 6, ProtoFraming::Raw),
+            None
+        );
+        // Field numbers above 15 use a multi-byte key
+        let high = proto_string(1000, b"gpt-4");
+        assert_eq!(
+            extract_model_from_protobuf(&high, 1000, ProtoFraming::Raw),
+            Some("gpt-4".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_model_from_protobuf_last_occurrence_wins() {
+        let mut message = proto_string(1, b"first");
+        message.extend(proto_string(1, b"second"));
+        assert_eq!(
+            extract_model_from_protobuf(&message, 1, ProtoFraming::Raw),
+            Some("second".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_model_from_protobuf_rejects_malformed() {
+        let message = proto_string(1, b"llama");
+        // Truncated value, truncated varint, group wire type, invalid UTF-8
+        assert_eq!(
+            extract_model_from_protobuf(&message[..message.len() - 1], 1, ProtoFraming::Raw),
+            None
+        );
+        assert_eq!(
+            extract_model_from_protobuf(&[0x08, 0x80], 1, ProtoFraming::Raw),
+            None
+        );
+        assert_eq!(
+            extract_model_from_protobuf(&[0x0b], 1, ProtoFraming::Raw),
+            None
+        );
+        assert_eq!(
+            extract_model_from_protobuf(&proto_string(1, &[0xff, 0xfe]), 1, ProtoFraming::Raw),
+            None
+        );
+        // Frame longer than the body, compressed frame, field 0
+        let mut frame = grpc_frame(&message);
+        frame[4] += 1;
+        assert_eq!(
+            extract_model_from_protobuf(&frame, 1, ProtoFraming::Grpc),
+            None
+        );
+        let mut compressed = grpc_frame(&message);
+        compressed[0] = 1;
+        assert_eq!(
+            extract_model_from_protobuf(&compressed, 1, ProtoFraming::Grpc),
+            None
+        );
+        assert_eq!(
+            extract_model_from_protobuf(&message, 0, ProtoFraming::Raw),
+            None
+        );
+        // A huge declared length does not allocate or panic
+        let huge = [0x0a, 0xff, 0xff, 0xff, 0xff, 0x0f];
+        assert_eq!(
+            extract_model_from_protobuf(&huge, 1, ProtoFraming::Raw),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ngx_inference_extract_model_ffi() {
+        let extract: unsafe extern "C" fn(*const u8, usize, *mut u8, *mut usize) -> i32 =
+            ngx_inference_extract_model;
+        let body = br#"{"model": "llama-3-8b", "prompt": "hi"}"#;
+        let mut out = [0u8; 32];
+
+        let mut len = out.len();
+        let rc = unsafe { extract(body.as_ptr(), body.len(), out.as_mut_ptr(), &mut len) };
+        assert_eq!(rc, 0);
+        assert_eq!(&out[..len], b"llama-3-8b");
+
+        // Too small: the needed length is reported and nothing is written
+        let mut small = [0u8; 4];
+        let mut len = small.len();
+        let rc = unsafe { extract(body.as_ptr(), body.len(), small.as_mut_ptr(), &mut len) };
+        assert_eq!((rc, len, small), (-1, 10, [0u8; 4]));
+
+        let no_model = br#"{"prompt": "hi"}"#;
+        let mut len = out.len();
+        let rc = unsafe {
+            extract(
+                no_model.as_ptr(),
+                no_model.len(),
+                out.as_mut_ptr(),
+                &mut len,
+            )
+        };
+        assert_eq!((rc, len), (1, 0));
+
+        let mut len = out.len();
+        let rc = unsafe { extract(std::ptr::null(), 8, out.as_mut_ptr(), &mut len) };
+        assert_eq!(rc, -2);
+        let rc = unsafe {
+            extract(
+                body.as_ptr(),
+                body.len(),
+                out.as_mut_ptr(),
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(rc, -2);
+    }
+
     #[test]
     fn test_extract_model_from_body_valid_model() {
         let json_body = r#"{"model": "gpt-4", "prompt": "Hello world"}"#;
@@ -147,6 +945,374 @@ mod tests {
         assert_eq!(result, None); // "Model" vs "model" - case sensitive
     }
 
+    #[test]
+    fn test_extract_model_from_path() {
+        let re = regex::Regex::new(r"^/models/([^/]+)/").unwrap();
+        assert_eq!(
+            extract_model_from_path("/models/gpt-4/completions", &re).as_deref(),
+            Some("gpt-4")
+        );
+        assert_eq!(
+            extract_model_from_path("/models/llama-3.1-8b/chat/completions", &re).as_deref(),
+            Some("llama-3.1-8b")
+        );
+        // Not matching: BBR reads the body instead
+        assert_eq!(extract_model_from_path("/v1/chat/completions", &re), None);
+        assert_eq!(extract_model_from_path("/models/", &re), None);
+
+        // An optional group that did not participate counts as no match
+        let optional = regex::Regex::new(r"^/v1(?:/m/([^/]*))?/completions").unwrap();
+        assert_eq!(extract_model_from_path("/v1/completions", &optional), None);
+        assert_eq!(
+            extract_model_from_path("/v1/m//completions", &optional),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_model_field_case_insensitive_option() {
+        let extract = |body: &str, ci| {
+            extract_model_from_body_with_options(body.as_bytes(), BbrArrayPolicy::First, ci)
+        };
+        for key in ["model", "Model", "MODEL"] {
+            let body = format!(r#"{{"{}": "gpt-4", "prompt": "test"}}"#, key);
+            assert_eq!(
+                extract(&body, true).as_deref(),
+                Some("gpt-4"),
+                "key {}",
+                key
+            );
+            let strict = extract(&body, false);
+            if key == "model" {
+                assert_eq!(strict.as_deref(), Some("gpt-4"));
+            } else {
+                assert_eq!(strict, None, "key {}", key);
+            }
+        }
+
+        // The exact key wins over other spellings, also inside array bodies
+        let body = r#"{"Model": "a", "model": "b"}"#;
+        assert_eq!(extract(body, true).as_deref(), Some("b"));
+        assert_eq!(extract(r#"[{"MODEL": "c"}]"#, true).as_deref(), Some("c"));
+        assert_eq!(extract(r#"{"models": "d"}"#, true), None);
+    }
+
+    #[test]
+    fn test_extract_model_field_fallback_order() {
+        let fields: Vec<String> = ["model", "request.model", "body.model"]
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        let extract = |body: &str| {
+            extract_model_from_body_with_fields(
+                body.as_bytes(),
+                BbrArrayPolicy::First,
+                false,
+                &fields,
+            )
+            .into_model()
+        };
+
+        assert_eq!(
+            extract(r#"{"model": "a", "request": {"model": "b"}}"#).as_deref(),
+            Some("a")
+        );
+        assert_eq!(
+            extract(r#"{"request": {"model": "b"}, "body": {"model": "c"}}"#).as_deref(),
+            Some("b")
+        );
+        // Empty, non-string and non-object values fall through to the next path
+        assert_eq!(
+            extract(r#"{"model": "", "request": {"model": 7}, "body": {"model": "c"}}"#).as_deref(),
+            Some("c")
+        );
+        assert_eq!(
+            extract(r#"{"request": "gpt-4", "body": {"model": "c"}}"#).as_deref(),
+            Some("c")
+        );
+        assert_eq!(extract(r#"{"request": {"name": "b"}}"#), None);
+        assert_eq!(
+            extract(r#"[{"body": {"model": "c"}}]"#).as_deref(),
+            Some("c")
+        );
+
+        // Only the configured paths are tried; an empty list means the default fields
+        let nested_only = vec!["request.model".to_string()];
+        assert_eq!(
+            extract_model_from_body_with_fields(
+                br#"{"model": "a"}"#,
+                BbrArrayPolicy::First,
+                false,
+                &nested_only
+            )
+            .into_model(),
+            None
+        );
+        assert_eq!(
+            extract_model_from_body_with_fields(
+                br#"{"model": "a"}"#,
+                BbrArrayPolicy::First,
+                false,
+                &[]
+            )
+            .into_model()
+            .as_deref(),
+            Some("a")
+        );
+        // Case-insensitive matching applies to every path segment
+        assert_eq!(
+            extract_model_from_body_with_fields(
+                br#"{"Request": {"MODEL": "b"}}"#,
+                BbrArrayPolicy::First,
+                true,
+                &nested_only
+            )
+            .into_model()
+            .as_deref(),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn test_string_at_path_walks_one_parsed_object() {
+        let object = JsonObject::parse(
+            r#"{"Model": "x", "model": "a", "request": {"Body": {"model": "b"}}, "n": 7}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            string_at_path(&object, "model", false).as_deref(),
+            Some("a")
+        );
+        assert_eq!(
+            string_at_path(&object, "request.Body.model", false).as_deref(),
+            Some("b")
+        );
+        assert_eq!(string_at_path(&object, "request.body.model", false), None);
+        assert_eq!(
+            string_at_path(&object, "REQUEST.body.MODEL", true).as_deref(),
+            Some("b")
+        );
+        // An exact key wins over other spellings
+        assert_eq!(string_at_path(&object, "model", true).as_deref(), Some("a"));
+        // Paths through non-objects, and non-string values, have no string
+        assert_eq!(string_at_path(&object, "model.name", false), None);
+        assert_eq!(string_at_path(&object, "n", false), None);
+        assert!(JsonObject::parse("[1, 2]").is_none());
+    }
+
+    #[test]
+    fn test_extract_model_default_field_priority() {
+        let extract = |body: &str| extract_model_from_body(body.as_bytes());
+
+        // Azure OpenAI names the deployment; older OpenAI APIs name the engine
+        assert_eq!(
+            extract(r#"{"deployment": "gpt-4o-prod", "messages": []}"#).as_deref(),
+            Some("gpt-4o-prod")
+        );
+        assert_eq!(
+            extract(r#"{"engine": "davinci", "prompt": "hi"}"#).as_deref(),
+            Some("davinci")
+        );
+
+        // `model` wins over `deployment` and `engine`, and `deployment` over `engine`
+        assert_eq!(
+            extract(r#"{"engine": "davinci", "model": "gpt-4"}"#).as_deref(),
+            Some("gpt-4")
+        );
+        assert_eq!(
+            extract(r#"{"engine": "davinci", "deployment": "gpt-4o-prod"}"#).as_deref(),
+            Some("gpt-4o-prod")
+        );
+        // Empty or non-string values fall through to the next field
+        assert_eq!(
+            extract(r#"{"model": "", "deployment": null, "engine": "davinci"}"#).as_deref(),
+            Some("davinci")
+        );
+
+        // A configured list replaces the defaults
+        assert_eq!(
+            extract_model_from_body_with_fields(
+                br#"{"engine": "davinci"}"#,
+                BbrArrayPolicy::First,
+                false,
+                &["model".to_string()]
+            )
+            .into_model(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_model_malformed_vs_missing() {
+        let extract = |body: &[u8]| {
+            extract_model_from_body_with_fields(body, BbrArrayPolicy::First, false, &[])
+        };
+
+        // Malformed JSON, non-UTF-8 and plain text are unparseable
+        assert_eq!(extract(br#"{"model": "a""#), BodyModel::Unparseable);
+        assert_eq!(extract(br#"[{"model": "a"},"#), BodyModel::Unparseable);
+        assert_eq!(extract(b"\xff\xfe{}"), BodyModel::Unparseable);
+        assert_eq!(extract(b"model=llama"), BodyModel::Unparseable);
+        assert_eq!(
+            extract(br#"{"prompt": "hi"} trailing"#),
+            BodyModel::Unparseable
+        );
+
+        // Valid JSON without a model, and the empty body, are missing a model
+        assert_eq!(extract(br#"{"prompt": "hi"}"#), BodyModel::Missing);
+        assert_eq!(extract(br#"{"model": 7}"#), BodyModel::Missing);
+        assert_eq!(extract(b"[]"), BodyModel::Missing);
+        assert_eq!(extract(b"42"), BodyModel::Missing);
+        assert_eq!(extract(b"[1, 2]"), BodyModel::Missing);
+        assert_eq!(extract(b""), BodyModel::Missing);
+        // A body with a model is still validated as a whole
+        assert_eq!(extract(br#"[{"model": "a"}] x"#), BodyModel::Unparseable);
+        assert_eq!(
+            extract(br#"{"model": "a", "x": [}"#),
+            BodyModel::Unparseable
+        );
+        assert_eq!(
+            extract_model_from_body_with_fields(
+                br#"[{"model": "a"}]"#,
+                BbrArrayPolicy::Reject,
+                false,
+                &[]
+            ),
+            BodyModel::Missing
+        );
+
+        assert_eq!(
+            extract(br#"{"model": "a"}"#),
+            BodyModel::Found("a".to_string())
+        );
+        assert_eq!(BodyModel::Unparseable.into_model(), None);
+    }
+
+    #[test]
+    fn test_extract_model_from_prefix() {
+        let extract =
+            |prefix: &[u8]| extract_model_from_prefix(prefix, BbrArrayPolicy::First, false, &[]);
+
+        // The model is found in a prefix that cuts the body off, and the body is left intact
+        let body = format!(
+            r#"{{"messages": [{{"role": "user", "content": "a \"quoted\" {{brace}}"}}], "model": "llama-3-8b", "prompt": "{}"}}"#,
+            "x".repeat(4096)
+        );
+        let original = body.clone();
+        assert_eq!(
+            extract(&body.as_bytes()[..96]),
+            BodyModel::Found("llama-3-8b".to_string())
+        );
+        assert_eq!(body, original);
+
+        // A lower-priority field is used when the model lies past the prefix
+        let body = br#"{"engine": "davinci", "prompt": "long", "model": "gpt-4"}"#;
+        assert_eq!(
+            extract(&body[..40]),
+            BodyModel::Found("davinci".to_string())
+        );
+        assert_eq!(extract(body), BodyModel::Found("gpt-4".to_string()));
+
+        // Cut off before any model field, or mid-value, there is no model
+        assert_eq!(extract(br#"{"prompt": "hello"#), BodyModel::Missing);
+        assert_eq!(extract(br#"{"model": "llam"#), BodyModel::Missing);
+        assert_eq!(extract(br#"{"model": 7, "n": 1"#), BodyModel::Missing);
+        assert_eq!(extract(b""), BodyModel::Missing);
+
+        // Syntax errors inside the prefix are unparseable
+        assert_eq!(extract(b"model=llama"), BodyModel::Unparseable);
+        assert_eq!(extract(br#"{"prompt" "x"}"#), BodyModel::Unparseable);
+        assert_eq!(extract(br#"{"a": 1 "model": "x"}"#), BodyModel::Unparseable);
+
+        // Escaped strings, a BOM, nested field paths and case-insensitive keys
+        assert_eq!(
+            extract(b"\xEF\xBB\xBF{\"model\": \"a\\u002db\"}"),
+            BodyModel::Found("a-b".to_string())
+        );
+        let fields = ["request.model".to_string()];
+        assert_eq!(
+            extract_model_from_prefix(
+                br#"{"model": "top", "request": {"x": [1, {"y": 2}], "model": "nested"}, "#,
+                BbrArrayPolicy::First,
+                false,
+                &fields
+            ),
+            BodyModel::Found("nested".to_string())
+        );
+        assert_eq!(
+            extract_model_from_prefix(
+                br#"{"Model": "loose", "model": "exact"}"#,
+                BbrArrayPolicy::First,
+                true,
+                &[]
+            ),
+            BodyModel::Found("exact".to_string())
+        );
+
+        // Root arrays are only searched for the first element
+        assert_eq!(
+            extract(br#"[{"model": "a"}, {"model": "#),
+            BodyModel::Found("a".to_string())
+        );
+        assert_eq!(
+            extract_model_from_prefix(br#"[{"model": "a"}]"#, BbrArrayPolicy::Last, false, &[]),
+            BodyModel::Missing
+        );
+    }
+
+    #[test]
+    fn test_extract_model_with_template() {
+        let template: ModelTemplate = "%provider%/%model%".parse().unwrap();
+        let extract = |body: &[u8]| {
+            extract_model_with_template(body, BbrArrayPolicy::First, false, &template)
+        };
+
+        // Composite key from several fields
+        assert_eq!(
+            extract(br#"{"model": "gpt-4", "provider": "openai"}"#),
+            BodyModel::Found("openai/gpt-4".to_string())
+        );
+        assert_eq!(
+            extract(br#"[{"provider": "azure", "model": "gpt-4o"}]"#),
+            BodyModel::Found("azure/gpt-4o".to_string())
+        );
+
+        // Missing, empty or non-string components substitute empty
+        assert_eq!(
+            extract(br#"{"model": "gpt-4"}"#),
+            BodyModel::Found("/gpt-4".to_string())
+        );
+        assert_eq!(
+            extract(br#"{"provider": "openai", "model": 4}"#),
+            BodyModel::Found("openai/".to_string())
+        );
+        assert_eq!(
+            extract(br#"{"provider": "", "model": "gpt-4"}"#),
+            BodyModel::Found("/gpt-4".to_string())
+        );
+
+        // None of the fields: no model; not JSON: unparseable
+        assert_eq!(extract(br#"{"prompt": "hi"}"#), BodyModel::Missing);
+        assert_eq!(extract(b"provider=openai"), BodyModel::Unparseable);
+        assert_eq!(
+            extract(br#"{"provider": "openai", "model": "gpt-4"} x"#),
+            BodyModel::Unparseable
+        );
+
+        // Nested paths and case-insensitive keys
+        let template: ModelTemplate = "%meta.tenant%:%model%".parse().unwrap();
+        assert_eq!(
+            extract_model_with_template(
+                br#"{"Model": "llama", "meta": {"tenant": "acme"}}"#,
+                BbrArrayPolicy::First,
+                true,
+                &template
+            ),
+            BodyModel::Found("acme:llama".to_string())
+        );
+    }
+
     #[test]
     fn test_extract_model_from_body_multiple_models() {
         let json_body = r#"{"model": "first", "prompt": "test", "fallback_model": "second"}"#;
@@ -172,4 +1338,189 @@ mod tests {
         let result = extract_model_from_body(json_body.as_bytes());
         assert_eq!(result, Some("gpt-4".to_string()));
     }
+
+    #[test]
+    fn test_extract_model_from_array_of_objects() {
+        let json_body = br#"[{"model": "a", "prompt": "x"}, {"model": "b", "prompt": "y"}]"#;
+        assert_eq!(
+            extract_model_from_body_with_policy(json_body, BbrArrayPolicy::First),
+            Some("a".to_string())
+        );
+        assert_eq!(
+            extract_model_from_body_with_policy(json_body, BbrArrayPolicy::Last),
+            Some("b".to_string())
+        );
+        assert_eq!(
+            extract_model_from_body_with_policy(json_body, BbrArrayPolicy::Reject),
+            None
+        );
+        // Default policy is "first"
+        assert_eq!(extract_model_from_body(json_body), Some("a".to_string()));
+    }
+
+    #[test]
+    fn test_extract_model_from_empty_array() {
+        for policy in [
+            BbrArrayPolicy::First,
+            BbrArrayPolicy::Last,
+            BbrArrayPolicy::Reject,
+        ] {
+            assert_eq!(extract_model_from_body_with_policy(b"[]", policy), None);
+        }
+    }
+
+    #[test]
+    fn test_extract_model_from_array_of_non_objects() {
+        let json_body = br#"["gpt-4", 1, null]"#;
+        assert_eq!(
+            extract_model_from_body_with_policy(json_body, BbrArrayPolicy::First),
+            None
+        );
+        assert_eq!(
+            extract_model_from_body_with_policy(json_body, BbrArrayPolicy::Last),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_model_from_array_selected_element_without_model() {
+        let json_body = br#"[{"prompt": "x"}, {"model": "b"}]"#;
+        // Only the selected element is consulted
+        assert_eq!(
+            extract_model_from_body_with_policy(json_body, BbrArrayPolicy::First),
+            None
+        );
+        assert_eq!(
+            extract_model_from_body_with_policy(json_body, BbrArrayPolicy::Last),
+            Some("b".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_model_from_pathological_nesting() {
+        // Deep nesting must not overflow the stack, inside or outside the model object
+        let deep_array = "[".repeat(100_000);
+        assert_eq!(extract_model_from_body(deep_array.as_bytes()), None);
+
+        let deep_field = format!(r#"{{"model": "gpt-4", "x": {}}}"#, "[".repeat(100_000));
+        assert_eq!(extract_model_from_body(deep_field.as_bytes()), None);
+
+        let nested_ok = format!(
+            r#"{{"x": {}1{}, "model": "gpt-4"}}"#,
+            "[".repeat(64),
+            "]".repeat(64)
+        );
+        assert_eq!(
+            extract_model_from_body(nested_ok.as_bytes()),
+            Some("gpt-4".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_model_from_body_trailing_garbage_and_whitespace() {
+        assert_eq!(extract_model_from_body(br#"{"model": "a"} x"#), None);
+        assert_eq!(
+            extract_model_from_body(b"\n\t {\"model\": \"a\"}\n"),
+            Some("a".to_string())
+        );
+        assert_eq!(
+            extract_model_from_body(br#"{"model": "gpt\u002d4\n"}"#),
+            Some("gpt-4\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_model_from_body_with_bom() {
+        assert_eq!(
+            extract_model_from_body(b"\xEF\xBB\xBF{\"model\": \"llama-3-8b\"}"),
+            Some("llama-3-8b".to_string())
+        );
+        assert_eq!(
+            extract_model_from_body(b"\xEF\xBB\xBF\r\n  [{\"model\": \"a\"}]"),
+            Some("a".to_string())
+        );
+        // Only a single leading BOM is stripped
+        assert_eq!(
+            extract_model_from_body(b"\xEF\xBB\xBF\xEF\xBB\xBF{\"model\": \"a\"}"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_model_from_body_leading_newlines() {
+        assert_eq!(
+            extract_model_from_body(b"\r\n\r\n\n{\"model\": \"gpt-4\"}"),
+            Some("gpt-4".to_string())
+        );
+        assert_eq!(
+            extract_model_from_body(b"\n\n  [{\"model\": \"a\"}, {\"model\": \"b\"}]"),
+            Some("a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sanitize_model_plain_unchanged() {
+        assert_eq!(
+            sanitize_model("meta-llama/Llama-3.1-8B-Instruct", false).as_deref(),
+            Some("meta-llama/Llama-3.1-8B-Instruct")
+        );
+    }
+
+    #[test]
+    fn test_sanitize_model_strips_crlf_injection() {
+        assert_eq!(
+            sanitize_model("gpt-4\r\nX-Inference-Upstream: evil:80", false).as_deref(),
+            Some("gpt-4X-Inference-Upstream: evil:80")
+        );
+        assert_eq!(
+            sanitize_model("llama\u{0}\t\u{7f}", false).as_deref(),
+            Some("llama")
+        );
+        assert_eq!(sanitize_model("\r\n", false), None);
+    }
+
+    #[test]
+    fn test_extract_model_from_stream() {
+        let sse = b"event: message\n: keep-alive\n\ndata: {\"id\":\"c1\",\"model\":\"llama-3-8b\",\"choices\":[]}\n\ndata: [DONE]\n\n";
+        assert_eq!(
+            extract_model_from_stream(sse).as_deref(),
+            Some("llama-3-8b")
+        );
+
+        // Newline-delimited JSON, and the first event carrying a model wins
+        let ndjson = b"{\"done\":false}\n{\"model\":\"qwen\"}\n{\"model\":\"other\"}\n";
+        assert_eq!(extract_model_from_stream(ndjson).as_deref(), Some("qwen"));
+
+        // An incomplete last line is not parsed until its newline arrives
+        assert_eq!(
+            extract_model_from_stream(b"data: {\"model\":\"llama\"}"),
+            None
+        );
+        assert_eq!(extract_model_from_stream(b"data: [DONE]\n"), None);
+    }
+
+    #[test]
+    fn test_sanitize_model_url_decode() {
+        assert_eq!(
+            sanitize_model("meta-llama%2FLlama-3.1-8B", true).as_deref(),
+            Some("meta-llama/Llama-3.1-8B")
+        );
+        // Without decoding the escapes are kept literally
+        assert_eq!(
+            sanitize_model("meta-llama%2FLlama-3.1-8B", false).as_deref(),
+            Some("meta-llama%2FLlama-3.1-8B")
+        );
+        // Malformed escapes are left as-is
+        assert_eq!(sanitize_model("a%zzb%4", true).as_deref(), Some("a%zzb%4"));
+        assert_eq!(sanitize_model("a%+1", true).as_deref(), Some("a%+1"));
+    }
+
+    #[test]
+    fn test_sanitize_model_strips_encoded_crlf() {
+        assert_eq!(
+            sanitize_model("gpt-4%0D%0AX-Evil:%201", true).as_deref(),
+            Some("gpt-4X-Evil: 1")
+        );
+        assert_eq!(sanitize_model("%0d%0a", true), None);
+    }
 }
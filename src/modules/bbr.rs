@@ -1,8 +1,23 @@
-use crate::model_extractor::extract_model_from_body;
-use crate::modules::config::ModuleConfig;
+use crate::epp::callbacks::set_upstream_header;
+use crate::epp::context::current_time_ms;
+use crate::logging::{
+    ngx_log_debug_http, ngx_log_error_http, ngx_log_info_http, ngx_log_warn_http,
+};
+use crate::model_extractor::{
+    extract_model_from_body_with_fields, extract_model_from_path, extract_model_from_prefix,
+    extract_model_from_protobuf, extract_model_with_template, proto_framing, sanitize_model,
+    BodyModel,
+};
+use crate::modules::config::{BbrEmptyBody, BbrOnParseError, BodyMemoryAction, ModuleConfig};
+use crate::modules::ctx::{
+    begin_body_read, cached_body, deadline_exceeded, invalidate_headers, mark_processed,
+    mark_upstream_written, request_body_len, request_body_presence, request_ctx, request_deadline,
+    request_headers, BodyPresence, BodyRead,
+};
+use crate::modules::metrics::{self, Counter};
 use crate::Module;
 use ngx::http::HttpModuleLocationConf;
-use ngx::{core, http, ngx_log_debug_http};
+use ngx::{core, http};
 use std::ffi::{c_char, c_void};
 
 // BBR Configuration Constants
@@ -13,27 +28,6 @@ const FILE_READ_CHUNK_SIZE: usize = 64 * 1024; // 64 KB
 /// Invalid file descriptor constant
 const INVALID_FD: i32 = -1;
 
-// Helper macro for info-level logging in BBR
-macro_rules! ngx_log_info_http {
-    ($request:expr, $($arg:tt)*) => {{
-        #[allow(unused_unsafe)]
-        unsafe {
-            let r = $request.as_mut();
-            if let Some(conn) = r.connection.as_ref() {
-                let msg = format!($($arg)*);
-                if let Ok(c_msg) = std::ffi::CString::new(msg) {
-                    ngx::ffi::ngx_log_error_core(
-                        ngx::ffi::NGX_LOG_INFO as ngx::ffi::ngx_uint_t,
-                        conn.log,
-                        0,
-                        c_msg.as_ptr(),
-                    );
-                }
-            }
-        }
-    }};
-}
-
 // Platform-agnostic string pointer casting for nginx FFI
 // c_char can be either i8 or u8 depending on platform
 #[inline]
@@ -58,6 +52,13 @@ pub fn get_header_in<'a>(request: &'a http::Request, key: &str) -> Option<&'a st
     None
 }
 
+/// The BBR model header of `request`, from the headers read once per request
+pub fn model_header(request: &http::Request, conf: &ModuleConfig) -> Option<String> {
+    let r = crate::logging::request_ptr(request) as *mut ngx::ffi::ngx_http_request_t;
+    // SAFETY: `request` is the live request, in the worker thread
+    unsafe { request_headers(r, conf) }.model.clone()
+}
+
 /// BBR (Body-Based Routing) processor
 /// Extracts model information from JSON request bodies and sets appropriate headers
 pub struct BbrProcessor;
@@ -65,7 +66,7 @@ pub struct BbrProcessor;
 impl BbrProcessor {
     /// Process BBR for a request if enabled
     pub fn process_request(request: &mut http::Request, conf: &ModuleConfig) -> core::Status {
-        if !conf.bbr_enable {
+        if !conf.bbr_enable.unwrap_or_default() {
             return core::Status::NGX_DECLINED;
         }
 
@@ -75,8 +76,11 @@ impl BbrProcessor {
             conf.bbr_header_name.clone()
         };
 
-        // If header already present, skip BBR
-        if get_header_in(request, &header_name).is_some() {
+        // If header already present, skip BBR (with inference_bbr_overwrite, only once BBR
+        // has set it itself)
+        let bbr_done = unsafe { request_ctx(request.as_mut()) }.is_some_and(|ctx| ctx.bbr_done());
+        let present = model_header(request, conf).is_some();
+        if skip_for_existing_model(conf.bbr_overwrite.unwrap_or_default(), present, bbr_done) {
             ngx_log_debug_http!(
                 request,
                 "ngx-inference: BBR header {} already present, skipping",
@@ -85,6 +89,24 @@ impl BbrProcessor {
             return core::Status::NGX_DECLINED;
         }
 
+        // inference_bbr_model_path_regex: a model in the URI path avoids reading the body
+        let path_model = conf.bbr_model_path_regex.as_ref().and_then(|re| {
+            extract_model_from_path(&String::from_utf8_lossy(request.path().as_bytes()), &re.0)
+        });
+        if let Some(model) = path_model.and_then(|raw| normalize_model(request, conf, raw)) {
+            if set_model_header(request, conf, &header_name, &model) {
+                if let Some(ctx) = unsafe { request_ctx(request.as_mut()) } {
+                    ctx.mark_bbr_done();
+                }
+                ngx_log_info_http!(
+                    request,
+                    "ngx-inference: BBR extracted model '{}' from request path",
+                    model
+                );
+                return core::Status::NGX_DECLINED;
+            }
+        }
+
         // Log BBR processing start at debug level to avoid noise from duplicate phase calls
         ngx_log_debug_http!(
             request,
@@ -93,10 +115,34 @@ impl BbrProcessor {
         );
 
         // Start body reading for BBR processing
-        Self::start_body_reading(request, conf)
+        Self::start_body_reading(request, conf, &header_name)
     }
 
-    fn start_body_reading(request: &mut http::Request, _conf: &ModuleConfig) -> core::Status {
+    fn start_body_reading(
+        request: &mut http::Request,
+        conf: &ModuleConfig,
+        header_name: &str,
+    ) -> core::Status {
+        // Another stage already started the read; a second call would replace its handler
+        let action = unsafe { begin_body_read(request.as_mut()) };
+        if action == BodyRead::Reuse {
+            // EPP ran first (inference_pipeline_order epp-bbr) and has read the whole body
+            ngx_log_debug_http!(request, "ngx-inference: BBR reusing body read by EPP");
+            let r: *mut ngx::ffi::ngx_http_request_t = request.as_mut();
+            return match unsafe { process_body(r, request, conf, header_name) } {
+                Ok(_) => core::Status::NGX_DECLINED,
+                Err(status) => core::Status(status),
+            };
+        }
+        if action != BodyRead::Initiate {
+            ngx_log_debug_http!(
+                request,
+                "ngx-inference: BBR body read already started ({:?}), not reading again",
+                action
+            );
+            return core::Status::NGX_DECLINED;
+        }
+
         ngx_log_debug_http!(request, "ngx-inference: BBR starting body reading");
 
         let rc = unsafe {
@@ -195,59 +241,285 @@ pub unsafe extern "C" fn bbr_body_read_handler(r: *mut ngx::ffi::ngx_http_reques
     };
 
     // If header already present, skip BBR - event loop will resume if needed
-    if get_header_in(request, &header_name).is_some() {
+    let bbr_done = unsafe { request_ctx(r) }.is_some_and(|ctx| ctx.bbr_done());
+    let present = model_header(request, conf).is_some();
+    if skip_for_existing_model(conf.bbr_overwrite.unwrap_or_default(), present, bbr_done) {
         return;
     }
 
     // Clear the request body post_handler to prevent re-execution
     unsafe { (*(*r).request_body).post_handler = None };
 
-    // Process the request body
-    let body = match unsafe { read_request_body(r, conf) } {
+    match unsafe { process_body(r, request, conf, &header_name) } {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(status) => {
+            // An error_page redirect for this status must not run BBR/EPP again
+            unsafe { mark_processed(r) };
+            unsafe {
+                ngx::ffi::ngx_http_special_response_handler(r, status);
+                ngx::ffi::ngx_http_finalize_request(r, status);
+            }
+            return;
+        }
+    }
+
+    // Body processing complete - resume phases from where we left off
+    // We must call ngx_http_core_run_phases to continue through content/proxy phase
+    unsafe {
+        if (*r).write_event_handler == Some(ngx::ffi::ngx_http_core_run_phases) {
+            ngx_log_debug_http!(
+                request,
+                "ngx-inference: BBR callback complete, resuming phases (async mode)"
+            );
+
+            // Resume phases - this will continue through content phase (proxy) and eventually log phase
+            ngx::ffi::ngx_http_core_run_phases(r);
+        } else {
+            ngx_log_info_http!(
+                request,
+                "ngx-inference: BBR callback complete (sync mode, no resume needed)"
+            );
+        }
+    }
+}
+
+/// Whether BBR leaves the request alone because the model header is already present. A header
+/// from the client or a gateway is kept unless `inference_bbr_overwrite` is on; one BBR has
+/// already processed is never replaced, e.g. when the access handler runs again.
+fn skip_for_existing_model(overwrite: bool, header_present: bool, bbr_done: bool) -> bool {
+    header_present && (!overwrite || bbr_done)
+}
+
+/// Set the model header, replacing an existing one with `inference_bbr_overwrite on`
+fn set_model_header(
+    request: &mut http::Request,
+    conf: &ModuleConfig,
+    header_name: &str,
+    model: &str,
+) -> bool {
+    if conf.bbr_overwrite.unwrap_or_default() {
+        unsafe { set_upstream_header(request.as_mut(), header_name, model) }
+    } else {
+        let added = request.add_header_in(header_name, model).is_some();
+        unsafe { invalidate_headers(request.as_mut()) };
+        added
+    }
+}
+
+/// Sanitize an extracted model and apply `inference_model_alias`; `None` if nothing is left.
+fn normalize_model(request: &http::Request, conf: &ModuleConfig, raw: String) -> Option<String> {
+    let sanitized = sanitize_model(&raw, conf.bbr_url_decode_model.unwrap_or_default());
+    if sanitized.as_deref() != Some(raw.as_str()) {
+        ngx_log_warn_http!(
+            request,
+            "ngx-inference: BBR sanitized model {:?} to {:?}",
+            raw,
+            sanitized
+        );
+    }
+    sanitized.map(|model| match conf.canonical_model(&model) {
+        canonical if canonical != model => {
+            ngx_log_debug_http!(
+                request,
+                "ngx-inference: BBR aliased model '{}' to '{}'",
+                model,
+                canonical
+            );
+            canonical.to_string()
+        }
+        _ => model,
+    })
+}
+
+/// Extract the model from the fully read request body and set the model header.
+///
+/// Shared by the body read handler and by BBR running after EPP has read the body
+/// (`inference_pipeline_order epp-bbr`). Returns `Ok(true)` once a routing header is set,
+/// `Ok(false)` if the body was skipped, or `Err(status)` if the request must end with `status`.
+///
+/// # Safety
+///
+/// `r` must be the valid request behind `request`, with its body completely read.
+#[allow(clippy::manual_c_str_literals)] // FFI code uses byte strings for cross-platform compatibility
+unsafe fn process_body(
+    r: *mut ngx::ffi::ngx_http_request_t,
+    request: &mut http::Request,
+    conf: &ModuleConfig,
+    header_name: &str,
+) -> Result<bool, ngx::ffi::ngx_int_t> {
+    // A slow body read may have used up the request deadline; fail-open lets EPP fall back
+    // to the default upstream, fail-closed ends the request here
+    let deadline = unsafe { request_deadline(r, conf.request_deadline_ms) };
+    if deadline_exceeded(deadline, current_time_ms()) {
+        ngx_log_error_http!(
+            request,
+            "ngx-inference: request deadline ({} ms) exceeded while reading body",
+            conf.request_deadline_ms
+        );
+        if !conf.epp_failure_mode_allow.unwrap_or_default() {
+            return Err(conf.epp_timeout_status as ngx::ffi::ngx_int_t);
+        }
+    }
+
+    if let Some(ctx) = unsafe { request_ctx(r) } {
+        ctx.mark_bbr_done();
+    }
+
+    // Process the request body (cached on the request context for reuse by EPP), charged to
+    // inference_total_body_memory before it is copied
+    let len = unsafe { request_body_len(r) }.min(body_copy_limit(conf));
+    let body = match unsafe {
+        cached_body(r, len, conf.total_body_memory, || {
+            read_request_body(r, conf)
+        })
+    } {
         Ok(body) => body,
         Err(_) => {
-            // Check if we already set a 413 status in read_request_body
-            if unsafe { (*r).headers_out.status }
-                == ngx::ffi::NGX_HTTP_REQUEST_ENTITY_TOO_LARGE as ngx::ffi::ngx_uint_t
-            {
-                // 413 error - send special response and finalize
-                unsafe {
-                    ngx::ffi::ngx_http_special_response_handler(
-                        r,
-                        ngx::ffi::NGX_HTTP_REQUEST_ENTITY_TOO_LARGE as ngx::ffi::ngx_int_t,
-                    );
-                    ngx::ffi::ngx_http_finalize_request(
-                        r,
-                        ngx::ffi::NGX_HTTP_REQUEST_ENTITY_TOO_LARGE as ngx::ffi::ngx_int_t,
-                    );
-                }
-            } else {
-                // Other error - send 500 error
-                unsafe {
-                    ngx::ffi::ngx_http_special_response_handler(
-                        r,
-                        ngx::ffi::NGX_HTTP_INTERNAL_SERVER_ERROR as ngx::ffi::ngx_int_t,
-                    );
-                    ngx::ffi::ngx_http_finalize_request(
-                        r,
-                        ngx::ffi::NGX_HTTP_INTERNAL_SERVER_ERROR as ngx::ffi::ngx_int_t,
+            // Check if we already set a 413 status in read_request_body; otherwise 500
+            let too_large = unsafe { (*r).headers_out.status }
+                == ngx::ffi::NGX_HTTP_REQUEST_ENTITY_TOO_LARGE as ngx::ffi::ngx_uint_t;
+            if !too_large {
+                return Err(ngx::ffi::NGX_HTTP_INTERNAL_SERVER_ERROR as ngx::ffi::ngx_int_t);
+            }
+            // inference_bbr_oversize_upstream: let the default upstream enforce its own limit
+            if let Some(upstream) = conf.oversize_upstream() {
+                if request
+                    .add_header_in(&conf.epp_header_name, upstream)
+                    .is_some()
+                {
+                    unsafe { mark_upstream_written(r) };
+                    unsafe { invalidate_headers(r) };
+                    unsafe { (*r).headers_out.status = 0 };
+                    ngx_log_warn_http!(
+                        request,
+                        "ngx-inference: BBR body exceeds {} bytes, routing to default upstream '{}' without a model",
+                        conf.max_body_size,
+                        upstream
                     );
+                    return Ok(true);
                 }
             }
-            return;
+            metrics::increment(Counter::BbrRejected);
+            return Err(ngx::ffi::NGX_HTTP_REQUEST_ENTITY_TOO_LARGE as ngx::ffi::ngx_int_t);
         }
     };
 
+    // inference_total_body_memory: bodies held by in-flight requests share one budget; a body
+    // that does not fit is not copied, and EPP applies the same action without reading it
+    let Some(body) = body else {
+        return match conf.total_body_memory_action.unwrap_or_default() {
+            BodyMemoryAction::Reject => {
+                ngx_log_warn_http!(
+                    request,
+                    "ngx-inference: BBR rejecting {} byte body, inference_total_body_memory ({} bytes) is in use",
+                    len,
+                    conf.total_body_memory
+                );
+                Err(ngx::ffi::NGX_HTTP_SERVICE_UNAVAILABLE as ngx::ffi::ngx_int_t)
+            }
+            BodyMemoryAction::Passthrough => {
+                ngx_log_warn_http!(
+                    request,
+                    "ngx-inference: BBR skipping {} byte body, inference_total_body_memory ({} bytes) is in use",
+                    len,
+                    conf.total_body_memory
+                );
+                Ok(false)
+            }
+        };
+    };
+
+    // Record the processed body size for $inference_bbr_body_size
+    if let Some(ctx) = unsafe { request_ctx(r) } {
+        ctx.set_bbr_body_size(body.len());
+    }
+
     // Extract model directly from JSON body
     if body.is_empty() {
-        // Empty body - skip model extraction, event loop will resume if needed
-        return;
+        match conf.bbr_empty_body.unwrap_or_default() {
+            BbrEmptyBody::Skip => {
+                // Empty body - skip model extraction, event loop will resume if needed
+                return Ok(false);
+            }
+            BbrEmptyBody::Reject => {
+                ngx_log_info_http!(
+                    request,
+                    "ngx-inference: BBR rejecting request with empty body (inference_bbr_empty_body reject)"
+                );
+                metrics::increment(Counter::BbrRejected);
+                return Err(ngx::ffi::NGX_HTTP_BAD_REQUEST as ngx::ffi::ngx_int_t);
+            }
+            BbrEmptyBody::DefaultModel => {
+                // No model can be extracted; falls through to the default model below
+            }
+        }
     }
 
-    // Extract model name from JSON body and add header
-    if let Some(model_name) = extract_model_from_body(&body) {
+    // Extract model name from the JSON body (or protobuf body, with inference_bbr_proto_field),
+    // make it safe for a header value, map it to its canonical name (inference_model_alias),
+    // and add the header
+    let framing = if conf.bbr_proto_field != 0 {
+        // SAFETY: nginx sets `headers_in.content_type` to the header entry or null
+        unsafe { request.as_ref().headers_in.content_type.as_ref() }
+            .and_then(|h| h.value.to_str().ok())
+            .and_then(proto_framing)
+    } else {
+        None
+    };
+    let policy = conf.bbr_array_policy.unwrap_or_default();
+    let case_insensitive = conf.bbr_field_case_insensitive.unwrap_or_default();
+    let prefix = conf.bbr_parse_prefix_bytes;
+    let extracted = match (framing, &conf.bbr_model_template) {
+        (Some(framing), _) => extract_model_from_protobuf(&body, conf.bbr_proto_field, framing)
+            .map_or(BodyModel::Missing, BodyModel::Found),
+        // inference_bbr_model_template: a composite key from several fields of the whole body
+        (None, Some(template)) => {
+            extract_model_with_template(&body, policy, case_insensitive, template)
+        }
+        // inference_bbr_parse_prefix_bytes: a body that fills the prefix is scanned only up to it
+        (None, None) if prefix > 0 && body.len() >= prefix => extract_model_from_prefix(
+            &body[..prefix],
+            policy,
+            case_insensitive,
+            &conf.bbr_model_fields,
+        ),
+        (None, None) => extract_model_from_body_with_fields(
+            &body,
+            policy,
+            case_insensitive,
+            &conf.bbr_model_fields,
+        ),
+    };
+    if extracted == BodyModel::Unparseable {
+        match conf.bbr_on_parse_error.unwrap_or_default() {
+            BbrOnParseError::DefaultModel => {
+                // Handled like JSON without a model below
+            }
+            BbrOnParseError::Skip => {
+                ngx_log_info_http!(
+                    request,
+                    "ngx-inference: BBR body is not valid JSON, continuing without a model header (inference_bbr_on_parse_error skip)"
+                );
+                return Ok(false);
+            }
+            BbrOnParseError::Reject => {
+                ngx_log_info_http!(
+                    request,
+                    "ngx-inference: BBR rejecting request whose body is not valid JSON (inference_bbr_on_parse_error reject)"
+                );
+                metrics::increment(Counter::BbrRejected);
+                return Err(ngx::ffi::NGX_HTTP_BAD_REQUEST as ngx::ffi::ngx_int_t);
+            }
+        }
+    }
+    let model = extracted
+        .into_model()
+        .and_then(|raw| normalize_model(request, conf, raw));
+    if let Some(model_name) = model {
         // Add the model header to the request
-        if request.add_header_in(&header_name, &model_name).is_some() {
+        if set_model_header(request, conf, header_name, &model_name) {
+            metrics::increment(Counter::BbrModel);
             // Log successful model extraction at INFO level
             ngx_log_info_http!(
                 request,
@@ -274,7 +546,8 @@ pub unsafe extern "C" fn bbr_body_read_handler(r: *mut ngx::ffi::ngx_http_reques
     } else {
         // No model found - use configured default to prevent reprocessing
         let default_model = &conf.bbr_default_model;
-        let _ = request.add_header_in(&header_name, default_model);
+        let _ = set_model_header(request, conf, header_name, default_model);
+        metrics::increment(Counter::BbrDefault);
 
         // Log default model usage at INFO level
         ngx_log_info_http!(
@@ -284,24 +557,7 @@ pub unsafe extern "C" fn bbr_body_read_handler(r: *mut ngx::ffi::ngx_http_reques
         );
     }
 
-    // Body processing complete - resume phases from where we left off
-    // We must call ngx_http_core_run_phases to continue through content/proxy phase
-    unsafe {
-        if (*r).write_event_handler == Some(ngx::ffi::ngx_http_core_run_phases) {
-            ngx_log_debug_http!(
-                request,
-                "ngx-inference: BBR callback complete, resuming phases (async mode)"
-            );
-
-            // Resume phases - this will continue through content phase (proxy) and eventually log phase
-            ngx::ffi::ngx_http_core_run_phases(r);
-        } else {
-            ngx_log_info_http!(
-                request,
-                "ngx-inference: BBR callback complete (sync mode, no resume needed)"
-            );
-        }
-    }
+    Ok(true)
 }
 
 /// Helper function to set 413 Request Entity Too Large error
@@ -345,30 +601,45 @@ unsafe fn set_413_error(r: *mut ngx::ffi::ngx_http_request_t, actual_size: usize
 /// - `conf` must contain valid configuration values
 /// - Caller must ensure no concurrent access to the request body buffers
 /// - File descriptors in nginx file structures must be valid if not INVALID_FD
+/// Bytes of the body BBR copies: with inference_bbr_parse_prefix_bytes only the prefix, unless
+/// EPP or inference_bbr_model_template needs the whole body
+fn body_copy_limit(conf: &ModuleConfig) -> usize {
+    match conf.bbr_parse_prefix_bytes {
+        0 => usize::MAX,
+        _ if conf.epp_enable.unwrap_or_default() || conf.bbr_model_template.is_some() => usize::MAX,
+        prefix => prefix,
+    }
+}
+
 unsafe fn read_request_body(
     r: *mut ngx::ffi::ngx_http_request_t,
     conf: &ModuleConfig,
 ) -> Result<Vec<u8>, ()> {
-    let request_body = unsafe { (*r).request_body };
-    if request_body.is_null() {
-        return Ok(Vec::new());
+    match unsafe { request_body_presence(r) } {
+        BodyPresence::Missing => {
+            let request: &mut http::Request =
+                unsafe { ngx::http::Request::from_ngx_http_request(r) };
+            ngx_log_error_http!(
+                request,
+                "ngx-inference: BBR request body missing after read"
+            );
+            return Err(());
+        }
+        BodyPresence::Empty => return Ok(Vec::new()),
+        BodyPresence::Buffered => {}
     }
 
-    let bufs = unsafe { (*request_body).bufs };
-    if bufs.is_null() {
-        return Ok(Vec::new());
-    }
+    let bufs = unsafe { (*(*r).request_body).bufs };
 
     // Get content length for pre-allocation hint (but don't trust it for validation)
-    let content_length = {
-        let request: &mut http::Request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
-        get_header_in(request, "Content-Length")
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(0)
-    };
+    // nginx has parsed it already: -1 when absent or invalid
+    let content_length = usize::try_from(unsafe { (*r).headers_in.content_length_n }).unwrap_or(0);
+
+    // The rest of the body is still counted against inference_max_body_size
+    let copy_limit = body_copy_limit(conf);
 
     // Cap memory allocation to reasonable size to prevent excessive memory usage
-    let safe_capacity = std::cmp::min(content_length, MAX_BODY_PREALLOC);
+    let safe_capacity = content_length.min(MAX_BODY_PREALLOC).min(copy_limit);
     let mut body: Vec<u8> = Vec::with_capacity(safe_capacity);
     let mut total_read = 0usize;
 
@@ -436,7 +707,8 @@ unsafe fn read_request_body(
                 }
 
                 let slice = unsafe { std::slice::from_raw_parts(pos as *const u8, len_usize) };
-                body.extend_from_slice(slice);
+                let copied = len_usize.min(copy_limit.saturating_sub(body.len()));
+                body.extend_from_slice(&slice[..copied]);
                 total_read += len_usize;
             }
         }
@@ -466,16 +738,19 @@ unsafe fn read_request_body(
                     return Err(());
                 }
 
+                // Past inference_bbr_parse_prefix_bytes the file is only counted, not read
+                let wanted = file_size.min(copy_limit.saturating_sub(body.len()));
+                total_read += file_size - wanted;
+
                 // Read from file descriptor
                 let fd = unsafe { (*file).fd };
                 if fd != INVALID_FD {
                     // Create buffer for file content
-                    let mut file_buffer = vec![0u8; file_size];
+                    let mut file_buffer = vec![0u8; wanted];
                     let mut bytes_read = 0usize;
                     // Read file content in chunks
-                    while bytes_read < file_size {
-                        let chunk_size =
-                            std::cmp::min(FILE_READ_CHUNK_SIZE, file_size - bytes_read);
+                    while bytes_read < wanted {
+                        let chunk_size = std::cmp::min(FILE_READ_CHUNK_SIZE, wanted - bytes_read);
                         // Use saturating_add to prevent integer overflow on large file positions
                         let offset = file_pos.saturating_add(bytes_read as i64);
                         let result = unsafe {
@@ -536,3 +811,20 @@ unsafe fn read_request_body(
 
     Ok(body)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_existing_model_header_skip_vs_overwrite() {
+        // Default: a model header already in the request is kept
+        assert!(skip_for_existing_model(false, true, false));
+        // inference_bbr_overwrite on: BBR runs and replaces it, but only once
+        assert!(!skip_for_existing_model(true, true, false));
+        assert!(skip_for_existing_model(true, true, true));
+        // Without a header BBR always runs
+        assert!(!skip_for_existing_model(false, false, false));
+        assert!(!skip_for_existing_model(true, false, false));
+    }
+}
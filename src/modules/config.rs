@@ -1,65 +1,899 @@
 use ngx::http::MergeConfigError;
 
+/// What to do when the EPP stream ends without returning the upstream header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EppOnNoHeader {
+    /// Continue without setting the upstream header
+    Continue,
+    /// Use `inference_default_upstream`, then continue
+    Default,
+    /// Treat as an EPP failure (subject to `inference_epp_failure_mode_allow`)
+    #[default]
+    Error,
+}
+
+impl std::str::FromStr for EppOnNoHeader {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("continue") {
+            Ok(EppOnNoHeader::Continue)
+        } else if val.eq_ignore_ascii_case("default") {
+            Ok(EppOnNoHeader::Default)
+        } else if val.eq_ignore_ascii_case("error") {
+            Ok(EppOnNoHeader::Error)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// Which EPP response mutations are trusted for the upstream header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EppHeaderSources {
+    /// Only mutations of the request headers (RequestHeaders, RequestBody and
+    /// RequestTrailers responses)
+    #[default]
+    RequestHeaders,
+    /// Any response variant, including response-side mutations and ImmediateResponse
+    Any,
+}
+
+impl std::str::FromStr for EppHeaderSources {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("request-headers") {
+            Ok(EppHeaderSources::RequestHeaders)
+        } else if val.eq_ignore_ascii_case("any") {
+            Ok(EppHeaderSources::Any)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// Compression used on the EPP gRPC stream
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EppGrpcCompression {
+    /// Send uncompressed messages
+    #[default]
+    None,
+    /// Send gzip-compressed messages and accept gzip-compressed responses
+    Gzip,
+}
+
+impl std::str::FromStr for EppGrpcCompression {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("none") {
+            Ok(EppGrpcCompression::None)
+        } else if val.eq_ignore_ascii_case("gzip") {
+            Ok(EppGrpcCompression::Gzip)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// Oldest TLS version accepted from the EPP (`inference_epp_tls_min_version`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EppTlsMinVersion {
+    /// TLS 1.2 and 1.3
+    Tls12,
+    /// TLS 1.3 only
+    Tls13,
+}
+
+impl std::str::FromStr for EppTlsMinVersion {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "1.2" => Ok(EppTlsMinVersion::Tls12),
+            "1.3" => Ok(EppTlsMinVersion::Tls13),
+            _ => Err(ParseError),
+        }
+    }
+}
+
+/// Body fingerprint sent to EPP (`inference_epp_body_hash`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EppBodyHash {
+    /// No fingerprint
+    #[default]
+    None,
+    /// Hex SHA-256 of the request body
+    Sha256,
+}
+
+impl std::str::FromStr for EppBodyHash {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("none") {
+            Ok(EppBodyHash::None)
+        } else if val.eq_ignore_ascii_case("sha256") {
+            Ok(EppBodyHash::Sha256)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// How the request body is sent to EPP (`inference_epp_body_send_mode`), as in the ext_proc
+/// `ProcessingMode.request_body_mode`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EppBodySendMode {
+    /// Headers only; the body is sent only if the EPP asks for it with a `mode_override`
+    #[default]
+    None,
+    /// The whole body in one message
+    Buffered,
+    /// The body in chunks, the last one marked end of stream
+    Streamed,
+    /// The body in one message; the module reads the body whole, so this is never partial
+    BufferedPartial,
+}
+
+impl std::str::FromStr for EppBodySendMode {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val.to_ascii_lowercase().as_str() {
+            "none" => Ok(EppBodySendMode::None),
+            "buffered" => Ok(EppBodySendMode::Buffered),
+            "streamed" => Ok(EppBodySendMode::Streamed),
+            "buffered_partial" => Ok(EppBodySendMode::BufferedPartial),
+            _ => Err(ParseError),
+        }
+    }
+}
+
+/// What BBR does when the request body is empty
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BbrEmptyBody {
+    /// Set the model header to `inference_bbr_default_model`
+    DefaultModel,
+    /// Continue without setting the model header
+    #[default]
+    Skip,
+    /// Reject the request with 400 Bad Request
+    Reject,
+}
+
+impl std::str::FromStr for BbrEmptyBody {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("default") {
+            Ok(BbrEmptyBody::DefaultModel)
+        } else if val.eq_ignore_ascii_case("skip") {
+            Ok(BbrEmptyBody::Skip)
+        } else if val.eq_ignore_ascii_case("reject") {
+            Ok(BbrEmptyBody::Reject)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// What BBR does when the request body is not valid JSON
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BbrOnParseError {
+    /// Set the model header to `inference_bbr_default_model`, as for JSON without a model
+    #[default]
+    DefaultModel,
+    /// Continue without setting the model header
+    Skip,
+    /// Reject the request with 400 Bad Request
+    Reject,
+}
+
+impl std::str::FromStr for BbrOnParseError {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("default") {
+            Ok(BbrOnParseError::DefaultModel)
+        } else if val.eq_ignore_ascii_case("skip") {
+            Ok(BbrOnParseError::Skip)
+        } else if val.eq_ignore_ascii_case("reject") {
+            Ok(BbrOnParseError::Reject)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// What happens to a request whose body does not fit in `inference_total_body_memory`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BodyMemoryAction {
+    /// Reject the request with 503 Service Unavailable
+    #[default]
+    Reject,
+    /// Continue without BBR/EPP processing
+    Passthrough,
+}
+
+impl std::str::FromStr for BodyMemoryAction {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("reject") {
+            Ok(BodyMemoryAction::Reject)
+        } else if val.eq_ignore_ascii_case("passthrough") {
+            Ok(BodyMemoryAction::Passthrough)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// What the access handler does for a request whose location configuration is missing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnMissingConfig {
+    /// Fail the request with 500 Internal Server Error
+    Fail,
+    /// Skip the module and let the request continue
+    #[default]
+    Passthrough,
+}
+
+impl OnMissingConfig {
+    /// Policy for a request without location configuration, read from its server
+    /// configuration when that is available
+    pub fn for_server(server: Option<&ModuleConfig>) -> Self {
+        server
+            .and_then(|conf| conf.on_missing_config)
+            .unwrap_or_default()
+    }
+}
+
+impl std::str::FromStr for OnMissingConfig {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("fail") {
+            Ok(OnMissingConfig::Fail)
+        } else if val.eq_ignore_ascii_case("passthrough") {
+            Ok(OnMissingConfig::Passthrough)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// Level at which the final routing decision is logged (`inference_log_decisions`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogDecisions {
+    Warn,
+    Info,
+    #[default]
+    Debug,
+    Off,
+}
+
+impl LogDecisions {
+    /// nginx log level for decision lines, or `None` when they are not logged
+    pub fn level(self) -> Option<u32> {
+        match self {
+            LogDecisions::Warn => Some(ngx::ffi::NGX_LOG_WARN),
+            LogDecisions::Info => Some(ngx::ffi::NGX_LOG_INFO),
+            LogDecisions::Debug => Some(ngx::ffi::NGX_LOG_DEBUG),
+            LogDecisions::Off => None,
+        }
+    }
+}
+
+impl std::str::FromStr for LogDecisions {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("warn") {
+            Ok(LogDecisions::Warn)
+        } else if val.eq_ignore_ascii_case("info") {
+            Ok(LogDecisions::Info)
+        } else if val.eq_ignore_ascii_case("debug") {
+            Ok(LogDecisions::Debug)
+        } else if val.eq_ignore_ascii_case("off") {
+            Ok(LogDecisions::Off)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// A stage of the access-phase pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Body-based routing, followed by the static model route table
+    Bbr,
+    /// Endpoint picker exchange
+    Epp,
+}
+
+/// Order in which the access handler runs BBR and EPP
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PipelineOrder {
+    /// BBR reads the body first, then EPP sees the model header
+    #[default]
+    BbrEpp,
+    /// EPP runs first; BBR reuses the body if EPP read it
+    EppBbr,
+}
+
+impl PipelineOrder {
+    /// The stages in the order they run
+    pub fn stages(self) -> [Stage; 2] {
+        match self {
+            PipelineOrder::BbrEpp => [Stage::Bbr, Stage::Epp],
+            PipelineOrder::EppBbr => [Stage::Epp, Stage::Bbr],
+        }
+    }
+}
+
+impl std::str::FromStr for PipelineOrder {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("bbr-epp") {
+            Ok(PipelineOrder::BbrEpp)
+        } else if val.eq_ignore_ascii_case("epp-bbr") {
+            Ok(PipelineOrder::EppBbr)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// gRPC service path for the EPP `Process` method (`inference_epp_service_path`), such as
+/// `/custom.v1.Processor`; must start with `/` and must not end with one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EppServicePath(pub String);
+
+impl std::str::FromStr for EppServicePath {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let valid_char = |c: char| c.is_ascii_graphic() && c != '?' && c != '#';
+        if val.len() > 1
+            && val.starts_with('/')
+            && !val.ends_with('/')
+            && val.chars().all(valid_char)
+        {
+            Ok(EppServicePath(val.to_string()))
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// Backend-bound header carrying the canonical model (`inference_upstream_model_header`); must be
+/// a valid header name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamModelHeader(pub String);
+
+impl std::str::FromStr for UpstreamModelHeader {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if is_header_name(val) {
+            Ok(UpstreamModelHeader(val.to_string()))
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// `inference_bbr_model_path_regex`: compiled pattern with at least one capture group, whose
+/// first group is the model
+#[derive(Clone, Debug)]
+pub struct ModelPathRegex(pub regex::Regex);
+
+impl std::str::FromStr for ModelPathRegex {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match regex::Regex::new(val) {
+            // captures_len counts the implicit whole-match group
+            Ok(re) if re.captures_len() > 1 => Ok(ModelPathRegex(re)),
+            _ => Err(ParseError),
+        }
+    }
+}
+
+/// One piece of an `inference_bbr_model_template`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplatePart {
+    /// Copied as is
+    Literal(String),
+    /// Replaced by the string at this dotted JSON field path, or nothing if it is missing
+    Field(String),
+}
+
+/// `inference_bbr_model_template`: literal text and `%path%` placeholders for JSON field paths,
+/// e.g. `%provider%/%model%`; `%%` is a literal `%`. At least one placeholder is required.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModelTemplate(pub Vec<TemplatePart>);
+
+impl std::str::FromStr for ModelTemplate {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = val;
+        while let Some(start) = rest.find('%') {
+            literal.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let end = after.find('%').ok_or(ParseError)?;
+            if end == 0 {
+                literal.push('%');
+            } else {
+                if !literal.is_empty() {
+                    parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(TemplatePart::Field(after[..end].to_string()));
+            }
+            rest = &after[end + 1..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        if !parts
+            .iter()
+            .any(|part| matches!(part, TemplatePart::Field(_)))
+        {
+            return Err(ParseError);
+        }
+        Ok(ModelTemplate(parts))
+    }
+}
+
+/// Which element's model BBR uses when the request body is a JSON array
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BbrArrayPolicy {
+    /// Use the first element's `model`
+    #[default]
+    First,
+    /// Use the last element's `model`
+    Last,
+    /// Ignore array bodies (the default model is used)
+    Reject,
+}
+
+impl std::str::FromStr for BbrArrayPolicy {
+    type Err = ParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val.eq_ignore_ascii_case("first") {
+            Ok(BbrArrayPolicy::First)
+        } else if val.eq_ignore_ascii_case("last") {
+            Ok(BbrArrayPolicy::Last)
+        } else if val.eq_ignore_ascii_case("reject") {
+            Ok(BbrArrayPolicy::Reject)
+        } else {
+            Err(ParseError)
+        }
+    }
+}
+
+/// Compiled `inference_epp_attribute`, `inference_epp_cache_key` or
+/// `inference_epp_request_id_header` value, allocated from the configuration pool.
+///
+/// The pointer stays valid for the lifetime of the configuration that holds it.
+#[derive(Clone, Copy)]
+pub struct EppAttributeValue(pub *const ngx::ffi::ngx_http_complex_value_t);
+
+/// `inference_epp_endpoint_map` table: the variable evaluated per request (lowercase, without
+/// `$`) and the EPP endpoint for each of its values
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EppEndpointMap {
+    pub variable: String,
+    pub entries: Vec<(String, String)>,
+}
+
+impl EppEndpointMap {
+    /// Endpoint mapped to `value`, if any
+    pub fn endpoint_for(&self, value: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(v, _)| v == value)
+            .map(|(_, endpoint)| endpoint.as_str())
+    }
+}
+
+/// `epp_timeout_ms` before `inference_epp_timeout_ms` is set, since 0 is a valid setting
+pub const EPP_TIMEOUT_UNSET: u64 = u64::MAX;
+
+/// `epp_cache_ttl_ms`/`epp_sticky_ttl_ms` before their directives are set, since 0 turns
+/// them off
+pub const EPP_TTL_UNSET: u64 = u64::MAX;
+
 /// Configuration structure for the ngx-inference module
 #[derive(Clone)]
 pub struct ModuleConfig {
     // Global settings
+    pub enable: Option<bool>, // master switch; `inference off` skips the access handler (default on)
+    pub process_subrequests: Option<bool>, // run BBR/EPP for subrequests such as auth_request (default off)
+    pub on_missing_config: Option<OnMissingConfig>, // no location conf at request time (default passthrough)
+    pub log_decisions: Option<LogDecisions>, // level of the routing decision log line (default debug)
+    pub debug_sample_rate: Option<f64>, // fraction of requests whose debug lines are always logged (default 0)
+    pub epp_log_response_sample: Option<f64>, // fraction of requests whose EPP responses are logged at info (default 0)
+    pub log_body_spill: Option<bool>, // info line when the body was read from a temp file (default off)
     pub default_upstream: Option<String>, // global default upstream for both BBR and EPP failures
+    pub upstream_unresolved_value: Option<String>, // $inference_upstream when nothing is resolved (None = not found)
+    pub force_upstream: Option<String>, // fixed upstream header for testing, skipping EPP and the route table
+    pub upstream_model_header: Option<UpstreamModelHeader>, // header set to the canonical model for the backend
     pub max_body_size: usize, // max body size for processing (applies to BBR and EPP, default 10MB)
+    pub pipeline_order: Option<PipelineOrder>, // order of the BBR and EPP stages (default bbr-epp)
+    pub total_body_memory: usize, // bytes of request bodies held at once per worker (0 = unlimited)
+    pub total_body_memory_action: Option<BodyMemoryAction>, // over the budget (default reject)
+    pub metrics_on_exit: Option<bool>, // log the worker's BBR/EPP counters when it exits (http only, default off)
+    pub access_handler: Option<bool>, // register the access phase handler running BBR/EPP (http only, default on)
 
     // BBR (Body-Based Routing) - implemented directly in module
-    pub bbr_enable: bool,
+    pub bbr_enable: Option<bool>,
     pub bbr_header_name: String,   // default "X-Gateway-Model-Name"
     pub bbr_default_model: String, // default model when none found in body
+    pub bbr_array_policy: Option<BbrArrayPolicy>, // model source for JSON array bodies (default first)
+    pub bbr_empty_body: Option<BbrEmptyBody>, // action for requests with an empty body (default skip)
+    pub bbr_on_parse_error: Option<BbrOnParseError>, // action for bodies that are not JSON (default default)
+    pub bbr_url_decode_model: Option<bool>, // percent-decode the extracted model before sanitizing (default off)
+    pub bbr_field_case_insensitive: Option<bool>, // match the JSON `model` key ignoring case (default off)
+    pub bbr_model_fields: Vec<String>, // JSON field paths tried in order for the model (empty = model, deployment, engine)
+    pub bbr_model_path_regex: Option<ModelPathRegex>, // capture 1 of the URI path is the model
+    pub bbr_model_template: Option<ModelTemplate>, // model header built from several JSON fields
+    pub bbr_oversize_upstream: Option<bool>, // route oversized bodies to inference_default_upstream instead of 413 (default off)
+    pub bbr_proto_field: u64, // protobuf field number holding the model for gRPC/protobuf bodies (0 = off)
+    pub bbr_parse_prefix_bytes: usize, // search only the first N body bytes for the model (0 = whole body)
+    pub bbr_overwrite: Option<bool>, // replace a model header already in the request instead of skipping BBR (default off)
+    pub bbr_set_response_header: Option<bool>, // also send the model header in the response (default off)
+    pub bbr_response: Option<bool>, // extract the model from the response body into $inference_response_model (default off)
+    pub bbr_response_streaming: Option<bool>, // also capture streamed (SSE/NDJSON) responses (default off)
 
     // EPP (Endpoint Picker Processor)
-    pub epp_enable: bool,
-    pub epp_endpoint: Option<String>, // host:port or https://host:port
-    pub epp_timeout_ms: u64,
-    pub epp_failure_mode_allow: bool, // fail-open
-    pub epp_header_name: String,      // default "X-Inference-Upstream"
-    pub epp_tls: bool,                // use TLS for connection
-    pub epp_ca_file: Option<String>,  // CA certificate file path for TLS verification
+    pub epp_enable: Option<bool>,
+    pub epp_endpoint: Option<String>, // host:port, https://host:port or $variable evaluated per request
+    pub epp_endpoint_map: Option<EppEndpointMap>, // per-request endpoint by variable value, ahead of epp_endpoint
+    pub epp_fallback_endpoint: Option<String>,    // tried once when the primary EPP fails
+    pub epp_timeout_ms: u64,                      // 0 = request deadline only (5s cap without one)
+    pub epp_max_timeout_ms: u64, // cap on EPP override_message_timeout extensions (0 = ignore them)
+    pub epp_timeout_jitter_pct: u64, // per-request ±% spread on epp_timeout_ms (0 = off)
+    pub epp_failure_mode_allow: Option<bool>, // fail-open
+    pub epp_header_name: String, // default "X-Inference-Upstream"
+    pub epp_tls: bool,           // use TLS for connection
+    pub epp_ca_file: Option<String>, // CA certificate file path for TLS verification
+    pub epp_grpc_compression: Option<EppGrpcCompression>, // gRPC compression to EPP (default none)
+    pub epp_tls_min_version: Option<EppTlsMinVersion>, // oldest TLS version for EPP (None = tonic default)
+    pub epp_body_hash: Option<EppBodyHash>, // body fingerprint header sent to EPP (default none)
+    pub epp_body_send_mode: Option<EppBodySendMode>, // how the body is sent to EPP (default none)
+    pub epp_header_sources: Option<EppHeaderSources>, // EPP responses trusted for the upstream header
+    pub epp_send_request_attributes: Option<bool>, // send method/path/host as ext_proc attributes (default off)
+    pub epp_send_client_cert: Option<bool>, // send the TLS client certificate DN/verify result to EPP (default off)
+    pub epp_send_host: Option<bool>, // send the request host as the ext_proc attribute request.host (default off)
+    pub epp_attributes: Vec<(String, EppAttributeValue)>, // extra ext_proc attributes (inference_epp_attribute)
+    pub epp_skip_if_set: Option<bool>, // skip EPP when upstream header already present (default on)
+    pub strip_upstream_header: Option<bool>, // remove the routing header before proxying upstream (default on)
+    pub upstream_validate_regex: Option<regex::Regex>, // validates EPP-returned upstream (None = built-in)
+    pub upstream_max_len: usize, // longest EPP upstream header value accepted (default 512)
+    pub upstream_allow: Vec<String>, // upstreams EPP may return (empty = any valid upstream)
+    pub epp_on_no_header: Option<EppOnNoHeader>, // action when EPP returns no upstream header (default error)
+    pub epp_immediate_status_map: Vec<(u16, u16)>, // ImmediateResponse status -> HTTP status overrides
+    pub epp_max_headers: usize, // max number of request headers forwarded to EPP (default 100)
+    pub epp_max_header_bytes: usize, // max total bytes of request headers forwarded to EPP (default 64KB)
+    pub epp_header_allow: Vec<String>, // only these request headers are forwarded to EPP (empty = all)
+    pub epp_send_headers: Option<bool>, // forward the client's request headers to EPP (default on)
+    pub epp_client_response_headers: Vec<String>, // EPP-set headers copied to the client response
+    pub request_deadline_ms: u64, // end-to-end budget for BBR + EPP access-phase processing (0 = off)
+    pub epp_failure_status: u64,  // fail-closed status on EPP errors (default 502)
+    pub epp_timeout_status: u64,  // fail-closed status on EPP timeout or deadline (default 504)
+    pub epp_max_messages: usize, // max EPP responses read without the upstream header (default 100)
+    pub epp_cache_ttl_ms: u64,   // how long a worker reuses an EPP decision (0 = cache off)
+    pub epp_sticky_ttl_ms: u64,  // min time a model keeps its EPP upstream in a worker (0 = off)
+    pub epp_cache_key: Option<EppAttributeValue>, // cache key template (None = the BBR model header)
+    pub epp_request_id_header: Option<(String, EppAttributeValue)>, // header name and value sent to EPP for correlation
+    pub epp_service_path: Option<EppServicePath>, // gRPC service path (None = Envoy ExternalProcessor)
+    pub epp_channel_idle_ms: u64, // reuse EPP channels until idle this long (0 = connect per call)
+    pub epp_max_recv_message_bytes: usize, // largest gRPC message from EPP (0 = tonic default, 4MB)
+    pub epp_max_send_message_bytes: usize, // largest gRPC message to EPP (0 = tonic default, unlimited)
+    pub epp_user_agent: Option<String>, // gRPC user-agent sent to EPP (None = ngx-inference/<version>)
+    pub model_routes: Vec<(String, String)>, // static model -> upstream table (inference_model_route)
+    pub model_aliases: Vec<(String, String)>, // model -> canonical model (inference_model_alias)
+    pub model_alias_ci: Option<bool>, // match inference_model_alias case-insensitively (default off)
 }
 
 impl Default for ModuleConfig {
     fn default() -> Self {
         Self {
+            enable: None,
+            process_subrequests: None,
+            on_missing_config: None,
+            log_decisions: None,
+            debug_sample_rate: None,
+            epp_log_response_sample: None,
+            log_body_spill: None,
             default_upstream: None,
+            upstream_unresolved_value: None,
+            force_upstream: None,
+            upstream_model_header: None,
             max_body_size: 10 * 1024 * 1024, // 10MB
+            total_body_memory: 0,
+            total_body_memory_action: None,
+            metrics_on_exit: None,
+            access_handler: None,
 
-            bbr_enable: false,
+            bbr_enable: None,
             bbr_header_name: "X-Gateway-Model-Name".to_string(),
             bbr_default_model: "unknown".to_string(),
+            pipeline_order: None,
+            bbr_array_policy: None,
+            bbr_empty_body: None,
+            bbr_on_parse_error: None,
+            bbr_url_decode_model: None,
+            bbr_field_case_insensitive: None,
+            bbr_model_fields: Vec::new(),
+            bbr_model_path_regex: None,
+            bbr_model_template: None,
+            bbr_oversize_upstream: None,
+            bbr_proto_field: 0,
+            bbr_parse_prefix_bytes: 0,
+            bbr_overwrite: None,
+            bbr_set_response_header: None,
+            bbr_response: None,
+            bbr_response_streaming: None,
 
-            epp_enable: false,
+            epp_enable: None,
             epp_endpoint: None,
+            epp_endpoint_map: None,
+            epp_fallback_endpoint: None,
             epp_timeout_ms: 200,
-            epp_failure_mode_allow: false,
+            epp_max_timeout_ms: 0,
+            epp_timeout_jitter_pct: 0,
+            epp_failure_mode_allow: None,
             epp_header_name: "X-Inference-Upstream".to_string(),
             epp_tls: true,
             epp_ca_file: None,
+            epp_grpc_compression: None,
+            epp_tls_min_version: None,
+            epp_body_hash: None,
+            epp_body_send_mode: None,
+            epp_header_sources: None,
+            epp_send_request_attributes: None,
+            epp_send_client_cert: None,
+            epp_send_host: None,
+            epp_attributes: Vec::new(),
+            epp_skip_if_set: None,
+            strip_upstream_header: None,
+            upstream_validate_regex: None,
+            upstream_max_len: crate::grpc::DEFAULT_UPSTREAM_MAX_LEN,
+            upstream_allow: Vec::new(),
+            epp_on_no_header: None,
+            epp_immediate_status_map: Vec::new(),
+            epp_max_headers: 100,
+            epp_max_header_bytes: 64 * 1024, // 64KB
+            epp_header_allow: Vec::new(),
+            epp_client_response_headers: Vec::new(),
+            epp_send_headers: None,
+            request_deadline_ms: 0,
+            epp_failure_status: 502,
+            epp_timeout_status: 504,
+            epp_max_messages: 100,
+            epp_cache_ttl_ms: 0,
+            epp_sticky_ttl_ms: 0,
+            epp_cache_key: None,
+            epp_request_id_header: None,
+            epp_service_path: None,
+            epp_channel_idle_ms: 0,
+            epp_max_recv_message_bytes: 0,
+            epp_max_send_message_bytes: 0,
+            model_routes: Vec::new(),
+            model_aliases: Vec::new(),
+            model_alias_ci: None,
+        }
+    }
+}
+
+impl ModuleConfig {
+    /// Upstream configured with `inference_model_route` for `model`, if any
+    pub fn route_for_model(&self, model: &str) -> Option<&str> {
+        self.model_routes
+            .iter()
+            .find(|(m, _)| m == model)
+            .map(|(_, upstream)| upstream.as_str())
+    }
+
+    /// Upstream for a body over `inference_max_body_size` instead of a 413, if
+    /// `inference_bbr_oversize_upstream` is on and `inference_default_upstream` is set
+    pub fn oversize_upstream(&self) -> Option<&str> {
+        self.default_upstream
+            .as_deref()
+            .filter(|_| self.bbr_oversize_upstream.unwrap_or_default())
+    }
+
+    /// Canonical name for `model` per `inference_model_alias`; unlisted models pass through
+    pub fn canonical_model<'a>(&'a self, model: &'a str) -> &'a str {
+        self.model_aliases
+            .iter()
+            .find(|(from, _)| {
+                if self.model_alias_ci.unwrap_or_default() {
+                    from.eq_ignore_ascii_case(model)
+                } else {
+                    from == model
+                }
+            })
+            .map_or(model, |(_, to)| to.as_str())
+    }
+
+    /// Configuration errors that must stop nginx from starting, checked for each `location`
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let has_endpoint = self.epp_endpoint.as_deref().is_some_and(|e| !e.is_empty())
+            || self.epp_endpoint_map.is_some();
+        if self.enabled() && self.epp_enable.unwrap_or_default() && !has_endpoint {
+            return Err(
+                "`inference_epp on` requires `inference_epp_endpoint` or `inference_epp_endpoint_map`",
+            );
+        }
+        if self
+            .epp_user_agent
+            .as_deref()
+            .is_some_and(|ua| !is_header_value(ua))
+        {
+            return Err("`inference_epp_user_agent` must be non-empty printable ASCII");
+        }
+        Ok(())
+    }
+
+    /// gRPC user-agent identifying the module to EPP: `inference_epp_user_agent`, or
+    /// `ngx-inference/<version>`
+    pub fn epp_user_agent(&self) -> String {
+        self.epp_user_agent
+            .clone()
+            .unwrap_or_else(|| format!("ngx-inference/{}", crate::module_version()))
+    }
+
+    /// Whether EPP connects with TLS verified against the system trust store, i.e. without
+    /// `inference_epp_ca_file`
+    pub fn epp_uses_system_roots(&self) -> bool {
+        self.enabled()
+            && self.epp_enable.unwrap_or_default()
+            && self.epp_tls
+            && self.epp_ca_file.is_none()
+    }
+
+    /// `inference on|off`, on unless turned off
+    pub fn enabled(&self) -> bool {
+        self.enable.unwrap_or(true)
+    }
+
+    /// Whether the module handles this request: `inference on`, and either a main request or
+    /// `inference_process_subrequests on`
+    pub fn applies_to(&self, is_subrequest: bool) -> bool {
+        self.enabled() && (!is_subrequest || self.process_subrequests.unwrap_or_default())
+    }
+
+    /// Configuration as created by NGINX for each `http`/`server`/`location` level.
+    ///
+    /// Numeric and string settings start unset (`0`/empty), like the on/off flags (`None`), so
+    /// `merge` can tell an explicit value from an inherited one; `merge` fills in the built-in
+    /// defaults.
+    pub fn unset() -> Self {
+        Self {
+            max_body_size: 0,
+            bbr_header_name: String::new(),
+            bbr_default_model: String::new(),
+            epp_timeout_ms: EPP_TIMEOUT_UNSET,
+            epp_cache_ttl_ms: EPP_TTL_UNSET,
+            epp_sticky_ttl_ms: EPP_TTL_UNSET,
+            epp_header_name: String::new(),
+            epp_max_headers: 0,
+            epp_max_header_bytes: 0,
+            epp_failure_status: 0,
+            epp_timeout_status: 0,
+            epp_max_messages: 0,
+            upstream_max_len: 0,
+            ..Default::default()
         }
     }
 }
 
 impl ngx::http::Merge for ModuleConfig {
     fn merge(&mut self, prev: &ModuleConfig) -> Result<(), MergeConfigError> {
-        // Inherit enable flags
-        if prev.bbr_enable {
-            self.bbr_enable = true;
+        // on/off flags stay None until their directive is given, so a level inherits the
+        // parent's value only when it does not set its own, and either value overrides
+        if self.enable.is_none() {
+            self.enable = prev.enable;
         }
-        if prev.epp_enable {
-            self.epp_enable = true;
+        if self.metrics_on_exit.is_none() {
+            self.metrics_on_exit = prev.metrics_on_exit;
+        }
+        if self.access_handler.is_none() {
+            self.access_handler = prev.access_handler;
+        }
+        if self.bbr_enable.is_none() {
+            self.bbr_enable = prev.bbr_enable;
+        }
+        if self.bbr_url_decode_model.is_none() {
+            self.bbr_url_decode_model = prev.bbr_url_decode_model;
+        }
+        if self.bbr_field_case_insensitive.is_none() {
+            self.bbr_field_case_insensitive = prev.bbr_field_case_insensitive;
+        }
+        if self.bbr_oversize_upstream.is_none() {
+            self.bbr_oversize_upstream = prev.bbr_oversize_upstream;
+        }
+        if self.bbr_overwrite.is_none() {
+            self.bbr_overwrite = prev.bbr_overwrite;
+        }
+        if self.bbr_set_response_header.is_none() {
+            self.bbr_set_response_header = prev.bbr_set_response_header;
+        }
+        if self.bbr_response.is_none() {
+            self.bbr_response = prev.bbr_response;
+        }
+        if self.bbr_response_streaming.is_none() {
+            self.bbr_response_streaming = prev.bbr_response_streaming;
+        }
+        if self.epp_enable.is_none() {
+            self.epp_enable = prev.epp_enable;
+        }
+        if self.epp_failure_mode_allow.is_none() {
+            self.epp_failure_mode_allow = prev.epp_failure_mode_allow;
+        }
+        if self.epp_send_request_attributes.is_none() {
+            self.epp_send_request_attributes = prev.epp_send_request_attributes;
+        }
+        if self.epp_send_client_cert.is_none() {
+            self.epp_send_client_cert = prev.epp_send_client_cert;
+        }
+        if self.epp_send_host.is_none() {
+            self.epp_send_host = prev.epp_send_host;
+        }
+        if self.epp_skip_if_set.is_none() {
+            self.epp_skip_if_set = prev.epp_skip_if_set;
+        }
+        if self.strip_upstream_header.is_none() {
+            self.strip_upstream_header = prev.strip_upstream_header;
+        }
+        if self.epp_send_headers.is_none() {
+            self.epp_send_headers = prev.epp_send_headers;
+        }
+        if self.model_alias_ci.is_none() {
+            self.model_alias_ci = prev.model_alias_ci;
+        }
+        if self.log_body_spill.is_none() {
+            self.log_body_spill = prev.log_body_spill;
+        }
+        if self.process_subrequests.is_none() {
+            self.process_subrequests = prev.process_subrequests;
         }
 
         // Inherit string options if not set
         if self.default_upstream.is_none() {
             self.default_upstream = prev.default_upstream.clone();
         }
+        if self.upstream_unresolved_value.is_none() {
+            self.upstream_unresolved_value = prev.upstream_unresolved_value.clone();
+        }
+        if self.force_upstream.is_none() {
+            self.force_upstream = prev.force_upstream.clone();
+        }
+        if self.upstream_model_header.is_none() {
+            self.upstream_model_header = prev.upstream_model_header.clone();
+        }
         if self.epp_endpoint.is_none() {
             self.epp_endpoint = prev.epp_endpoint.clone();
         }
+        // The endpoint map is inherited as a whole when this level defines no entries
+        if self.epp_endpoint_map.is_none() {
+            self.epp_endpoint_map = prev.epp_endpoint_map.clone();
+        }
+        if self.epp_fallback_endpoint.is_none() {
+            self.epp_fallback_endpoint = prev.epp_fallback_endpoint.clone();
+        }
 
         // Inherit numeric with defaults
         if self.max_body_size == 0 {
@@ -69,13 +903,68 @@ impl ngx::http::Merge for ModuleConfig {
                 prev.max_body_size
             }; // 10MB default
         }
-        if self.epp_timeout_ms == 0 {
-            self.epp_timeout_ms = if prev.epp_timeout_ms == 0 {
+        // An explicit 0 is a setting of its own, so unset is marked with EPP_TIMEOUT_UNSET
+        if self.epp_timeout_ms == EPP_TIMEOUT_UNSET {
+            self.epp_timeout_ms = if prev.epp_timeout_ms == EPP_TIMEOUT_UNSET {
                 200
             } else {
                 prev.epp_timeout_ms
             };
         }
+        if self.epp_max_timeout_ms == 0 {
+            self.epp_max_timeout_ms = prev.epp_max_timeout_ms;
+        }
+        if self.epp_timeout_jitter_pct == 0 {
+            self.epp_timeout_jitter_pct = prev.epp_timeout_jitter_pct;
+        }
+        if self.epp_max_headers == 0 {
+            self.epp_max_headers = if prev.epp_max_headers == 0 {
+                100
+            } else {
+                prev.epp_max_headers
+            };
+        }
+        if self.epp_max_header_bytes == 0 {
+            self.epp_max_header_bytes = if prev.epp_max_header_bytes == 0 {
+                64 * 1024
+            } else {
+                prev.epp_max_header_bytes
+            }; // 64KB default
+        }
+        if self.epp_max_messages == 0 {
+            self.epp_max_messages = if prev.epp_max_messages == 0 {
+                100
+            } else {
+                prev.epp_max_messages
+            };
+        }
+        if self.upstream_max_len == 0 {
+            self.upstream_max_len = if prev.upstream_max_len == 0 {
+                crate::grpc::DEFAULT_UPSTREAM_MAX_LEN
+            } else {
+                prev.upstream_max_len
+            };
+        }
+        if self.epp_failure_status == 0 {
+            self.epp_failure_status = if prev.epp_failure_status == 0 {
+                502
+            } else {
+                prev.epp_failure_status
+            };
+        }
+        if self.epp_timeout_status == 0 {
+            self.epp_timeout_status = if prev.epp_timeout_status == 0 {
+                504
+            } else {
+                prev.epp_timeout_status
+            };
+        }
+        if self.request_deadline_ms == 0 {
+            self.request_deadline_ms = prev.request_deadline_ms; // 0 = no deadline
+        }
+        if self.total_body_memory == 0 {
+            self.total_body_memory = prev.total_body_memory; // 0 = unlimited
+        }
         if self.bbr_header_name.is_empty() {
             self.bbr_header_name = if prev.bbr_header_name.is_empty() {
                 "X-Gateway-Model-Name".to_string()
@@ -98,9 +987,11 @@ impl ngx::http::Merge for ModuleConfig {
             }
         }
 
-        // Inherit bools - only inherit true values if current level hasn't explicitly set false
-        if prev.epp_failure_mode_allow {
-            self.epp_failure_mode_allow = true;
+        if self.bbr_model_path_regex.is_none() {
+            self.bbr_model_path_regex = prev.bbr_model_path_regex.clone();
+        }
+        if self.bbr_model_template.is_none() {
+            self.bbr_model_template = prev.bbr_model_template.clone();
         }
         // Note: epp_tls should not inherit - each level uses its own explicit value or default
 
@@ -109,6 +1000,126 @@ impl ngx::http::Merge for ModuleConfig {
             self.epp_ca_file = prev.epp_ca_file.clone();
         }
 
+        // Inherit upstream validation regex if not set
+        if self.upstream_validate_regex.is_none() {
+            self.upstream_validate_regex = prev.upstream_validate_regex.clone();
+        }
+        if self.upstream_allow.is_empty() {
+            self.upstream_allow = prev.upstream_allow.clone();
+        }
+        if self.bbr_model_fields.is_empty() {
+            self.bbr_model_fields = prev.bbr_model_fields.clone();
+        }
+        if self.epp_on_no_header.is_none() {
+            self.epp_on_no_header = prev.epp_on_no_header;
+        }
+        if self.epp_header_sources.is_none() {
+            self.epp_header_sources = prev.epp_header_sources;
+        }
+        if self.epp_grpc_compression.is_none() {
+            self.epp_grpc_compression = prev.epp_grpc_compression;
+        }
+        if self.epp_tls_min_version.is_none() {
+            self.epp_tls_min_version = prev.epp_tls_min_version;
+        }
+        if self.epp_body_hash.is_none() {
+            self.epp_body_hash = prev.epp_body_hash;
+        }
+        if self.epp_body_send_mode.is_none() {
+            self.epp_body_send_mode = prev.epp_body_send_mode;
+        }
+        if self.on_missing_config.is_none() {
+            self.on_missing_config = prev.on_missing_config;
+        }
+        if self.log_decisions.is_none() {
+            self.log_decisions = prev.log_decisions;
+        }
+        if self.debug_sample_rate.is_none() {
+            self.debug_sample_rate = prev.debug_sample_rate;
+        }
+        if self.epp_log_response_sample.is_none() {
+            self.epp_log_response_sample = prev.epp_log_response_sample;
+        }
+        if self.pipeline_order.is_none() {
+            self.pipeline_order = prev.pipeline_order;
+        }
+        if self.bbr_array_policy.is_none() {
+            self.bbr_array_policy = prev.bbr_array_policy;
+        }
+        if self.bbr_proto_field == 0 {
+            self.bbr_proto_field = prev.bbr_proto_field;
+        }
+        if self.bbr_parse_prefix_bytes == 0 {
+            self.bbr_parse_prefix_bytes = prev.bbr_parse_prefix_bytes;
+        }
+        if self.bbr_empty_body.is_none() {
+            self.bbr_empty_body = prev.bbr_empty_body;
+        }
+        if self.bbr_on_parse_error.is_none() {
+            self.bbr_on_parse_error = prev.bbr_on_parse_error;
+        }
+        if self.total_body_memory_action.is_none() {
+            self.total_body_memory_action = prev.total_body_memory_action;
+        }
+        // An explicit 0 turns it off below a level that has it on
+        if self.epp_cache_ttl_ms == EPP_TTL_UNSET {
+            self.epp_cache_ttl_ms = if prev.epp_cache_ttl_ms == EPP_TTL_UNSET {
+                0
+            } else {
+                prev.epp_cache_ttl_ms
+            };
+        }
+        // An explicit 0 turns it off below a level that has it on
+        if self.epp_sticky_ttl_ms == EPP_TTL_UNSET {
+            self.epp_sticky_ttl_ms = if prev.epp_sticky_ttl_ms == EPP_TTL_UNSET {
+                0
+            } else {
+                prev.epp_sticky_ttl_ms
+            };
+        }
+        if self.epp_cache_key.is_none() {
+            self.epp_cache_key = prev.epp_cache_key;
+        }
+        if self.epp_request_id_header.is_none() {
+            self.epp_request_id_header = prev.epp_request_id_header.clone();
+        }
+        if self.epp_service_path.is_none() {
+            self.epp_service_path = prev.epp_service_path.clone();
+        }
+        if self.epp_channel_idle_ms == 0 {
+            self.epp_channel_idle_ms = prev.epp_channel_idle_ms;
+        }
+        if self.epp_max_recv_message_bytes == 0 {
+            self.epp_max_recv_message_bytes = prev.epp_max_recv_message_bytes;
+        }
+        if self.epp_max_send_message_bytes == 0 {
+            self.epp_max_send_message_bytes = prev.epp_max_send_message_bytes;
+        }
+        if self.epp_user_agent.is_none() {
+            self.epp_user_agent = prev.epp_user_agent.clone();
+        }
+
+        // The route table is inherited as a whole when this level defines no routes
+        if self.model_routes.is_empty() {
+            self.model_routes = prev.model_routes.clone();
+        }
+        // Likewise for the EPP attribute, header allow-list and alias tables
+        if self.epp_attributes.is_empty() {
+            self.epp_attributes = prev.epp_attributes.clone();
+        }
+        if self.epp_header_allow.is_empty() {
+            self.epp_header_allow = prev.epp_header_allow.clone();
+        }
+        if self.epp_client_response_headers.is_empty() {
+            self.epp_client_response_headers = prev.epp_client_response_headers.clone();
+        }
+        if self.model_aliases.is_empty() {
+            self.model_aliases = prev.model_aliases.clone();
+        }
+        if self.epp_immediate_status_map.is_empty() {
+            self.epp_immediate_status_map = prev.epp_immediate_status_map.clone();
+        }
+
         Ok(())
     }
 }
@@ -130,7 +1141,7 @@ pub fn set_string_opt(target: &mut Option<String>, val: &str) {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct ParseError;
 
 pub fn set_usize(target: &mut usize, val: &str) -> Result<(), ParseError> {
@@ -152,3 +1163,917 @@ pub fn set_u64(target: &mut u64, val: &str) -> Result<(), ParseError> {
         Err(_) => Err(ParseError),
     }
 }
+
+/// Parse an HTTP error status (400-599) for the fail-closed status directives
+pub fn set_http_status(target: &mut u64, val: &str) -> Result<(), ParseError> {
+    match val.parse::<u64>() {
+        Ok(parsed) if (400..=599).contains(&parsed) => {
+            *target = parsed;
+            Ok(())
+        }
+        _ => Err(ParseError),
+    }
+}
+
+/// Parse a sampling rate (0.0-1.0)
+pub fn set_rate(target: &mut Option<f64>, val: &str) -> Result<(), ParseError> {
+    match val.parse::<f64>() {
+        Ok(parsed) if (0.0..=1.0).contains(&parsed) => {
+            *target = Some(parsed);
+            Ok(())
+        }
+        _ => Err(ParseError),
+    }
+}
+
+/// Parse a percentage (0-100)
+pub fn set_percent(target: &mut u64, val: &str) -> Result<(), ParseError> {
+    match val.parse::<u64>() {
+        Ok(parsed) if parsed <= 100 => {
+            *target = parsed;
+            Ok(())
+        }
+        _ => Err(ParseError),
+    }
+}
+
+pub fn set_regex(target: &mut Option<regex::Regex>, val: &str) -> Result<(), ParseError> {
+    match regex::Regex::new(val) {
+        Ok(re) => {
+            *target = Some(re);
+            Ok(())
+        }
+        Err(_) => Err(ParseError),
+    }
+}
+
+/// Append one occurrence of a repeatable list directive; values must be non-empty and unique
+/// (ignoring ASCII case) per level
+pub fn push_string_list(target: &mut Vec<String>, val: &str) -> Result<(), ParseError> {
+    if val.is_empty() || target.iter().any(|v| v.eq_ignore_ascii_case(val)) {
+        return Err(ParseError);
+    }
+    target.push(val.to_string());
+    Ok(())
+}
+
+/// Add a `inference_model_route` entry; models must be non-empty and unique per level
+pub fn add_model_route(
+    routes: &mut Vec<(String, String)>,
+    model: &str,
+    upstream: &str,
+) -> Result<(), ParseError> {
+    if model.is_empty() || upstream.is_empty() || routes.iter().any(|(m, _)| m == model) {
+        return Err(ParseError);
+    }
+    routes.push((model.to_string(), upstream.to_string()));
+    Ok(())
+}
+
+/// Add a `inference_epp_endpoint_map` entry. Every entry of a level must key on the same
+/// `$variable`; values must be unique and endpoints non-empty.
+pub fn add_epp_endpoint_map(
+    map: &mut Option<EppEndpointMap>,
+    variable: &str,
+    value: &str,
+    endpoint: &str,
+) -> Result<(), ParseError> {
+    let variable = variable
+        .strip_prefix('$')
+        .filter(|name| !name.is_empty())
+        .ok_or(ParseError)?
+        .to_ascii_lowercase();
+    if value.is_empty() || endpoint.is_empty() || endpoint.starts_with('$') {
+        return Err(ParseError);
+    }
+    let map = map.get_or_insert_with(|| EppEndpointMap {
+        variable: variable.clone(),
+        entries: Vec::new(),
+    });
+    if map.variable != variable || map.endpoint_for(value).is_some() {
+        return Err(ParseError);
+    }
+    map.entries.push((value.to_string(), endpoint.to_string()));
+    Ok(())
+}
+
+/// Add a `inference_model_alias` entry; source models must be non-empty and unique per level
+pub fn add_model_alias(
+    aliases: &mut Vec<(String, String)>,
+    from: &str,
+    to: &str,
+) -> Result<(), ParseError> {
+    if from.is_empty() || to.is_empty() || aliases.iter().any(|(f, _)| f == from) {
+        return Err(ParseError);
+    }
+    aliases.push((from.to_string(), to.to_string()));
+    Ok(())
+}
+
+/// Add a `inference_epp_immediate_status_map` entry: an ImmediateResponse status (0-599, 0 being
+/// Envoy's `Empty`) and the HTTP status (200-599) to return for it; sources are unique per level
+pub fn add_immediate_status(
+    map: &mut Vec<(u16, u16)>,
+    from: &str,
+    to: &str,
+) -> Result<(), ParseError> {
+    let from = from.parse::<u16>().map_err(|_| ParseError)?;
+    let to = to.parse::<u16>().map_err(|_| ParseError)?;
+    if from > 599 || !(200..=599).contains(&to) || map.iter().any(|(f, _)| *f == from) {
+        return Err(ParseError);
+    }
+    map.push((from, to));
+    Ok(())
+}
+
+/// Whether `name` is a valid HTTP header field name (an RFC 9110 token)
+pub fn is_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Whether `value` can be sent as an HTTP header value: non-empty visible ASCII, spaces and tabs
+pub fn is_header_value(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_graphic() || b == b' ' || b == b'\t')
+}
+
+/// Add a `inference_epp_attribute` entry; keys must be non-empty and unique per level
+pub fn add_epp_attribute(
+    attributes: &mut Vec<(String, EppAttributeValue)>,
+    key: &str,
+    value: EppAttributeValue,
+) -> Result<(), ParseError> {
+    if key.is_empty() || attributes.iter().any(|(k, _)| k == key) {
+        return Err(ParseError);
+    }
+    attributes.push((key.to_string(), value));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ngx::http::Merge;
+
+    #[test]
+    fn test_debug_sample_rate_parse() {
+        let mut rate = None;
+        assert!(set_rate(&mut rate, "0.05").is_ok());
+        assert_eq!(rate, Some(0.05));
+        assert!(set_rate(&mut rate, "1").is_ok());
+        assert_eq!(rate, Some(1.0));
+        for invalid in ["1.5", "-0.1", "NaN", "5%", ""] {
+            assert!(set_rate(&mut rate, invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(rate, Some(1.0));
+    }
+
+    #[test]
+    fn test_failure_status_validation_and_merge() {
+        let mut status = 0;
+        assert!(set_http_status(&mut status, "503").is_ok());
+        assert_eq!(status, 503);
+        for invalid in ["200", "302", "600", "abc", "-1"] {
+            assert!(
+                set_http_status(&mut status, invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+        assert_eq!(status, 503);
+
+        // Unset levels get the current hard-coded defaults
+        let mut conf = ModuleConfig::unset();
+        conf.merge(&ModuleConfig::unset()).unwrap();
+        assert_eq!(conf.epp_failure_status, 502);
+        assert_eq!(conf.epp_timeout_status, 504);
+
+        let parent = ModuleConfig {
+            epp_failure_status: 503,
+            epp_timeout_status: 503,
+            ..ModuleConfig::unset()
+        };
+        let mut child = ModuleConfig::unset();
+        child.merge(&parent).unwrap();
+        assert_eq!(child.epp_failure_status, 503);
+        assert_eq!(child.epp_timeout_status, 503);
+    }
+
+    #[test]
+    fn test_oversize_upstream_needs_flag_and_default() {
+        let mut conf = ModuleConfig {
+            default_upstream: Some("10.0.0.1:8000".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(conf.oversize_upstream(), None);
+
+        conf.bbr_oversize_upstream = Some(true);
+        assert_eq!(conf.oversize_upstream(), Some("10.0.0.1:8000"));
+
+        // Nothing to route to: the 413 stands
+        conf.default_upstream = None;
+        assert_eq!(conf.oversize_upstream(), None);
+    }
+
+    #[test]
+    fn test_model_routes_lookup_and_merge() {
+        let mut routes = Vec::new();
+        add_model_route(&mut routes, "llama-3-8b", "10.0.0.1:8000").unwrap();
+        add_model_route(&mut routes, "llama-3-70b", "10.0.0.2:8000").unwrap();
+        assert!(add_model_route(&mut routes, "llama-3-8b", "10.0.0.3:8000").is_err());
+        assert!(add_model_route(&mut routes, "", "10.0.0.3:8000").is_err());
+
+        let parent = ModuleConfig {
+            model_routes: routes,
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert_eq!(child.route_for_model("llama-3-70b"), Some("10.0.0.2:8000"));
+        assert_eq!(child.route_for_model("Llama-3-70b"), None);
+
+        // A level with its own routes replaces the inherited table
+        let mut override_child = ModuleConfig {
+            model_routes: vec![("other".to_string(), "10.0.0.9:8000".to_string())],
+            ..Default::default()
+        };
+        override_child.merge(&parent).unwrap();
+        assert_eq!(override_child.route_for_model("llama-3-8b"), None);
+    }
+
+    #[test]
+    fn test_epp_endpoint_map_entries_and_merge() {
+        let mut map = None;
+        add_epp_endpoint_map(&mut map, "$URI", "/v1/chat", "epp-chat:9002").unwrap();
+        add_epp_endpoint_map(&mut map, "$uri", "/v1/embed", "epp-embed:9002").unwrap();
+        assert!(add_epp_endpoint_map(&mut map, "$uri", "/v1/chat", "other:9002").is_err());
+        assert!(add_epp_endpoint_map(&mut map, "$request_method", "GET", "x:9002").is_err());
+        assert!(add_epp_endpoint_map(&mut map, "uri", "/x", "x:9002").is_err());
+        assert!(add_epp_endpoint_map(&mut map, "$uri", "/x", "$epp_host").is_err());
+        assert!(add_epp_endpoint_map(&mut map, "$uri", "/x", "").is_err());
+
+        let map = map.unwrap();
+        assert_eq!(map.variable, "uri");
+        assert_eq!(map.endpoint_for("/v1/embed"), Some("epp-embed:9002"));
+        assert_eq!(map.endpoint_for("/v1/other"), None);
+
+        // A map alone satisfies `inference_epp on`
+        let parent = ModuleConfig {
+            epp_enable: Some(true),
+            epp_endpoint_map: Some(map.clone()),
+            ..Default::default()
+        };
+        assert!(parent.validate().is_ok());
+
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert_eq!(child.epp_endpoint_map.as_ref(), Some(&map));
+    }
+
+    #[test]
+    fn test_immediate_status_map_entries() {
+        let mut map = Vec::new();
+        add_immediate_status(&mut map, "429", "503").unwrap();
+        add_immediate_status(&mut map, "0", "502").unwrap();
+        assert!(add_immediate_status(&mut map, "429", "500").is_err());
+        assert!(add_immediate_status(&mut map, "401", "100").is_err());
+        assert!(add_immediate_status(&mut map, "600", "500").is_err());
+        assert!(add_immediate_status(&mut map, "abc", "500").is_err());
+        assert_eq!(map, vec![(429, 503), (0, 502)]);
+    }
+
+    #[test]
+    fn test_model_aliases_exact_and_case_insensitive() {
+        let mut aliases = Vec::new();
+        add_model_alias(&mut aliases, "GPT-4", "gpt-4").unwrap();
+        add_model_alias(&mut aliases, "openai/gpt-4", "gpt-4").unwrap();
+        assert!(add_model_alias(&mut aliases, "GPT-4", "other").is_err());
+        assert!(add_model_alias(&mut aliases, "", "gpt-4").is_err());
+
+        let parent = ModuleConfig {
+            model_aliases: aliases,
+            ..Default::default()
+        };
+        let mut exact = ModuleConfig::default();
+        exact.merge(&parent).unwrap();
+        assert_eq!(exact.canonical_model("GPT-4"), "gpt-4");
+        assert_eq!(exact.canonical_model("openai/gpt-4"), "gpt-4");
+        assert_eq!(exact.canonical_model("gpt-4"), "gpt-4");
+        assert_eq!(exact.canonical_model("OpenAI/GPT-4"), "OpenAI/GPT-4");
+        assert_eq!(exact.canonical_model("llama-3-8b"), "llama-3-8b");
+
+        let mut ci = ModuleConfig {
+            model_alias_ci: Some(true),
+            ..Default::default()
+        };
+        ci.merge(&parent).unwrap();
+        assert_eq!(ci.canonical_model("OpenAI/GPT-4"), "gpt-4");
+        assert_eq!(ci.canonical_model("gpt-4"), "gpt-4");
+        assert_eq!(ci.canonical_model("llama-3-8b"), "llama-3-8b");
+    }
+
+    #[test]
+    fn test_string_list_accumulates_across_repeats() {
+        use ngx::http::Merge;
+
+        // inference_epp_header_allow x-request-id; inference_epp_header_allow x-tenant;
+        let mut allow = Vec::new();
+        push_string_list(&mut allow, "x-request-id").unwrap();
+        push_string_list(&mut allow, "x-tenant").unwrap();
+        assert_eq!(allow, vec!["x-request-id", "x-tenant"]);
+        assert!(push_string_list(&mut allow, "X-Tenant").is_err());
+        assert!(push_string_list(&mut allow, "").is_err());
+
+        // A level with its own list replaces the inherited one
+        let parent = ModuleConfig {
+            epp_header_allow: allow,
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert_eq!(child.epp_header_allow, vec!["x-request-id", "x-tenant"]);
+
+        let mut child = ModuleConfig {
+            epp_header_allow: vec!["authorization".to_string()],
+            ..Default::default()
+        };
+        child.merge(&parent).unwrap();
+        assert_eq!(child.epp_header_allow, vec!["authorization"]);
+    }
+
+    #[test]
+    fn test_validate_epp_requires_endpoint() {
+        let mut conf = ModuleConfig {
+            epp_enable: Some(true),
+            ..Default::default()
+        };
+        assert!(conf.validate().is_err());
+
+        conf.epp_endpoint = Some(String::new());
+        assert!(conf.validate().is_err());
+
+        conf.epp_endpoint = Some("epp.example.com:9002".to_string());
+        assert!(conf.validate().is_ok());
+
+        // Nothing to check with EPP or the whole module off
+        assert!(ModuleConfig::default().validate().is_ok());
+        let off = ModuleConfig {
+            enable: Some(false),
+            epp_enable: Some(true),
+            ..Default::default()
+        };
+        assert!(off.validate().is_ok());
+    }
+
+    #[test]
+    fn test_epp_user_agent_default_and_validation() {
+        let mut conf = ModuleConfig::default();
+        assert_eq!(
+            conf.epp_user_agent(),
+            format!("ngx-inference/{}", crate::module_version())
+        );
+
+        conf.epp_user_agent = Some("acme-gateway/2.1".to_string());
+        assert_eq!(conf.epp_user_agent(), "acme-gateway/2.1");
+        assert!(conf.validate().is_ok());
+
+        for invalid in ["", "bad\nagent", "caf\u{e9}"] {
+            conf.epp_user_agent = Some(invalid.to_string());
+            assert!(conf.validate().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_epp_uses_system_roots() {
+        let mut conf = ModuleConfig {
+            epp_enable: Some(true),
+            epp_endpoint: Some("epp.example.com:9002".to_string()),
+            ..Default::default()
+        };
+        assert!(conf.epp_uses_system_roots());
+
+        conf.epp_ca_file = Some("/etc/ssl/epp-ca.pem".to_string());
+        assert!(!conf.epp_uses_system_roots());
+
+        conf.epp_ca_file = None;
+        conf.epp_tls = false;
+        assert!(!conf.epp_uses_system_roots());
+    }
+
+    #[test]
+    fn test_is_header_name() {
+        assert!(is_header_name("X-Request-Id"));
+        assert!(is_header_name("x_trace.id"));
+        assert!(!is_header_name(""));
+        assert!(!is_header_name("X Request"));
+        assert!(!is_header_name("X-Id:"));
+        assert!(!is_header_name("X-Id\r\n"));
+    }
+
+    #[test]
+    fn test_epp_attributes_unique_keys_and_merge() {
+        let value = EppAttributeValue(std::ptr::null());
+        let mut attributes = Vec::new();
+        add_epp_attribute(&mut attributes, "source.address", value).unwrap();
+        add_epp_attribute(&mut attributes, "tenant", value).unwrap();
+        assert!(add_epp_attribute(&mut attributes, "tenant", value).is_err());
+        assert!(add_epp_attribute(&mut attributes, "", value).is_err());
+
+        let parent = ModuleConfig {
+            epp_attributes: attributes,
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        let keys: Vec<&str> = child
+            .epp_attributes
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect();
+        assert_eq!(keys, ["source.address", "tenant"]);
+    }
+
+    #[test]
+    fn test_inference_off_defaults_on_and_inherits_off() {
+        assert!(ModuleConfig::default().enabled());
+
+        let parent = ModuleConfig {
+            enable: Some(false),
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert!(!child.enabled());
+
+        // `inference on` in a location undoes a server-level `inference off`
+        let mut on = ModuleConfig {
+            enable: Some(true),
+            ..Default::default()
+        };
+        on.merge(&parent).unwrap();
+        assert!(on.enabled());
+    }
+
+    #[test]
+    fn test_on_missing_config_policy() {
+        use ngx::http::Merge;
+
+        assert_eq!("fail".parse(), Ok(OnMissingConfig::Fail));
+        assert_eq!("PassThrough".parse(), Ok(OnMissingConfig::Passthrough));
+        assert!("ignore".parse::<OnMissingConfig>().is_err());
+
+        // Neither location nor server conf: the safe default lets the request through
+        assert_eq!(
+            OnMissingConfig::for_server(None),
+            OnMissingConfig::Passthrough
+        );
+        assert_eq!(
+            OnMissingConfig::for_server(Some(&ModuleConfig::default())),
+            OnMissingConfig::Passthrough
+        );
+
+        // Set at http level, inherited by the server
+        let main = ModuleConfig {
+            on_missing_config: Some(OnMissingConfig::Fail),
+            ..Default::default()
+        };
+        let mut server = ModuleConfig::default();
+        server.merge(&main).unwrap();
+        assert_eq!(
+            OnMissingConfig::for_server(Some(&server)),
+            OnMissingConfig::Fail
+        );
+    }
+
+    #[test]
+    fn test_log_decisions_levels() {
+        assert_eq!("WARN".parse(), Ok(LogDecisions::Warn));
+        assert_eq!("info".parse(), Ok(LogDecisions::Info));
+        assert_eq!("debug".parse(), Ok(LogDecisions::Debug));
+        assert_eq!("off".parse(), Ok(LogDecisions::Off));
+        assert!("error".parse::<LogDecisions>().is_err());
+
+        assert_eq!(LogDecisions::default(), LogDecisions::Debug);
+        assert_eq!(LogDecisions::Warn.level(), Some(ngx::ffi::NGX_LOG_WARN));
+        assert_eq!(LogDecisions::Debug.level(), Some(ngx::ffi::NGX_LOG_DEBUG));
+        // off: no decision line at any error_log level
+        assert_eq!(LogDecisions::Off.level(), None);
+    }
+
+    #[test]
+    fn test_subrequests_skipped_unless_enabled() {
+        use ngx::http::Merge;
+
+        let conf = ModuleConfig::default();
+        assert!(conf.applies_to(false));
+        assert!(!conf.applies_to(true));
+
+        let parent = ModuleConfig {
+            process_subrequests: Some(true),
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert!(child.applies_to(true));
+
+        // A location can turn a server-level opt-in back off
+        let mut off = ModuleConfig {
+            process_subrequests: Some(false),
+            ..Default::default()
+        };
+        off.merge(&parent).unwrap();
+        assert!(!off.applies_to(true));
+
+        // `inference off` wins over the subrequest opt-in
+        child.enable = Some(false);
+        assert!(!child.applies_to(false));
+        assert!(!child.applies_to(true));
+    }
+
+    #[test]
+    fn test_epp_on_no_header_parse() {
+        assert_eq!("continue".parse(), Ok(EppOnNoHeader::Continue));
+        assert_eq!("DEFAULT".parse(), Ok(EppOnNoHeader::Default));
+        assert_eq!("error".parse(), Ok(EppOnNoHeader::Error));
+        assert!("ignore".parse::<EppOnNoHeader>().is_err());
+        assert_eq!(EppOnNoHeader::default(), EppOnNoHeader::Error);
+    }
+
+    #[test]
+    fn test_pipeline_order_parse_stages_and_merge() {
+        use ngx::http::Merge;
+
+        assert_eq!("bbr-epp".parse(), Ok(PipelineOrder::BbrEpp));
+        assert_eq!("EPP-BBR".parse(), Ok(PipelineOrder::EppBbr));
+        assert!("epp".parse::<PipelineOrder>().is_err());
+        assert_eq!(PipelineOrder::default().stages(), [Stage::Bbr, Stage::Epp]);
+        assert_eq!(PipelineOrder::EppBbr.stages(), [Stage::Epp, Stage::Bbr]);
+
+        let parent = ModuleConfig {
+            pipeline_order: Some(PipelineOrder::EppBbr),
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert_eq!(child.pipeline_order, Some(PipelineOrder::EppBbr));
+    }
+
+    #[test]
+    fn test_epp_service_path_parse() {
+        assert_eq!(
+            "/custom.v1.Processor".parse(),
+            Ok(EppServicePath("/custom.v1.Processor".to_string()))
+        );
+        assert!("/epp/envoy.service.ext_proc.v3.ExternalProcessor"
+            .parse::<EppServicePath>()
+            .is_ok());
+        for bad in ["", "/", "custom.v1.Processor", "/custom/", "/a b", "/a?x=1"] {
+            assert!(bad.parse::<EppServicePath>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_upstream_model_header_parse() {
+        assert_eq!(
+            "X-Backend-Model".parse(),
+            Ok(UpstreamModelHeader("X-Backend-Model".to_string()))
+        );
+        for bad in ["", "X Model", "X-Model:", "X-Model\r\n"] {
+            assert!(bad.parse::<UpstreamModelHeader>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_model_path_regex_requires_capture_group() {
+        assert!(r"^/models/([^/]+)/".parse::<ModelPathRegex>().is_ok());
+        assert!(r"^/models/[^/]+/".parse::<ModelPathRegex>().is_err());
+        assert!(r"^/models/(?:[^/]+)/".parse::<ModelPathRegex>().is_err());
+        assert!(r"^/models/([".parse::<ModelPathRegex>().is_err());
+    }
+
+    #[test]
+    fn test_model_template_parse() {
+        assert_eq!(
+            "%provider%/%model%".parse(),
+            Ok(ModelTemplate(vec![
+                TemplatePart::Field("provider".to_string()),
+                TemplatePart::Literal("/".to_string()),
+                TemplatePart::Field("model".to_string()),
+            ]))
+        );
+        assert_eq!(
+            "pool-%request.model%-100%%".parse(),
+            Ok(ModelTemplate(vec![
+                TemplatePart::Literal("pool-".to_string()),
+                TemplatePart::Field("request.model".to_string()),
+                TemplatePart::Literal("-100%".to_string()),
+            ]))
+        );
+        for bad in ["", "model", "100%%", "%model", "%provider%/%model"] {
+            assert!(bad.parse::<ModelTemplate>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_bbr_array_policy_parse() {
+        assert_eq!("first".parse(), Ok(BbrArrayPolicy::First));
+        assert_eq!("Last".parse(), Ok(BbrArrayPolicy::Last));
+        assert_eq!("reject".parse(), Ok(BbrArrayPolicy::Reject));
+        assert!("all".parse::<BbrArrayPolicy>().is_err());
+        assert_eq!(BbrArrayPolicy::default(), BbrArrayPolicy::First);
+    }
+
+    #[test]
+    fn test_bbr_empty_body_parse_and_merge() {
+        use ngx::http::Merge;
+
+        assert_eq!("default".parse(), Ok(BbrEmptyBody::DefaultModel));
+        assert_eq!("Skip".parse(), Ok(BbrEmptyBody::Skip));
+        assert_eq!("REJECT".parse(), Ok(BbrEmptyBody::Reject));
+        assert!("ignore".parse::<BbrEmptyBody>().is_err());
+        assert_eq!(BbrEmptyBody::default(), BbrEmptyBody::Skip);
+
+        let parent = ModuleConfig {
+            bbr_empty_body: Some(BbrEmptyBody::Reject),
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert_eq!(child.bbr_empty_body, Some(BbrEmptyBody::Reject));
+    }
+
+    #[test]
+    fn test_bbr_on_parse_error_parse_and_merge() {
+        use ngx::http::Merge;
+
+        assert_eq!("default".parse(), Ok(BbrOnParseError::DefaultModel));
+        assert_eq!("SKIP".parse(), Ok(BbrOnParseError::Skip));
+        assert_eq!("reject".parse(), Ok(BbrOnParseError::Reject));
+        assert!("ignore".parse::<BbrOnParseError>().is_err());
+        assert_eq!(BbrOnParseError::default(), BbrOnParseError::DefaultModel);
+
+        let parent = ModuleConfig {
+            bbr_on_parse_error: Some(BbrOnParseError::Skip),
+            ..Default::default()
+        };
+        let mut child = ModuleConfig::default();
+        child.merge(&parent).unwrap();
+        assert_eq!(child.bbr_on_parse_error, Some(BbrOnParseError::Skip));
+    }
+
+    #[test]
+    fn test_epp_header_sources_parse() {
+        assert_eq!(
+            "request-headers".parse(),
+            Ok(EppHeaderSources::RequestHeaders)
+        );
+        assert_eq!("ANY".parse(), Ok(EppHeaderSources::Any));
+        assert!("response-headers".parse::<EppHeaderSources>().is_err());
+        assert_eq!(
+            EppHeaderSources::default(),
+            EppHeaderSources::RequestHeaders
+        );
+    }
+
+    #[test]
+    fn test_epp_grpc_compression_parse() {
+        assert_eq!("gzip".parse(), Ok(EppGrpcCompression::Gzip));
+        assert_eq!("NONE".parse(), Ok(EppGrpcCompression::None));
+        assert!("zstd".parse::<EppGrpcCompression>().is_err());
+        assert_eq!(EppGrpcCompression::default(), EppGrpcCompression::None);
+    }
+
+    #[test]
+    fn test_epp_tls_min_version_parse() {
+        assert_eq!("1.2".parse(), Ok(EppTlsMinVersion::Tls12));
+        assert_eq!("1.3".parse(), Ok(EppTlsMinVersion::Tls13));
+        assert!("1.1".parse::<EppTlsMinVersion>().is_err());
+        assert!("tls1.3".parse::<EppTlsMinVersion>().is_err());
+    }
+
+    #[test]
+    fn test_epp_body_hash_parse() {
+        assert_eq!("sha256".parse(), Ok(EppBodyHash::Sha256));
+        assert_eq!("None".parse(), Ok(EppBodyHash::None));
+        assert!("md5".parse::<EppBodyHash>().is_err());
+        assert_eq!(EppBodyHash::default(), EppBodyHash::None);
+    }
+
+    #[test]
+    fn test_epp_body_send_mode_parse() {
+        assert_eq!("none".parse(), Ok(EppBodySendMode::None));
+        assert_eq!("Buffered".parse(), Ok(EppBodySendMode::Buffered));
+        assert_eq!("streamed".parse(), Ok(EppBodySendMode::Streamed));
+        assert_eq!(
+            "buffered_partial".parse(),
+            Ok(EppBodySendMode::BufferedPartial)
+        );
+        assert!("buffered-partial".parse::<EppBodySendMode>().is_err());
+        assert_eq!(EppBodySendMode::default(), EppBodySendMode::None);
+    }
+
+    #[test]
+    fn test_epp_on_no_header_merge() {
+        let parent = ModuleConfig {
+            epp_on_no_header: Some(EppOnNoHeader::Continue),
+            ..Default::default()
+        };
+
+        let mut inherited = ModuleConfig::default();
+        inherited.merge(&parent).unwrap();
+        assert_eq!(inherited.epp_on_no_header, Some(EppOnNoHeader::Continue));
+
+        let mut overridden = ModuleConfig {
+            epp_on_no_header: Some(EppOnNoHeader::Error),
+            ..Default::default()
+        };
+        overridden.merge(&parent).unwrap();
+        assert_eq!(overridden.epp_on_no_header, Some(EppOnNoHeader::Error));
+    }
+
+    #[test]
+    fn test_merge_keeps_explicit_zero_epp_timeout() {
+        let mut parent = ModuleConfig::unset();
+        parent.merge(&ModuleConfig::unset()).unwrap();
+        assert_eq!(parent.epp_timeout_ms, 200);
+
+        let mut zero = ModuleConfig {
+            epp_timeout_ms: 0,
+            ..ModuleConfig::unset()
+        };
+        zero.merge(&parent).unwrap();
+        assert_eq!(zero.epp_timeout_ms, 0);
+
+        let mut inheriting = ModuleConfig::unset();
+        inheriting.merge(&zero).unwrap();
+        assert_eq!(inheriting.epp_timeout_ms, 0);
+    }
+
+    #[test]
+    fn test_merge_precedence_location_over_server_over_main() {
+        // http {} level
+        let mut main = ModuleConfig {
+            epp_timeout_ms: 1000,
+            epp_endpoint: Some("main-epp:9002".to_string()),
+            epp_fallback_endpoint: Some("dr-epp:9002".to_string()),
+            default_upstream: Some("main-default:8000".to_string()),
+            ..ModuleConfig::unset()
+        };
+        main.merge(&ModuleConfig::unset()).unwrap();
+
+        // server {} level, merged as in merge_srv_conf
+        let mut server = ModuleConfig {
+            epp_enable: Some(true),
+            epp_endpoint: Some("server-epp:9002".to_string()),
+            epp_header_name: "x-gateway-destination-endpoint".to_string(),
+            ..ModuleConfig::unset()
+        };
+        server.merge(&main).unwrap();
+
+        // location {} overriding the server endpoint and timeout
+        let mut overriding = ModuleConfig {
+            epp_endpoint: Some("location-epp:9002".to_string()),
+            epp_timeout_ms: 50,
+            ..ModuleConfig::unset()
+        };
+        overriding.merge(&server).unwrap();
+        assert_eq!(
+            overriding.epp_endpoint.as_deref(),
+            Some("location-epp:9002")
+        );
+        assert_eq!(overriding.epp_timeout_ms, 50);
+        assert_eq!(overriding.epp_header_name, "x-gateway-destination-endpoint");
+        assert_eq!(overriding.epp_enable, Some(true));
+
+        // location {} without overrides inherits server, then main, then built-in defaults
+        let mut inheriting = ModuleConfig::unset();
+        inheriting.merge(&server).unwrap();
+        assert_eq!(inheriting.epp_endpoint.as_deref(), Some("server-epp:9002"));
+        assert_eq!(
+            inheriting.epp_fallback_endpoint.as_deref(),
+            Some("dr-epp:9002")
+        );
+        assert_eq!(inheriting.epp_timeout_ms, 1000);
+        assert_eq!(
+            inheriting.default_upstream.as_deref(),
+            Some("main-default:8000")
+        );
+        assert_eq!(inheriting.max_body_size, 10 * 1024 * 1024);
+        assert_eq!(inheriting.upstream_max_len, 512);
+        assert_eq!(inheriting.bbr_header_name, "X-Gateway-Model-Name");
+        assert_eq!(inheriting.epp_max_headers, 100);
+    }
+
+    #[test]
+    fn test_log_body_spill_location_off() {
+        let server = ModuleConfig {
+            log_body_spill: Some(true),
+            ..Default::default()
+        };
+        let mut location = ModuleConfig {
+            log_body_spill: Some(false),
+            ..Default::default()
+        };
+        location.merge(&server).unwrap();
+        assert_eq!(location.log_body_spill, Some(false));
+
+        let mut inheriting = ModuleConfig::default();
+        inheriting.merge(&server).unwrap();
+        assert_eq!(inheriting.log_body_spill, Some(true));
+    }
+
+    #[test]
+    fn test_location_turns_off_server_flags() {
+        use ngx::http::Merge;
+
+        let mut server = ModuleConfig {
+            bbr_enable: Some(true),
+            epp_enable: Some(true),
+            epp_failure_mode_allow: Some(true),
+            epp_skip_if_set: Some(false),
+            epp_endpoint: Some("server-epp:9002".to_string()),
+            ..ModuleConfig::unset()
+        };
+        server.merge(&ModuleConfig::unset()).unwrap();
+
+        // location {} with `inference_bbr off; inference_epp off;
+        // inference_epp_failure_mode_allow off; inference_epp_skip_if_set on;`
+        let mut location = ModuleConfig {
+            bbr_enable: Some(false),
+            epp_enable: Some(false),
+            epp_failure_mode_allow: Some(false),
+            epp_skip_if_set: Some(true),
+            ..ModuleConfig::unset()
+        };
+        location.merge(&server).unwrap();
+        assert_eq!(location.bbr_enable, Some(false));
+        assert_eq!(location.epp_enable, Some(false));
+        assert_eq!(location.epp_failure_mode_allow, Some(false));
+        assert_eq!(location.epp_skip_if_set, Some(true));
+
+        // A location that sets none of them keeps the server's values
+        let mut inheriting = ModuleConfig::unset();
+        inheriting.merge(&server).unwrap();
+        assert_eq!(inheriting.bbr_enable, Some(true));
+        assert_eq!(inheriting.epp_enable, Some(true));
+        assert_eq!(inheriting.epp_failure_mode_allow, Some(true));
+        assert_eq!(inheriting.epp_skip_if_set, Some(false));
+    }
+
+    #[test]
+    fn test_cache_ttl_ms_zero_turns_off_inherited() {
+        let mut server = ModuleConfig {
+            epp_cache_ttl_ms: 5000,
+            ..ModuleConfig::unset()
+        };
+        server.merge(&ModuleConfig::unset()).unwrap();
+
+        // `inference_epp_cache_ttl_ms 0` in a location turns it off again
+        let mut location = ModuleConfig {
+            epp_cache_ttl_ms: 0,
+            ..ModuleConfig::unset()
+        };
+        location.merge(&server).unwrap();
+        assert_eq!(location.epp_cache_ttl_ms, 0);
+
+        let mut inheriting = ModuleConfig::unset();
+        inheriting.merge(&server).unwrap();
+        assert_eq!(inheriting.epp_cache_ttl_ms, 5000);
+
+        // Unset everywhere: off
+        let mut unset = ModuleConfig::unset();
+        unset.merge(&ModuleConfig::unset()).unwrap();
+        assert_eq!(unset.epp_cache_ttl_ms, 0);
+    }
+
+    #[test]
+    fn test_sticky_ttl_ms_zero_turns_off_inherited() {
+        let mut server = ModuleConfig {
+            epp_sticky_ttl_ms: 5000,
+            ..ModuleConfig::unset()
+        };
+        server.merge(&ModuleConfig::unset()).unwrap();
+
+        // `inference_epp_sticky_ttl_ms 0` in a location turns it off again
+        let mut location = ModuleConfig {
+            epp_sticky_ttl_ms: 0,
+            ..ModuleConfig::unset()
+        };
+        location.merge(&server).unwrap();
+        assert_eq!(location.epp_sticky_ttl_ms, 0);
+
+        let mut inheriting = ModuleConfig::unset();
+        inheriting.merge(&server).unwrap();
+        assert_eq!(inheriting.epp_sticky_ttl_ms, 5000);
+
+        // Unset everywhere: off
+        let mut unset = ModuleConfig::unset();
+        unset.merge(&ModuleConfig::unset()).unwrap();
+        assert_eq!(unset.epp_sticky_ttl_ms, 0);
+    }
+}
@@ -0,0 +1,1162 @@
+//! Per-request module context shared by BBR and EPP.
+//!
+//! The context is stored in nginx's per-module request context slot
+//! (`r->ctx[ngx_http_inference_module.ctx_index]`) and allocated from the request pool with a
+//! cleanup handler, so it is dropped when the request is freed. Only the slot for this module is
+//! written; the `r->ctx` array itself is owned by nginx and must never be replaced.
+//!
+//! An internal redirect (`error_page`, `X-Accel-Redirect`, named locations) clears `r->ctx`.
+//! The context is found again through a marker entry in the request pool's cleanup list, as
+//! `ngx_http_realip_module` does, so the routing decision survives the redirect. Subrequests
+//! share their parent's pool, so only a context created for the same request is restored.
+
+use crate::epp::context::{current_time_ms, random_u64};
+use crate::epp::{header_allowed, limit_headers};
+use crate::logging::ngx_log_info_raw;
+use crate::modules::config::ModuleConfig;
+use crate::modules::response::ResponseCapture;
+use crate::Module;
+use ngx::ffi::ngx_http_request_t;
+use ngx::http::{HttpModule, HttpModuleLocationConf};
+use std::ffi::c_void;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Request body bytes held by the request contexts of this worker (`inference_total_body_memory`)
+static BODY_MEMORY: BodyBudget = BodyBudget::new();
+
+/// Per-request state for the inference module
+#[derive(Default)]
+pub struct RequestCtx {
+    /// Address of the request the context was created for; subrequests share the pool, and
+    /// so the cleanup list, of their parent
+    owner: usize,
+    /// Request body, populated by whichever stage (BBR or EPP) reads it first and dropped once
+    /// both are done with the request
+    body: Option<Arc<SharedBody>>,
+    /// Set when the body did not fit in `inference_total_body_memory`, so a later stage applies
+    /// `inference_total_body_memory_action` without reading it again
+    over_budget: bool,
+    /// Absolute `inference_request_deadline_ms` deadline (ms since the epoch), set on first entry
+    deadline_ms: Option<u64>,
+    /// Routing header value removed from `headers_in` by `inference_strip_upstream_header`
+    stripped_upstream: Option<String>,
+    /// Set once the module wrote the routing header (EPP, `inference_force_upstream` or a
+    /// default upstream), so `inference_strip_upstream_header` removes it with EPP off too
+    upstream_written: bool,
+    /// Bytes of request body read by BBR (`$inference_bbr_body_size`)
+    bbr_body_size: usize,
+    /// Upstream selected by the `inference_model_route` table
+    routed_upstream: Option<String>,
+    /// Set once BBR has processed the request, so a model header it wrote is not replaced again
+    bbr_done: bool,
+    /// Set once BBR/EPP have finished with the request, so an internal redirect reuses the
+    /// decision instead of running them again
+    processed: bool,
+    /// Set once a stage has called `ngx_http_read_client_request_body` for this request
+    body_read_started: bool,
+    /// When the access handler first ran for this request (ms since the epoch)
+    started_ms: Option<u64>,
+    /// When the request body was completely read
+    body_read_ms: Option<u64>,
+    /// When the EPP result (or its failure) arrived
+    epp_done_ms: Option<u64>,
+    /// Whether `inference_debug_sample_rate` picked this request, decided on first entry
+    debug_sampled: Option<bool>,
+    /// Response body copy while `inference_bbr_response` searches it for the model
+    response_capture: Option<ResponseCapture>,
+    /// Model found in the response body (`$inference_response_model`)
+    response_model: Option<String>,
+    /// EPP-set headers to add to the client response (`inference_epp_client_response_header`)
+    client_headers: Vec<(String, String)>,
+    /// Request headers read for the location config at this address, until the module
+    /// changes `headers_in`
+    headers: Option<(usize, Rc<RequestHeaders>)>,
+}
+
+/// Request headers BBR and EPP look up, read from `headers_in` in a single pass instead of
+/// one pass per lookup (see [`request_headers`])
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RequestHeaders {
+    /// Headers forwarded to EPP: allowed by `inference_epp_header_allow` and within
+    /// `inference_epp_max_headers` and `inference_epp_max_header_bytes`
+    pub forwarded: Vec<(String, Vec<u8>)>,
+    /// Headers left out of `forwarded` by the EPP limits
+    pub dropped: usize,
+    /// First BBR model header; `None` if absent or not UTF-8
+    pub model: Option<String>,
+    /// First EPP upstream header; `None` if absent or not UTF-8
+    pub upstream: Option<String>,
+}
+
+impl RequestHeaders {
+    /// Read the `(name, value)` pairs of `headers_in` once for `conf`. Every header is visited
+    /// for the lookups; only the forwarded ones within the EPP limits are copied.
+    pub fn collect<'a>(
+        headers: impl IntoIterator<Item = (&'a [u8], &'a [u8])>,
+        conf: &ModuleConfig,
+    ) -> Self {
+        let model_header = match conf.bbr_header_name.as_str() {
+            "" => "X-Gateway-Model-Name",
+            name => name,
+        };
+        let upstream_header = match conf.epp_header_name.as_str() {
+            "" => "X-Inference-Upstream",
+            name => name,
+        };
+        // Like a lookup by name: the first header of that name wins, even if not UTF-8
+        let mut model = None;
+        let mut upstream = None;
+        let first = |found: &mut Option<Option<String>>, value: &[u8]| {
+            if found.is_none() {
+                *found = Some(std::str::from_utf8(value).ok().map(str::to_string));
+            }
+        };
+
+        let forwardable = headers
+            .into_iter()
+            .filter_map(|(name, value)| {
+                // Headers with non-UTF-8 names are neither looked up nor forwarded
+                let name = std::str::from_utf8(name).ok()?;
+                if name.eq_ignore_ascii_case(model_header) {
+                    first(&mut model, value);
+                }
+                if name.eq_ignore_ascii_case(upstream_header) {
+                    first(&mut upstream, value);
+                }
+                Some((name, value))
+            })
+            .filter(|(name, _)| {
+                conf.epp_send_headers.unwrap_or(true)
+                    && header_allowed(&conf.epp_header_allow, name)
+            })
+            .map(|(name, value)| (name.to_string(), value.to_vec()));
+        let (forwarded, dropped) =
+            limit_headers(forwardable, conf.epp_max_headers, conf.epp_max_header_bytes);
+
+        Self {
+            forwarded,
+            dropped,
+            model: model.flatten(),
+            upstream: upstream.flatten(),
+        }
+    }
+}
+
+/// What a stage should do about the request body, see [`RequestCtx::begin_body_read`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyRead {
+    /// No stage has started the read; this stage calls `ngx_http_read_client_request_body`
+    Initiate,
+    /// Another stage has read the whole body; use it without reading again
+    Reuse,
+    /// Another stage is still reading the body; its handler owns the request until it is done
+    InProgress,
+}
+
+/// Byte budget shared by the request bodies held in memory at the same time
+#[derive(Default)]
+pub struct BodyBudget {
+    in_use: AtomicUsize,
+}
+
+impl BodyBudget {
+    pub const fn new() -> Self {
+        Self {
+            in_use: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bytes currently reserved
+    pub fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::Relaxed)
+    }
+
+    /// Reserve `bytes` if that keeps the total within `limit`; the bytes are returned when the
+    /// reservation is dropped
+    pub fn try_reserve(&'static self, bytes: usize, limit: usize) -> Option<BodyReservation> {
+        self.in_use
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_use| {
+                in_use.checked_add(bytes).filter(|total| *total <= limit)
+            })
+            .ok()
+            .map(|_| BodyReservation {
+                budget: self,
+                bytes,
+            })
+    }
+}
+
+/// Bytes reserved from a [`BodyBudget`] for one request body
+pub struct BodyReservation {
+    budget: &'static BodyBudget,
+    bytes: usize,
+}
+
+impl BodyReservation {
+    /// Return the bytes beyond `bytes` to the budget, once the copy turned out smaller
+    fn shrink_to(&mut self, bytes: usize) {
+        if bytes < self.bytes {
+            self.budget
+                .in_use
+                .fetch_sub(self.bytes - bytes, Ordering::AcqRel);
+            self.bytes = bytes;
+        }
+    }
+}
+
+impl Drop for BodyReservation {
+    fn drop(&mut self) {
+        self.budget.in_use.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+/// Request body copy shared by BBR, EPP and the EPP task. Its share of
+/// `inference_total_body_memory` is returned when the last of them drops it.
+pub struct SharedBody {
+    bytes: Vec<u8>,
+    _reservation: Option<BodyReservation>,
+}
+
+impl SharedBody {
+    fn new(bytes: Vec<u8>, mut reservation: Option<BodyReservation>) -> Self {
+        if let Some(reservation) = &mut reservation {
+            reservation.shrink_to(bytes.len());
+        }
+        Self {
+            bytes,
+            _reservation: reservation,
+        }
+    }
+}
+
+/// A body not charged to any budget
+impl From<Vec<u8>> for SharedBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::new(bytes, None)
+    }
+}
+
+impl std::ops::Deref for SharedBody {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// What nginx holds for a request body whose read has been started, see [`body_presence`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyPresence {
+    /// `r->request_body` is null: nginx never set up the read, so there is no body to use
+    Missing,
+    /// The read completed but produced no buffers (zero-length body)
+    Empty,
+    /// The body is held in `r->request_body->bufs`
+    Buffered,
+}
+
+/// Classify a request body from its `r->request_body` and `bufs` pointers.
+///
+/// A null `request_body` after a stage initiated the read means the read never happened,
+/// which is an error; a null `bufs` is an empty body and is processed normally.
+pub fn body_presence(request_body_null: bool, bufs_null: bool) -> BodyPresence {
+    if request_body_null {
+        BodyPresence::Missing
+    } else if bufs_null {
+        BodyPresence::Empty
+    } else {
+        BodyPresence::Buffered
+    }
+}
+
+/// [`body_presence`] of the body of `r`
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn request_body_presence(r: *mut ngx_http_request_t) -> BodyPresence {
+    let request_body = unsafe { (*r).request_body };
+    let bufs_null = request_body.is_null() || unsafe { (*request_body).bufs }.is_null();
+    body_presence(request_body.is_null(), bufs_null)
+}
+
+impl RequestCtx {
+    /// Context of the request at address `owner`
+    fn new(owner: usize) -> Self {
+        Self {
+            owner,
+            ..Self::default()
+        }
+    }
+
+    /// Whether the context was created for the request at address `r`, not for a request
+    /// sharing its pool
+    fn owned_by(&self, r: usize) -> bool {
+        self.owner == r
+    }
+
+    /// Return the cached body, reading it with `read` on first use once its `len` bytes are
+    /// charged to `budget`, which allows at most `limit` bytes (0 = unlimited).
+    ///
+    /// `Ok(None)` if the body does not fit, now or when an earlier stage tried: it is then
+    /// never copied. Read errors are not cached, so a failed read is retried by the next stage.
+    pub fn body_or_read<E>(
+        &mut self,
+        len: usize,
+        budget: &'static BodyBudget,
+        limit: usize,
+        read: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<Option<Arc<SharedBody>>, E> {
+        if let Some(body) = &self.body {
+            return Ok(Some(Arc::clone(body)));
+        }
+        if self.over_budget {
+            return Ok(None);
+        }
+        let reservation = match limit {
+            0 => None,
+            _ => match budget.try_reserve(len, limit) {
+                Some(reservation) => Some(reservation),
+                None => {
+                    self.over_budget = true;
+                    return Ok(None);
+                }
+            },
+        };
+        let body = Arc::new(SharedBody::new(read()?, reservation));
+        self.body = Some(Arc::clone(&body));
+        Ok(Some(body))
+    }
+
+    /// Return the request headers read for `conf_id` (the location config address), reading
+    /// them with `collect` on first use or after [`RequestCtx::invalidate_headers`].
+    pub fn headers_or_collect(
+        &mut self,
+        conf_id: usize,
+        collect: impl FnOnce() -> RequestHeaders,
+    ) -> Rc<RequestHeaders> {
+        match &self.headers {
+            Some((id, headers)) if *id == conf_id => Rc::clone(headers),
+            _ => {
+                let headers = Rc::new(collect());
+                self.headers = Some((conf_id, Rc::clone(&headers)));
+                headers
+            }
+        }
+    }
+
+    /// Forget the request headers read so far, after the module changed `headers_in`
+    pub fn invalidate_headers(&mut self) {
+        self.headers = None;
+    }
+
+    /// Return the request deadline, starting it at `now_ms` on first use.
+    ///
+    /// The access handler runs again after an async body read, so only the first call starts
+    /// the clock. A `budget_ms` of 0 disables the deadline.
+    pub fn deadline_or_start(&mut self, now_ms: u64, budget_ms: u64) -> Option<u64> {
+        if self.deadline_ms.is_none() && budget_ms > 0 {
+            self.deadline_ms = Some(now_ms.saturating_add(budget_ms));
+        }
+        self.deadline_ms
+    }
+
+    /// Claim the request body read for the calling stage.
+    ///
+    /// `ngx_http_read_client_request_body` must be called at most once per request: a second
+    /// call replaces the first stage's post handler. Only the first caller gets
+    /// [`BodyRead::Initiate`]; later callers reuse the body or leave the read to its owner.
+    pub fn begin_body_read(&mut self, body_complete: bool) -> BodyRead {
+        if !self.body_read_started {
+            self.body_read_started = true;
+            BodyRead::Initiate
+        } else if body_complete {
+            BodyRead::Reuse
+        } else {
+            BodyRead::InProgress
+        }
+    }
+
+    /// Record that the module wrote the routing header to `headers_in`
+    pub fn mark_upstream_written(&mut self) {
+        self.upstream_written = true;
+    }
+
+    /// Whether the module wrote the routing header to `headers_in`
+    pub fn upstream_written(&self) -> bool {
+        self.upstream_written
+    }
+
+    /// Remember the routing header value after it has been stripped from `headers_in`
+    pub fn set_stripped_upstream(&mut self, upstream: String) {
+        self.stripped_upstream = Some(upstream);
+    }
+
+    /// Routing header value stripped from `headers_in`, if any
+    pub fn stripped_upstream(&self) -> Option<&str> {
+        self.stripped_upstream.as_deref()
+    }
+
+    /// Record the number of body bytes processed by BBR
+    pub fn set_bbr_body_size(&mut self, size: usize) {
+        self.bbr_body_size = size;
+    }
+
+    /// Body bytes processed by BBR; 0 if BBR did not run
+    pub fn bbr_body_size(&self) -> usize {
+        self.bbr_body_size
+    }
+
+    /// Remember the upstream selected by the static model route table
+    pub fn set_routed_upstream(&mut self, upstream: String) {
+        self.routed_upstream = Some(upstream);
+    }
+
+    /// Upstream selected by the static model route table, if any
+    pub fn routed_upstream(&self) -> Option<&str> {
+        self.routed_upstream.as_deref()
+    }
+
+    /// Decide once whether the request is traced at `rate`; re-entry keeps the first decision
+    pub fn sample_debug(&mut self, rate: f64, random: u64) -> bool {
+        *self
+            .debug_sampled
+            .get_or_insert_with(|| in_sample(rate, random))
+    }
+
+    /// Whether debug lines of the request are written regardless of the log level
+    pub fn debug_sampled(&self) -> bool {
+        self.debug_sampled.unwrap_or(false)
+    }
+
+    /// Record that BBR and EPP are done with the request, dropping the cached body (an EPP
+    /// task still sending it keeps its own reference until it finishes)
+    pub fn mark_processed(&mut self) {
+        self.processed = true;
+        self.body = None;
+    }
+
+    /// Whether BBR and EPP are done with the request
+    pub fn processed(&self) -> bool {
+        self.processed
+    }
+
+    /// Record that BBR has processed the request
+    pub fn mark_bbr_done(&mut self) {
+        self.bbr_done = true;
+    }
+
+    /// Whether BBR has processed the request
+    pub fn bbr_done(&self) -> bool {
+        self.bbr_done
+    }
+
+    /// Record when the access handler first ran; re-entry keeps the first time
+    pub fn mark_started(&mut self, now_ms: u64) {
+        self.started_ms.get_or_insert(now_ms);
+    }
+
+    /// Record when the request body was completely read
+    pub fn mark_body_read(&mut self, now_ms: u64) {
+        self.body_read_ms.get_or_insert(now_ms);
+    }
+
+    /// Record when the EPP call finished
+    pub fn mark_epp_done(&mut self, now_ms: u64) {
+        self.epp_done_ms.get_or_insert(now_ms);
+    }
+
+    /// Start searching the response body for the model
+    pub fn start_response_capture(&mut self, capture: ResponseCapture) {
+        self.response_capture = Some(capture);
+    }
+
+    /// Response body capture in progress, if any
+    pub fn response_capture(&mut self) -> Option<&mut ResponseCapture> {
+        self.response_capture.as_mut()
+    }
+
+    /// End the response body capture, dropping the copy, and keep the model found
+    pub fn finish_response_capture(&mut self, model: Option<String>) {
+        self.response_capture = None;
+        self.response_model = model;
+    }
+
+    /// Model found in the response body, if any
+    pub fn response_model(&self) -> Option<&str> {
+        self.response_model.as_deref()
+    }
+
+    /// Remember the EPP-set headers to copy to the client response
+    pub fn set_client_headers(&mut self, headers: Vec<(String, String)>) {
+        self.client_headers = headers;
+    }
+
+    /// EPP-set headers for the client response, empty unless EPP sent any configured ones
+    pub fn client_headers(&self) -> &[(String, String)] {
+        &self.client_headers
+    }
+
+    /// Time from the start of module processing until the body was read
+    /// (`$inference_bbr_ms`); 0 if the body was not read
+    pub fn bbr_ms(&self) -> u64 {
+        match (self.started_ms, self.body_read_ms) {
+            (Some(start), Some(read)) => read.saturating_sub(start),
+            _ => 0,
+        }
+    }
+
+    /// Time from the body read until the EPP result (`$inference_epp_rpc_ms`); 0 if EPP
+    /// did not run
+    pub fn epp_rpc_ms(&self) -> u64 {
+        match (self.body_read_ms, self.epp_done_ms) {
+            (Some(start), Some(done)) => done.saturating_sub(start),
+            _ => 0,
+        }
+    }
+}
+
+/// Whether a request with the random value `random` is in the `rate` fraction (0.0-1.0) picked
+/// by `inference_debug_sample_rate` or `inference_epp_log_response_sample`
+pub fn in_sample(rate: f64, random: u64) -> bool {
+    rate >= 1.0 || (random as f64 / u64::MAX as f64) < rate
+}
+
+/// Decide whether request `r` gets the `inference_debug_sample_rate` debug trace; 0 never does.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn sample_debug(r: *mut ngx_http_request_t, rate: f64) {
+    if rate <= 0.0 {
+        return;
+    }
+    if let Some(ctx) = unsafe { request_ctx(r) } {
+        ctx.sample_debug(rate, random_u64());
+    }
+}
+
+/// Cleanup handler of the pool entry whose `data` points at this module's request context.
+///
+/// It does nothing: the context is dropped by its own pool cleanup. The entry only lets
+/// [`find_request_ctx`] tell the context apart from other cleanup data.
+unsafe extern "C" fn request_ctx_marker(_data: *mut c_void) {}
+
+/// Find the request context of `r`, restoring it to this module's `r->ctx` slot when an
+/// internal redirect has cleared it. Null if none was created for `r` itself: the pool may also
+/// hold the contexts of its parent and sibling requests.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer with a non-null `ctx` array, in the NGINX worker thread.
+unsafe fn find_request_ctx(r: *mut ngx_http_request_t) -> *mut RequestCtx {
+    let slot = unsafe { (*r).ctx.add(Module::module().ctx_index) };
+    let existing = unsafe { *slot } as *mut RequestCtx;
+    if !existing.is_null() || unsafe { (*r).pool.is_null() } {
+        return existing;
+    }
+
+    let marker: unsafe extern "C" fn(*mut c_void) = request_ctx_marker;
+    let mut cln = unsafe { (*(*r).pool).cleanup };
+    while !cln.is_null() {
+        let entry = unsafe { &*cln };
+        if entry
+            .handler
+            .is_some_and(|handler| std::ptr::fn_addr_eq(handler, marker))
+            && unsafe { &*(entry.data as *const RequestCtx) }.owned_by(r as usize)
+        {
+            unsafe { *slot = entry.data };
+            return entry.data as *mut RequestCtx;
+        }
+        cln = entry.next;
+    }
+    std::ptr::null_mut()
+}
+
+/// This module's request context if it has been created, without creating it.
+///
+/// # Safety
+///
+/// `r` must be null or a valid request pointer, in the NGINX worker thread.
+pub unsafe fn existing_request_ctx<'a>(r: *mut ngx_http_request_t) -> Option<&'a RequestCtx> {
+    if r.is_null() || unsafe { (*r).ctx.is_null() } {
+        return None;
+    }
+    unsafe { find_request_ctx(r).as_ref() }
+}
+
+/// Get this module's request context, creating it on first use.
+///
+/// Returns `None` if the context could not be allocated from the request pool.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn request_ctx<'a>(r: *mut ngx_http_request_t) -> Option<&'a mut RequestCtx> {
+    if r.is_null() || unsafe { (*r).ctx.is_null() } {
+        return None;
+    }
+
+    let existing = unsafe { find_request_ctx(r) };
+    if !existing.is_null() {
+        return Some(unsafe { &mut *existing });
+    }
+
+    let pool = unsafe { ngx::core::Pool::from_ngx_pool((*r).pool) };
+    let ctx = pool.allocate(RequestCtx::new(r as usize));
+    if ctx.is_null() {
+        return None;
+    }
+    // Without the marker the context would be lost on an internal redirect; it is still
+    // usable until then
+    let cln = unsafe { ngx::ffi::ngx_pool_cleanup_add((*r).pool, 0) };
+    if !cln.is_null() {
+        unsafe {
+            (*cln).handler = Some(request_ctx_marker);
+            (*cln).data = ctx as *mut c_void;
+        }
+    }
+    unsafe { *(*r).ctx.add(Module::module().ctx_index) = ctx as *mut c_void };
+    Some(unsafe { &mut *ctx })
+}
+
+/// Read the request body once per request and share it between BBR and EPP, charging the
+/// `len` bytes `read` copies to the worker's `inference_total_body_memory` budget of `limit`
+/// bytes first (see [`RequestCtx::body_or_read`]). `Ok(None)` if the body does not fit.
+///
+/// Falls back to an uncached, uncounted read if the request context cannot be allocated.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn cached_body<E>(
+    r: *mut ngx_http_request_t,
+    len: usize,
+    limit: usize,
+    read: impl FnOnce() -> Result<Vec<u8>, E>,
+) -> Result<Option<Arc<SharedBody>>, E> {
+    // Only the stage that actually reads the body reports a spill, so it is logged once
+    let read = || {
+        let body = read()?;
+        unsafe { log_body_spill(r) };
+        Ok(body)
+    };
+    match unsafe { request_ctx(r) } {
+        Some(ctx) => {
+            let body = ctx.body_or_read(len, &BODY_MEMORY, limit, read)?;
+            if body.is_some() {
+                ctx.mark_body_read(current_time_ms());
+            }
+            Ok(body)
+        }
+        None => read().map(|bytes| Some(Arc::new(bytes.into()))),
+    }
+}
+
+/// Bytes of the body of `r` held by nginx, in memory and in the temp file, so they can be
+/// charged before a stage copies them
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn request_body_len(r: *mut ngx_http_request_t) -> usize {
+    let request_body = unsafe { (*r).request_body };
+    if request_body.is_null() {
+        return 0;
+    }
+    let mut len = 0usize;
+    let mut cl = unsafe { (*request_body).bufs };
+    while !cl.is_null() {
+        let buf = unsafe { (*cl).buf };
+        if !buf.is_null() {
+            let buf = unsafe { &*buf };
+            if !buf.pos.is_null() && buf.last > buf.pos {
+                len = len.saturating_add(unsafe { buf.last.offset_from(buf.pos) } as usize);
+            }
+            if !buf.file.is_null() && buf.file_last > buf.file_pos {
+                len = len.saturating_add((buf.file_last - buf.file_pos) as usize);
+            }
+        }
+        cl = unsafe { (*cl).next };
+    }
+    len
+}
+
+/// Request headers of `r` for `conf`, read from `headers_in` once and shared by BBR and EPP
+/// until the module changes `headers_in` (see [`invalidate_headers`]).
+///
+/// Falls back to an uncached read if the request context cannot be allocated.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn request_headers(
+    r: *mut ngx_http_request_t,
+    conf: &ModuleConfig,
+) -> Rc<RequestHeaders> {
+    let collect = || {
+        let request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
+        RequestHeaders::collect(
+            request
+                .headers_in_iterator()
+                .map(|(name, value)| (name.as_bytes(), value.as_bytes())),
+            conf,
+        )
+    };
+    match unsafe { request_ctx(r) } {
+        Some(ctx) => ctx.headers_or_collect(conf as *const ModuleConfig as usize, collect),
+        None => Rc::new(collect()),
+    }
+}
+
+/// Forget the request headers read for `r` after the module added or changed a header in
+/// `headers_in`, so the next [`request_headers`] call sees the change.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn invalidate_headers(r: *mut ngx_http_request_t) {
+    if let Some(ctx) = unsafe { request_ctx(r) } {
+        ctx.invalidate_headers();
+    }
+}
+
+/// Record that the module wrote the routing header of `r`, see
+/// [`RequestCtx::mark_upstream_written`].
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn mark_upstream_written(r: *mut ngx_http_request_t) {
+    if let Some(ctx) = unsafe { request_ctx(r) } {
+        ctx.mark_upstream_written();
+    }
+}
+
+/// Bytes of the body of `r` that nginx wrote to a temp file because it did not fit in
+/// `client_body_buffer_size`; `None` if the body is held in memory
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn body_spill_size(r: *mut ngx_http_request_t) -> Option<u64> {
+    let request_body = unsafe { (*r).request_body };
+    if request_body.is_null() {
+        return None;
+    }
+    let temp_file = unsafe { (*request_body).temp_file };
+    if temp_file.is_null() {
+        return None;
+    }
+    Some(unsafe { (*temp_file).file.offset }.max(0) as u64)
+}
+
+/// Log at info level that the body of `r` was read from a temp file (`inference_log_body_spill`)
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+unsafe fn log_body_spill(r: *mut ngx_http_request_t) {
+    let request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
+    if !Module::location_conf(request).is_some_and(|conf| conf.log_body_spill.unwrap_or_default()) {
+        return;
+    }
+    if let Some(size) = unsafe { body_spill_size(r) } {
+        ngx_log_info_raw!(
+            r,
+            "ngx-inference: request body read from a {} byte temp file; client_body_buffer_size is smaller than the body",
+            size
+        );
+    }
+}
+
+/// Claim the request body read for the calling stage of `r`, see [`RequestCtx::begin_body_read`].
+///
+/// Without a request context the body is read only if no stage has allocated `request_body` yet.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn begin_body_read(r: *mut ngx_http_request_t) -> BodyRead {
+    let request_body = unsafe { (*r).request_body };
+    let body_complete = !request_body.is_null() && unsafe { (*request_body).rest } == 0;
+    match unsafe { request_ctx(r) } {
+        Some(ctx) => ctx.begin_body_read(body_complete),
+        None if request_body.is_null() => BodyRead::Initiate,
+        None if body_complete => BodyRead::Reuse,
+        None => BodyRead::InProgress,
+    }
+}
+
+/// Record that BBR and EPP are done with request `r`, see [`RequestCtx::mark_processed`].
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn mark_processed(r: *mut ngx_http_request_t) {
+    if let Some(ctx) = unsafe { request_ctx(r) } {
+        ctx.mark_processed();
+    }
+}
+
+/// Whether BBR and EPP already finished with `r` before an internal redirect.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn already_processed(r: *mut ngx_http_request_t) -> bool {
+    unsafe { existing_request_ctx(r) }.is_some_and(RequestCtx::processed)
+}
+
+/// Record the current time with `mark` on the request context of `r`, if it has one.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn mark_time(r: *mut ngx_http_request_t, mark: fn(&mut RequestCtx, u64)) {
+    if let Some(ctx) = unsafe { request_ctx(r) } {
+        mark(ctx, current_time_ms());
+    }
+}
+
+/// Absolute request deadline for `r`, started on the first call for this request.
+///
+/// Returns `None` if no deadline is configured or the request context cannot be allocated.
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+pub unsafe fn request_deadline(r: *mut ngx_http_request_t, budget_ms: u64) -> Option<u64> {
+    if budget_ms == 0 {
+        return None;
+    }
+    unsafe { request_ctx(r) }.and_then(|ctx| ctx.deadline_or_start(current_time_ms(), budget_ms))
+}
+
+/// Whether `deadline_ms` has passed at `now_ms`
+pub fn deadline_exceeded(deadline_ms: Option<u64>, now_ms: u64) -> bool {
+    deadline_ms.is_some_and(|deadline| now_ms > deadline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_ctx_only_owned_by_its_request() {
+        // A subrequest sharing the parent's pool must not pick up the parent's context
+        let parent = RequestCtx::new(0x1000);
+        assert!(parent.owned_by(0x1000));
+        assert!(!parent.owned_by(0x2000));
+    }
+
+    static UNUSED_BUDGET: BodyBudget = BodyBudget::new();
+
+    /// `body_or_read` without an `inference_total_body_memory` limit
+    fn read_body<E>(
+        ctx: &mut RequestCtx,
+        read: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<Arc<SharedBody>, E> {
+        ctx.body_or_read(0, &UNUSED_BUDGET, 0, read)
+            .map(|body| body.expect("unlimited budget"))
+    }
+
+    #[test]
+    fn test_bbr_then_epp_reads_body_once() {
+        let reads = Cell::new(0);
+        let read = || -> Result<Vec<u8>, ()> {
+            reads.set(reads.get() + 1);
+            Ok(br#"{"model":"llama"}"#.to_vec())
+        };
+        let mut ctx = RequestCtx::default();
+
+        // BBR reads first, EPP reuses the cached body
+        let bbr_body = read_body(&mut ctx, read).unwrap();
+        let epp_body = read_body(&mut ctx, || -> Result<Vec<u8>, ()> {
+            panic!("body read twice")
+        })
+        .unwrap();
+
+        assert_eq!(reads.get(), 1);
+        assert_eq!(&**bbr_body, br#"{"model":"llama"}"#);
+        assert!(Arc::ptr_eq(&bbr_body, &epp_body));
+    }
+
+    #[test]
+    fn test_total_body_memory_budget() {
+        static BUDGET: BodyBudget = BodyBudget::new();
+        const MB: usize = 1024 * 1024;
+        let with_body = |ctx: &mut RequestCtx, len: usize| {
+            ctx.body_or_read(len, &BUDGET, 10 * MB, || -> Result<Vec<u8>, ()> {
+                Ok(vec![0; len])
+            })
+            .unwrap()
+            .is_some()
+        };
+
+        // Three concurrent 4MB bodies against a 10MB budget: the third does not fit
+        let (mut first, mut second, mut third) = Default::default();
+        assert!(with_body(&mut first, 4 * MB));
+        assert!(with_body(&mut second, 4 * MB));
+        assert!(!with_body(&mut third, 4 * MB));
+        assert_eq!(BUDGET.in_use(), 8 * MB);
+
+        // The body is charged before it is copied, so an over-budget body is never read, and
+        // the later stage does not try again
+        let unread = || -> Result<Vec<u8>, ()> { panic!("over-budget body read") };
+        assert!(third.body.is_none());
+        assert!(third
+            .body_or_read(4 * MB, &BUDGET, 10 * MB, unread)
+            .unwrap()
+            .is_none());
+
+        // Reading again for the same body is served from the cache and charges nothing
+        assert!(with_body(&mut first, 4 * MB));
+        assert_eq!(BUDGET.in_use(), 8 * MB);
+
+        // Routing done: the body and its share of the budget are released, even though the
+        // context lives until the request ends
+        first.mark_processed();
+        assert_eq!(BUDGET.in_use(), 4 * MB);
+        let mut fourth = RequestCtx::default();
+        assert!(with_body(&mut fourth, 4 * MB));
+
+        // An EPP task still holding the body keeps it charged until it finishes
+        let in_flight = fourth.body.clone();
+        fourth.mark_processed();
+        assert_eq!(BUDGET.in_use(), 8 * MB);
+        drop(in_flight);
+        drop(second);
+        assert_eq!(BUDGET.in_use(), 0);
+
+        // Worker threads racing for the budget never overshoot it
+        let reserved: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| BUDGET.try_reserve(MB, 5 * MB)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(reserved.iter().flatten().count(), 5);
+        assert_eq!(BUDGET.in_use(), 5 * MB);
+        drop(reserved);
+        assert_eq!(BUDGET.in_use(), 0);
+    }
+
+    #[test]
+    fn test_body_presence_missing_vs_empty() {
+        // No request_body at all: the read was never set up
+        assert_eq!(body_presence(true, true), BodyPresence::Missing);
+        // request_body present without buffers: a zero-length body
+        assert_eq!(body_presence(false, true), BodyPresence::Empty);
+        assert_eq!(body_presence(false, false), BodyPresence::Buffered);
+    }
+
+    #[test]
+    fn test_epp_only_reads_and_caches_body() {
+        let mut ctx = RequestCtx::default();
+        let body = read_body(&mut ctx, || -> Result<Vec<u8>, ()> {
+            Ok(b"payload".to_vec())
+        })
+        .unwrap();
+
+        assert_eq!(&**body, b"payload");
+        assert_eq!(
+            ctx.body.as_deref().map(|body| &**body),
+            Some(&b"payload"[..])
+        );
+    }
+
+    #[test]
+    fn test_read_error_is_not_cached() {
+        let mut ctx = RequestCtx::default();
+        assert!(read_body(&mut ctx, || -> Result<Vec<u8>, &str> { Err("too large") }).is_err());
+        assert!(ctx.body.is_none());
+
+        let body = read_body(&mut ctx, || -> Result<Vec<u8>, &str> {
+            Ok(b"retry".to_vec())
+        })
+        .unwrap();
+        assert_eq!(&**body, b"retry");
+    }
+
+    #[test]
+    fn test_stage_timings() {
+        let mut ctx = RequestCtx::default();
+        assert_eq!(ctx.bbr_ms(), 0);
+        assert_eq!(ctx.epp_rpc_ms(), 0);
+
+        ctx.mark_started(1_000);
+        ctx.mark_body_read(1_040);
+        ctx.mark_epp_done(1_100);
+        // Re-entry after an async body read does not move the timestamps
+        ctx.mark_started(1_050);
+        ctx.mark_body_read(1_090);
+
+        assert_eq!(ctx.bbr_ms(), 40);
+        assert_eq!(ctx.epp_rpc_ms(), 60);
+
+        // EPP not run (or cache hit): no RPC time
+        let mut ctx = RequestCtx::default();
+        ctx.mark_started(1_000);
+        ctx.mark_body_read(1_010);
+        assert_eq!(ctx.epp_rpc_ms(), 0);
+    }
+
+    #[test]
+    fn test_deadline_started_once() {
+        let mut ctx = RequestCtx::default();
+        assert_eq!(ctx.deadline_or_start(1_000, 300), Some(1_300));
+        // Re-entry after an async body read keeps the original deadline
+        assert_eq!(ctx.deadline_or_start(5_000, 300), Some(1_300));
+    }
+
+    #[test]
+    fn test_deadline_disabled() {
+        let mut ctx = RequestCtx::default();
+        assert_eq!(ctx.deadline_or_start(1_000, 0), None);
+        assert!(!deadline_exceeded(None, u64::MAX));
+    }
+
+    #[test]
+    fn test_deadline_exceeded() {
+        assert!(!deadline_exceeded(Some(1_300), 1_200));
+        assert!(!deadline_exceeded(Some(1_300), 1_300));
+        assert!(deadline_exceeded(Some(1_300), 1_301));
+    }
+
+    #[test]
+    fn test_bbr_and_epp_never_double_initiate_body_read() {
+        let mut ctx = RequestCtx::default();
+
+        // BBR starts the read; EPP arrives while it is still in flight, then after it completes
+        assert_eq!(ctx.begin_body_read(false), BodyRead::Initiate);
+        assert_eq!(ctx.begin_body_read(false), BodyRead::InProgress);
+        assert_eq!(ctx.begin_body_read(true), BodyRead::Reuse);
+
+        // EPP-only requests read the body themselves, once
+        let mut epp_only = RequestCtx::default();
+        assert_eq!(epp_only.begin_body_read(false), BodyRead::Initiate);
+        assert_eq!(epp_only.begin_body_read(true), BodyRead::Reuse);
+    }
+
+    #[test]
+    fn test_bbr_body_size_defaults_to_zero() {
+        let mut ctx = RequestCtx::default();
+        assert_eq!(ctx.bbr_body_size(), 0);
+
+        ctx.set_bbr_body_size(1234);
+        assert_eq!(ctx.bbr_body_size(), 1234);
+    }
+
+    #[test]
+    fn test_debug_sample_rate_fraction() {
+        let sampled = (0..10_000).filter(|_| in_sample(0.1, random_u64())).count();
+        assert!((700..=1300).contains(&sampled), "sampled {}", sampled);
+
+        assert!(!in_sample(0.0, u64::MIN));
+        assert!(in_sample(1.0, u64::MAX));
+
+        // The first decision sticks for the rest of the request
+        let mut ctx = RequestCtx::default();
+        assert!(!ctx.debug_sampled());
+        assert!(ctx.sample_debug(1.0, 0));
+        assert!(ctx.sample_debug(0.0, 0));
+        assert!(ctx.debug_sampled());
+    }
+
+    #[test]
+    fn test_processed_flag() {
+        let mut ctx = RequestCtx::default();
+        assert!(!ctx.processed());
+        let body = read_body(&mut ctx, || -> Result<Vec<u8>, ()> {
+            Ok(b"payload".to_vec())
+        })
+        .unwrap();
+        ctx.mark_processed();
+        assert!(ctx.processed());
+        // The cached body is released; an in-flight EPP task keeps only its own reference
+        assert!(ctx.body.is_none());
+        assert_eq!(Arc::strong_count(&body), 1);
+    }
+
+    #[test]
+    fn test_request_headers_read_in_one_pass() {
+        // A pathological request: thousands of headers, the ones BBR and EPP need at the end
+        let mut names: Vec<String> = (0..5000).map(|i| format!("X-Filler-{}", i)).collect();
+        names.push("x-gateway-model-name".to_string());
+        names.push("X-Inference-Upstream".to_string());
+        names.push("X-Gateway-Model-Name".to_string());
+        let values: Vec<Vec<u8>> = (0..names.len())
+            .map(|i| match i {
+                5000 => b"llama".to_vec(),
+                5001 => b"10.0.0.1:8000".to_vec(),
+                _ => b"second".to_vec(),
+            })
+            .collect();
+        let visits = Cell::new(0);
+        let headers = || {
+            names
+                .iter()
+                .zip(&values)
+                .map(|(n, v)| (n.as_bytes(), v.as_slice()))
+                .inspect(|_| visits.set(visits.get() + 1))
+        };
+
+        let conf = ModuleConfig::default();
+        let collected = RequestHeaders::collect(headers(), &conf);
+        // Every header is visited exactly once, for all lookups and the EPP copy together
+        assert_eq!(visits.get(), 5003);
+        assert_eq!(collected.forwarded.len(), conf.epp_max_headers);
+        assert_eq!(collected.dropped, 5003 - conf.epp_max_headers);
+        // Lookups see headers past the EPP cap; the first of a name wins
+        assert_eq!(collected.model.as_deref(), Some("llama"));
+        assert_eq!(collected.upstream.as_deref(), Some("10.0.0.1:8000"));
+
+        // inference_epp_send_headers off: nothing is copied, lookups still work
+        let conf = ModuleConfig {
+            epp_send_headers: Some(false),
+            ..Default::default()
+        };
+        let collected = RequestHeaders::collect(headers(), &conf);
+        assert!(collected.forwarded.is_empty());
+        assert_eq!(collected.dropped, 0);
+        assert_eq!(collected.model.as_deref(), Some("llama"));
+
+        // A first model header that is not UTF-8 hides later ones, like a lookup by name
+        let invalid = [
+            (&b"X-Gateway-Model-Name"[..], &b"\xff"[..]),
+            (&b"X-Gateway-Model-Name"[..], &b"llama"[..]),
+        ];
+        let collected = RequestHeaders::collect(invalid, &ModuleConfig::default());
+        assert_eq!(collected.model, None);
+    }
+
+    #[test]
+    fn test_request_headers_reused_until_invalidated() {
+        let reads = Cell::new(0);
+        let collect = || {
+            reads.set(reads.get() + 1);
+            RequestHeaders {
+                model: Some("llama".to_string()),
+                ..Default::default()
+            }
+        };
+        let mut ctx = RequestCtx::default();
+
+        // BBR and EPP lookups share one read
+        let first = ctx.headers_or_collect(1, collect);
+        let second = ctx.headers_or_collect(1, collect);
+        assert_eq!(reads.get(), 1);
+        assert!(Rc::ptr_eq(&first, &second));
+
+        // A header written by the module, or another location config, reads them again
+        ctx.invalidate_headers();
+        ctx.headers_or_collect(1, collect);
+        assert_eq!(reads.get(), 2);
+        ctx.headers_or_collect(2, collect);
+        assert_eq!(reads.get(), 3);
+    }
+}
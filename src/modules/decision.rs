@@ -0,0 +1,198 @@
+//! What the access handler does after each pipeline stage, kept free of nginx state so the
+//! decision can be unit tested.
+//!
+//! The handler turns the status a stage returned into a [`StageOutcome`], and
+//! [`next_action`] decides whether the next stage runs, the request is suspended, or the
+//! handler ends the request.
+
+use crate::modules::config::Stage;
+use ngx::core;
+use ngx::ffi::{
+    ngx_int_t, ngx_uint_t, NGX_HTTP_INTERNAL_SERVER_ERROR, NGX_HTTP_REQUEST_ENTITY_TOO_LARGE,
+    NGX_HTTP_SPECIAL_RESPONSE,
+};
+
+/// How a BBR or EPP stage ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StageOutcome {
+    /// The stage is done or had nothing to do (`NGX_DECLINED`, `NGX_OK`)
+    Done,
+    /// The stage is reading the body or waiting for the EPP and resumes the request itself
+    /// (`NGX_DONE`)
+    Suspended,
+    /// `NGX_OK` after the stage already sent the response: BBR's 413 for an oversized body
+    Finalized,
+    /// The stage failed internally (`NGX_ERROR`)
+    Error,
+    /// The stage rejected the request with this HTTP status
+    Rejected(ngx_int_t),
+}
+
+impl StageOutcome {
+    /// Outcome for the `status` a stage returned.
+    ///
+    /// `response_status` reads the request's `headers_out.status`; it is only called for
+    /// `NGX_OK`, since after `NGX_DONE` the request may already have been freed.
+    pub fn from_status(status: core::Status, response_status: impl FnOnce() -> ngx_uint_t) -> Self {
+        match status {
+            core::Status::NGX_DONE => StageOutcome::Suspended,
+            core::Status::NGX_ERROR => StageOutcome::Error,
+            core::Status::NGX_OK
+                if response_status() == NGX_HTTP_REQUEST_ENTITY_TOO_LARGE as ngx_uint_t =>
+            {
+                StageOutcome::Finalized
+            }
+            status if status.0 >= NGX_HTTP_SPECIAL_RESPONSE as ngx_int_t => {
+                StageOutcome::Rejected(status.0)
+            }
+            _ => StageOutcome::Done,
+        }
+    }
+}
+
+/// What the access handler does next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Run the next stage, or let the request through after the last one
+    Continue,
+    /// Return `NGX_DONE`; the stage runs the phases again when it is done
+    Suspend,
+    /// End the request with this HTTP status
+    Fail(ngx_int_t),
+    /// The response was already sent; return `NGX_OK` so nginx does not send a second one
+    AlreadyFinalized,
+}
+
+impl Action {
+    /// Status for the access handler to return
+    pub fn status(self) -> core::Status {
+        match self {
+            Action::Continue => core::Status::NGX_DECLINED,
+            Action::Suspend => core::Status::NGX_DONE,
+            Action::Fail(status) => core::Status(status),
+            Action::AlreadyFinalized => core::Status::NGX_OK,
+        }
+    }
+}
+
+/// Next action after `stage` ended with `outcome`.
+///
+/// A BBR error is always a 500: BBR has no fail-open mode (a body that is not JSON is not an
+/// error; see `inference_bbr_on_parse_error`). An EPP error ends the request with
+/// `epp_failure_status` unless `epp_failure_mode_allow` is on.
+pub fn next_action(
+    stage: Stage,
+    outcome: StageOutcome,
+    epp_failure_mode_allow: bool,
+    epp_failure_status: ngx_int_t,
+) -> Action {
+    match (stage, outcome) {
+        (_, StageOutcome::Done) => Action::Continue,
+        (_, StageOutcome::Suspended) => Action::Suspend,
+        (_, StageOutcome::Finalized) => Action::AlreadyFinalized,
+        (_, StageOutcome::Rejected(status)) => Action::Fail(status),
+        (Stage::Bbr, StageOutcome::Error) => {
+            Action::Fail(NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t)
+        }
+        (Stage::Epp, StageOutcome::Error) if epp_failure_mode_allow => Action::Continue,
+        (Stage::Epp, StageOutcome::Error) => Action::Fail(epp_failure_status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOO_LARGE: ngx_uint_t = NGX_HTTP_REQUEST_ENTITY_TOO_LARGE as ngx_uint_t;
+
+    fn outcome(status: core::Status, response_status: ngx_uint_t) -> StageOutcome {
+        StageOutcome::from_status(status, || response_status)
+    }
+
+    #[test]
+    fn test_stage_outcome_from_status() {
+        assert_eq!(outcome(core::Status::NGX_DECLINED, 0), StageOutcome::Done);
+        assert_eq!(outcome(core::Status::NGX_OK, 0), StageOutcome::Done);
+        assert_eq!(outcome(core::Status::NGX_DONE, 0), StageOutcome::Suspended);
+        assert_eq!(outcome(core::Status::NGX_ERROR, 0), StageOutcome::Error);
+        // BBR's 413 was already sent; with inference_bbr_oversize_upstream the status is cleared
+        assert_eq!(
+            outcome(core::Status::NGX_OK, TOO_LARGE),
+            StageOutcome::Finalized
+        );
+        assert_eq!(
+            outcome(core::Status(507), 0),
+            StageOutcome::Rejected(507),
+            "inference_total_body_memory"
+        );
+        assert_eq!(outcome(core::Status(413), 0), StageOutcome::Rejected(413));
+    }
+
+    #[test]
+    fn test_response_status_not_read_after_suspend() {
+        let outcome = StageOutcome::from_status(core::Status::NGX_DONE, || {
+            panic!("request may be freed after NGX_DONE")
+        });
+        assert_eq!(outcome, StageOutcome::Suspended);
+    }
+
+    #[test]
+    fn test_next_action_matrix() {
+        let cases = [
+            // (stage, outcome, epp_failure_mode_allow, expected)
+            (Stage::Bbr, StageOutcome::Done, false, Action::Continue),
+            (Stage::Bbr, StageOutcome::Suspended, false, Action::Suspend),
+            (
+                Stage::Bbr,
+                StageOutcome::Finalized,
+                false,
+                Action::AlreadyFinalized,
+            ),
+            (Stage::Bbr, StageOutcome::Error, false, Action::Fail(500)),
+            // BBR errors are not governed by the EPP failure mode
+            (Stage::Bbr, StageOutcome::Error, true, Action::Fail(500)),
+            (
+                Stage::Bbr,
+                StageOutcome::Rejected(507),
+                true,
+                Action::Fail(507),
+            ),
+            (Stage::Epp, StageOutcome::Done, false, Action::Continue),
+            (Stage::Epp, StageOutcome::Suspended, false, Action::Suspend),
+            (
+                Stage::Epp,
+                StageOutcome::Finalized,
+                true,
+                Action::AlreadyFinalized,
+            ),
+            // Fail-open lets the request through, fail-closed uses inference_epp_failure_status
+            (Stage::Epp, StageOutcome::Error, true, Action::Continue),
+            (Stage::Epp, StageOutcome::Error, false, Action::Fail(503)),
+            // A rejection is final even when the EPP fails open
+            (
+                Stage::Epp,
+                StageOutcome::Rejected(507),
+                true,
+                Action::Fail(507),
+            ),
+        ];
+        for (stage, outcome, allow, expected) in cases {
+            assert_eq!(
+                next_action(stage, outcome, allow, 503),
+                expected,
+                "{:?} {:?} fail-open={}",
+                stage,
+                outcome,
+                allow
+            );
+        }
+    }
+
+    #[test]
+    fn test_action_status() {
+        assert_eq!(Action::Continue.status().0, core::Status::NGX_DECLINED.0);
+        assert_eq!(Action::Suspend.status().0, core::Status::NGX_DONE.0);
+        assert_eq!(Action::Fail(502).status().0, 502);
+        assert_eq!(Action::AlreadyFinalized.status().0, core::Status::NGX_OK.0);
+    }
+}
@@ -0,0 +1,96 @@
+//! Per-worker BBR and EPP counters, written to the error log when the worker exits with
+//! `inference_metrics_on_exit on` (for postmortems where nothing scrapes the worker).
+//!
+//! Counters start at zero in each worker process and are never reset.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A counted BBR or EPP outcome
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    /// BBR set the model header from the request
+    BbrModel,
+    /// BBR set `inference_bbr_default_model`
+    BbrDefault,
+    /// BBR rejected the request (oversized, empty or unparseable body)
+    BbrRejected,
+    /// The EPP selected an upstream
+    EppSelected,
+    /// The EPP call failed
+    EppFailure,
+    /// The EPP call timed out
+    EppTimeout,
+}
+
+impl Counter {
+    const ALL: [Counter; 6] = [
+        Counter::BbrModel,
+        Counter::BbrDefault,
+        Counter::BbrRejected,
+        Counter::EppSelected,
+        Counter::EppFailure,
+        Counter::EppTimeout,
+    ];
+
+    /// Name in the exit log line
+    fn name(self) -> &'static str {
+        match self {
+            Counter::BbrModel => "bbr_model",
+            Counter::BbrDefault => "bbr_default",
+            Counter::BbrRejected => "bbr_rejected",
+            Counter::EppSelected => "epp_selected",
+            Counter::EppFailure => "epp_failure",
+            Counter::EppTimeout => "epp_timeout",
+        }
+    }
+}
+
+static COUNTERS: [AtomicU64; 6] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Count one occurrence of `counter`
+pub fn increment(counter: Counter) {
+    COUNTERS[counter as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Current value of `counter` in this worker
+pub fn value(counter: Counter) -> u64 {
+    COUNTERS[counter as usize].load(Ordering::Relaxed)
+}
+
+/// All counters as `name=value` pairs, for the exit log line
+pub fn summary() -> String {
+    Counter::ALL
+        .iter()
+        .map(|&counter| format!("{}={}", counter.name(), value(counter)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_in_summary() {
+        let before = value(Counter::EppTimeout);
+        increment(Counter::EppTimeout);
+        increment(Counter::EppTimeout);
+        assert_eq!(value(Counter::EppTimeout), before + 2);
+
+        let summary = summary();
+        assert!(summary.starts_with("bbr_model="), "{}", summary);
+        assert!(
+            summary.contains(&format!("epp_timeout={}", before + 2)),
+            "{}",
+            summary
+        );
+        assert_eq!(summary.split(' ').count(), Counter::ALL.len());
+    }
+}
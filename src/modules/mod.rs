@@ -1,5 +1,9 @@
 pub mod bbr;
 pub mod config;
+pub mod ctx;
+pub mod decision;
+pub mod metrics;
+pub mod response;
 
 pub use bbr::{bbr_body_read_handler, BbrProcessor};
 pub use config::*;
@@ -0,0 +1,308 @@
+//! Response-side BBR: the model the backend reports in its response body, for
+//! `$inference_response_model` (`inference_bbr_response`), and the request's model header copied
+//! to the response (`inference_bbr_set_response_header`), and EPP-set headers copied to the
+//! response (`inference_epp_client_response_header`).
+//!
+//! The header filter decides whether a response is captured; the body filter copies each chain
+//! as it passes and hands it on unchanged, so the response is never held back. The copy is
+//! bounded by `inference_max_body_size` and dropped as soon as the model is known.
+
+use crate::logging::ngx_log_debug_raw;
+use crate::model_extractor::{extract_model_from_body, extract_model_from_stream, sanitize_model};
+use crate::modules::bbr::get_header_in;
+use crate::modules::ctx::{existing_request_ctx, request_ctx};
+use crate::Module;
+use ngx::ffi::{
+    ngx_chain_t, ngx_http_output_body_filter_pt, ngx_http_output_header_filter_pt,
+    ngx_http_request_t, ngx_int_t, NGX_OK,
+};
+use ngx::http::HttpModuleLocationConf;
+
+static mut NEXT_HEADER_FILTER: ngx_http_output_header_filter_pt = None;
+static mut NEXT_BODY_FILTER: ngx_http_output_body_filter_pt = None;
+
+/// Whether a response with this `Content-Type` is streamed (server-sent events or
+/// newline-delimited JSON) and so only captured with `inference_bbr_response_streaming on`
+pub fn is_streaming_response(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    media_type.eq_ignore_ascii_case("text/event-stream")
+        || media_type.eq_ignore_ascii_case("application/x-ndjson")
+}
+
+/// Result of feeding a response body chunk to a [`ResponseCapture`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Capture {
+    /// The model is not known yet; keep copying
+    More,
+    /// Capture is over, with the model if one was found
+    Done(Option<String>),
+}
+
+/// Copy of a response body being searched for the model
+pub struct ResponseCapture {
+    body: Vec<u8>,
+    /// `inference_max_body_size`; a longer body is abandoned without a model
+    limit: usize,
+    /// Streamed body: checked line by line as it arrives instead of once at the end
+    streaming: bool,
+    /// Bytes of a streamed body already searched (complete lines only)
+    scanned: usize,
+}
+
+impl ResponseCapture {
+    pub fn new(limit: usize, streaming: bool) -> Self {
+        Self {
+            body: Vec::new(),
+            limit,
+            streaming,
+            scanned: 0,
+        }
+    }
+
+    /// Append `chunk`; `last` marks the end of the response body
+    pub fn feed(&mut self, chunk: &[u8], last: bool) -> Capture {
+        if self.body.len().saturating_add(chunk.len()) > self.limit {
+            return Capture::Done(None);
+        }
+        self.body.extend_from_slice(chunk);
+
+        if self.streaming {
+            let pending = &self.body[self.scanned..];
+            if let Some(model) = extract_model_from_stream(pending) {
+                return Capture::Done(Some(model));
+            }
+            if let Some(end) = pending.iter().rposition(|&b| b == b'\n') {
+                self.scanned += end + 1;
+            }
+            // A final line without a newline is still an event
+            if last {
+                let mut rest = self.body[self.scanned..].to_vec();
+                rest.push(b'\n');
+                return Capture::Done(extract_model_from_stream(&rest));
+            }
+            return Capture::More;
+        }
+
+        if last {
+            Capture::Done(extract_model_from_body(&self.body))
+        } else {
+            Capture::More
+        }
+    }
+}
+
+/// Insert the response filters at the top of the filter chains
+///
+/// # Safety
+///
+/// Must only be called from postconfiguration, before any request is processed.
+pub unsafe fn init_filters() {
+    unsafe {
+        NEXT_HEADER_FILTER = ngx::ffi::ngx_http_top_header_filter;
+        ngx::ffi::ngx_http_top_header_filter = Some(response_header_filter);
+        NEXT_BODY_FILTER = ngx::ffi::ngx_http_top_body_filter;
+        ngx::ffi::ngx_http_top_body_filter = Some(response_body_filter);
+    }
+}
+
+unsafe extern "C" fn response_header_filter(r: *mut ngx_http_request_t) -> ngx_int_t {
+    unsafe { set_model_header_out(r) };
+    unsafe { set_epp_headers_out(r) };
+    unsafe { start_capture(r) };
+    match unsafe { NEXT_HEADER_FILTER } {
+        Some(next) => unsafe { next(r) },
+        None => NGX_OK as ngx_int_t,
+    }
+}
+
+unsafe extern "C" fn response_body_filter(
+    r: *mut ngx_http_request_t,
+    chain: *mut ngx_chain_t,
+) -> ngx_int_t {
+    unsafe { capture_chain(r, chain) };
+    match unsafe { NEXT_BODY_FILTER } {
+        Some(next) => unsafe { next(r, chain) },
+        None => NGX_OK as ngx_int_t,
+    }
+}
+
+/// Copy the BBR model header of `r` to its response with `inference_bbr_set_response_header on`
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+unsafe fn set_model_header_out(r: *mut ngx_http_request_t) {
+    let request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
+    let conf = match Module::location_conf(request) {
+        Some(conf)
+            if conf.bbr_enable.unwrap_or_default()
+                && conf.bbr_set_response_header.unwrap_or_default()
+                && request.is_main() =>
+        {
+            conf
+        }
+        _ => return,
+    };
+    let Some(model) = get_header_in(request, &conf.bbr_header_name).map(str::to_string) else {
+        return;
+    };
+    if request
+        .add_header_out(&conf.bbr_header_name, &model)
+        .is_none()
+    {
+        ngx_log_debug_raw!(
+            r,
+            "ngx-inference: failed to add BBR header {} to the response",
+            conf.bbr_header_name
+        );
+    }
+}
+
+/// Add the headers EPP set for the client (`inference_epp_client_response_header`) to the
+/// response of `r`
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+unsafe fn set_epp_headers_out(r: *mut ngx_http_request_t) {
+    let Some(ctx) = (unsafe { existing_request_ctx(r) }) else {
+        return;
+    };
+    let request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
+    for (name, value) in ctx.client_headers() {
+        if request.add_header_out(name, value).is_none() {
+            ngx_log_debug_raw!(
+                r,
+                "ngx-inference: failed to add EPP header {} to the response",
+                name
+            );
+        }
+    }
+}
+
+/// Start capturing the response body of `r` if `inference_bbr_response` applies to it
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer and this must be called in the NGINX worker thread.
+unsafe fn start_capture(r: *mut ngx_http_request_t) {
+    let request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
+    let conf = match Module::location_conf(request) {
+        Some(conf)
+            if conf.bbr_response.unwrap_or_default() && conf.applies_to(!request.is_main()) =>
+        {
+            conf
+        }
+        _ => return,
+    };
+    let headers_out = unsafe { &(*r).headers_out };
+
+    // Compressed bodies cannot be searched
+    let encoding = headers_out.content_encoding;
+    if !encoding.is_null() {
+        let value = unsafe { (*encoding).value };
+        if value.len > 0 && !value.as_bytes().eq_ignore_ascii_case(b"identity") {
+            return;
+        }
+    }
+    if headers_out.content_length_n > conf.max_body_size as i64 {
+        return;
+    }
+    let content_type = headers_out.content_type.to_str().unwrap_or_default();
+    let streaming = is_streaming_response(content_type);
+    if streaming && !conf.bbr_response_streaming.unwrap_or_default() {
+        ngx_log_debug_raw!(
+            r,
+            "ngx-inference: BBR response is streamed, not captured (inference_bbr_response_streaming off)"
+        );
+        return;
+    }
+
+    if let Some(ctx) = unsafe { request_ctx(r) } {
+        ctx.start_response_capture(ResponseCapture::new(conf.max_body_size, streaming));
+    }
+}
+
+/// Copy the memory buffers of `chain` into the response capture of `r`, if one is running
+///
+/// # Safety
+///
+/// `r` must be a valid request pointer, `chain` a valid (possibly null) chain, and this must
+/// be called in the NGINX worker thread.
+unsafe fn capture_chain(r: *mut ngx_http_request_t, chain: *mut ngx_chain_t) {
+    let request = unsafe { ngx::http::Request::from_ngx_http_request(r) };
+    if !Module::location_conf(request).is_some_and(|conf| conf.bbr_response.unwrap_or_default()) {
+        return;
+    }
+    let Some(ctx) = (unsafe { request_ctx(r) }) else {
+        return;
+    };
+    let Some(capture) = ctx.response_capture() else {
+        return;
+    };
+
+    let mut cl = chain;
+    while !cl.is_null() {
+        let buf = unsafe { (*cl).buf };
+        cl = unsafe { (*cl).next };
+        if buf.is_null() {
+            continue;
+        }
+        let buf = unsafe { &*buf };
+        let in_memory = buf.temporary() != 0 || buf.memory() != 0 || buf.mmap() != 0;
+        let chunk: &[u8] = if in_memory && !buf.pos.is_null() && buf.last > buf.pos {
+            unsafe { std::slice::from_raw_parts(buf.pos, buf.last.offset_from(buf.pos) as usize) }
+        } else if buf.in_file() != 0 {
+            // Responses buffered to a temp file are not read back
+            ctx.finish_response_capture(None);
+            return;
+        } else {
+            &[]
+        };
+        let last = buf.last_buf() != 0 || buf.last_in_chain() != 0;
+        if let Capture::Done(model) = capture.feed(chunk, last) {
+            let model = model.and_then(|model| sanitize_model(&model, false));
+            ngx_log_debug_raw!(r, "ngx-inference: BBR response model: {:?}", model);
+            ctx.finish_response_capture(model);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_capture_buffered_and_streamed() {
+        // A buffered JSON response is searched once it is complete
+        let mut capture = ResponseCapture::new(1024, false);
+        assert_eq!(capture.feed(br#"{"id":"x","model":"#, false), Capture::More);
+        assert_eq!(
+            capture.feed(br#""llama-3-8b"}"#, true),
+            Capture::Done(Some("llama-3-8b".to_string()))
+        );
+
+        // A streamed response ends the capture at the first event with a model
+        let mut capture = ResponseCapture::new(1024, true);
+        assert_eq!(
+            capture.feed(b": ping\n\ndata: {\"mod", false),
+            Capture::More
+        );
+        assert_eq!(
+            capture.feed(b"el\":\"qwen\"}\n\n", false),
+            Capture::Done(Some("qwen".to_string()))
+        );
+
+        // Bodies over inference_max_body_size are abandoned without a model
+        let mut capture = ResponseCapture::new(8, false);
+        assert_eq!(
+            capture.feed(br#"{"model":"llama"}"#, true),
+            Capture::Done(None)
+        );
+
+        assert!(is_streaming_response("text/event-stream; charset=utf-8"));
+        assert!(is_streaming_response("Application/X-NDJSON"));
+        assert!(!is_streaming_response("application/json"));
+    }
+}
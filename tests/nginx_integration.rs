@@ -0,0 +1,1766 @@
+//! End-to-end tests running a real nginx with the ngx-inference module loaded.
+//!
+//! These tests exercise the access-phase handler, directive parsing and the
+//! `$inference_upstream` variable through actual FFI, which unit tests cannot cover.
+//! They need a built module and an nginx binary compatible with it, so they are
+//! `#[ignore]`d by default and only compiled with the `extproc-mock` feature
+//! (the mock EPP server is spawned from the `extproc_mock` binary):
+//!
+//! ```bash
+//! make build
+//! NGINX_BIN=$(which nginx) cargo test --features extproc-mock --test nginx_integration -- --ignored
+//! ```
+//!
+//! Environment:
+//! - `NGINX_BIN`: nginx binary to run (default: `nginx`)
+//! - `NGX_INFERENCE_MODULE`: path to the built module (default: `target/debug/libngx_inference.so`)
+#![cfg(feature = "extproc-mock")]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Kills the wrapped process when dropped so a failing assertion doesn't leak nginx or the mock.
+struct ChildGuard(Child);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Running test environment: echo upstream, mock EPP and nginx.
+struct Harness {
+    nginx_port: u16,
+    echo_addr: SocketAddr,
+    prefix: PathBuf,
+    _mock: ChildGuard,
+    _nginx: ChildGuard,
+}
+
+impl Harness {
+    fn start(name: &str) -> Harness {
+        Harness::start_with_role(name, "EPP")
+    }
+
+    /// Starts the environment with the mock EPP running in the given `MOCK_ROLE`.
+    fn start_with_role(name: &str, role: &str) -> Harness {
+        Harness::start_with_mock_env(name, &[("MOCK_ROLE", role)])
+    }
+
+    /// Starts the environment with extra environment variables for the mock EPP.
+    fn start_with_mock_env(name: &str, mock_env: &[(&str, &str)]) -> Harness {
+        Harness::launch(name, mock_env, "")
+    }
+
+    /// Starts the environment with extra directives at the top of the `http {}` block.
+    fn start_with_http(name: &str, http_extra: &str) -> Harness {
+        Harness::launch(name, &[], http_extra)
+    }
+
+    fn launch(name: &str, mock_env: &[(&str, &str)], http_extra: &str) -> Harness {
+        let echo_addr = spawn_echo_upstream();
+        let mock_port = free_port();
+        let nginx_port = free_port();
+
+        let mock = Command::new(env!("CARGO_BIN_EXE_extproc_mock"))
+            .arg(format!("127.0.0.1:{}", mock_port))
+            .env("MOCK_ROLE", "EPP")
+            .envs(mock_env.iter().copied())
+            .env("EPP_UPSTREAM", echo_addr.to_string())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to spawn extproc_mock");
+        let mock = ChildGuard(mock);
+        wait_for_port(mock_port);
+
+        let prefix =
+            std::env::temp_dir().join(format!("ngx-inference-it-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&prefix);
+        std::fs::create_dir_all(prefix.join("logs")).expect("failed to create nginx prefix");
+        let conf_path = prefix.join("nginx.conf");
+        std::fs::write(
+            &conf_path,
+            nginx_config(&prefix, nginx_port, mock_port, echo_addr, http_extra),
+        )
+        .expect("failed to write nginx.conf");
+
+        let nginx_bin = std::env::var("NGINX_BIN").unwrap_or_else(|_| "nginx".to_string());
+        let nginx = Command::new(nginx_bin)
+            .arg("-p")
+            .arg(&prefix)
+            .arg("-c")
+            .arg(&conf_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .expect("failed to spawn nginx (set NGINX_BIN)");
+        let nginx = ChildGuard(nginx);
+        wait_for_port(nginx_port);
+
+        Harness {
+            nginx_port,
+            echo_addr,
+            prefix,
+            _mock: mock,
+            _nginx: nginx,
+        }
+    }
+
+    /// Sends a POST request and returns the status code and response body.
+    fn post(&self, path: &str, body: &str) -> (u16, String) {
+        self.post_slow(path, body, Duration::ZERO)
+    }
+
+    /// Like [`Harness::post`], but pauses for `pause` halfway through sending the body.
+    fn post_slow(&self, path: &str, body: &str, pause: Duration) -> (u16, String) {
+        let (status, _, body) = self.post_with_head(path, &[], body, pause);
+        (status, body)
+    }
+
+    /// Like [`Harness::post_slow`], with extra request headers, and also returns the response
+    /// status line and headers.
+    fn post_with_head(
+        &self,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+        pause: Duration,
+    ) -> (u16, String, String) {
+        let mut stream =
+            TcpStream::connect(("127.0.0.1", self.nginx_port)).expect("connect to nginx");
+        stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        let extra: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+             {}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            path,
+            extra,
+            body.len()
+        );
+        let (first, rest) = body.split_at(body.len() / 2);
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(first.as_bytes()).unwrap();
+        if !pause.is_zero() {
+            stream.flush().unwrap();
+            thread::sleep(pause);
+        }
+        stream.write_all(rest.as_bytes()).unwrap();
+        read_response(stream)
+    }
+
+    /// Like [`Harness::post`], but sends the request line, headers and body in a single write,
+    /// so nginx has the whole body buffered before the module's handler runs.
+    fn post_buffered(&self, path: &str, body: &str) -> (u16, String) {
+        let mut stream =
+            TcpStream::connect(("127.0.0.1", self.nginx_port)).expect("connect to nginx");
+        stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let (status, _, body) = read_response(stream);
+        (status, body)
+    }
+
+    fn error_log(&self) -> String {
+        std::fs::read_to_string(self.prefix.join("logs/error.log")).unwrap_or_default()
+    }
+}
+
+/// Reads a whole `Connection: close` response: status, status line and headers, and body.
+fn read_response(mut stream: TcpStream) -> (u16, String, String) {
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or_default();
+    (status, head.to_string(), body.to_string())
+}
+
+fn module_path() -> PathBuf {
+    std::env::var("NGX_INFERENCE_MODULE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("target/debug/libngx_inference.so")
+        })
+}
+
+/// Runs `nginx -t` on a minimal configuration with `server` as the only server block and
+/// returns whether the test passed, with nginx's output.
+fn nginx_config_check(name: &str, server: &str) -> (bool, String) {
+    let prefix =
+        std::env::temp_dir().join(format!("ngx-inference-it-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&prefix);
+    std::fs::create_dir_all(prefix.join("logs")).expect("failed to create nginx prefix");
+    let conf_path = prefix.join("nginx.conf");
+    std::fs::write(
+        &conf_path,
+        format!(
+            "load_module {module};\nerror_log {prefix}/logs/error.log info;\npid {prefix}/nginx.pid;\n\
+             events {{ worker_connections 64; }}\nhttp {{\n{server}\n}}\n",
+            module = module_path().display(),
+            prefix = prefix.display(),
+        ),
+    )
+    .expect("failed to write nginx.conf");
+
+    let nginx_bin = std::env::var("NGINX_BIN").unwrap_or_else(|_| "nginx".to_string());
+    let output = Command::new(nginx_bin)
+        .arg("-t")
+        .arg("-p")
+        .arg(&prefix)
+        .arg("-c")
+        .arg(&conf_path)
+        .output()
+        .expect("failed to run nginx -t (set NGINX_BIN)");
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+fn nginx_config(
+    prefix: &Path,
+    nginx_port: u16,
+    mock_port: u16,
+    echo: SocketAddr,
+    http_extra: &str,
+) -> String {
+    format!(
+        r#"load_module {module};
+
+daemon off;
+master_process off;
+worker_processes 1;
+error_log {prefix}/logs/error.log info;
+pid {prefix}/nginx.pid;
+
+events {{
+    worker_connections 64;
+}}
+
+http {{
+    access_log off;
+    client_body_temp_path {prefix}/client_body_temp;
+    proxy_temp_path {prefix}/proxy_temp;
+    fastcgi_temp_path {prefix}/fastcgi_temp;
+    scgi_temp_path {prefix}/scgi_temp;
+    uwsgi_temp_path {prefix}/uwsgi_temp;
+    {http_extra}
+
+    # Per-tenant EPP endpoint; tenant "none" has no EPP
+    map $http_x_tenant $epp_host {{
+        default "127.0.0.1:{mock_port}";
+        none "";
+    }}
+
+    server {{
+        listen 127.0.0.1:{nginx_port};
+
+        location /v1/chat/completions {{
+            inference_bbr on;
+            inference_bbr_default_model "default-model";
+
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_timeout_ms 5000;
+            # Forward the routing header so tests can see the EPP result upstream
+            inference_strip_upstream_header off;
+
+            proxy_set_header X-Bbr-Body-Size $inference_bbr_body_size;
+            proxy_set_header X-Bbr-Ms $inference_bbr_ms;
+            proxy_set_header X-Epp-Rpc-Ms $inference_epp_rpc_ms;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /epp-zero-timeout {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_timeout_ms 0;
+            inference_epp_failure_mode_allow off;
+            inference_strip_upstream_header off;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /no-header-continue {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_on_no_header continue;
+            proxy_set_header X-Bbr-Body-Size $inference_bbr_body_size;
+            proxy_pass http://{echo};
+        }}
+
+        location /no-header-default {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_on_no_header default;
+            inference_default_upstream "{echo}";
+            inference_strip_upstream_header off;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /no-header-error {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_on_no_header error;
+            proxy_pass http://{echo};
+        }}
+
+        location /deadline {{
+            inference_bbr on;
+            inference_request_deadline_ms 300;
+
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_timeout_ms 5000;
+            inference_strip_upstream_header off;
+
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /strip {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /gzip {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_grpc_compression gzip;
+            inference_strip_upstream_header off;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /version {{
+            return 200 "$inference_version";
+        }}
+
+        location /unresolved {{
+            return 200 "[$inference_upstream]";
+        }}
+
+        location /unresolved-fallback {{
+            inference_upstream_unresolved_value "blackhole";
+            return 200 "[$inference_upstream]";
+        }}
+
+        location /empty-body-default {{
+            inference_bbr on;
+            inference_bbr_default_model "default-model";
+            inference_bbr_empty_body default;
+            proxy_pass http://{echo};
+        }}
+
+        location /empty-body-skip {{
+            inference_bbr on;
+            inference_bbr_empty_body skip;
+            proxy_pass http://{echo};
+        }}
+
+        location /empty-body-reject {{
+            inference_bbr on;
+            inference_bbr_empty_body reject;
+            proxy_pass http://{echo};
+        }}
+
+        location /parse-error-skip {{
+            inference_bbr on;
+            inference_bbr_default_model "default-model";
+            inference_bbr_on_parse_error skip;
+            proxy_pass http://{echo};
+        }}
+
+        location /parse-error-reject {{
+            inference_bbr on;
+            inference_bbr_default_model "default-model";
+            inference_bbr_on_parse_error reject;
+            proxy_pass http://{echo};
+        }}
+
+        # A dead, fail-closed EPP: any EPP call would end in 502
+        location /force-upstream {{
+            inference_bbr on;
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:1";
+            inference_epp_tls off;
+            inference_epp_failure_mode_allow off;
+            inference_force_upstream "{echo}";
+            inference_strip_upstream_header off;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        # Forced upstream without EPP: the header the module wrote is still stripped
+        location /force-upstream-strip {{
+            inference_force_upstream "{echo}";
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /model-template {{
+            inference_bbr on;
+            inference_bbr_default_model "default-model";
+            inference_bbr_model_template "%provider%/%model%";
+            proxy_pass http://{echo};
+        }}
+
+        location /parse-prefix {{
+            inference_bbr on;
+            inference_bbr_default_model "default-model";
+            inference_bbr_parse_prefix_bytes 64;
+            proxy_pass http://{echo};
+        }}
+
+        location /route-table {{
+            inference_bbr on;
+            inference_epp off;
+            inference_model_route "llama-3-8b" "{echo}";
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /failure-status {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_timeout_ms 200;
+            inference_epp_failure_status 503;
+            inference_epp_timeout_status 599;
+            proxy_pass http://{echo};
+        }}
+
+        location /inference-off {{
+            inference off;
+            inference_bbr on;
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            proxy_set_header X-Bbr-Body-Size $inference_bbr_body_size;
+            proxy_pass http://{echo};
+        }}
+
+        location /epp-first {{
+            inference_pipeline_order epp-bbr;
+            inference_bbr on;
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_strip_upstream_header off;
+            proxy_set_header X-Bbr-Body-Size $inference_bbr_body_size;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /auth-sub {{
+            auth_request /auth-check;
+            proxy_pass http://{echo};
+        }}
+
+        location = /auth-check {{
+            internal;
+            # Fail-closed against a dead EPP: any attempt to process the subrequest fails it.
+            # Answered in the content phase: `return` would end it before the module runs.
+            inference_bbr on;
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:1";
+            inference_epp_tls off;
+            inference_epp_failure_mode_allow off;
+            proxy_pass http://{echo};
+        }}
+
+        # The main request is routed by BBR first, so its context exists in the pool the
+        # auth subrequest shares
+        location /auth-sub-processed {{
+            inference_bbr on;
+            auth_request /auth-check-processed;
+            proxy_pass http://{echo};
+        }}
+
+        location = /auth-check-processed {{
+            internal;
+            inference_process_subrequests on;
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:1";
+            inference_epp_tls off;
+            inference_epp_failure_mode_allow off;
+            proxy_pass http://{echo};
+        }}
+
+        location /models/ {{
+            inference_bbr on;
+            inference_bbr_model_path_regex "^/models/([^/]+)/";
+            proxy_set_header X-Bbr-Body-Size $inference_bbr_body_size;
+            proxy_pass http://{echo};
+        }}
+
+        location /decisions-info {{
+            inference_bbr on;
+            inference_log_decisions info;
+            inference_model_route "llama-3-8b" "{echo}";
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /decisions-off {{
+            inference_bbr on;
+            inference_log_decisions off;
+            inference_model_route "llama-3-8b" "{echo}";
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /bbr-oversize {{
+            inference_bbr on;
+            inference_max_body_size 64;
+            inference_bbr_oversize_upstream on;
+            inference_default_upstream "{echo}";
+            inference_strip_upstream_header off;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /body-spill {{
+            # Bodies over 1k are written to a temp file
+            client_body_buffer_size 1k;
+            inference_bbr on;
+            inference_log_body_spill on;
+            proxy_set_header X-Body-Spilled $inference_body_spilled;
+            proxy_set_header X-Bbr-Body-Size $inference_bbr_body_size;
+            proxy_pass http://{echo};
+        }}
+
+        location /upstream-allowed {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_upstream_allow "127.0.0.1:1";
+            inference_upstream_allow "{echo}";
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /upstream-not-allowed {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_failure_mode_allow off;
+            inference_upstream_allow "127.0.0.1:1";
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /epp-body-no-bbr {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_strip_upstream_header off;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /epp-body-no-bbr-limit {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_max_body_size 64;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /upstream-model-header {{
+            inference_bbr on;
+            inference_model_alias "openai/Llama-3-8B" "llama-3-8b";
+            inference_upstream_model_header X-Backend-Model;
+            proxy_pass http://{echo};
+        }}
+
+        location /bbr-overwrite {{
+            inference_bbr on;
+            inference_bbr_overwrite on;
+            proxy_pass http://{echo};
+        }}
+
+        location /bbr-response-header {{
+            inference_bbr on;
+            inference_bbr_set_response_header on;
+            proxy_pass http://{echo};
+        }}
+
+        location /epp-no-headers {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_send_headers off;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /epp-client-header {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_client_response_header x-served-model;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /epp-body-buffered {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_body_send_mode buffered;
+            inference_epp_client_response_header x-mock-body-chunks;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /epp-body-streamed {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_epp_body_send_mode streamed;
+            inference_epp_client_response_header x-mock-body-chunks;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /epp-endpoint-var {{
+            inference_epp on;
+            inference_epp_endpoint $epp_host;
+            inference_epp_tls off;
+            inference_strip_upstream_header off;
+            proxy_pass http://{echo};
+        }}
+
+        location /epp-endpoint-map/ {{
+            inference_epp on;
+            inference_epp_endpoint_map $uri /epp-endpoint-map/live "127.0.0.1:{mock_port}";
+            inference_epp_endpoint_map $uri /epp-endpoint-map/dead "127.0.0.1:1";
+            inference_epp_tls off;
+            inference_epp_failure_mode_allow on;
+            inference_strip_upstream_header off;
+            proxy_pass http://{echo};
+        }}
+
+        location /redirect-once {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_log_decisions info;
+            # The dead upstream's 502 redirects to a location that also runs EPP
+            error_page 502 = /redirect-target;
+            proxy_pass http://127.0.0.1:1;
+        }}
+
+        location /redirect-target {{
+            inference_epp on;
+            inference_epp_endpoint "127.0.0.1:{mock_port}";
+            inference_epp_tls off;
+            inference_log_decisions info;
+            proxy_pass http://$inference_upstream;
+        }}
+
+        location /bbr-limit {{
+            inference_bbr on;
+            inference_max_body_size 64;
+            proxy_pass http://{echo};
+        }}
+    }}
+}}
+"#,
+        module = module_path().display(),
+        prefix = prefix.display(),
+        nginx_port = nginx_port,
+        mock_port = mock_port,
+        echo = echo,
+        http_extra = http_extra,
+    )
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .map(|a| a.port())
+        .expect("failed to allocate a port")
+}
+
+fn wait_for_port(port: u16) {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while Instant::now() < deadline {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("timed out waiting for 127.0.0.1:{}", port);
+}
+
+/// Minimal upstream that answers every request with its headers as JSON, using the same
+/// `{"request": {"headers": {...}}}` shape as the docker echo server.
+fn spawn_echo_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind echo upstream");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || echo_one(stream));
+        }
+    });
+    addr
+}
+
+fn echo_one(stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+    let mut reader = BufReader::new(stream);
+    let mut headers = serde_json::Map::new();
+    let mut content_length = 0usize;
+    let mut line = String::new();
+    // Skip the request line
+    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+        return;
+    }
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let trimmed = line.trim_end();
+        if trimmed.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = trimmed.split_once(':') {
+            let name = name.trim().to_ascii_lowercase();
+            let value = value.trim().to_string();
+            if name == "content-length" {
+                content_length = value.parse().unwrap_or(0);
+            }
+            headers.insert(name, serde_json::Value::String(value));
+        }
+    }
+    let mut body = vec![0u8; content_length];
+    let _ = reader.read_exact(&mut body);
+
+    let payload = serde_json::json!({ "request": { "headers": headers } }).to_string();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        payload.len(),
+        payload
+    );
+    let mut stream = reader.into_inner();
+    let _ = stream.write_all(response.as_bytes());
+}
+
+fn echoed_header(body: &str, name: &str) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_str(body).ok()?;
+    json.get("request")?
+        .get("headers")?
+        .get(name)?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Value of header `name` (case-insensitive) in a response status line and headers.
+fn response_header(head: &str, name: &str) -> Option<String> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().to_string())
+    })
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_and_epp_select_upstream_and_set_model_header() {
+    let h = Harness::start("bbr-epp");
+    let (status, body) = h.post(
+        "/v1/chat/completions",
+        r#"{"model": "llama-3-8b", "messages": [{"role": "user", "content": "hi"}]}"#,
+    );
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b")
+    );
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_before_bbr_reuses_body() {
+    let h = Harness::start("epp-first");
+    let request = r#"{"model": "llama-3-8b", "messages": [{"role": "user", "content": "hi"}]}"#;
+    let (status, body) = h.post("/epp-first", request);
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    // EPP read the body first; BBR extracted the model from the same body
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b")
+    );
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+    assert_eq!(
+        echoed_header(&body, "x-bbr-body-size"),
+        Some(request.len().to_string())
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_uses_default_model_when_body_has_none() {
+    let h = Harness::start("bbr-default");
+    let (status, body) = h.post("/v1/chat/completions", r#"{"prompt": "hello"}"#);
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("default-model")
+    );
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_existing_model_header_skip_vs_overwrite() {
+    let h = Harness::start("bbr-overwrite");
+    let placeholder = [("X-Gateway-Model-Name", "placeholder")];
+    let body = r#"{"model": "llama-3-8b"}"#;
+
+    // Default: BBR skips and the gateway's value reaches the upstream
+    let (status, _, echoed) = h.post_with_head("/bbr-limit", &placeholder, body, Duration::ZERO);
+    assert_eq!(
+        status,
+        200,
+        "body: {}\nerror.log:\n{}",
+        echoed,
+        h.error_log()
+    );
+    assert_eq!(
+        echoed_header(&echoed, "x-gateway-model-name").as_deref(),
+        Some("placeholder")
+    );
+
+    // inference_bbr_overwrite on: the model from the body replaces it
+    let (status, _, echoed) =
+        h.post_with_head("/bbr-overwrite", &placeholder, body, Duration::ZERO);
+    assert_eq!(
+        status,
+        200,
+        "body: {}\nerror.log:\n{}",
+        echoed,
+        h.error_log()
+    );
+    assert_eq!(
+        echoed_header(&echoed, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b")
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_upstream_model_header() {
+    let h = Harness::start("upstream-model-header");
+
+    // The model BBR extracted, after the alias, under the backend's header name
+    let (status, body) = h.post(
+        "/upstream-model-header",
+        r#"{"model": "openai/Llama-3-8B"}"#,
+    );
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-backend-model").as_deref(),
+        Some("llama-3-8b")
+    );
+
+    // A client-supplied model header is kept as is, but the backend header is canonical
+    let (status, _, body) = h.post_with_head(
+        "/upstream-model-header",
+        &[("X-Gateway-Model-Name", "openai/Llama-3-8B")],
+        r#"{"prompt": "hi"}"#,
+        Duration::ZERO,
+    );
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("openai/Llama-3-8B")
+    );
+    assert_eq!(
+        echoed_header(&body, "x-backend-model").as_deref(),
+        Some("llama-3-8b")
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_set_response_header() {
+    let h = Harness::start("bbr-response-header");
+    let (status, head, body) = h.post_with_head(
+        "/bbr-response-header",
+        &[],
+        r#"{"model": "llama-3-8b"}"#,
+        Duration::ZERO,
+    );
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    // The upstream still gets the header, and the client sees it too
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b")
+    );
+    assert_eq!(
+        response_header(&head, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b"),
+        "{}",
+        head
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_rejects_body_over_max_size() {
+    let h = Harness::start("bbr-limit");
+    let big = format!(r#"{{"model": "m", "pad": "{}"}}"#, "x".repeat(256));
+    let (status, _) = h.post("/bbr-limit", &big);
+
+    assert_eq!(status, 413, "error.log:\n{}", h.error_log());
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_oversize_routes_to_default_upstream() {
+    let h = Harness::start("bbr-oversize");
+    let big = format!(r#"{{"model": "m", "pad": "{}"}}"#, "x".repeat(256));
+    let (status, body) = h.post("/bbr-oversize", &big);
+
+    // No 413: the default upstream gets the request, without a model header
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+    assert_eq!(echoed_header(&body, "x-gateway-model-name"), None);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_sends_body_when_requested_by_mode_override() {
+    let h = Harness::start_with_role("epp-body", "EPP_BODY");
+    let (status, body) = h.post(
+        "/v1/chat/completions",
+        r#"{"model": "llama-3-8b", "messages": [{"role": "user", "content": "hi"}]}"#,
+    );
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_sends_body_without_bbr() {
+    let h = Harness::start_with_role("epp-body-no-bbr", "EPP_BODY");
+    let request = r#"{"model": "llama-3-8b", "messages": [{"role": "user", "content": "hi"}]}"#;
+
+    // BBR is off, so EPP reads the body itself before answering the mode_override
+    let (status, body) = h.post("/epp-body-no-bbr", request);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+    assert_eq!(echoed_header(&body, "x-gateway-model-name"), None);
+
+    // The read still honors inference_max_body_size (fail-closed by default)
+    let (status, _) = h.post("/epp-body-no-bbr-limit", request);
+    assert_eq!(status, 502, "error.log:\n{}", h.error_log());
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_zero_timeout() {
+    // Without a request deadline the call may take up to the 5s cap, far more than the mock needs
+    let h = Harness::start_with_mock_env("epp-zero-timeout", &[("MOCK_DELAY_MS", "300")]);
+    let (status, body) = h.post("/epp-zero-timeout", r#"{"model": "m"}"#);
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+    assert!(
+        !h.error_log().contains("timeout exceeded"),
+        "error.log:\n{}",
+        h.error_log()
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_no_header_continue() {
+    let h = Harness::start_with_role("no-header-continue", "EPP_NO_HEADER");
+    let (status, body) = h.post("/no-header-continue", r#"{"model": "m"}"#);
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(echoed_header(&body, "x-inference-upstream"), None);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_no_header_default() {
+    let h = Harness::start_with_role("no-header-default", "EPP_NO_HEADER");
+    let (status, body) = h.post("/no-header-default", r#"{"model": "m"}"#);
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_no_header_error() {
+    let h = Harness::start_with_role("no-header-error", "EPP_NO_HEADER");
+    let (status, _) = h.post("/no-header-error", r#"{"model": "m"}"#);
+
+    assert_eq!(status, 502, "error.log:\n{}", h.error_log());
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_request_deadline_exceeded_by_slow_epp() {
+    let h = Harness::start_with_mock_env("deadline-epp", &[("MOCK_DELAY_MS", "1000")]);
+    let started = Instant::now();
+    let (status, _) = h.post("/deadline", r#"{"model": "m"}"#);
+
+    assert_eq!(status, 504, "error.log:\n{}", h.error_log());
+    // Well before the 5s EPP timeout
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(h.error_log().contains("request deadline exceeded"));
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_stage_timing_variables_split_body_read_and_epp() {
+    let h = Harness::start_with_mock_env("stage-timing", &[("MOCK_DELAY_MS", "300")]);
+    let (status, body) = h.post_slow(
+        "/v1/chat/completions",
+        r#"{"model": "llama-3-8b", "messages": []}"#,
+        Duration::from_millis(300),
+    );
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    let ms = |name| {
+        echoed_header(&body, name)
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or_else(|| panic!("missing {}: {}", name, body))
+    };
+    // The slow client shows up in the body read, the slow EPP in the RPC time
+    assert!(ms("x-bbr-ms") >= 250, "body: {}", body);
+    assert!(ms("x-epp-rpc-ms") >= 250, "body: {}", body);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_request_deadline_exceeded_by_slow_body() {
+    let h = Harness::start("deadline-body");
+    let (status, _) = h.post_slow(
+        "/deadline",
+        r#"{"model": "llama-3-8b", "messages": []}"#,
+        Duration::from_millis(600),
+    );
+
+    assert_eq!(status, 504, "error.log:\n{}", h.error_log());
+    assert!(h.error_log().contains("exceeded while reading body"));
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_request_within_deadline_succeeds() {
+    let h = Harness::start("deadline-ok");
+    let (status, body) = h.post("/deadline", r#"{"model": "llama-3-8b"}"#);
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_upstream_header_stripped_before_proxying() {
+    let h = Harness::start("strip");
+    let (status, body) = h.post("/strip", r#"{"model": "m"}"#);
+
+    // $inference_upstream still routed the request to the EPP-selected echo upstream
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(echoed_header(&body, "x-inference-upstream"), None);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_grpc_compression_gzip() {
+    let h = Harness::start_with_mock_env("gzip", &[("MOCK_GZIP", "1")]);
+    let (status, body) = h.post("/gzip", r#"{"model": "m"}"#);
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_body_size_variable() {
+    let h = Harness::start("bbr-body-size");
+    let request = r#"{"model": "llama-3-8b"}"#;
+    let (status, body) = h.post("/v1/chat/completions", request);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-bbr-body-size"),
+        Some(request.len().to_string())
+    );
+
+    // BBR is off for this location
+    let (status, body) = h.post("/no-header-continue", request);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-bbr-body-size").as_deref(),
+        Some("0")
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_upstream_allowlist() {
+    let h = Harness::start("upstream-allow");
+
+    // The mock EPP selects the echo upstream, which is on the list
+    let (status, body) = h.post("/upstream-allowed", r#"{"model": "llama-3-8b"}"#);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+
+    // Not on the list: fail-closed with the EPP failure status
+    let (status, _) = h.post("/upstream-not-allowed", r#"{"model": "llama-3-8b"}"#);
+    assert_eq!(status, 502, "error.log:\n{}", h.error_log());
+    let log = h.error_log();
+    let warning = format!(
+        "EPP selected upstream '{}' not in inference_upstream_allow",
+        h.echo_addr
+    );
+    assert!(
+        log.lines()
+            .any(|line| line.contains("[warn]") && line.contains(&warning)),
+        "{}",
+        log
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_body_spilled_to_temp_file() {
+    let h = Harness::start("body-spill");
+
+    let (status, body) = h.post("/body-spill", r#"{"model": "llama-3-8b"}"#);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(echoed_header(&body, "x-body-spilled").as_deref(), Some("0"));
+    assert!(!h.error_log().contains("temp file"), "{}", h.error_log());
+
+    // Larger than client_body_buffer_size: BBR reads the model from the file-backed buffer
+    let request = format!(
+        r#"{{"model": "llama-3-8b", "prompt": "{}"}}"#,
+        "x".repeat(8 * 1024)
+    );
+    let (status, body) = h.post("/body-spill", &request);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(echoed_header(&body, "x-body-spilled").as_deref(), Some("1"));
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b")
+    );
+    assert_eq!(
+        echoed_header(&body, "x-bbr-body-size"),
+        Some(request.len().to_string())
+    );
+    let log = h.error_log();
+    assert!(
+        log.contains(&format!(
+            "request body read from a {} byte temp file",
+            request.len()
+        )),
+        "{}",
+        log
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_model_from_path_regex() {
+    let h = Harness::start("model-path-regex");
+    let (status, body) = h.post(
+        "/models/llama-3-8b/completions",
+        r#"{"model": "from-body"}"#,
+    );
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b")
+    );
+    // The path matched, so the body was never read
+    assert_eq!(
+        echoed_header(&body, "x-bbr-body-size").as_deref(),
+        Some("0")
+    );
+
+    // No match falls back to the body
+    let (status, body) = h.post("/models/", r#"{"model": "from-body"}"#);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("from-body")
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_version_variable() {
+    let h = Harness::start("version");
+    let (status, body) = h.post("/version", "");
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(body, ngx_inference::module_version());
+    assert!(body.starts_with(env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_empty_body_default() {
+    let h = Harness::start("empty-body-default");
+    let (status, body) = h.post("/empty-body-default", "");
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("default-model")
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_empty_body_skip() {
+    let h = Harness::start("empty-body-skip");
+    let (status, body) = h.post("/empty-body-skip", "");
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(echoed_header(&body, "x-gateway-model-name"), None);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_empty_body_reject() {
+    let h = Harness::start("empty-body-reject");
+    let (status, body) = h.post("/empty-body-reject", "");
+
+    assert_eq!(status, 400, "body: {}\nerror.log:\n{}", body, h.error_log());
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_on_parse_error() {
+    let h = Harness::start("parse-error");
+
+    // Malformed JSON: no model header with skip, 400 with reject
+    let (status, body) = h.post("/parse-error-skip", r#"{"model": "llama"#);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(echoed_header(&body, "x-gateway-model-name"), None);
+
+    let (status, body) = h.post("/parse-error-reject", "not json");
+    assert_eq!(status, 400, "body: {}\nerror.log:\n{}", body, h.error_log());
+
+    // Valid JSON without a model still gets the default model
+    for path in ["/parse-error-skip", "/parse-error-reject"] {
+        let (status, body) = h.post(path, r#"{"prompt": "hi"}"#);
+        assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+        assert_eq!(
+            echoed_header(&body, "x-gateway-model-name").as_deref(),
+            Some("default-model")
+        );
+    }
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_parse_prefix_bytes() {
+    let h = Harness::start("parse-prefix");
+    let padding = "x".repeat(64 * 1024);
+
+    // The model is within the first 64 bytes; the whole body still reaches the upstream
+    let request = format!(r#"{{"model": "llama-3-8b", "prompt": "{}"}}"#, padding);
+    let (status, body) = h.post("/parse-prefix", &request);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b")
+    );
+    assert_eq!(
+        echoed_header(&body, "content-length"),
+        Some(request.len().to_string())
+    );
+
+    // A model past the prefix is not seen
+    let request = format!(r#"{{"prompt": "{}", "model": "llama-3-8b"}}"#, padding);
+    let (status, body) = h.post("/parse-prefix", &request);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("default-model")
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_force_upstream_skips_epp() {
+    let h = Harness::start("force-upstream");
+    let (status, body) = h.post("/force-upstream", r#"{"model": "llama-3-8b"}"#);
+
+    // Routed to the forced upstream without calling the dead EPP; BBR still runs
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b")
+    );
+    assert!(
+        h.error_log().contains("inference_force_upstream in effect"),
+        "error.log:\n{}",
+        h.error_log()
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_forced_upstream_header_stripped_without_epp() {
+    let h = Harness::start("force-upstream-strip");
+    let (status, body) = h.post("/force-upstream-strip", r#"{"model": "m"}"#);
+
+    // $inference_upstream still routed to the forced upstream, without the header
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(echoed_header(&body, "x-inference-upstream"), None);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_bbr_model_template() {
+    let h = Harness::start("model-template");
+
+    let cases = [
+        (
+            r#"{"provider": "openai", "model": "gpt-4"}"#,
+            "openai/gpt-4",
+        ),
+        // Missing components substitute empty
+        (r#"{"model": "gpt-4"}"#, "/gpt-4"),
+        (r#"{"provider": "openai"}"#, "openai/"),
+        // None of the fields: the default model
+        (r#"{"prompt": "hi"}"#, "default-model"),
+    ];
+    for (request, expected) in cases {
+        let (status, body) = h.post("/model-template", request);
+        assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+        assert_eq!(
+            echoed_header(&body, "x-gateway-model-name").as_deref(),
+            Some(expected),
+            "request: {}",
+            request
+        );
+    }
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_handler_off_keeps_upstream_variable() {
+    let h = Harness::start_with_http("handler-off", "inference_handler off;");
+
+    // No access handler: BBR never runs, so no model header reaches the upstream
+    let (status, body) = h.post("/parse-prefix", r#"{"model": "llama-3-8b"}"#);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(echoed_header(&body, "x-gateway-model-name"), None);
+
+    // $inference_upstream still resolves from a header set in front of nginx
+    let (status, _, body) = h.post_with_head(
+        "/unresolved",
+        &[("X-Inference-Upstream", "10.0.0.1:8000")],
+        "{}",
+        Duration::ZERO,
+    );
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(body, "[10.0.0.1:8000]");
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_model_route_table_without_epp() {
+    let h = Harness::start("route-table");
+    let (status, body) = h.post("/route-table", r#"{"model": "llama-3-8b"}"#);
+
+    // Routed to the echo upstream by the static table; no EPP call involved
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        echoed_header(&body, "x-gateway-model-name").as_deref(),
+        Some("llama-3-8b")
+    );
+    assert_eq!(echoed_header(&body, "x-inference-upstream"), None);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_log_decisions_level() {
+    let h = Harness::start("log-decisions");
+
+    // error_log is at info, so only the level of the decision line decides
+    let (status, body) = h.post("/decisions-off", r#"{"model": "llama-3-8b"}"#);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert!(
+        !h.error_log().contains("routing decision"),
+        "error.log:\n{}",
+        h.error_log()
+    );
+
+    let (status, body) = h.post("/decisions-info", r#"{"model": "llama-3-8b"}"#);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert!(
+        h.error_log().contains(&format!(
+            "routing decision: model='llama-3-8b' upstream='{}' source=model-route",
+            h.echo_addr
+        )),
+        "error.log:\n{}",
+        h.error_log()
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_failure_status_configurable() {
+    // EPP_NO_HEADER with the default on_no_header error is an EPP failure
+    let h = Harness::start_with_role("failure-status", "EPP_NO_HEADER");
+    let (status, body) = h.post("/failure-status", r#"{"model": "m"}"#);
+
+    assert_eq!(status, 503, "body: {}\nerror.log:\n{}", body, h.error_log());
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_timeout_status_configurable() {
+    let h = Harness::start_with_mock_env("timeout-status", &[("MOCK_DELAY_MS", "1000")]);
+    let (status, body) = h.post("/failure-status", r#"{"model": "m"}"#);
+
+    assert_eq!(status, 599, "body: {}\nerror.log:\n{}", body, h.error_log());
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_auth_request_subrequest_not_processed() {
+    let h = Harness::start("auth-sub");
+    let (status, body) = h.post("/auth-sub", r#"{"model": "llama-3-8b"}"#);
+
+    // The auth subrequest reaches the backend because the module skipped it
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert!(!h.error_log().contains("EPP failed"), "{}", h.error_log());
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_auth_request_subrequest_processed_when_enabled() {
+    let h = Harness::start("auth-sub-processed");
+    let (status, body) = h.post("/auth-sub-processed", r#"{"model": "llama-3-8b"}"#);
+
+    // The subrequest runs EPP with its own context, not the routed main request's: the dead
+    // EPP fails it closed, and auth_request turns that into a 500
+    assert_eq!(status, 500, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert!(h.error_log().contains("EPP failed"), "{}", h.error_log());
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_inference_off_skips_module() {
+    let h = Harness::start("inference-off");
+    let (status, body) = h.post("/inference-off", r#"{"model": "llama-3-8b"}"#);
+
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    // Neither BBR nor EPP ran, and the body was never read by the module
+    assert_eq!(
+        echoed_header(&body, "x-bbr-body-size").as_deref(),
+        Some("0")
+    );
+    assert_eq!(echoed_header(&body, "x-gateway-model-name"), None);
+    assert_eq!(echoed_header(&body, "x-inference-upstream"), None);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_without_endpoint_rejected_at_startup() {
+    let (ok, output) = nginx_config_check(
+        "epp-no-endpoint",
+        "server { listen 127.0.0.1:1; location /v1/ { inference_epp on; } }",
+    );
+    assert!(!ok, "nginx -t accepted the config:\n{}", output);
+    assert!(
+        output.contains("`inference_epp on` requires `inference_epp_endpoint`"),
+        "{}",
+        output
+    );
+
+    // An endpoint inherited from the server level is enough
+    let (ok, output) = nginx_config_check(
+        "epp-server-endpoint",
+        "server { listen 127.0.0.1:1; inference_epp_endpoint \"127.0.0.1:9002\"; \
+         location /v1/ { inference_epp on; inference_epp_tls off; } }",
+    );
+    assert!(ok, "{}", output);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_internal_redirect_runs_epp_once() {
+    let h = Harness::start("redirect-once");
+    let (status, body) = h.post("/redirect-once", r#"{"model": "llama-3-8b"}"#);
+
+    // The redirect target proxies to the upstream EPP picked before the redirect
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        h.error_log().matches("source=epp").count(),
+        1,
+        "error.log:\n{}",
+        h.error_log()
+    );
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_send_headers_off_forwards_no_client_headers() {
+    // The mock returns no upstream (a 502 here) when it sees X-Tenant-Secret
+    let h = Harness::start_with_mock_env(
+        "epp-no-headers",
+        &[
+            ("MOCK_ROLE", "EPP"),
+            ("MOCK_FORBID_HEADER", "x-tenant-secret"),
+        ],
+    );
+    let headers = [("X-Tenant-Secret", "s3cr3t")];
+    let body = r#"{"model": "llama-3-8b"}"#;
+
+    let (status, _, _) = h.post_with_head("/strip", &headers, body, Duration::ZERO);
+    assert_eq!(status, 502, "error.log:\n{}", h.error_log());
+
+    let (status, _, body) = h.post_with_head("/epp-no-headers", &headers, body, Duration::ZERO);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_body_buffered_before_handler() {
+    let h = Harness::start("epp-buffered-body");
+
+    // The body arrives with the headers, so nginx reads it synchronously; the request must be
+    // released exactly once, whether the EPP call succeeds or fails closed
+    for _ in 0..3 {
+        let (status, body) = h.post_buffered("/strip", r#"{"model": "llama-3-8b"}"#);
+        // Routed through $inference_upstream to the EPP-selected echo upstream
+        assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    }
+    let (status, _) = h.post_buffered("/upstream-not-allowed", r#"{"model": "llama-3-8b"}"#);
+    assert_eq!(status, 502, "error.log:\n{}", h.error_log());
+
+    let log = h.error_log();
+    assert!(
+        !log.contains("request count is zero"),
+        "error.log:\n{}",
+        log
+    );
+    assert!(!log.contains("[alert]"), "error.log:\n{}", log);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_body_send_mode() {
+    let h = Harness::start_with_role("epp-body-send-mode", "EPP_BODY");
+    // 40k of content: three 16k chunks when streamed
+    let request = format!(
+        r#"{{"model": "llama-3-8b", "messages": [{{"role": "user", "content": "{}"}}]}}"#,
+        "a".repeat(40 * 1024)
+    );
+
+    // The mock reports how many body messages it received
+    for (path, chunks) in [("/epp-body-buffered", "1"), ("/epp-body-streamed", "3")] {
+        let (status, head, body) = h.post_with_head(path, &[], &request, Duration::ZERO);
+        assert_eq!(
+            status,
+            200,
+            "{}: {}\nerror.log:\n{}",
+            path,
+            body,
+            h.error_log()
+        );
+        assert_eq!(
+            response_header(&head, "x-mock-body-chunks").as_deref(),
+            Some(chunks),
+            "{}: {}",
+            path,
+            head
+        );
+    }
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_client_response_header() {
+    let h = Harness::start_with_mock_env(
+        "epp-client-header",
+        &[
+            ("MOCK_ROLE", "EPP"),
+            ("MOCK_EXTRA_HEADER", "x-served-model:llama-3-8b-a"),
+        ],
+    );
+    let request = r#"{"model": "llama-3-8b"}"#;
+
+    // The configured EPP header reaches the client; the upstream header does not
+    let (status, head, body) = h.post_with_head("/epp-client-header", &[], request, Duration::ZERO);
+    assert_eq!(status, 200, "body: {}\nerror.log:\n{}", body, h.error_log());
+    assert_eq!(
+        response_header(&head, "x-served-model").as_deref(),
+        Some("llama-3-8b-a"),
+        "{}",
+        head
+    );
+    assert_eq!(
+        response_header(&head, "x-inference-upstream"),
+        None,
+        "{}",
+        head
+    );
+
+    // Locations without the directive leave the response alone
+    let (status, head, _) = h.post_with_head("/strip", &[], request, Duration::ZERO);
+    assert_eq!(status, 200, "error.log:\n{}", h.error_log());
+    assert_eq!(response_header(&head, "x-served-model"), None, "{}", head);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_endpoint_from_variable() {
+    let h = Harness::start("epp-endpoint-var");
+    let body = r#"{"model": "llama-3-8b"}"#;
+
+    // The map resolves the endpoint to the mock EPP
+    let (status, _, response) = h.post_with_head(
+        "/epp-endpoint-var",
+        &[("X-Tenant", "acme")],
+        body,
+        Duration::ZERO,
+    );
+    assert_eq!(
+        status,
+        200,
+        "body: {}\nerror.log:\n{}",
+        response,
+        h.error_log()
+    );
+    assert_eq!(
+        echoed_header(&response, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+
+    // An empty endpoint skips EPP for this request
+    let (status, _, response) = h.post_with_head(
+        "/epp-endpoint-var",
+        &[("X-Tenant", "none")],
+        body,
+        Duration::ZERO,
+    );
+    assert_eq!(
+        status,
+        200,
+        "body: {}\nerror.log:\n{}",
+        response,
+        h.error_log()
+    );
+    assert_eq!(echoed_header(&response, "x-inference-upstream"), None);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_epp_endpoint_map_per_path() {
+    let h = Harness::start("epp-endpoint-map");
+    let body = r#"{"model": "llama-3-8b"}"#;
+
+    // This path maps to the mock EPP, which picks the echo upstream
+    let (status, response) = h.post("/epp-endpoint-map/live", body);
+    assert_eq!(
+        status,
+        200,
+        "body: {}\nerror.log:\n{}",
+        response,
+        h.error_log()
+    );
+    assert_eq!(
+        echoed_header(&response, "x-inference-upstream"),
+        Some(h.echo_addr.to_string())
+    );
+
+    // This one maps to a dead endpoint; fail-open passes the request on without a pick
+    let (status, response) = h.post("/epp-endpoint-map/dead", body);
+    assert_eq!(
+        status,
+        200,
+        "body: {}\nerror.log:\n{}",
+        response,
+        h.error_log()
+    );
+    assert_eq!(echoed_header(&response, "x-inference-upstream"), None);
+}
+
+#[test]
+#[ignore = "requires nginx and a built module (NGINX_BIN, NGX_INFERENCE_MODULE)"]
+fn test_upstream_unresolved_value() {
+    let h = Harness::start("upstream-unresolved");
+    let body = r#"{"model": "llama-3-8b"}"#;
+
+    // No header, route or default upstream: the variable is not found
+    let (status, response) = h.post("/unresolved", body);
+    assert_eq!(status, 200, "error.log:\n{}", h.error_log());
+    assert_eq!(response, "[]");
+
+    // With the fallback set it resolves to the configured value
+    let (status, response) = h.post("/unresolved-fallback", body);
+    assert_eq!(status, 200, "error.log:\n{}", h.error_log());
+    assert_eq!(response, "[blackhole]");
+}